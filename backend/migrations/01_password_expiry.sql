@@ -0,0 +1,6 @@
+-- Password rotation tracking --
+ALTER TABLE users ADD COLUMN password_changed_at TIMESTAMP; -- NULL if never set --
+ALTER TABLE users ADD COLUMN must_change_password BOOL NOT NULL DEFAULT FALSE;
+
+-- Count existing passwords as changed when this was applied --
+UPDATE users SET password_changed_at = NOW() WHERE pass IS NOT NULL;
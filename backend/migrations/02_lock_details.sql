@@ -0,0 +1,3 @@
+-- Optional expiry and reason for account locks --
+ALTER TABLE users ADD COLUMN locked_until TIMESTAMP; -- If NULL the lock never expires --
+ALTER TABLE users ADD COLUMN locked_reason TEXT;
@@ -0,0 +1,7 @@
+-- Soft deletion of users --
+ALTER TABLE users ADD COLUMN deleted_at TIMESTAMP; -- If set the user is deleted --
+
+-- Remove sessions with their user, so deleted users can be purged --
+ALTER TABLE sessions DROP CONSTRAINT sessions_userid_fkey;
+ALTER TABLE sessions ADD CONSTRAINT sessions_userid_fkey
+  FOREIGN KEY (userid) REFERENCES users ON DELETE CASCADE;
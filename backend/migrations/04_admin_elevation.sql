@@ -0,0 +1,2 @@
+-- Admin rights require a recent password confirmation in the session --
+ALTER TABLE sessions ADD COLUMN elevated_until TIMESTAMP; -- NULL if never elevated --
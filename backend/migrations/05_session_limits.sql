@@ -0,0 +1,2 @@
+-- Per user override of the concurrent session limit --
+ALTER TABLE users ADD COLUMN max_sessions INTEGER; -- If NULL the limit for the role applies --
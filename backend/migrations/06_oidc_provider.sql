@@ -0,0 +1,23 @@
+-- Applications that may use this service for login through OpenID Connect --
+CREATE TABLE oidc_clients(
+  id SERIAL PRIMARY KEY,
+  client_id TEXT NOT NULL UNIQUE,
+  name TEXT NOT NULL,
+  secret TEXT, -- Hashed like passwords, if NULL the client is public (PKCE only) --
+  redirect_uris TEXT[] NOT NULL
+);
+
+-- Authorization codes, exchanged once for tokens at the token endpoint --
+CREATE TABLE oidc_codes(
+  code TEXT PRIMARY KEY,
+  clientid INTEGER NOT NULL REFERENCES oidc_clients ON DELETE CASCADE,
+  userid INTEGER NOT NULL REFERENCES users ON DELETE CASCADE,
+  redirect_uri TEXT NOT NULL,
+  scope TEXT NOT NULL,
+  nonce TEXT,
+  code_challenge TEXT NOT NULL,
+  until TIMESTAMP NOT NULL
+);
+
+-- Access tokens are sessions belonging to a client, only valid for userinfo --
+ALTER TABLE sessions ADD COLUMN clientid INTEGER REFERENCES oidc_clients ON DELETE CASCADE;
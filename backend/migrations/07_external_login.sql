@@ -0,0 +1,23 @@
+-- Users logging in through an external OpenID Connect identity provider --
+CREATE TABLE external_identities(
+  issuer TEXT NOT NULL,
+  subject TEXT NOT NULL,
+  userid INTEGER NOT NULL REFERENCES users ON DELETE CASCADE,
+
+  PRIMARY KEY (issuer, subject)
+);
+
+-- Logins sent to the external provider, until it sends the user back --
+CREATE TABLE external_logins(
+  state TEXT PRIMARY KEY,
+  nonce TEXT NOT NULL,
+  code_verifier TEXT NOT NULL,
+  until TIMESTAMP NOT NULL
+);
+
+-- Finished logins, until the frontend redeems the ticket for the session --
+CREATE TABLE external_login_tickets(
+  ticket TEXT PRIMARY KEY,
+  sessionid INTEGER NOT NULL REFERENCES sessions ON DELETE CASCADE,
+  until TIMESTAMP NOT NULL
+);
@@ -0,0 +1,11 @@
+-- Tokens giving integrations, such as SCIM provisioning, admin access --
+CREATE TABLE api_tokens(
+  id SERIAL PRIMARY KEY,
+  name TEXT NOT NULL,
+  token_hash TEXT NOT NULL UNIQUE, -- SHA-256, since tokens are random --
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  last_used_at TIMESTAMP
+);
+
+-- The id provisioning clients know a user by --
+ALTER TABLE users ADD COLUMN external_id TEXT;
@@ -0,0 +1,19 @@
+-- Sessions deleted before expiring, whose access tokens must be refused --
+CREATE TABLE revoked_sessions(
+  sessionid INTEGER PRIMARY KEY,
+  revoked_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- Recorded by a trigger, so that every way of deleting sessions is covered --
+CREATE FUNCTION record_revoked_session() RETURNS TRIGGER AS $$
+BEGIN
+  IF OLD.until > NOW() THEN
+    INSERT INTO revoked_sessions(sessionid) VALUES(OLD.id)
+      ON CONFLICT DO NOTHING;
+  END IF;
+  RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER sessions_revoked AFTER DELETE ON sessions
+  FOR EACH ROW EXECUTE FUNCTION record_revoked_session();
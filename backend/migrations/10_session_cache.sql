@@ -0,0 +1,20 @@
+-- Notify backends caching sessions when sessions or users change --
+CREATE FUNCTION notify_session_change() RETURNS TRIGGER AS $$
+BEGIN
+  PERFORM pg_notify('session_invalidation', 'session:' || OLD.id);
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER sessions_changed AFTER UPDATE OR DELETE ON sessions
+  FOR EACH ROW EXECUTE FUNCTION notify_session_change();
+
+CREATE FUNCTION notify_user_change() RETURNS TRIGGER AS $$
+BEGIN
+  PERFORM pg_notify('session_invalidation', 'user:' || OLD.id);
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER users_changed AFTER UPDATE OR DELETE ON users
+  FOR EACH ROW EXECUTE FUNCTION notify_user_change();
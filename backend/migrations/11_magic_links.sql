@@ -0,0 +1,10 @@
+-- Where login links are sent --
+ALTER TABLE users ADD COLUMN email TEXT;
+
+-- Emailed single-use login links, by hash of the token --
+CREATE TABLE magic_links(
+  token_hash TEXT PRIMARY KEY,
+  userid INTEGER NOT NULL REFERENCES users ON DELETE CASCADE,
+  extended BOOLEAN NOT NULL,
+  until TIMESTAMP NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Profile fields users set themselves, NULL if unset --
+ALTER TABLE users ADD COLUMN display_name TEXT;
+ALTER TABLE users ADD COLUMN locale TEXT; -- BCP 47 language tag, such as en-GB --
+ALTER TABLE users ADD COLUMN timezone TEXT; -- IANA time zone name, such as Europe/Stockholm --
@@ -0,0 +1,10 @@
+-- Key-value preferences per user, namespaced by the application using them --
+CREATE TABLE preferences(
+  userid INTEGER NOT NULL REFERENCES users ON DELETE CASCADE,
+  namespace TEXT NOT NULL,
+  key TEXT NOT NULL,
+  value JSONB NOT NULL,
+  updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+
+  PRIMARY KEY (userid, namespace, key)
+);
@@ -0,0 +1,3 @@
+-- The version of the user's avatar, whose images are stored in AVATAR_DIR --
+-- NULL if the user has none --
+ALTER TABLE users ADD COLUMN avatar TEXT;
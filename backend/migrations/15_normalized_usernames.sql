@@ -0,0 +1,28 @@
+-- Usernames are compared in a normalized form, NFKC then lowercased, so that --
+-- 'Alice', 'alice' and 'ａｌｉｃｅ' are the same user --
+-- (Needs a UTF8 database, and lowercases by ICU to not depend on its locale) --
+CREATE FUNCTION normalize_username(username TEXT) RETURNS TEXT AS $$
+  SELECT lower(normalize(username, NFKC) COLLATE "und-x-icu");
+$$ LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+
+-- Usernames that are already the same once normalized have to be renamed --
+-- by hand before migrating, this lists them --
+DO $$
+DECLARE
+  conflicts TEXT;
+BEGIN
+  SELECT string_agg(duplicates, '; ') INTO conflicts FROM (
+    SELECT string_agg(id || ' ' || quote_literal(username), ', ' ORDER BY id) AS duplicates
+    FROM users GROUP BY normalize_username(username) HAVING COUNT(*) > 1
+  ) AS grouped;
+  IF conflicts IS NOT NULL THEN
+    RAISE EXCEPTION 'Usernames conflict once normalized, rename all but one of each: %', conflicts;
+  END IF;
+END;
+$$;
+
+ALTER TABLE users ADD COLUMN username_normalized TEXT NOT NULL
+  GENERATED ALWAYS AS (normalize_username(username)) STORED;
+ALTER TABLE users ADD CONSTRAINT users_username_normalized_key UNIQUE (username_normalized);
+-- Implied by the above --
+ALTER TABLE users DROP CONSTRAINT users_username_key;
@@ -0,0 +1,31 @@
+-- Organizations (tenants) that users belong to, administrated by their org --
+-- admins. Admins (users.admin) are above organizations and manage all of them --
+CREATE TABLE organizations (
+  id SERIAL PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE memberships (
+  orgid INTEGER NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+  userid INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  org_admin BOOLEAN NOT NULL DEFAULT false,
+  PRIMARY KEY (orgid, userid)
+);
+CREATE INDEX memberships_userid ON memberships(userid);
+
+-- The organization a session is scoped to, if any. Leaving it ends the session --
+ALTER TABLE sessions ADD COLUMN orgid INTEGER;
+ALTER TABLE sessions ADD FOREIGN KEY (orgid, userid)
+  REFERENCES memberships(orgid, userid) ON DELETE CASCADE;
+
+-- Becoming or ceasing to be org admin changes cached permissions --
+CREATE FUNCTION notify_membership_change() RETURNS TRIGGER AS $$
+BEGIN
+  PERFORM pg_notify('session_invalidation', 'user:' || OLD.userid);
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER memberships_changed AFTER UPDATE OR DELETE ON memberships
+  FOR EACH ROW EXECUTE FUNCTION notify_membership_change();
@@ -0,0 +1,46 @@
+-- Versioned terms of service, the latest of which users must accept --
+CREATE TABLE terms (
+  version SERIAL PRIMARY KEY,
+  text TEXT NOT NULL,
+  published_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE terms_acceptances (
+  userid INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  version INTEGER NOT NULL REFERENCES terms(version),
+  accepted_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (userid, version)
+);
+CREATE INDEX terms_acceptances_version ON terms_acceptances(version);
+
+-- If terms are published and the user hasn't accepted the latest version --
+CREATE FUNCTION must_accept_terms(userid INTEGER) RETURNS BOOLEAN AS $$
+  SELECT EXISTS(
+    SELECT 1 FROM terms WHERE version = (SELECT MAX(version) FROM terms) AND NOT EXISTS(
+      SELECT 1 FROM terms_acceptances
+      WHERE terms_acceptances.userid = must_accept_terms.userid AND
+        terms_acceptances.version = terms.version
+    )
+  );
+$$ LANGUAGE SQL STABLE STRICT;
+
+-- Publishing terms changes every session's permissions, accepting one user's --
+CREATE FUNCTION notify_terms_published() RETURNS TRIGGER AS $$
+BEGIN
+  PERFORM pg_notify('session_invalidation', 'all');
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER terms_published AFTER INSERT OR DELETE ON terms
+  FOR EACH ROW EXECUTE FUNCTION notify_terms_published();
+
+CREATE FUNCTION notify_terms_accepted() RETURNS TRIGGER AS $$
+BEGIN
+  PERFORM pg_notify('session_invalidation', 'user:' || NEW.userid);
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER terms_accepted AFTER INSERT ON terms_acceptances
+  FOR EACH ROW EXECUTE FUNCTION notify_terms_accepted();
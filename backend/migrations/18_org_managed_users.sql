@@ -0,0 +1,15 @@
+-- The users an org admin manages: members of their organization only, who --
+-- aren't admins or org admins anywhere. Others are shared with other --
+-- organizations or above the org admin, so they are left to admins --
+CREATE FUNCTION org_manages(orgid INTEGER, userid INTEGER) RETURNS BOOLEAN AS $$
+  SELECT EXISTS(
+    SELECT 1 FROM memberships
+    WHERE memberships.orgid = org_manages.orgid AND memberships.userid = org_manages.userid
+  ) AND NOT EXISTS(
+    SELECT 1 FROM memberships
+    WHERE memberships.userid = org_manages.userid AND
+      (memberships.orgid <> org_manages.orgid OR memberships.org_admin)
+  ) AND NOT EXISTS(
+    SELECT 1 FROM users WHERE users.id = org_manages.userid AND users.admin
+  );
+$$ LANGUAGE SQL STABLE STRICT;
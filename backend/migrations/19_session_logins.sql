@@ -0,0 +1,4 @@
+-- When the user logged in to create the session. NULL for sessions not --
+-- logged in by the user themself (impersonation, OIDC clients) and those --
+-- from before this was recorded --
+ALTER TABLE sessions ADD COLUMN authenticated_at TIMESTAMP;
@@ -0,0 +1,12 @@
+-- Only notify backends caching sessions of changes to what sessions are --
+-- validated with, not on every login sync or profile edit --
+DROP TRIGGER users_changed ON users;
+CREATE TRIGGER users_changed AFTER UPDATE ON users
+  FOR EACH ROW
+  WHEN ((OLD.username, OLD.admin, OLD.locked, OLD.locked_until, OLD.pass,
+      OLD.must_change_password, OLD.password_changed_at, OLD.deleted_at)
+    IS DISTINCT FROM (NEW.username, NEW.admin, NEW.locked, NEW.locked_until, NEW.pass,
+      NEW.must_change_password, NEW.password_changed_at, NEW.deleted_at))
+  EXECUTE FUNCTION notify_user_change();
+CREATE TRIGGER users_deleted AFTER DELETE ON users
+  FOR EACH ROW EXECUTE FUNCTION notify_user_change();
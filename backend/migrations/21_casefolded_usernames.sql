@@ -0,0 +1,37 @@
+-- Lowercasing alone misses case folding, 'STRASSE' and 'straße' or 'ﬁ' and --
+-- 'fi' have to be the same user as well. Full uppercasing then lowercasing --
+-- by ICU folds these, and NFKC again afterwards as for NFKC_Casefold --
+-- (Needs PostgreSQL built with ICU, for the "und-x-icu" collation) --
+DO $$
+BEGIN
+  IF NOT EXISTS (SELECT 1 FROM pg_collation WHERE collname = 'und-x-icu') THEN
+    RAISE EXCEPTION 'The "und-x-icu" collation is missing, PostgreSQL has to be built with ICU';
+  END IF;
+END;
+$$;
+
+CREATE OR REPLACE FUNCTION normalize_username(username TEXT) RETURNS TEXT AS $$
+  SELECT normalize(
+    lower(upper(normalize(username, NFKC) COLLATE "und-x-icu") COLLATE "und-x-icu"),
+    NFKC
+  );
+$$ LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+
+-- Usernames that are only now the same once normalized have to be renamed --
+-- by hand before migrating, this lists them --
+DO $$
+DECLARE
+  conflicts TEXT;
+BEGIN
+  SELECT string_agg(duplicates, '; ') INTO conflicts FROM (
+    SELECT string_agg(id || ' ' || quote_literal(username), ', ' ORDER BY id) AS duplicates
+    FROM users GROUP BY normalize_username(username) HAVING COUNT(*) > 1
+  ) AS grouped;
+  IF conflicts IS NOT NULL THEN
+    RAISE EXCEPTION 'Usernames conflict once normalized, rename all but one of each: %', conflicts;
+  END IF;
+END;
+$$;
+
+-- Stored generated columns are only recomputed when the row is updated --
+UPDATE users SET username = username;
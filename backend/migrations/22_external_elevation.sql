@@ -0,0 +1,5 @@
+-- Logins at the external provider can also confirm an admin's session, like --
+-- their password does, instead of logging in --
+ALTER TABLE external_logins
+  ADD COLUMN sessionid INTEGER REFERENCES sessions ON DELETE CASCADE,
+  ADD COLUMN started_at TIMESTAMP NOT NULL DEFAULT NOW();
@@ -0,0 +1,208 @@
+//! Short-lived signed access tokens, issued next to sessions if configured
+//!
+//! They are verified without the database, so deleting a session doesn't
+//! invalidate its tokens by itself. Deleted sessions are instead recorded
+//! by a trigger and kept in memory until their tokens would have expired.
+//! Likewise tokens issued before the latest terms were published are treated
+//! as having to accept them, until refreshed.
+
+use super::Permissions;
+use crate::Error;
+use crate::State;
+
+use chrono::{offset::Utc, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use shared_types::AccessToken;
+use std::collections::HashSet;
+
+// How often the revocation list is reloaded from the database
+const REVOCATION_REFRESH_SECONDS: u64 = 2;
+// Tokens are refused once the list hasn't been reloaded for this long, as it
+// may be missing sessions revoked since
+const REVOCATION_STALE_SECONDS: u64 = 5 * REVOCATION_REFRESH_SECONDS;
+// Marks the token as an access token, since ID tokens are signed with the same key
+const TOKEN_USE: &str = "access";
+
+#[derive(Serialize, Deserialize)]
+pub struct AccessTokenClaims {
+  pub iss: String,
+  pub aud: String,
+  pub sub: String, // The user's id
+  pub exp: i64,
+  pub iat: i64,
+  pub token_use: String,
+  pub sid: i32, // The session the token was issued for
+  pub preferred_username: String,
+  pub admin: bool,
+  pub must_change_password: bool,
+  #[serde(default)]
+  pub must_accept_terms: bool,
+  // The latest version of the terms when issued, if any
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub terms_version: Option<i32>,
+  // When admin privileges stop being elevated, if they are
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub elevated_until: Option<i64>,
+  // The organization the session is scoped to, and if administrating it
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub orgid: Option<i32>,
+  #[serde(default)]
+  pub org_admin: bool,
+}
+
+// What an access token is issued for, as read from the session
+pub struct TokenSubject {
+  pub userid: i32,
+  pub sessionid: i32,
+  pub username: String,
+  pub admin: bool,
+  pub must_change_password: bool,
+  pub must_accept_terms: bool,
+  pub terms_version: Option<i32>,
+  pub elevated_until: Option<NaiveDateTime>,
+  pub until: NaiveDateTime,
+  pub orgid: Option<i32>,
+  pub org_admin: bool,
+}
+
+// Sign a new access token for the session with the given key
+// Only sessions (not tokens) can be refreshed, and not those of OpenID clients
+pub async fn refresh(state: &'static State, key: Option<String>) -> Result<AccessToken, Error> {
+  let lifetime = match state.access_token_lifetime {
+    Some(lifetime) => lifetime,
+    None => return Err(Error::unauthorized()),
+  };
+  let subject = match key {
+    Some(key) => {
+      sqlx::query_as!(
+        TokenSubject,
+        "
+SELECT sessions.userid, sessions.id AS sessionid, username, admin,
+  (must_change_password OR COALESCE(password_changed_at < $2, false))
+    AS \"must_change_password!\",
+  must_accept_terms(users.id) AS \"must_accept_terms!\",
+  (SELECT MAX(version) FROM terms) AS terms_version,
+  sessions.elevated_until, sessions.until, sessions.orgid,
+  COALESCE(memberships.org_admin, false) AS \"org_admin!\"
+FROM sessions
+JOIN users ON sessions.userid = users.id
+LEFT JOIN memberships
+  ON memberships.orgid = sessions.orgid AND memberships.userid = sessions.userid
+WHERE sessions.key = $1 AND sessions.until > NOW() AND users.deleted_at IS NULL AND
+  sessions.clientid IS NULL
+        ",
+        key,
+        state.password_expiry_cutoff(),
+      )
+      .fetch_optional(&state.db_pool)
+      .await?
+    }
+    None => None,
+  };
+  let subject = match subject {
+    Some(subject) => subject,
+    None => return Err(Error::unauthorized()),
+  };
+  // Tokens never outlive their session
+  let now = Utc::now().naive_utc();
+  let until = (now + lifetime).min(subject.until);
+  let access_token = state.signing_key.sign(&AccessTokenClaims {
+    iss: state.oidc_issuer.clone(),
+    aud: state.oidc_issuer.clone(),
+    sub: subject.userid.to_string(),
+    exp: until.timestamp(),
+    iat: now.timestamp(),
+    token_use: TOKEN_USE.to_string(),
+    sid: subject.sessionid,
+    preferred_username: subject.username,
+    admin: subject.admin,
+    must_change_password: subject.must_change_password,
+    must_accept_terms: subject.must_accept_terms,
+    terms_version: subject.terms_version,
+    elevated_until: subject.elevated_until.map(|until| until.timestamp()),
+    orgid: subject.orgid,
+    org_admin: subject.org_admin,
+  })?;
+  Ok(AccessToken {
+    access_token,
+    until,
+  })
+}
+
+// Session keys are plain random strings, so anything with a '.' is a token
+pub fn is_access_token(key: &str) -> bool {
+  key.contains('.')
+}
+
+// Check an access token, giving the permissions it was issued with
+pub fn verify(state: &'static State, token: &str) -> Option<Permissions> {
+  state.access_token_lifetime?;
+  let claims: AccessTokenClaims = state.signing_key.verify(token)?;
+  let now = Utc::now().timestamp();
+  if claims.iss != state.oidc_issuer
+    || claims.aud != state.oidc_issuer
+    || claims.token_use != TOKEN_USE
+    || claims.exp <= now
+  {
+    return None;
+  }
+  let refreshed = *state.revocations_refreshed.read().unwrap();
+  let stale = std::time::Duration::from_secs(REVOCATION_STALE_SECONDS);
+  if refreshed.is_none_or(|refreshed| refreshed.elapsed() >= stale) {
+    return None;
+  }
+  if state.revoked_sessions.read().unwrap().contains(&claims.sid) {
+    return None;
+  }
+  // Newer terms have been published since the token was issued
+  let outdated_terms = *state.latest_terms.read().unwrap() > claims.terms_version;
+  Some(Permissions {
+    username: claims.preferred_username,
+    userid: claims.sub.parse().ok()?,
+    sessionid: claims.sid,
+    admin: claims.admin,
+    must_change_password: claims.must_change_password,
+    must_accept_terms: claims.must_accept_terms || outdated_terms,
+    elevated: claims.elevated_until.is_some_and(|until| until > now),
+    clientid: None,
+    orgid: claims.orgid,
+    org_admin: claims.org_admin,
+  })
+}
+
+// Reload the revocation list and the latest version of the terms
+async fn load_revocations(state: &'static State, lifetime: chrono::Duration) -> Result<(), Error> {
+  // Tokens for sessions revoked longer ago than their lifetime have expired
+  let cutoff = Utc::now().naive_utc() - lifetime;
+  let revoked = sqlx::query!(
+    "SELECT sessionid FROM revoked_sessions WHERE revoked_at > $1",
+    cutoff,
+  )
+  .fetch_all(&state.db_pool)
+  .await?;
+  let latest = sqlx::query!("SELECT MAX(version) AS version FROM terms")
+    .fetch_one(&state.db_pool)
+    .await?
+    .version;
+  *state.revoked_sessions.write().unwrap() = revoked
+    .into_iter()
+    .map(|row| row.sessionid)
+    .collect::<HashSet<i32>>();
+  *state.latest_terms.write().unwrap() = latest;
+  Ok(())
+}
+
+// An async task that keeps the revocation list and the latest version of the
+// terms in memory up to date
+// Runs indefinitely, failures are retried while tokens are refused as stale
+pub async fn refresh_revoked_sessions(state: &'static State, lifetime: chrono::Duration) {
+  loop {
+    match load_revocations(state, lifetime).await {
+      Ok(()) => {
+        *state.revocations_refreshed.write().unwrap() = Some(std::time::Instant::now());
+      }
+      Err(e) => eprintln!("Failed to load revoked sessions: {:?}", e),
+    }
+    tokio::time::sleep(tokio::time::Duration::from_secs(REVOCATION_REFRESH_SECONDS)).await;
+  }
+}
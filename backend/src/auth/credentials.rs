@@ -0,0 +1,257 @@
+//! Where the passwords of users are checked
+//!
+//! Either against the hashes in the database, or by binding as the user
+//! against an LDAP directory. Directory users get a shadow row in users,
+//! which sessions, locks and limits then apply to as for local users.
+
+use crate::Error;
+use crate::State;
+
+use async_trait::async_trait;
+
+// The user the credentials were valid for
+pub struct AuthenticatedUser {
+  pub id: i32,
+  pub admin: bool,
+  pub max_sessions: Option<i32>,
+  pub locked: bool,
+  pub locked_until: Option<chrono::NaiveDateTime>,
+  pub locked_reason: Option<String>,
+}
+
+#[async_trait]
+pub trait CredentialBackend: Send + Sync {
+  // Returns None if the user doesn't exist or the password is wrong
+  // Errors are only for failures, not for bad credentials
+  async fn check(
+    &self,
+    state: &'static State,
+    username: &str,
+    password: String,
+  ) -> Result<Option<AuthenticatedUser>, Error>;
+  // If passwords are checked elsewhere, so users without one here may still
+  // log in
+  fn directory(&self) -> bool {
+    false
+  }
+}
+
+// Check the password of an existing user, such as to confirm a sensitive
+// action, through the same backend as logins
+pub async fn check_user(
+  state: &'static State,
+  userid: i32,
+  password: String,
+) -> Result<Option<AuthenticatedUser>, Error> {
+  let user = sqlx::query!(
+    "SELECT username FROM users WHERE id = $1 AND deleted_at IS NULL",
+    userid,
+  )
+  .fetch_optional(&state.db_pool)
+  .await?;
+  let user = match user {
+    Some(user) => {
+      state
+        .credentials
+        .check(state, &user.username, password)
+        .await?
+    }
+    None => None,
+  };
+  // The username may have been taken over since the check started
+  Ok(user.filter(|user| user.id == userid))
+}
+
+// Check against the argon2 hashes in the database
+pub struct LocalCredentials;
+
+#[async_trait]
+impl CredentialBackend for LocalCredentials {
+  async fn check(
+    &self,
+    state: &'static State,
+    username: &str,
+    password: String,
+  ) -> Result<Option<AuthenticatedUser>, Error> {
+    // Get the user from database
+    // if none found, exit early
+    let user = match sqlx::query!(
+      "
+SELECT id, pass, admin, locked_until, locked_reason, max_sessions,
+  locked AND (locked_until IS NULL OR locked_until > NOW()) AS \"locked!\"
+FROM users WHERE username_normalized = normalize_username($1) AND deleted_at IS NULL
+      ",
+      username,
+    )
+    .fetch_optional(&state.db_pool)
+    .await?
+    {
+      Some(user) => user,
+      None => {
+        return Ok(None);
+      }
+    };
+
+    // If the password is nulled the user is deactivated
+    let passhash = match user.pass {
+      Some(x) => x,
+      None => {
+        return Ok(None);
+      }
+    };
+
+    // If there is a user we check the hash
+    let current = super::hash::is_current(&passhash);
+    let old_hash = passhash.clone();
+    match super::hash::verify(
+      &state.cpu_semaphore,
+      &state.hasher,
+      passhash,
+      password.clone(),
+    )
+    .await?
+    {
+      // Wrong password is not an error, but is is an early return
+      false => Ok(None),
+      true => {
+        // Replace imported (or outdated) hashes now that we know the password
+        // In the background, to not make such logins take longer
+        if !current {
+          tokio::task::spawn(rehash(state, user.id, old_hash, password));
+        }
+        Ok(Some(AuthenticatedUser {
+          id: user.id,
+          admin: user.admin,
+          max_sessions: user.max_sessions,
+          locked: user.locked,
+          locked_until: user.locked_until,
+          locked_reason: user.locked_reason,
+        }))
+      }
+    }
+  }
+}
+
+async fn rehash(state: &'static State, userid: i32, old_hash: String, password: String) {
+  let result: Result<(), Error> = async {
+    let hash = super::hash::hash(&state.cpu_semaphore, &state.hasher, password).await?;
+    // Unless the password was changed meanwhile
+    sqlx::query!(
+      "UPDATE users SET pass = $1 WHERE id = $2 AND pass = $3",
+      hash,
+      userid,
+      old_hash,
+    )
+    .execute(&state.db_pool)
+    .await?;
+    Ok(())
+  }
+  .await;
+  if let Err(e) = result {
+    eprintln!("Failed to rehash password of user {}: {:?}", userid, e);
+  }
+}
+
+// Check by binding as the user against an LDAP directory
+pub struct LdapCredentials {
+  // Such as ldaps://ldap.example.com
+  pub url: String,
+  // DN to bind as, with {username} replaced by the (escaped) username
+  pub user_dn: String,
+  // DN of the group (with member attributes) whose members are admin
+  // If None admin status is managed here instead
+  pub admin_group: Option<String>,
+}
+
+// Result code for a bind with the wrong password (or unknown DN)
+const LDAP_INVALID_CREDENTIALS: u32 = 49;
+
+#[async_trait]
+impl CredentialBackend for LdapCredentials {
+  fn directory(&self) -> bool {
+    true
+  }
+
+  async fn check(
+    &self,
+    state: &'static State,
+    username: &str,
+    password: String,
+  ) -> Result<Option<AuthenticatedUser>, Error> {
+    // An empty password makes an unauthenticated bind, which always succeeds
+    if password.is_empty() {
+      return Ok(None);
+    }
+    let dn = self
+      .user_dn
+      .replace("{username}", &ldap3::dn_escape(username));
+    // Round trips to the directory must fit within the login delay, so
+    // don't wait for a connection or an operation longer than that
+    let timeout = std::time::Duration::from_millis(state.login_delay);
+    let settings = ldap3::LdapConnSettings::new().set_conn_timeout(timeout);
+    let (conn, mut ldap) = ldap3::LdapConnAsync::with_settings(settings, &self.url).await?;
+    ldap3::drive!(conn);
+    let bind = ldap
+      .with_timeout(timeout)
+      .simple_bind(&dn, &password)
+      .await?;
+    if bind.rc == LDAP_INVALID_CREDENTIALS {
+      return Ok(None);
+    }
+    bind.success()?;
+    let admin = match &self.admin_group {
+      Some(group) => Some(
+        ldap
+          .with_timeout(timeout)
+          .compare(group, "member", &dn)
+          .await?
+          .equal()?,
+      ),
+      None => None,
+    };
+    ldap.unbind().await?;
+
+    // Create or sync the shadow row of the user
+    // Users deleted here stay deleted, even if still in the directory, and the
+    // reserved accounts can't be logged in as through the directory
+    let mut tx = state.db_pool.begin().await?;
+    let admins = crate::db::lock_active_admins(state, &mut tx).await?;
+    // Users new here have to follow the rules for usernames, existing ones
+    // keep their username as for renames
+    let existing = sqlx::query!(
+      "SELECT id FROM users WHERE username_normalized = normalize_username($1)",
+      username,
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    if existing.is_none() {
+      state.username_rules.verify(username)?;
+    }
+    let user = sqlx::query!(
+      "
+INSERT INTO users(username, admin) VALUES($1, COALESCE($2, false))
+ON CONFLICT (username_normalized) DO UPDATE SET admin = COALESCE($2, users.admin)
+  WHERE users.deleted_at IS NULL AND NOT (users.id = ANY($3))
+RETURNING id, admin, locked_until, locked_reason, max_sessions,
+  locked AND (locked_until IS NULL OR locked_until > NOW()) AS \"locked!\"
+      ",
+      username,
+      admin,
+      &crate::db::PROTECTED_USERIDS[..],
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    // Leaving the directory's admin group can't remove the last admin
+    // If it changed, cached sessions are invalidated by the users trigger
+    crate::db::verify_admins_remain(state, &mut tx, admins).await?;
+    tx.commit().await?;
+    Ok(user.map(|user| AuthenticatedUser {
+      id: user.id,
+      admin: user.admin,
+      max_sessions: user.max_sessions,
+      locked: user.locked,
+      locked_until: user.locked_until,
+      locked_reason: user.locked_reason,
+    }))
+  }
+}
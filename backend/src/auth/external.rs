@@ -0,0 +1,377 @@
+//! Logging in through an external OpenID Connect identity provider
+//!
+//! The provider's users are linked to rows in users by issuer and subject,
+//! and created on first login if so configured. Logging in at the provider
+//! again also confirms an admin's session, as their password does for others.
+
+use crate::Error;
+use crate::State;
+
+use chrono::{offset::Utc, Duration};
+use hyper::body::Buf;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use serde::{de::DeserializeOwned, Deserialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+// How long the user has to log in at the provider
+pub const LOGIN_LIFETIME_MINUTES: i64 = 10;
+// How long the frontend has to redeem the ticket
+const TICKET_LIFETIME_MINUTES: i64 = 1;
+// Leeway for the provider's clock, when checking when the user logged in
+const CLOCK_SKEW_SECONDS: i64 = 60;
+
+pub struct ExternalIdp {
+  pub issuer: String,
+  pub client_id: String,
+  pub client_secret: String,
+  // Claim and value that give admin rights, if roles follow the provider
+  pub admin_claim: Option<(String, String)>,
+  // If unknown users are created on their first login
+  pub provision: bool,
+  pub client: Client<HttpsConnector<HttpConnector>>,
+}
+
+// The parts of the provider's metadata we use
+#[derive(Deserialize)]
+struct ProviderMetadata {
+  issuer: String,
+  authorization_endpoint: String,
+  token_endpoint: String,
+}
+#[derive(Deserialize)]
+struct TokenResponse {
+  id_token: String,
+}
+#[derive(Deserialize)]
+struct Claims {
+  iss: String,
+  sub: String,
+  aud: serde_json::Value, // A string or a list of strings
+  exp: i64,
+  auth_time: Option<i64>,
+  nonce: Option<String>,
+  preferred_username: Option<String>,
+  #[serde(flatten)]
+  other: HashMap<String, serde_json::Value>,
+}
+
+// If the provider may be reached at the URI
+// The ID token is trusted because it comes over TLS, so only https is allowed,
+// besides plain http to this host for development.
+pub fn secure_uri(uri: &str) -> bool {
+  match uri.parse::<hyper::Uri>() {
+    Ok(uri) => match (uri.scheme_str(), uri.host()) {
+      (Some("https"), Some(_)) => true,
+      (Some("http"), Some(host)) => matches!(host, "localhost" | "127.0.0.1" | "[::1]"),
+      _ => false,
+    },
+    Err(_) => false,
+  }
+}
+
+impl ExternalIdp {
+  async fn request<T: DeserializeOwned>(&self, req: Request<Body>) -> Result<T, Error> {
+    if !secure_uri(&req.uri().to_string()) {
+      return Err(Error::external_idp(format!(
+        "Refusing insecure request to {}",
+        req.uri()
+      )));
+    }
+    let res = self
+      .client
+      .request(req)
+      .await
+      .map_err(|e| Error::external_idp(e.to_string()))?;
+    if !res.status().is_success() {
+      return Err(Error::external_idp(format!(
+        "Request failed with status {}",
+        res.status()
+      )));
+    }
+    let body = hyper::body::aggregate(res.into_body())
+      .await
+      .map_err(|e| Error::external_idp(e.to_string()))?;
+    serde_json::from_reader(body.reader()).map_err(|e| Error::external_idp(e.to_string()))
+  }
+  async fn metadata(&self) -> Result<ProviderMetadata, Error> {
+    let req = Request::get(format!("{}/.well-known/openid-configuration", self.issuer))
+      .body(Body::empty())
+      .map_err(|e| Error::external_idp(e.to_string()))?;
+    self.request(req).await
+  }
+  fn has_admin_claim(&self, claims: &Claims) -> Option<bool> {
+    let (claim, value) = self.admin_claim.as_ref()?;
+    Some(match claims.other.get(claim) {
+      Some(serde_json::Value::String(s)) => s == value,
+      Some(serde_json::Value::Array(list)) => list.iter().any(|v| v.as_str() == Some(value)),
+      _ => false,
+    })
+  }
+}
+
+fn redirect_uri(state: &State) -> String {
+  format!("{}/api/external_login/callback", state.oidc_issuer)
+}
+
+// How a login at the provider finished
+pub enum Finished {
+  // Logged in, with a ticket the frontend redeems for the created session
+  Ticket(String),
+  // Confirmed the session the login was started for
+  Elevated,
+}
+
+// Begin a login, returning the URI to send the user to and the login's state
+// If a session is given the login confirms it instead, so the provider is
+// asked to have the user log in again
+pub async fn start(
+  state: &'static State,
+  idp: &ExternalIdp,
+  sessionid: Option<i32>,
+) -> Result<(String, String), Error> {
+  let metadata = idp.metadata().await?;
+  let login_state = nanoid::nanoid!(32);
+  let nonce = nanoid::nanoid!(32);
+  let verifier = nanoid::nanoid!(64);
+  sqlx::query!(
+    "
+INSERT INTO external_logins(state, nonce, code_verifier, until, sessionid)
+VALUES($1, $2, $3, $4, $5)
+    ",
+    &login_state,
+    &nonce,
+    &verifier,
+    Utc::now().naive_utc() + Duration::minutes(LOGIN_LIFETIME_MINUTES),
+    sessionid,
+  )
+  .execute(&state.db_pool)
+  .await?;
+  let challenge =
+    base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+  let redirect_uri = redirect_uri(state);
+  let mut params = vec![
+    ("response_type", "code"),
+    ("client_id", &idp.client_id),
+    ("redirect_uri", &redirect_uri),
+    ("scope", "openid profile"),
+    ("state", &login_state),
+    ("nonce", &nonce),
+    ("code_challenge", &challenge),
+    ("code_challenge_method", "S256"),
+  ];
+  if sessionid.is_some() {
+    params.extend([("prompt", "login"), ("max_age", "0")]);
+  }
+  let query =
+    serde_urlencoded::to_string(params).map_err(|e| Error::external_idp(e.to_string()))?;
+  if !secure_uri(&metadata.authorization_endpoint) {
+    return Err(Error::external_idp(
+      "Insecure authorization endpoint".to_string(),
+    ));
+  }
+  Ok((
+    format!("{}?{}", metadata.authorization_endpoint, query),
+    login_state,
+  ))
+}
+
+// Confirm the session the login was started for, if the user logged in at the
+// provider since then as the identity linked to the session's user
+async fn elevate(
+  state: &'static State,
+  claims: &Claims,
+  sessionid: i32,
+  started_at: chrono::NaiveDateTime,
+) -> Result<(), Error> {
+  let logged_in_again = claims
+    .auth_time
+    .is_some_and(|auth_time| auth_time >= started_at.timestamp() - CLOCK_SKEW_SECONDS);
+  if !logged_in_again {
+    return Err(Error::bad_login());
+  }
+  let until = Utc::now().naive_utc() + state.admin_elevation;
+  let elevated = sqlx::query!(
+    "
+UPDATE sessions SET elevated_until = $2
+FROM external_identities JOIN users ON users.id = external_identities.userid
+WHERE sessions.id = $1 AND sessions.until > NOW() AND
+  external_identities.issuer = $3 AND external_identities.subject = $4 AND
+  external_identities.userid = sessions.userid AND users.deleted_at IS NULL AND
+  NOT (users.locked AND (users.locked_until IS NULL OR users.locked_until > NOW()))
+    ",
+    sessionid,
+    until,
+    claims.iss,
+    claims.sub,
+  )
+  .execute(&state.db_pool)
+  .await?
+  .rows_affected();
+  if elevated == 0 {
+    return Err(Error::bad_login());
+  }
+  super::session_cache::forget_session(state, sessionid);
+  Ok(())
+}
+
+// Finish a login when the provider sends the user back with a code
+pub async fn finish(
+  state: &'static State,
+  idp: &ExternalIdp,
+  code: &str,
+  login_state: &str,
+) -> Result<Finished, Error> {
+  // Deleting the login makes sure it is only finished once
+  let login = match sqlx::query!(
+    "
+DELETE FROM external_logins WHERE state = $1 AND until > NOW()
+RETURNING nonce, code_verifier, sessionid, started_at
+    ",
+    login_state,
+  )
+  .fetch_optional(&state.db_pool)
+  .await?
+  {
+    Some(login) => login,
+    None => return Err(Error::bad_login()),
+  };
+
+  // Exchange the code for the ID token
+  let metadata = idp.metadata().await?;
+  let form = serde_urlencoded::to_string([
+    ("grant_type", "authorization_code"),
+    ("code", code),
+    ("redirect_uri", &redirect_uri(state)),
+    ("client_id", &idp.client_id),
+    ("client_secret", &idp.client_secret),
+    ("code_verifier", &login.code_verifier),
+  ])
+  .map_err(|e| Error::external_idp(e.to_string()))?;
+  let req = Request::post(&metadata.token_endpoint)
+    .header("Content-Type", "application/x-www-form-urlencoded")
+    .body(form.into())
+    .map_err(|e| Error::external_idp(e.to_string()))?;
+  let tokens: TokenResponse = idp.request(req).await?;
+  // The token came directly from the provider over TLS (as request ensures),
+  // authenticated by our client secret, which OpenID Connect accepts in place
+  // of its signature
+  let claims: Claims = super::jwt::decode_unverified(&tokens.id_token)
+    .ok_or_else(|| Error::external_idp("Unreadable ID token".to_string()))?;
+  let audience_matches = match &claims.aud {
+    serde_json::Value::String(aud) => aud == &idp.client_id,
+    serde_json::Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(&idp.client_id)),
+    _ => false,
+  };
+  if claims.iss != metadata.issuer
+    || !audience_matches
+    || claims.exp < Utc::now().timestamp()
+    || claims.nonce.as_deref() != Some(login.nonce.as_str())
+  {
+    return Err(Error::external_idp("Invalid ID token claims".to_string()));
+  }
+  if let Some(sessionid) = login.sessionid {
+    elevate(state, &claims, sessionid, login.started_at).await?;
+    return Ok(Finished::Elevated);
+  }
+  let admin = idp.has_admin_claim(&claims);
+
+  // Find the linked user, or create it
+  let mut tx = state.db_pool.begin().await?;
+  let user = sqlx::query!(
+    "
+SELECT users.id, admin, max_sessions, locked_until, locked_reason,
+  locked AND (locked_until IS NULL OR locked_until > NOW()) AS \"locked!\",
+  deleted_at IS NOT NULL AS \"deleted!\"
+FROM external_identities JOIN users ON users.id = external_identities.userid
+WHERE issuer = $1 AND subject = $2
+    ",
+    claims.iss,
+    claims.sub,
+  )
+  .fetch_optional(&mut tx)
+  .await?;
+  let (userid, is_admin, max_sessions) = match user {
+    Some(user) => {
+      if user.deleted {
+        return Err(Error::bad_login());
+      }
+      if user.locked {
+        let reason = if state.show_lock_reason {
+          user.locked_reason
+        } else {
+          None
+        };
+        return Err(Error::account_locked(user.locked_until, reason));
+      }
+      // Roles follow the provider, if mapped, but not to the last admin's demotion
+      let is_admin = admin.unwrap_or(user.admin);
+      if is_admin != user.admin {
+        let admins = crate::db::lock_active_admins(state, &mut tx).await?;
+        sqlx::query!(
+          "UPDATE users SET admin = $2 WHERE id = $1",
+          user.id,
+          is_admin
+        )
+        .execute(&mut tx)
+        .await?;
+        crate::db::verify_admins_remain(state, &mut tx, admins).await?;
+        super::session_cache::forget_user(state, user.id);
+      }
+      (user.id, is_admin, user.max_sessions)
+    }
+    None if idp.provision => {
+      // Named as the provider prefers if that follows the rules for usernames,
+      // else by subject, which has to follow them then
+      let username = match &claims.preferred_username {
+        Some(preferred) if state.username_rules.verify(preferred).is_ok() => preferred,
+        _ => {
+          state.username_rules.verify(&claims.sub)?;
+          &claims.sub
+        }
+      };
+      let is_admin = admin.unwrap_or(false);
+      let created = sqlx::query!(
+        "INSERT INTO users(username, admin) VALUES($1, $2) RETURNING id",
+        username,
+        is_admin,
+      )
+      .fetch_one(&mut tx)
+      .await
+      .map_err(|e| -> Error {
+        match e {
+          sqlx::Error::Database(ref err) => match err.constraint() {
+            Some("users_username_normalized_key") => Error::username_taken(),
+            _ => e.into(),
+          },
+          _ => e.into(),
+        }
+      })?;
+      sqlx::query!(
+        "INSERT INTO external_identities(issuer, subject, userid) VALUES($1, $2, $3)",
+        claims.iss,
+        claims.sub,
+        created.id,
+      )
+      .execute(&mut tx)
+      .await?;
+      (created.id, is_admin, None)
+    }
+    None => return Err(Error::bad_login()),
+  };
+  tx.commit().await?;
+
+  let session = super::create_session(state, userid, is_admin, max_sessions, false).await?;
+  let ticket = nanoid::nanoid!(32);
+  sqlx::query!(
+    "INSERT INTO external_login_tickets(ticket, sessionid, until) VALUES($1, $2, $3)",
+    &ticket,
+    session.id,
+    Utc::now().naive_utc() + Duration::minutes(TICKET_LIFETIME_MINUTES),
+  )
+  .execute(&state.db_pool)
+  .await?;
+  Ok(Finished::Ticket(ticket))
+}
@@ -4,9 +4,12 @@
 //! provides implementations of warp::Filter
 //! that extract and validate sessions
 
-use argon2::password_hash::SaltString;
-use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::{Error as HashingError, SaltString};
+use argon2::{Argon2, Params, PasswordHash, PasswordHasher};
+use pbkdf2::Pbkdf2;
 use rand_core::OsRng;
+use scrypt::Scrypt;
+use std::convert::TryFrom;
 use tokio::sync::Semaphore;
 
 use crate::Error;
@@ -55,16 +58,66 @@ pub async fn verify(
   // If the password wasn't owned we'd need to clone that as well
 
   match tokio::task::spawn_blocking(move || {
+    // bcrypt hashes are in the older modular crypt format, not PHC
+    if is_bcrypt(&hash) {
+      return match bcrypt::verify(&password, &hash) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(HashingError::Password),
+        Err(_) => Err(HashingError::PhcStringInvalid),
+      };
+    }
     // Parse the hash into a struct which provides the configuration and salt
     // to hash the password identically
     let hash = PasswordHash::new(&hash)?;
-    // Then hash the password using the given hasher and generated salt
-    hasher.verify_password(&password.as_bytes(), &hash)
+    // Then hash the password with whichever hasher the hash is from
+    hash.verify_password(&[&hasher, &Pbkdf2, &Scrypt], &password)
   }).await
     ? // To unwrap the outer layer of this Result<Result<>>
   {
     Ok(()) => Ok(true),
-    Err(argon2::password_hash::Error::Password) => Ok(false),
+    Err(HashingError::Password) => Ok(false),
     Err(e) => Err(Error::from(e)),
   }
 }
+
+// Hashes imported from other systems may be in these formats, but new hashes
+// are always argon2
+// Not argon2 itself, since hashes here are made with SECRET_KEY as the argon2
+// secret and other systems' hashes couldn't be told apart from them
+const SUPPORTED_ALGORITHMS: [&str; 3] = ["pbkdf2-sha256", "pbkdf2-sha512", "scrypt"];
+
+// As $2b$12$ followed by 53 characters of salt and hash
+fn is_bcrypt(hash: &str) -> bool {
+  let parts: Vec<&str> = hash.split('$').collect();
+  matches!(parts.as_slice(), ["", "2a" | "2b" | "2x" | "2y", cost, rest]
+    if cost.len() == 2 && cost.parse::<u32>().is_ok_and(|cost| (4..=31).contains(&cost))
+      && rest.len() == 53)
+}
+
+// If the hash is in a format verify can check, without checking a password
+pub fn is_supported(hash: &str) -> bool {
+  if is_bcrypt(hash) {
+    return true;
+  }
+  match PasswordHash::new(hash) {
+    Ok(hash) => {
+      SUPPORTED_ALGORITHMS.contains(&hash.algorithm.as_str())
+        && hash.salt.is_some()
+        && hash.hash.is_some()
+    }
+    Err(_) => false,
+  }
+}
+
+// If the hash is made the way new hashes are, otherwise it should be
+// replaced with a new hash when the password is known
+// New hashes use the default algorithm and parameters, see init_state
+pub fn is_current(hash: &str) -> bool {
+  match PasswordHash::new(hash) {
+    Ok(hash) => {
+      hash.algorithm == argon2::Algorithm::default().ident()
+        && Params::try_from(&hash).is_ok_and(|params| params == Params::default())
+    }
+    Err(_) => false,
+  }
+}
@@ -0,0 +1,111 @@
+//! Signing and verification of JSON Web Tokens
+//!
+//! Only supports EdDSA (Ed25519), which is all we issue.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+
+fn encode(data: &[u8]) -> String {
+  base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+fn decode(data: &str) -> Option<Vec<u8>> {
+  base64::decode_config(data, base64::URL_SAFE_NO_PAD).ok()
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+  alg: String,
+  typ: String,
+  kid: String,
+}
+
+// The public key in JWK format, for publishing in a JWKS
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Jwk {
+  pub kty: String,
+  pub crv: String,
+  pub alg: String,
+  #[serde(rename = "use")]
+  pub key_use: String,
+  pub kid: String,
+  pub x: String,
+}
+impl Jwk {
+  pub fn verifying_key(&self) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = decode(&self.x)?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Jwks {
+  pub keys: Vec<Jwk>,
+}
+
+pub struct JwtKey {
+  key: SigningKey,
+  // Identifies the key, so a new key can be published alongside the old
+  kid: String,
+}
+impl JwtKey {
+  // Takes the base64 encoded 32 byte seed of the key
+  pub fn from_base64(seed: &str) -> Option<Self> {
+    let bytes: [u8; 32] = base64::decode(seed).ok()?.try_into().ok()?;
+    Some(Self::from_bytes(&bytes))
+  }
+  pub fn from_bytes(seed: &[u8; 32]) -> Self {
+    let key = SigningKey::from_bytes(seed);
+    let kid = encode(&Sha256::digest(key.verifying_key().as_bytes())[..8]);
+    Self { key, kid }
+  }
+  pub fn jwk(&self) -> Jwk {
+    Jwk {
+      kty: "OKP".to_string(),
+      crv: "Ed25519".to_string(),
+      alg: "EdDSA".to_string(),
+      key_use: "sig".to_string(),
+      kid: self.kid.clone(),
+      x: encode(self.key.verifying_key().as_bytes()),
+    }
+  }
+  pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, serde_json::Error> {
+    let header = Header {
+      alg: "EdDSA".to_string(),
+      typ: "JWT".to_string(),
+      kid: self.kid.clone(),
+    };
+    let message = format!(
+      "{}.{}",
+      encode(&serde_json::to_vec(&header)?),
+      encode(&serde_json::to_vec(claims)?),
+    );
+    let signature = self.key.sign(message.as_bytes());
+    Ok(format!("{}.{}", message, encode(&signature.to_bytes())))
+  }
+  pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Option<T> {
+    verify(&self.key.verifying_key(), token)
+  }
+}
+
+// Parse out the claims of a token without verifying its signature
+// Only for tokens received directly from their issuer over TLS
+pub fn decode_unverified<T: DeserializeOwned>(token: &str) -> Option<T> {
+  let claims = token.split('.').nth(1)?;
+  serde_json::from_slice(&decode(claims)?).ok()
+}
+
+// Verify the signature of a token and parse out its claims
+// Note that this doesn't check any claims, such as expiry
+pub fn verify<T: DeserializeOwned>(key: &VerifyingKey, token: &str) -> Option<T> {
+  let (message, signature) = token.rsplit_once('.')?;
+  let (header, claims) = message.split_once('.')?;
+  let header: Header = serde_json::from_slice(&decode(header)?).ok()?;
+  if header.alg != "EdDSA" {
+    return None;
+  }
+  let signature = Signature::from_slice(&decode(signature)?).ok()?;
+  key.verify(message.as_bytes(), &signature).ok()?;
+  serde_json::from_slice(&decode(claims)?).ok()
+}
@@ -1,5 +1,5 @@
 use crate::Error;
-use crate::State;
+use crate::{SessionLimitPolicy, State};
 
 // Time struct, for session timeout creation
 use chrono::offset::Utc;
@@ -14,17 +14,24 @@ use shared_types::{Login, Session};
 // convert it into Some session key if valid
 // if input was invalid returns none
 pub async fn login(state: &'static State, form: Login) -> Result<Option<Session>, Error> {
+  with_login_delay(state, login_inner(state, form)).await
+}
+
+// Wrap the execution of a handler in a delay
+// This should be longer than the processing time
+// so no changes in flow can affect execution time
+pub async fn with_login_delay<T>(
+  state: &'static State,
+  handler: impl std::future::Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
   use futures::{pin_mut, select, FutureExt};
   use rand::Rng;
 
-  // Wrap the execution in a delay
-  // This should be longer than the processing time
-  // so no changes in flow can affect execution time
   let delay =
     rand::thread_rng().gen_range(state.login_delay..(state.login_delay as f64 * 1.2) as u64);
   let delay = tokio::time::sleep(tokio::time::Duration::from_millis(delay)).fuse();
   // Call the inner handler
-  let res = login_inner(state, form).fuse();
+  let res = handler.fuse();
 
   // Select to receive the future which returns fastest
   pin_mut!(res);
@@ -46,14 +53,12 @@ pub async fn login(state: &'static State, form: Login) -> Result<Option<Session>
 // Using this directly will allow an attacker to see
 // if users exist, since it exits early on failure
 async fn login_inner(state: &'static State, form: Login) -> Result<Option<Session>, Error> {
-  // Get the user from database
-  // if none found, exit early
-  let user = match sqlx::query!(
-    "SELECT id,username,pass,locked,admin FROM users WHERE username = $1",
-    form.username,
-  )
-  .fetch_optional(&state.db_pool)
-  .await?
+  // Check the credentials with the configured backend
+  // if not valid, exit early
+  let user = match state
+    .credentials
+    .check(state, &form.username, form.password)
+    .await?
   {
     Some(user) => user,
     None => {
@@ -61,26 +66,79 @@ async fn login_inner(state: &'static State, form: Login) -> Result<Option<Sessio
     }
   };
 
-  // If the password is nulled the user is deactivated
-  let passhash = match user.pass {
-    Some(x) => x,
-    None => {
-      return Ok(None);
-    }
-  };
+  // Finally, check if the user account is locked (and the lock hasn't expired)
+  if user.locked {
+    let reason = if state.show_lock_reason {
+      user.locked_reason
+    } else {
+      None
+    };
+    return Err(Error::account_locked(user.locked_until, reason));
+  }
 
-  // If there is a user we check the hash
-  match super::hash::verify(&state.cpu_semaphore, &state.hasher, passhash, form.password).await? {
-    // Wrong password is not an error, but is is an early return
-    false => {
-      return Ok(None);
-    }
-    _ => (),
-  };
+  Ok(Some(
+    create_session(state, user.id, user.admin, user.max_sessions, form.extended).await?,
+  ))
+}
 
-  // Finally, check if the user account is locked
-  if user.locked {
-    return Err(Error::account_locked());
+// Create a session for an authenticated user
+// Also used by other ways of logging in, so they all give the same sessions
+pub async fn create_session(
+  state: &'static State,
+  userid: i32,
+  admin: bool,
+  max_sessions: Option<i32>,
+  extended: bool,
+) -> Result<Session, Error> {
+  // Make room for the new session within the limit on concurrent sessions, if any
+  // A limit of 0 is unlimited, whether configured or set for the user
+  let max_sessions = match max_sessions {
+    Some(max) => Some(max as i64),
+    None if admin => state.max_sessions_admin,
+    None => state.max_sessions_user,
+  }
+  .filter(|max| *max > 0);
+  // Locking the user, so concurrent logins count each other's sessions
+  let mut tx = state.db_pool.begin().await?;
+  let mut evicted = 0;
+  if let Some(max) = max_sessions {
+    sqlx::query!("SELECT id FROM users WHERE id = $1 FOR UPDATE", userid)
+      .fetch_one(&mut tx)
+      .await?;
+    match state.session_limit_policy {
+      SessionLimitPolicy::Reject => {
+        let active = sqlx::query!(
+          "
+SELECT COUNT(*) AS \"count!\" FROM sessions
+WHERE userid = $1 AND until > NOW() AND clientid IS NULL
+          ",
+          userid,
+        )
+        .fetch_one(&mut tx)
+        .await?
+        .count;
+        if active >= max {
+          return Err(Error::too_many_sessions());
+        }
+      }
+      SessionLimitPolicy::Evict => {
+        // Keep the newest live sessions, leaving room for one more
+        // Expired sessions don't count, as for Reject
+        evicted = sqlx::query!(
+          "
+DELETE FROM sessions WHERE id IN (
+  SELECT id FROM sessions WHERE userid = $1 AND until > NOW() AND clientid IS NULL
+  ORDER BY id DESC OFFSET $2
+)
+          ",
+          userid,
+          max - 1,
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+      }
+    }
   }
 
   // If we get here we should create a random key
@@ -89,30 +147,39 @@ async fn login_inner(state: &'static State, form: Login) -> Result<Option<Sessio
   let key = nanoid::nanoid!(32);
   // Also create the deadline for the session, after which it becomes invalid
   let until = Utc::now().naive_utc()
-    + if form.extended {
+    + if extended {
       Duration::days(1)
     } else {
       Duration::days(365)
     };
 
   // Make the database insert and return the session key
+  // Scoped to the user's organization if they're in exactly one, otherwise
+  // they choose which through /api/user/organization
   let ret = sqlx::query_as!(
     Session,
     "
 WITH s AS (
-  INSERT INTO sessions(userid, key, until) VALUES($1, $2, $3)
-  RETURNING id, userid, key, until
+  INSERT INTO sessions(userid, key, until, orgid, authenticated_at)
+  VALUES($1, $2, $3, (SELECT MIN(orgid) FROM memberships WHERE userid = $1 HAVING COUNT(*) = 1),
+    NOW())
+  RETURNING id, userid, key, until, orgid
 )
-SELECT s.id, s.key, users.admin AS is_admin, users.username, s.until
+SELECT s.id, s.key, users.admin AS is_admin, users.username, s.until,
+  (users.must_change_password OR COALESCE(users.password_changed_at < $4, false))
+    AS \"must_change_password!\",
+  s.orgid,
+  must_accept_terms(users.id) AS \"must_accept_terms!\"
 FROM s
 JOIN users
 ON users.id = $1
     ",
-    user.id,
+    userid,
     &key,
     &until,
+    state.password_expiry_cutoff(),
   )
-  .fetch_one(&state.db_pool)
+  .fetch_one(&mut tx)
   .await
   .map_err(|e| -> Error {
     match e {
@@ -126,22 +193,23 @@ ON users.id = $1
       _ => e.into(),
     }
   })?;
+  tx.commit().await?;
+  if evicted > 0 {
+    super::session_cache::forget_user(state, userid);
+  }
 
-  Ok(Some(ret))
+  Ok(ret)
 }
 
-// Small helper for invalidating session keys
+// Small helper for invalidating sessions
+// By id, since the session may have been authenticated by an access token
 // Note that you may need to delete it client side as well (cookies)
-pub async fn logout(state: &'static State, key: Option<String>) -> Result<(), Error> {
-  match key {
-    Some(k) => {
-      sqlx::query!("DELETE FROM sessions WHERE key = $1", k)
-        .execute(&state.db_pool)
-        .await
-        ? // Converts error if needed
-      ;
-      Ok(())
-    }
-    None => Err(Error::unauthorized()),
-  }
+pub async fn logout(state: &'static State, sessionid: i32) -> Result<(), Error> {
+  sqlx::query!("DELETE FROM sessions WHERE id = $1", sessionid)
+    .execute(&state.db_pool)
+    .await
+    ? // Converts error if needed
+  ;
+  super::session_cache::forget_session(state, sessionid);
+  Ok(())
 }
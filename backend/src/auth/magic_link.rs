@@ -0,0 +1,127 @@
+//! Passwordless login by single-use links sent by email
+//!
+//! Requesting a link behaves identically whether or not the account exists
+//! (or may log in this way), as login does. Redeeming one gives a normal
+//! session.
+
+use crate::Error;
+use crate::State;
+
+use chrono::offset::Utc;
+use chrono::Duration;
+use shared_types::{MagicLinkRequest, Session};
+
+// How long links are valid
+const MAGIC_LINK_MINUTES: i64 = 15;
+// Minimum time between links for the same user, to not flood their inbox
+const MAGIC_LINK_INTERVAL_MINUTES: i64 = 1;
+
+// If users of the role may log in by link
+fn allowed(state: &'static State, admin: bool) -> bool {
+  if admin {
+    state.magic_link_admins
+  } else {
+    state.magic_link_users
+  }
+}
+
+// Email a login link to the user, if the user exists, may log in by link
+// and has an email address. Returns the same regardless.
+pub async fn request(state: &'static State, form: MagicLinkRequest) -> Result<(), Error> {
+  super::with_login_delay(state, request_inner(state, form)).await
+}
+async fn request_inner(state: &'static State, form: MagicLinkRequest) -> Result<(), Error> {
+  let mailer = match &state.mailer {
+    Some(mailer) => mailer,
+    None => return Ok(()),
+  };
+  let user = sqlx::query!(
+    "
+SELECT id, admin, email AS \"email!\" FROM users
+WHERE username_normalized = normalize_username($1) AND deleted_at IS NULL AND email IS NOT NULL
+    ",
+    form.username,
+  )
+  .fetch_optional(&state.db_pool)
+  .await?;
+  let user = match user {
+    Some(user) if allowed(state, user.admin) => user,
+    _ => return Ok(()),
+  };
+
+  let token = nanoid::nanoid!(32);
+  let until = Utc::now().naive_utc() + Duration::minutes(MAGIC_LINK_MINUTES);
+  // Unless a link was sent very recently
+  let created = sqlx::query!(
+    "
+INSERT INTO magic_links(token_hash, userid, extended, until)
+SELECT $1, $2, $3, $4
+WHERE NOT EXISTS (SELECT 1 FROM magic_links WHERE userid = $2 AND until > $5)
+    ",
+    super::hash_token(&token),
+    user.id,
+    form.extended,
+    until,
+    until - Duration::minutes(MAGIC_LINK_INTERVAL_MINUTES),
+  )
+  .execute(&state.db_pool)
+  .await?
+  .rows_affected();
+  if created > 0 {
+    mailer.send_later(
+      &user.email,
+      "Your login link",
+      format!(
+        "Log in by opening this link:\n\n{}/#magic_link/{}\n\n\
+        It is valid for {} minutes and can only be used once.\n\
+        If you didn't ask to log in you can ignore this mail.\n",
+        state.oidc_issuer, token, MAGIC_LINK_MINUTES,
+      ),
+    );
+  }
+  Ok(())
+}
+
+// Use up a link, creating a session for its user
+// None if the link is invalid, used or expired
+pub async fn redeem(state: &'static State, token: &str) -> Result<Option<Session>, Error> {
+  let link = sqlx::query!(
+    "
+DELETE FROM magic_links WHERE token_hash = $1 AND until > NOW()
+RETURNING userid, extended
+    ",
+    super::hash_token(token),
+  )
+  .fetch_optional(&state.db_pool)
+  .await?;
+  let link = match link {
+    Some(link) => link,
+    None => return Ok(None),
+  };
+  let user = sqlx::query!(
+    "
+SELECT id, admin, locked_until, locked_reason, max_sessions,
+  locked AND (locked_until IS NULL OR locked_until > NOW()) AS \"locked!\"
+FROM users WHERE id = $1 AND deleted_at IS NULL
+    ",
+    link.userid,
+  )
+  .fetch_optional(&state.db_pool)
+  .await?;
+  // The user may have been made admin since the link was sent
+  let user = match user {
+    Some(user) if allowed(state, user.admin) => user,
+    _ => return Ok(None),
+  };
+  if user.locked {
+    let reason = if state.show_lock_reason {
+      user.locked_reason
+    } else {
+      None
+    };
+    return Err(Error::account_locked(user.locked_until, reason));
+  }
+  Ok(Some(
+    super::create_session(state, user.id, user.admin, user.max_sessions, link.extended).await?,
+  ))
+}
@@ -4,8 +4,14 @@
 //! provides implementations of warp::Filter
 //! that extract and validate sessions
 
+pub mod access_token;
+pub mod credentials;
 pub mod hash;
 pub mod session;
+pub mod session_cache;
 pub use session::*;
 pub mod login;
 pub use login::*;
+pub mod external;
+pub mod jwt;
+pub mod magic_link;
@@ -4,14 +4,40 @@ use crate::State;
 // The struct given to each handler
 // It should contain everything needed to know
 // the user and its permissions
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Permissions {
   // For use by html rendering handlers to print in top-bar
   pub username: String,
   // To identify if the current user owns a resource
   pub userid: i32,
+  // To identify the current session
+  pub sessionid: i32,
   // To identify if the current user has admin perms
   pub admin: bool,
+  // If set the session may only be used to change password
+  pub must_change_password: bool,
+  // If set the session may only be used to accept the latest terms
+  pub must_accept_terms: bool,
+  // If admin privileges were recently confirmed with a password
+  pub elevated: bool,
+  // Set if the session is an OpenID Connect access token for a client
+  // Such sessions may only be used for userinfo
+  pub clientid: Option<i32>,
+  // The organization the session is scoped to, if any
+  pub orgid: Option<i32>,
+  // If the user administrates that organization
+  pub org_admin: bool,
+}
+impl Permissions {
+  // The organization admin routes are limited to, None if unlimited
+  // Admins are above organizations, org admins only manage their own
+  pub fn tenant(&self) -> Option<i32> {
+    if self.admin {
+      None
+    } else {
+      self.orgid
+    }
+  }
 }
 
 // An async task that clears out outdated sessions every hour
@@ -23,6 +49,37 @@ pub async fn prune_sessions(state: &'static State) {
       .execute(&state.db_pool)
       .await
       .expect("Failed to prune sessions!");
+    // Unused OpenID Connect authorization codes as well
+    sqlx::query!("DELETE FROM oidc_codes WHERE until < NOW()")
+      .execute(&state.db_pool)
+      .await
+      .expect("Failed to prune authorization codes!");
+    // And logins through an external identity provider
+    sqlx::query!("DELETE FROM external_logins WHERE until < NOW()")
+      .execute(&state.db_pool)
+      .await
+      .expect("Failed to prune external logins!");
+    sqlx::query!("DELETE FROM external_login_tickets WHERE until < NOW()")
+      .execute(&state.db_pool)
+      .await
+      .expect("Failed to prune external login tickets!");
+    // And emailed login links
+    sqlx::query!("DELETE FROM magic_links WHERE until < NOW()")
+      .execute(&state.db_pool)
+      .await
+      .expect("Failed to prune login links!");
+    // And revocations older than any access token could be
+    let revocation_cutoff = chrono::offset::Utc::now().naive_utc()
+      - state
+        .access_token_lifetime
+        .unwrap_or_else(chrono::Duration::zero);
+    sqlx::query!(
+      "DELETE FROM revoked_sessions WHERE revoked_at < $1",
+      revocation_cutoff,
+    )
+    .execute(&state.db_pool)
+    .await
+    .expect("Failed to prune revoked sessions!");
 
     // Delay for one hour before doing again
     tokio::time::sleep(tokio::time::Duration::from_secs(3600)).await;
@@ -35,19 +92,60 @@ pub async fn session(
   key: Option<String>,
 ) -> Result<Option<Permissions>, Error> {
   if let Some(key) = key {
-    let sess = sqlx::query_as!(
-      Permissions,
+    // Access tokens are checked without the database
+    if super::access_token::is_access_token(&key) {
+      return Ok(super::access_token::verify(state, &key));
+    }
+    if let Some(cached) = state
+      .session_cache
+      .as_ref()
+      .and_then(|cache| cache.get(&key))
+    {
+      return Ok(Some(cached));
+    }
+    let sess = sqlx::query!(
       "
-SELECT username, userid, admin
+SELECT username, sessions.userid, sessions.id AS sessionid, admin,
+  (must_change_password OR COALESCE(password_changed_at < $2, false))
+    AS \"must_change_password!\",
+  must_accept_terms(users.id) AS \"must_accept_terms!\",
+  COALESCE(sessions.elevated_until > NOW(), false) AS \"elevated!\",
+  sessions.clientid, sessions.until, sessions.elevated_until, sessions.orgid,
+  COALESCE(memberships.org_admin, false) AS \"org_admin!\"
 FROM sessions
 JOIN users ON sessions.userid = users.id
-WHERE sessions.key = $1 AND sessions.until > NOW()
+LEFT JOIN memberships
+  ON memberships.orgid = sessions.orgid AND memberships.userid = sessions.userid
+WHERE sessions.key = $1 AND sessions.until > NOW() AND users.deleted_at IS NULL
       ",
       key,
+      state.password_expiry_cutoff(),
     )
     .fetch_optional(&state.db_pool)
     .await?;
-    Ok(sess)
+    Ok(sess.map(|sess| {
+      let permissions = Permissions {
+        username: sess.username,
+        userid: sess.userid,
+        sessionid: sess.sessionid,
+        admin: sess.admin,
+        must_change_password: sess.must_change_password,
+        must_accept_terms: sess.must_accept_terms,
+        elevated: sess.elevated,
+        clientid: sess.clientid,
+        orgid: sess.orgid,
+        org_admin: sess.org_admin,
+      };
+      if let Some(cache) = &state.session_cache {
+        // Until the session expires, or elevation does if elevated
+        let changes_at = match sess.elevated_until {
+          Some(elevated_until) if sess.elevated => elevated_until.min(sess.until),
+          _ => sess.until,
+        };
+        cache.insert(&key, permissions.clone(), changes_at);
+      }
+      permissions
+    }))
   } else {
     Ok(None)
   }
@@ -64,7 +162,8 @@ pub async fn require_session(
     None => Err(Error::unauthorized()),
   }
 }
-// Check the required session key and error if invalid or admin
+// Check the required session key and error if invalid or not admin
+// Admins of the session's organization pass as well, see Permissions::tenant
 pub async fn require_admin(
   state: &'static State,
   key: Option<String>,
@@ -72,9 +171,49 @@ pub async fn require_admin(
   // First we require a session
   let data = require_session(state, key).await?;
   // Then, if not admin, we error
-  if data.admin {
+  if data.admin || data.org_admin {
     Ok(data)
   } else {
     Err(Error::forbidden())
   }
 }
+// Hash of a token (API token or magic link), as stored
+// Tokens are long and random, so a fast hash suffices
+pub fn hash_token(token: &str) -> String {
+  use sha2::{Digest, Sha256};
+  base64::encode_config(Sha256::digest(token.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+// Check the required API token and error if invalid
+// Returns the id of the token
+pub async fn require_api_token(state: &'static State, token: Option<String>) -> Result<i32, Error> {
+  let token = match token {
+    Some(token) => token,
+    None => return Err(Error::unauthorized()),
+  };
+  let found = sqlx::query!(
+    "UPDATE api_tokens SET last_used_at = NOW() WHERE token_hash = $1 RETURNING id",
+    hash_token(&token),
+  )
+  .fetch_optional(&state.db_pool)
+  .await?;
+  match found {
+    Some(found) => Ok(found.id),
+    None => Err(Error::unauthorized()),
+  }
+}
+// Error unless the user is admin above organizations
+pub fn require_super_admin(permissions: &Permissions) -> Result<(), Error> {
+  if permissions.admin {
+    Ok(())
+  } else {
+    Err(Error::forbidden())
+  }
+}
+// Error if admin privileges haven't been recently confirmed in the session
+pub fn require_elevation(permissions: &Permissions) -> Result<(), Error> {
+  if permissions.elevated {
+    Ok(())
+  } else {
+    Err(Error::elevation_required())
+  }
+}
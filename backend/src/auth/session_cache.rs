@@ -0,0 +1,160 @@
+//! In-memory cache of validated sessions, if configured
+//!
+//! Entries are dropped here when sessions or users change, and triggers in
+//! the database notify all instances (this one included) of such changes,
+//! so changes made elsewhere or missed here still apply within moments.
+//! The TTL bounds how stale an entry can get if notifications are lost.
+
+use super::Permissions;
+use crate::State;
+
+use chrono::{offset::Utc, NaiveDateTime};
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// The channel the database triggers notify on
+const CHANNEL: &str = "session_invalidation";
+
+fn hash_key(key: &str) -> [u8; 32] {
+  Sha256::digest(key.as_bytes()).into()
+}
+
+struct Entry {
+  permissions: Permissions,
+  expires: Instant,
+}
+
+pub struct SessionCache {
+  ttl: Duration,
+  // Keyed by hash, so that session keys aren't kept in memory
+  entries: Mutex<LruCache<[u8; 32], Entry>>,
+  // Counts of lookups answered here and from the database
+  hits: AtomicU64,
+  misses: AtomicU64,
+}
+impl SessionCache {
+  pub fn new(size: usize, ttl: Duration) -> Self {
+    Self {
+      ttl,
+      entries: Mutex::new(LruCache::new(size)),
+      hits: AtomicU64::new(0),
+      misses: AtomicU64::new(0),
+    }
+  }
+
+  pub fn get(&self, key: &str) -> Option<Permissions> {
+    let mut entries = self.entries.lock().unwrap();
+    let hash = hash_key(key);
+    let found = match entries.get(&hash) {
+      Some(entry) if entry.expires > Instant::now() => Some(entry.permissions.clone()),
+      Some(_) => {
+        entries.pop(&hash);
+        None
+      }
+      None => None,
+    };
+    match found {
+      Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+      None => self.misses.fetch_add(1, Ordering::Relaxed),
+    };
+    found
+  }
+
+  // Cache the permissions of a session until the TTL passes, or until they
+  // would change by themselves (the session or elevation expiring)
+  pub fn insert(&self, key: &str, permissions: Permissions, changes_at: NaiveDateTime) {
+    let remaining = (changes_at - Utc::now().naive_utc())
+      .to_std()
+      .unwrap_or_default();
+    let entry = Entry {
+      permissions,
+      expires: Instant::now() + remaining.min(self.ttl),
+    };
+    let hash = hash_key(key);
+    self.entries.lock().unwrap().put(hash, entry);
+  }
+
+  fn remove_where(&self, matches: impl Fn(&Permissions) -> bool) {
+    let mut entries = self.entries.lock().unwrap();
+    let hashes: Vec<[u8; 32]> = entries
+      .iter()
+      .filter(|(_, entry)| matches(&entry.permissions))
+      .map(|(hash, _)| *hash)
+      .collect();
+    for hash in hashes {
+      entries.pop(&hash);
+    }
+  }
+  pub fn forget_session(&self, sessionid: i32) {
+    self.remove_where(|permissions| permissions.sessionid == sessionid);
+  }
+  pub fn forget_user(&self, userid: i32) {
+    self.remove_where(|permissions| permissions.userid == userid);
+  }
+  pub fn clear(&self) {
+    self.entries.lock().unwrap().clear();
+  }
+
+  // Number of lookups answered from the cache and from the database
+  pub fn stats(&self) -> (u64, u64) {
+    (
+      self.hits.load(Ordering::Relaxed),
+      self.misses.load(Ordering::Relaxed),
+    )
+  }
+}
+
+// Helpers for where sessions or users change, doing nothing if not cached
+pub fn forget_session(state: &'static State, sessionid: i32) {
+  if let Some(cache) = &state.session_cache {
+    cache.forget_session(sessionid);
+  }
+}
+pub fn forget_user(state: &'static State, userid: i32) {
+  if let Some(cache) = &state.session_cache {
+    cache.forget_user(userid);
+  }
+}
+pub fn forget_all(state: &'static State) {
+  if let Some(cache) = &state.session_cache {
+    cache.clear();
+  }
+}
+
+// An async task applying changes notified by the database
+// Runs indefinitely
+pub async fn listen_for_invalidations(state: &'static State, cache: &'static SessionCache) {
+  let mut listener = sqlx::postgres::PgListener::connect_with(&state.db_pool)
+    .await
+    .expect("Failed to connect session cache listener!");
+  listener
+    .listen(CHANNEL)
+    .await
+    .expect("Failed to listen for session invalidations!");
+  loop {
+    match listener.try_recv().await {
+      // Payloads are 'session:$id' or 'user:$id', anything else ('all') clears
+      Ok(Some(notification)) => match notification.payload().split_once(':') {
+        Some(("session", id)) => match id.parse() {
+          Ok(id) => cache.forget_session(id),
+          Err(_) => cache.clear(),
+        },
+        Some(("user", id)) => match id.parse() {
+          Ok(id) => cache.forget_user(id),
+          Err(_) => cache.clear(),
+        },
+        _ => cache.clear(),
+      },
+      // Notifications may have been missed while disconnected
+      Ok(None) => cache.clear(),
+      Err(e) => {
+        eprintln!("Session cache listener failed: {}", e);
+        cache.clear();
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+      }
+    }
+  }
+}
@@ -0,0 +1,124 @@
+//! Users' profile pictures, resized to a few fixed sizes
+//!
+//! Uploads are decoded and re-encoded as PNG, so only the pixels are kept.
+//! The images are stored as files in AVATAR_DIR, named by user, version and
+//! size. The current version is kept on the user and doubles as ETag. Versions
+//! are random, so avatars can only be fetched by those shown the user.
+
+use crate::Error;
+use crate::State;
+
+use image::{imageops::FilterType, ImageError, ImageFormat, ImageReader, Limits};
+use std::io::Cursor;
+use std::path::PathBuf;
+
+// The sizes (width and height in pixels) avatars are stored in
+pub const SIZES: [u32; 2] = [64, 256];
+// Larger uploads are refused before decoding them
+const MAX_DIMENSION: u32 = 8192;
+
+pub fn path(state: &State, userid: i32, version: &str, size: u32) -> PathBuf {
+  state
+    .avatar_dir
+    .join(format!("{}_{}_{}.png", userid, version, size))
+}
+
+fn invalid_upload(e: ImageError) -> Error {
+  Error::invalid_image(&e.to_string())
+}
+
+// Decode an uploaded image and encode it in each of the sizes
+// Only failing to decode is the upload's fault, encoding is ours
+fn resize(upload: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+  let mut reader = ImageReader::new(Cursor::new(upload))
+    .with_guessed_format()
+    .map_err(|e| invalid_upload(e.into()))?;
+  match reader.format() {
+    Some(ImageFormat::Png) | Some(ImageFormat::Jpeg) | Some(ImageFormat::WebP) => (),
+    _ => return Err(Error::invalid_image("Expected a PNG, JPEG or WebP image")),
+  }
+  let mut limits = Limits::default();
+  limits.max_image_width = Some(MAX_DIMENSION);
+  limits.max_image_height = Some(MAX_DIMENSION);
+  reader.limits(limits);
+  let image = reader.decode().map_err(invalid_upload)?;
+  SIZES
+    .iter()
+    .map(|&size| {
+      // Cropped to a square around the center
+      let mut encoded = Vec::new();
+      image
+        .resize_to_fill(size, size, FilterType::Lanczos3)
+        .write_to(&mut Cursor::new(&mut encoded), ImageFormat::Png)?;
+      Ok(encoded)
+    })
+    .collect()
+}
+
+// Set the user's avatar version, returning the previous one
+async fn set_version(
+  state: &'static State,
+  userid: i32,
+  version: Option<&str>,
+) -> Result<Option<String>, Error> {
+  let mut tx = state.db_pool.begin().await?;
+  let previous = sqlx::query!(
+    "SELECT avatar FROM users WHERE id = $1 FOR NO KEY UPDATE",
+    userid
+  )
+  .fetch_optional(&mut tx)
+  .await?
+  .and_then(|user| user.avatar);
+  sqlx::query!(
+    "UPDATE users SET avatar = $2 WHERE id = $1",
+    userid,
+    version
+  )
+  .execute(&mut tx)
+  .await?;
+  tx.commit().await?;
+  Ok(previous)
+}
+
+// Remove the files of an avatar version, already gone is fine
+pub async fn remove_files(state: &'static State, userid: i32, version: &str) -> Result<(), Error> {
+  for &size in SIZES.iter() {
+    match tokio::fs::remove_file(path(state, userid, version, size)).await {
+      Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+      _ => (),
+    }
+  }
+  Ok(())
+}
+
+// Replace the user's avatar with the uploaded image
+// Returns the new version
+pub async fn store(state: &'static State, userid: i32, upload: Vec<u8>) -> Result<String, Error> {
+  let images = {
+    // Decoding and resizing are CPU-bound, so they share the semaphore with
+    // password hashing
+    let _handle = state.cpu_semaphore.acquire().await;
+    tokio::task::spawn_blocking(move || resize(&upload)).await??
+  };
+  let version = nanoid::nanoid!(22);
+  // Written before the version is set, so they exist whenever it is
+  for (&size, image) in SIZES.iter().zip(&images) {
+    tokio::fs::write(path(state, userid, &version, size), image).await?;
+  }
+  if let Some(previous) = set_version(state, userid, Some(&version)).await? {
+    remove_files(state, userid, &previous).await?;
+  }
+  Ok(version)
+}
+
+// Remove the user's avatar
+// Returns false if there was none
+pub async fn remove(state: &'static State, userid: i32) -> Result<bool, Error> {
+  match set_version(state, userid, None).await? {
+    Some(previous) => {
+      remove_files(state, userid, &previous).await?;
+      Ok(true)
+    }
+    None => Ok(false),
+  }
+}
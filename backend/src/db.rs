@@ -1,21 +1,28 @@
-use sqlx::postgres::PgPool;
+use sqlx::postgres::{PgPool, Postgres};
+use sqlx::Transaction;
 
 use crate::Error;
+use crate::{DeletedUsernamePolicy, State};
+use shared_types::{AdminReturnableUser, ImportRowReport, ImportUser};
 
 // Declare a variant sqlx macro for ORDER BY
 /// Generates a match over $matchee, where each branch contains a full query execution.
 /// For each match a different middle is inserted between qhead and qtail, but arguments unaffected.
 /// (The middle is mostly for ORDER BY and the tail is usually a secondary ORDER BY or LIMIT).
+/// Prefixed with 'stream' it instead gives a boxed stream of the rows.
 #[macro_export]
 macro_rules! sqlx_order {
+  ( stream $ret_struct:path, $db:expr ; $qhead:literal, $qtail:literal $(, $argument:expr)* $(,)? ; $matchee:expr $(; $pattern:pat , $middle:literal)* $(;)? ) => {
+    sqlx_order!( @bracketed stream $ret_struct, $db; $qhead, $qtail, [$( $argument ),*] ; $matchee $(; $pattern, $middle )* )
+  };
   ( $ret_struct:path, $db:expr ; $qhead:literal, $qtail:literal $(, $argument:expr)* $(,)? ; $matchee:expr $(; $pattern:pat , $middle:literal)* $(;)? ) => {
-    sqlx_order!( @bracketed $ret_struct, $db; $qhead, $qtail, [$( $argument ),*] ; $matchee $(; $pattern, $middle )* )
+    sqlx_order!( @bracketed inner $ret_struct, $db; $qhead, $qtail, [$( $argument ),*] ; $matchee $(; $pattern, $middle )* )
   };
-  ( @bracketed $ret_struct:path, $db:expr ; $qhead:literal, $qtail:literal, $argument:tt ; $matchee:expr $(; $pattern:pat , $middle:literal)* $(;)? ) => {
+  ( @bracketed $mode:ident $ret_struct:path, $db:expr ; $qhead:literal, $qtail:literal, $argument:tt ; $matchee:expr $(; $pattern:pat , $middle:literal)* $(;)? ) => {
     match $matchee {
       $(
       $pattern => {
-        sqlx_order!( @inner $ret_struct, $db; $qhead + $middle + $qtail, $argument )
+        sqlx_order!( @ $mode $ret_struct, $db; $qhead + $middle + $qtail, $argument )
       }
       )*
     }
@@ -25,14 +32,303 @@ macro_rules! sqlx_order {
       .fetch_all($db)
       .await?
   };
+  ( @stream $ret_struct:path, $db:expr ; $query:expr, [ $( $argument:expr),* ] ) => {
+    futures::StreamExt::boxed(
+      sqlx::query_as!( $ret_struct, $query, $( $argument ),* )
+        .fetch($db)
+    )
+  };
+}
+
+// The ids reserved for the system admin and the testing users by the first migration
+pub const PROTECTED_USERIDS: [i32; 3] = [0, -1, -2];
+
+// Error if the given user is one of the reserved accounts, which are managed
+// through configuration and testing instead of the API
+pub fn verify_not_protected(userid: i32) -> Result<(), Error> {
+  if PROTECTED_USERIDS.contains(&userid) {
+    Err(Error::protected_account())
+  } else {
+    Ok(())
+  }
+}
+
+// Lock and count the admins that can log in (excluding reserved accounts)
+// Call before changing users in a transaction and give the count to
+// verify_admins_remain before committing.
+// The reserved accounts aren't counted, since the system admin's password is
+// set from configuration on every start and the testing accounts only exist
+// for tests. Admins count if they have some way to log in: a password here, a
+// linked external identity, the LDAP directory or, if enabled, login links.
+pub async fn lock_active_admins(
+  state: &'static State,
+  tx: &mut Transaction<'_, Postgres>,
+) -> Result<usize, Error> {
+  let admins = sqlx::query!(
+    "
+SELECT id FROM users
+WHERE admin AND deleted_at IS NULL AND
+  NOT (locked AND (locked_until IS NULL OR locked_until > NOW())) AND
+  NOT (id = ANY($1)) AND
+  (pass IS NOT NULL OR $2 OR ($3 AND email IS NOT NULL) OR
+    EXISTS(SELECT 1 FROM external_identities WHERE userid = users.id))
+FOR UPDATE
+    ",
+    &PROTECTED_USERIDS[..],
+    state.credentials.directory(),
+    state.mailer.is_some() && state.magic_link_admins,
+  )
+  .fetch_all(&mut *tx)
+  .await?;
+  Ok(admins.len())
+}
+// Error if a change removed the last admin that could log in
+pub async fn verify_admins_remain(
+  state: &'static State,
+  tx: &mut Transaction<'_, Postgres>,
+  admins_before: usize,
+) -> Result<(), Error> {
+  let admins_after = lock_active_admins(state, tx).await?;
+  if admins_before > 0 && admins_after == 0 {
+    Err(Error::last_admin())
+  } else {
+    Ok(())
+  }
+}
+
+// Creates users with password hashes from another system in one
+// transaction, row by row so that imports needn't be held in memory.
+// A real import fails on the first invalid row, a dry run reports on every
+// row and creates nothing.
+// Imports by org admins create non-admin members of their organization.
+pub struct Importer {
+  state: &'static State,
+  // Begun with the first row, so it isn't held open while waiting on the body
+  tx: Option<Transaction<'static, Postgres>>,
+  dry_run: bool,
+  orgid: Option<i32>,
+  rows: usize,
+  created: Vec<AdminReturnableUser>,
+  report: Vec<ImportRowReport>,
+}
+impl Importer {
+  pub fn new(state: &'static State, dry_run: bool, orgid: Option<i32>) -> Self {
+    Self {
+      state: state,
+      tx: None,
+      dry_run: dry_run,
+      orgid: orgid,
+      rows: 0,
+      created: Vec::new(),
+      report: Vec::new(),
+    }
+  }
+
+  // Takes the row as parsed, so that parse errors are reported as well
+  pub async fn add(&mut self, user: Result<ImportUser, Error>) -> Result<(), Error> {
+    self.rows += 1;
+    let tx = match &mut self.tx {
+      Some(tx) => tx,
+      None => self.tx.insert(self.state.db_pool.begin().await?),
+    };
+    if !self.dry_run {
+      let created = insert_imported(self.state, tx, user?, self.orgid).await?;
+      self.created.push(created);
+      return Ok(());
+    }
+    let username = user.as_ref().ok().map(|user| user.username.clone());
+    // A savepoint, so a failed row doesn't abort the transaction
+    let mut savepoint = sqlx::Connection::begin(&mut **tx).await?;
+    let result = match user {
+      Ok(user) => insert_imported(self.state, &mut savepoint, user, self.orgid).await,
+      Err(e) => Err(e),
+    };
+    let error = match result {
+      Ok(_) => {
+        savepoint.commit().await?;
+        None
+      }
+      Err(Error::ClientError(e)) => {
+        savepoint.rollback().await?;
+        Some(e)
+      }
+      Err(e) => return Err(e),
+    };
+    self.report.push(ImportRowReport {
+      row: self.rows,
+      username: username,
+      error: error,
+    });
+    Ok(())
+  }
+
+  pub async fn commit(self) -> Result<Vec<AdminReturnableUser>, Error> {
+    if let Some(tx) = self.tx {
+      tx.commit().await?;
+    }
+    Ok(self.created)
+  }
+
+  // Undoes the dry run, giving the report
+  pub async fn report(self) -> Result<Vec<ImportRowReport>, Error> {
+    if let Some(tx) = self.tx {
+      tx.rollback().await?;
+    }
+    Ok(self.report)
+  }
+}
+
+async fn insert_imported(
+  state: &'static State,
+  tx: &mut Transaction<'_, Postgres>,
+  user: ImportUser,
+  orgid: Option<i32>,
+) -> Result<AdminReturnableUser, Error> {
+  if orgid.is_some() && user.admin {
+    return Err(Error::forbidden());
+  }
+  state.username_rules.verify(&user.username)?;
+  if let Some(hash) = &user.password_hash {
+    if !crate::auth::hash::is_supported(hash) {
+      return Err(Error::unsupported_hash(&user.username));
+    }
+  }
+  crate::mail::verify_address(user.email.as_deref())?;
+  let created = sqlx::query_as!(
+    AdminReturnableUser,
+    "
+INSERT INTO users(username, pass, password_changed_at, admin, email)
+VALUES($1, $2, CASE WHEN $2::text IS NULL THEN NULL ELSE NOW() END, $3, $4)
+RETURNING id, username, admin, locked, locked_until, locked_reason, deleted_at,
+  max_sessions, email
+    ",
+    user.username,
+    user.password_hash,
+    user.admin,
+    user.email,
+  )
+  .fetch_one(&mut *tx)
+  .await
+  .map_err(|e| -> Error {
+    match e {
+      sqlx::Error::Database(ref err) => match err.constraint() {
+        Some("users_username_normalized_key") => Error::username_taken(),
+        _ => e.into(),
+      },
+      _ => e.into(),
+    }
+  })?;
+  if let Some(orgid) = orgid {
+    join_organization(tx, orgid, created.id).await?;
+  }
+  Ok(created)
+}
+
+// Add a user to an organization, as users created by its org admins are
+pub async fn join_organization(
+  tx: &mut Transaction<'_, Postgres>,
+  orgid: i32,
+  userid: i32,
+) -> Result<(), Error> {
+  sqlx::query!(
+    "INSERT INTO memberships(orgid, userid) VALUES($1, $2)",
+    orgid,
+    userid,
+  )
+  .execute(tx)
+  .await?;
+  Ok(())
+}
+
+// Create users with password hashes from another system, all or none
+pub async fn import_users(
+  state: &'static State,
+  users: Vec<ImportUser>,
+) -> Result<Vec<AdminReturnableUser>, Error> {
+  let mut importer = Importer::new(state, false, None);
+  for user in users {
+    importer.add(Ok(user)).await?;
+  }
+  importer.commit().await
+}
+
+// Mark a user as deleted, the purge task removes it later
+// Returns false if there was no such (undeleted) user
+pub async fn delete_user(state: &'static State, userid: i32) -> Result<bool, Error> {
+  let free_username = match state.deleted_username_policy {
+    DeletedUsernamePolicy::Keep => false,
+    DeletedUsernamePolicy::Free => true,
+  };
+  let mut tx = state.db_pool.begin().await?;
+  let admins = lock_active_admins(state, &mut tx).await?;
+  let affected = sqlx::query!(
+    "
+UPDATE users SET
+  deleted_at = NOW(),
+  username = CASE WHEN $2 THEN 'deleted:' || id || ':' || username ELSE username END
+WHERE id = $1 AND deleted_at IS NULL
+    ",
+    userid,
+    free_username,
+  )
+  .execute(&mut tx)
+  .await?
+  .rows_affected();
+  // Also revoke all the user's sessions
+  sqlx::query!("DELETE FROM sessions WHERE userid = $1", userid)
+    .execute(&mut tx)
+    .await?;
+  verify_admins_remain(state, &mut tx, admins).await?;
+  tx.commit().await?;
+  crate::auth::session_cache::forget_user(state, userid);
+  Ok(affected > 0)
 }
 
 pub async fn update_admin(db_pool: &PgPool, hash: String) -> Result<(), Error> {
   sqlx::query!(
-    "UPDATE users SET username = 'admin', pass = $1, admin = true, locked = false WHERE id = 0",
+    "
+UPDATE users SET
+  username = 'admin',
+  pass = $1,
+  password_changed_at = NOW(),
+  must_change_password = false,
+  admin = true,
+  locked = false
+WHERE id = 0
+    ",
     hash,
   )
   .execute(db_pool)
   .await?;
   Ok(())
 }
+
+// An async task that purges users deleted longer ago than the retention period
+// Runs indefinitely
+pub async fn purge_deleted_users(state: &'static State, retention: chrono::Duration) {
+  loop {
+    // Run the purge query (sessions are removed by cascade)
+    let cutoff = chrono::offset::Utc::now().naive_utc() - retention;
+    let purged = sqlx::query!(
+      "DELETE FROM users WHERE deleted_at < $1 RETURNING id, avatar",
+      cutoff
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .expect("Failed to purge deleted users!");
+    // Their avatars are files, outside the database
+    for user in purged {
+      if let Some(avatar) = user.avatar {
+        if let Err(e) = crate::avatars::remove_files(state, user.id, &avatar).await {
+          eprintln!(
+            "Failed to remove avatar of purged user {}: {:?}",
+            user.id, e
+          );
+        }
+      }
+    }
+
+    // Delay for one hour before doing again
+    tokio::time::sleep(tokio::time::Duration::from_secs(3600)).await;
+  }
+}
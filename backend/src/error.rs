@@ -3,17 +3,23 @@
 // Needed types
 use crate::Reply;
 use argon2::password_hash;
+use chrono::NaiveDateTime;
 use hyper::header::HeaderValue;
 use hyper::{Body, Request, Response, StatusCode};
 // Public errors to wrap
+use csv::Error as CsvError;
 use hyper::header::ToStrError as UnreadableHeaderError;
+use multer::Error as MultipartError;
 use serde_json::Error as JsonError;
 use serde_urlencoded::de::Error as UrlEncodingError;
 use std::num::ParseIntError;
 // Private errors to wrap
 use hyper::Error as ConnectionError;
+use image::ImageError;
+use ldap3::LdapError;
 use password_hash::Error as HashingError;
 use sqlx::Error as DbError;
+use std::io::Error as IoError;
 use tokio::sync::AcquireError;
 use tokio::task::JoinError;
 // Client facing error type
@@ -24,11 +30,15 @@ use shared_types::ClientError;
 #[derive(Debug)]
 pub enum InternalError {
   SessionKeyCollision,
+  ExternalIdp(String), // The external identity provider misbehaved
   Join(JoinError),
   Semaphore(AcquireError),
   Hash(HashingError),
   Db(DbError),
   Connection(ConnectionError),
+  Ldap(LdapError),
+  Io(IoError),       // Reading or writing stored avatars
+  Image(ImageError), // Encoding avatars, decoding errors are the client's
 }
 impl Reply for InternalError {
   fn into_response(self) -> Response<Body> {
@@ -55,15 +65,44 @@ impl Reply for ClientError {
       Self::PathDataBeforeRoot(_) => StatusCode::BAD_REQUEST,
       Self::UnreadableHeader(_) => StatusCode::BAD_REQUEST,
       Self::InvalidContentLength(_) => StatusCode::BAD_REQUEST,
+      Self::ContentTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+      Self::BodyTimeout => StatusCode::REQUEST_TIMEOUT,
       Self::InvalidContentType(_) => StatusCode::BAD_REQUEST,
       Self::InvalidJson(_) => StatusCode::BAD_REQUEST,
+      Self::InvalidCsv(_) => StatusCode::BAD_REQUEST,
+      Self::InvalidMultipart(_) => StatusCode::BAD_REQUEST,
       Self::InvalidUrlEncoding(_) => StatusCode::BAD_REQUEST,
       Self::InvalidIndexPath(_) => StatusCode::BAD_REQUEST,
+      Self::InvalidFilter(_) => StatusCode::BAD_REQUEST,
+      Self::InvalidPatch(_) => StatusCode::BAD_REQUEST,
 
       Self::BadPassword => StatusCode::BAD_REQUEST,
       Self::UsernameTaken => StatusCode::BAD_REQUEST,
+      Self::InvalidUsername => StatusCode::BAD_REQUEST,
+      Self::InvalidEmail => StatusCode::BAD_REQUEST,
+      Self::InvalidLocale => StatusCode::BAD_REQUEST,
+      Self::InvalidTimezone => StatusCode::BAD_REQUEST,
+      Self::InvalidMaxSessions => StatusCode::BAD_REQUEST,
+      Self::InvalidImage(_) => StatusCode::BAD_REQUEST,
+      Self::InvalidPreferenceName => StatusCode::BAD_REQUEST,
+      Self::PreferencesFull => StatusCode::BAD_REQUEST,
+      Self::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+      Self::UnsupportedHash(_) => StatusCode::BAD_REQUEST,
       Self::BadLogin => StatusCode::UNAUTHORIZED,
-      Self::AccountLocked => StatusCode::UNAUTHORIZED,
+      Self::AccountLocked { .. } => StatusCode::UNAUTHORIZED,
+      Self::PasswordChangeRequired => StatusCode::FORBIDDEN,
+      Self::TermsAcceptanceRequired => StatusCode::FORBIDDEN,
+      Self::OutdatedTerms => StatusCode::BAD_REQUEST,
+      Self::ProtectedAccount => StatusCode::FORBIDDEN,
+      Self::LastAdmin => StatusCode::BAD_REQUEST,
+      Self::ElevationRequired => StatusCode::FORBIDDEN,
+      Self::TooManySessions => StatusCode::FORBIDDEN,
+      Self::CsrfMismatch => StatusCode::FORBIDDEN,
+      Self::InvalidClient => StatusCode::BAD_REQUEST,
+      Self::InvalidRedirectUri => StatusCode::BAD_REQUEST,
+      Self::ClientIdTaken => StatusCode::BAD_REQUEST,
+      Self::OrganizationNameTaken => StatusCode::BAD_REQUEST,
+      Self::ExternalIdentityTaken => StatusCode::BAD_REQUEST,
     };
     re.headers_mut().insert(
       "Content-Type",
@@ -85,6 +124,9 @@ impl Error {
   pub fn session_key_collision() -> Self {
     Self::InternalError(InternalError::SessionKeyCollision)
   }
+  pub fn external_idp(message: String) -> Self {
+    Self::InternalError(InternalError::ExternalIdp(message))
+  }
   pub fn path_data_before_root(data: String) -> Self {
     Self::ClientError(ClientError::PathDataBeforeRoot(data))
   }
@@ -100,6 +142,9 @@ impl Error {
   pub fn forbidden() -> Self {
     Self::ClientError(ClientError::Forbidden)
   }
+  pub fn elevation_required() -> Self {
+    Self::ClientError(ClientError::ElevationRequired)
+  }
 
   // Most of the parsing errors are created by From
   // but not these
@@ -121,7 +166,7 @@ impl Error {
     )))
   }
   pub fn content_length_too_large(parsed: usize, max: usize) -> Self {
-    Self::ClientError(ClientError::InvalidContentLength(format!(
+    Self::ClientError(ClientError::ContentTooLarge(format!(
       "Too large. Maximum allowed is {}, received {}",
       max, parsed
     )))
@@ -133,6 +178,9 @@ impl Error {
       promised, at_least, given
     )))
   }
+  pub fn body_timeout() -> Self {
+    Self::ClientError(ClientError::BodyTimeout)
+  }
   pub fn invalid_content_type(parsed: &str, expected: &str) -> Self {
     Self::ClientError(ClientError::InvalidContentType(format!(
       "Expected {}, received {}",
@@ -147,11 +195,83 @@ impl Error {
   pub fn username_taken() -> Self {
     Self::ClientError(ClientError::UsernameTaken)
   }
+  pub fn invalid_username() -> Self {
+    Self::ClientError(ClientError::InvalidUsername)
+  }
+  pub fn invalid_email() -> Self {
+    Self::ClientError(ClientError::InvalidEmail)
+  }
+  pub fn invalid_locale() -> Self {
+    Self::ClientError(ClientError::InvalidLocale)
+  }
+  pub fn invalid_timezone() -> Self {
+    Self::ClientError(ClientError::InvalidTimezone)
+  }
+  pub fn invalid_max_sessions() -> Self {
+    Self::ClientError(ClientError::InvalidMaxSessions)
+  }
+  pub fn invalid_image(message: &str) -> Self {
+    Self::ClientError(ClientError::InvalidImage(message.to_string()))
+  }
+  pub fn invalid_preference_name() -> Self {
+    Self::ClientError(ClientError::InvalidPreferenceName)
+  }
+  pub fn preferences_full() -> Self {
+    Self::ClientError(ClientError::PreferencesFull)
+  }
+  pub fn precondition_failed() -> Self {
+    Self::ClientError(ClientError::PreconditionFailed)
+  }
+  pub fn unsupported_hash(username: &str) -> Self {
+    Self::ClientError(ClientError::UnsupportedHash(username.to_string()))
+  }
   pub fn bad_login() -> Self {
     Self::ClientError(ClientError::BadLogin)
   }
-  pub fn account_locked() -> Self {
-    Self::ClientError(ClientError::AccountLocked)
+  pub fn account_locked(until: Option<NaiveDateTime>, reason: Option<String>) -> Self {
+    Self::ClientError(ClientError::AccountLocked { until, reason })
+  }
+  pub fn invalid_client() -> Self {
+    Self::ClientError(ClientError::InvalidClient)
+  }
+  pub fn invalid_redirect_uri() -> Self {
+    Self::ClientError(ClientError::InvalidRedirectUri)
+  }
+  pub fn external_identity_taken() -> Self {
+    Self::ClientError(ClientError::ExternalIdentityTaken)
+  }
+  pub fn client_id_taken() -> Self {
+    Self::ClientError(ClientError::ClientIdTaken)
+  }
+  pub fn organization_name_taken() -> Self {
+    Self::ClientError(ClientError::OrganizationNameTaken)
+  }
+  pub fn invalid_filter(message: &str) -> Self {
+    Self::ClientError(ClientError::InvalidFilter(message.to_string()))
+  }
+  pub fn invalid_patch(message: &str) -> Self {
+    Self::ClientError(ClientError::InvalidPatch(message.to_string()))
+  }
+  pub fn csrf_mismatch() -> Self {
+    Self::ClientError(ClientError::CsrfMismatch)
+  }
+  pub fn too_many_sessions() -> Self {
+    Self::ClientError(ClientError::TooManySessions)
+  }
+  pub fn password_change_required() -> Self {
+    Self::ClientError(ClientError::PasswordChangeRequired)
+  }
+  pub fn terms_acceptance_required() -> Self {
+    Self::ClientError(ClientError::TermsAcceptanceRequired)
+  }
+  pub fn outdated_terms() -> Self {
+    Self::ClientError(ClientError::OutdatedTerms)
+  }
+  pub fn protected_account() -> Self {
+    Self::ClientError(ClientError::ProtectedAccount)
+  }
+  pub fn last_admin() -> Self {
+    Self::ClientError(ClientError::LastAdmin)
   }
 }
 
@@ -175,6 +295,27 @@ impl From<JsonError> for Error {
     Self::ClientError(ClientError::InvalidJson(format!("{}", e)))
   }
 }
+impl From<CsvError> for Error {
+  fn from(e: CsvError) -> Self {
+    Self::ClientError(ClientError::InvalidCsv(format!("{}", e)))
+  }
+}
+impl From<MultipartError> for Error {
+  fn from(e: MultipartError) -> Self {
+    // Exceeding the limit while reading fields comes wrapped as a read error
+    let cause = match &e {
+      MultipartError::StreamReadFailed(cause) => cause.downcast_ref::<MultipartError>(),
+      _ => None,
+    };
+    match cause.unwrap_or(&e) {
+      size @ (MultipartError::StreamSizeExceeded { .. }
+      | MultipartError::FieldSizeExceeded { .. }) => {
+        Self::ClientError(ClientError::ContentTooLarge(format!("{}", size)))
+      }
+      _ => Self::ClientError(ClientError::InvalidMultipart(format!("{}", e))),
+    }
+  }
+}
 impl From<UrlEncodingError> for Error {
   fn from(e: UrlEncodingError) -> Self {
     Self::ClientError(ClientError::InvalidUrlEncoding(format!("{}", e)))
@@ -210,3 +351,18 @@ impl From<ConnectionError> for Error {
     Self::InternalError(InternalError::Connection(e))
   }
 }
+impl From<LdapError> for Error {
+  fn from(e: LdapError) -> Self {
+    Self::InternalError(InternalError::Ldap(e))
+  }
+}
+impl From<IoError> for Error {
+  fn from(e: IoError) -> Self {
+    Self::InternalError(InternalError::Io(e))
+  }
+}
+impl From<ImageError> for Error {
+  fn from(e: ImageError) -> Self {
+    Self::InternalError(InternalError::Image(e))
+  }
+}
@@ -11,22 +11,58 @@ mod error;
 pub use error::*;
 
 mod auth;
+mod avatars;
 mod db;
+mod mail;
+mod preferences;
 mod routes;
+mod terms;
+mod usernames;
 
 #[cfg(test)]
 mod test;
 
 #[tokio::main]
 async fn main() {
+  // Check the arguments before doing anything, so mistakes don't have effects
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  let import_path = match args.as_slice() {
+    [] => None,
+    [command, path] if command == "import-users" => Some(path.clone()),
+    _ => {
+      eprintln!("Usage: backend [import-users <file>]");
+      std::process::exit(2);
+    }
+  };
+
   let state = init_state().await;
 
+  // Import users instead of serving, if asked to
+  if let Some(path) = import_path {
+    import_users(state, &path).await;
+    return;
+  }
+
   // Define the socket to bind to
   let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
 
   run_server(state, addr).await
 }
 
+// Import users from a file with a JSON array of them, as the admin API does
+async fn import_users(state: &'static State, path: &str) {
+  let file = std::fs::read(path).expect("Could not read the file to import.");
+  let users: Vec<shared_types::ImportUser> =
+    serde_json::from_slice(&file).expect("Could not parse the file as a JSON array of users.");
+  match db::import_users(state, users).await {
+    Ok(created) => println!("Imported {} users.", created.len()),
+    Err(e) => {
+      eprintln!("Import failed, no users were imported: {:?}", e);
+      std::process::exit(1);
+    }
+  }
+}
+
 pub(crate) async fn run_server(state: &'static State, addr: SocketAddr) {
   // Define what to do with requests
   // - A Service is a stateful worker that responds to one request at a time.
@@ -45,6 +81,24 @@ pub(crate) async fn run_server(state: &'static State, addr: SocketAddr) {
   let _cleaner = tokio::task::spawn(async move {
     auth::session::prune_sessions(state).await;
   });
+  // And one to purge deleted users, if configured
+  let _purger = state.deleted_user_retention.map(|retention| {
+    tokio::task::spawn(async move {
+      db::purge_deleted_users(state, retention).await;
+    })
+  });
+  // And one to keep revoked sessions in memory, if access tokens are issued
+  let _revocations = state.access_token_lifetime.map(|lifetime| {
+    tokio::task::spawn(async move {
+      auth::access_token::refresh_revoked_sessions(state, lifetime).await;
+    })
+  });
+  // And one to drop cached sessions as they change, if caching
+  let _invalidator = state.session_cache.as_ref().map(|cache| {
+    tokio::task::spawn(async move {
+      auth::session_cache::listen_for_invalidations(state, cache).await;
+    })
+  });
 
   // Finally run it all (forever)
   match server.await {
@@ -0,0 +1,207 @@
+//! Per-user key-value store of JSON values, namespaced per application
+//!
+//! Values carry an ETag (a hash of the value) so that clients can update
+//! them without overwriting changes made elsewhere in the meantime.
+
+use crate::Error;
+use crate::State;
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+// Longest allowed namespace or key
+const MAX_NAME_LEN: usize = 64;
+
+pub struct Preference {
+  pub value: Value,
+  pub etag: String,
+}
+impl Preference {
+  fn new(value: Value) -> Self {
+    // Values are always read back from the database before hashing, so
+    // equal values give equal ETags regardless of how they were sent
+    let hash = Sha256::digest(value.to_string().as_bytes());
+    Self {
+      etag: format!(
+        "\"{}\"",
+        base64::encode_config(&hash[..12], base64::URL_SAFE_NO_PAD)
+      ),
+      value: value,
+    }
+  }
+}
+
+// The condition, from If-Match or If-None-Match, for a change to apply
+pub enum Precondition {
+  Always,
+  Exists,               // If-Match: *
+  Absent,               // If-None-Match: *
+  Matches(Vec<String>), // If-Match with ETags
+}
+impl Precondition {
+  fn holds(&self, current: Option<&Preference>) -> bool {
+    match self {
+      Self::Always => true,
+      Self::Exists => current.is_some(),
+      Self::Absent => current.is_none(),
+      Self::Matches(etags) => current.is_some_and(|current| etags.contains(&current.etag)),
+    }
+  }
+}
+
+// Namespaces and keys are short and safe to put in paths unescaped
+pub fn verify_name(name: &str) -> Result<(), Error> {
+  let valid = !name.is_empty()
+    && name.len() <= MAX_NAME_LEN
+    && name
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+  if valid {
+    Ok(())
+  } else {
+    Err(Error::invalid_preference_name())
+  }
+}
+
+pub async fn get(
+  state: &'static State,
+  userid: i32,
+  namespace: &str,
+  key: &str,
+) -> Result<Option<Preference>, Error> {
+  let row = sqlx::query!(
+    "SELECT value FROM preferences WHERE userid = $1 AND namespace = $2 AND key = $3",
+    userid,
+    namespace,
+    key,
+  )
+  .fetch_optional(&state.db_pool)
+  .await?;
+  Ok(row.map(|row| Preference::new(row.value)))
+}
+
+// All of a user's values, by namespace and key, optionally only one namespace
+// Takes the pool or a transaction to read in
+pub async fn list(
+  db: impl sqlx::PgExecutor<'_>,
+  userid: i32,
+  namespace: Option<&str>,
+) -> Result<BTreeMap<String, BTreeMap<String, Value>>, Error> {
+  let rows = sqlx::query!(
+    "
+SELECT namespace, key, value FROM preferences
+WHERE userid = $1 AND (namespace = $2 OR $2 IS NULL)
+    ",
+    userid,
+    namespace,
+  )
+  .fetch_all(db)
+  .await?;
+  let mut preferences: BTreeMap<String, BTreeMap<String, Value>> = BTreeMap::new();
+  for row in rows {
+    preferences
+      .entry(row.namespace)
+      .or_default()
+      .insert(row.key, row.value);
+  }
+  Ok(preferences)
+}
+
+// Set a value, if the precondition holds
+// Returns the stored value and if it was created
+pub async fn put(
+  state: &'static State,
+  userid: i32,
+  namespace: &str,
+  key: &str,
+  value: Value,
+  precondition: Precondition,
+) -> Result<(Preference, bool), Error> {
+  let mut tx = state.db_pool.begin().await?;
+  // Serialize changes per user, for the precondition and size checks
+  sqlx::query!(
+    "SELECT id FROM users WHERE id = $1 FOR NO KEY UPDATE",
+    userid
+  )
+  .fetch_one(&mut tx)
+  .await?;
+  let current = sqlx::query!(
+    "SELECT value FROM preferences WHERE userid = $1 AND namespace = $2 AND key = $3",
+    userid,
+    namespace,
+    key,
+  )
+  .fetch_optional(&mut tx)
+  .await?
+  .map(|row| Preference::new(row.value));
+  if !precondition.holds(current.as_ref()) {
+    return Err(Error::precondition_failed());
+  }
+  let stored = sqlx::query!(
+    "
+INSERT INTO preferences(userid, namespace, key, value) VALUES($1, $2, $3, $4)
+ON CONFLICT (userid, namespace, key) DO UPDATE SET value = EXCLUDED.value, updated_at = NOW()
+RETURNING value
+    ",
+    userid,
+    namespace,
+    key,
+    value,
+  )
+  .fetch_one(&mut tx)
+  .await?
+  .value;
+  let total = sqlx::query!(
+    "
+SELECT COALESCE(SUM(octet_length(value::text)), 0) AS \"total!\"
+FROM preferences WHERE userid = $1
+    ",
+    userid,
+  )
+  .fetch_one(&mut tx)
+  .await?
+  .total;
+  if total > state.preferences_max_len as i64 {
+    return Err(Error::preferences_full());
+  }
+  tx.commit().await?;
+  Ok((Preference::new(stored), current.is_none()))
+}
+
+// Remove a value, if the precondition holds
+// Returns false if there was no value
+pub async fn delete(
+  state: &'static State,
+  userid: i32,
+  namespace: &str,
+  key: &str,
+  precondition: Precondition,
+) -> Result<bool, Error> {
+  let mut tx = state.db_pool.begin().await?;
+  let current = sqlx::query!(
+    "
+SELECT value FROM preferences WHERE userid = $1 AND namespace = $2 AND key = $3
+FOR UPDATE
+    ",
+    userid,
+    namespace,
+    key,
+  )
+  .fetch_optional(&mut tx)
+  .await?
+  .map(|row| Preference::new(row.value));
+  if !precondition.holds(current.as_ref()) {
+    return Err(Error::precondition_failed());
+  }
+  sqlx::query!(
+    "DELETE FROM preferences WHERE userid = $1 AND namespace = $2 AND key = $3",
+    userid,
+    namespace,
+    key,
+  )
+  .execute(&mut tx)
+  .await?;
+  tx.commit().await?;
+  Ok(current.is_some())
+}
@@ -0,0 +1,64 @@
+use super::*;
+
+use shared_types::{ApiToken, CreatedApiToken, NewApiToken};
+
+pub async fn route(
+  state: &'static State,
+  mut req: Request,
+  mut path_vec: Vec<String>,
+) -> Result<Response, Error> {
+  match path_vec.pop().as_deref() {
+    None | Some("") => {
+      verify_path_end(&path_vec, &req)?;
+      match req.method() {
+        &Method::GET => {
+          let tokens = sqlx::query_as!(
+            ApiToken,
+            "SELECT id, name, created_at, last_used_at FROM api_tokens ORDER BY id"
+          )
+          .fetch_all(&state.db_pool)
+          .await?;
+          if tokens.is_empty() {
+            empty()
+          } else {
+            json(&tokens)
+          }
+        }
+        &Method::POST => {
+          let new_token: NewApiToken = parse_json(&mut req, state.max_content_len).await?;
+          // Only the hash of the token is stored, so this is the only time it is shown
+          let token = nanoid::nanoid!(48);
+          let api_token = sqlx::query_as!(
+            ApiToken,
+            "
+INSERT INTO api_tokens(name, token_hash) VALUES($1, $2)
+RETURNING id, name, created_at, last_used_at
+            ",
+            new_token.name,
+            crate::auth::hash_token(&token),
+          )
+          .fetch_one(&state.db_pool)
+          .await?;
+          set_status(
+            json(&CreatedApiToken { api_token, token }),
+            StatusCode::CREATED,
+          )
+        }
+        _ => Err(Error::method_not_found(&req)),
+      }
+    }
+    // If there is more than base path parse it as the id of a token
+    Some(id) => {
+      verify_method_path_end(&path_vec, &req, &Method::DELETE)?;
+      let id = id.parse::<i32>()?;
+      let affected = sqlx::query!("DELETE FROM api_tokens WHERE id = $1", id)
+        .execute(&state.db_pool)
+        .await?
+        .rows_affected();
+      match affected {
+        0 => Err(Error::path_not_found(&req)),
+        _ => empty(),
+      }
+    }
+  }
+}
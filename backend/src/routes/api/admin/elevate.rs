@@ -0,0 +1,66 @@
+use super::*;
+
+use shared_types::{Elevate, Elevation, ExternalElevation};
+
+pub async fn route(
+  state: &'static State,
+  req: Request,
+  mut path_vec: Vec<String>,
+  permissions: Permissions,
+) -> Result<Response, Error> {
+  match path_vec.pop().as_deref() {
+    None | Some("") => password(state, req, path_vec, permissions).await,
+    // Users without a password log in at the provider again instead
+    Some("external") => {
+      verify_method_path_end(&path_vec, &req, &Method::POST)?;
+      let idp = match &state.external_idp {
+        Some(idp) => idp,
+        None => return Err(Error::path_not_found(&req)),
+      };
+      let (uri, login_state) =
+        crate::auth::external::start(state, idp, Some(permissions.sessionid)).await?;
+      let mut re = json(&ExternalElevation { uri })?;
+      super::super::external_login::set_login_cookie(&mut re, &login_state);
+      Ok(re)
+    }
+    Some(_) => Err(Error::path_not_found(&req)),
+  }
+}
+
+// Note that this password validation does allow an attacker to know if the
+// admin whose session they have stolen has a password or not via timing.
+// But there shouldn't be a session otherwise, so not really a risk.
+async fn password(
+  state: &'static State,
+  mut req: Request,
+  path_vec: Vec<String>,
+  permissions: Permissions,
+) -> Result<Response, Error> {
+  verify_method_path_end(&path_vec, &req, &Method::POST)?;
+  let query: Elevate = parse_json(&mut req, state.max_content_len).await?;
+
+  // Verify the password, so it takes more than a session key to
+  // use admin privileges
+  // Impersonated sessions of users without a password can't be elevated
+  let admin_user =
+    match crate::auth::credentials::check_user(state, permissions.userid, query.password).await? {
+      Some(user) => user,
+      None => return Err(Error::bad_login()),
+    };
+  if admin_user.locked {
+    return Err(Error::account_locked(None, None));
+  }
+
+  // With all verification done we elevate the session
+  let until = chrono::offset::Utc::now().naive_utc() + state.admin_elevation;
+  let ret = sqlx::query_as!(
+    Elevation,
+    "UPDATE sessions SET elevated_until = $2 WHERE id = $1 RETURNING elevated_until AS \"until!\"",
+    permissions.sessionid,
+    until,
+  )
+  .fetch_one(&state.db_pool)
+  .await?;
+  crate::auth::session_cache::forget_session(state, permissions.sessionid);
+  json(&ret)
+}
@@ -1,6 +1,11 @@
 use super::*;
 
+mod api_tokens;
+mod elevate;
+mod oidc_clients;
+mod organizations;
 mod sessions;
+mod terms;
 mod users;
 
 pub async fn route(
@@ -14,8 +19,34 @@ pub async fn route(
       verify_method_path_end(&path_vec, &req, &Method::GET)?;
       Ok(Response::new(include_str!("doc_body.txt").into()))
     }
-    Some("users") => users::route(state, req, path_vec, permissions).await,
-    Some("sessions") => sessions::route(state, req, path_vec).await,
+    Some("elevate") => elevate::route(state, req, path_vec, permissions).await,
+    Some("users") => {
+      crate::auth::require_elevation(&permissions)?;
+      users::route(state, req, path_vec, permissions).await
+    }
+    Some("sessions") => {
+      crate::auth::require_elevation(&permissions)?;
+      sessions::route(state, req, path_vec, permissions).await
+    }
+    Some("organizations") => {
+      crate::auth::require_elevation(&permissions)?;
+      organizations::route(state, req, path_vec, permissions).await
+    }
+    Some("terms") => {
+      crate::auth::require_elevation(&permissions)?;
+      terms::route(state, req, path_vec, permissions).await
+    }
+    // Configuration for all organizations, so not for org admins
+    Some("oidc_clients") => {
+      crate::auth::require_super_admin(&permissions)?;
+      crate::auth::require_elevation(&permissions)?;
+      oidc_clients::route(state, req, path_vec).await
+    }
+    Some("api_tokens") => {
+      crate::auth::require_super_admin(&permissions)?;
+      crate::auth::require_elevation(&permissions)?;
+      api_tokens::route(state, req, path_vec).await
+    }
     Some(_) => Err(Error::path_not_found(&req)),
   }
 }
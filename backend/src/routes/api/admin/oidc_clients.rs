@@ -0,0 +1,141 @@
+use super::*;
+
+use shared_types::{CreatedOidcClient, NewOidcClient, OidcClient, UpdateOidcClient};
+
+// Redirect URIs must be absolute http(s) URIs, since codes are sent to them
+fn verify_redirect_uris(uris: &[String]) -> Result<(), Error> {
+  for uri in uris {
+    let parsed: hyper::Uri = uri.parse().map_err(|_| Error::invalid_redirect_uri())?;
+    match (parsed.scheme_str(), parsed.host()) {
+      (Some("https"), Some(_)) | (Some("http"), Some(_)) => (),
+      _ => return Err(Error::invalid_redirect_uri()),
+    }
+  }
+  Ok(())
+}
+
+fn map_client_id_taken(e: sqlx::Error) -> Error {
+  match e {
+    sqlx::Error::Database(ref err) => match err.constraint() {
+      Some("oidc_clients_client_id_key") => Error::client_id_taken(),
+      _ => e.into(),
+    },
+    _ => e.into(),
+  }
+}
+
+pub async fn route(
+  state: &'static State,
+  mut req: Request,
+  mut path_vec: Vec<String>,
+) -> Result<Response, Error> {
+  match path_vec.pop().as_deref() {
+    None | Some("") => {
+      verify_path_end(&path_vec, &req)?;
+      match req.method() {
+        &Method::GET => {
+          let clients = sqlx::query_as!(
+            OidcClient,
+            "
+SELECT id, client_id, name, secret IS NOT NULL AS \"confidential!\", redirect_uris
+FROM oidc_clients ORDER BY id
+            "
+          )
+          .fetch_all(&state.db_pool)
+          .await?;
+          if clients.is_empty() {
+            empty()
+          } else {
+            json(&clients)
+          }
+        }
+        &Method::POST => {
+          let new_client: NewOidcClient = parse_json(&mut req, state.max_content_len).await?;
+          verify_redirect_uris(&new_client.redirect_uris)?;
+          // Only the hash of the secret is stored, so this is the only time it is shown
+          let secret = if new_client.confidential {
+            Some(nanoid::nanoid!(48))
+          } else {
+            None
+          };
+          let hash = match &secret {
+            Some(secret) => Some(
+              crate::auth::hash::hash(&state.cpu_semaphore, &state.hasher, secret.clone()).await?,
+            ),
+            None => None,
+          };
+          let client = sqlx::query_as!(
+            OidcClient,
+            "
+INSERT INTO oidc_clients(client_id, name, secret, redirect_uris) VALUES($1, $2, $3, $4)
+RETURNING id, client_id, name, secret IS NOT NULL AS \"confidential!\", redirect_uris
+            ",
+            new_client.client_id,
+            new_client.name,
+            hash,
+            &new_client.redirect_uris,
+          )
+          .fetch_one(&state.db_pool)
+          .await
+          .map_err(map_client_id_taken)?;
+          set_status(
+            json(&CreatedOidcClient { client, secret }),
+            StatusCode::CREATED,
+          )
+        }
+        _ => Err(Error::method_not_found(&req)),
+      }
+    }
+    // If there is more than base path parse it as the id of a client
+    Some(id) => {
+      verify_path_end(&path_vec, &req)?;
+      let id = id.parse::<i32>()?;
+      let client = match req.method() {
+        &Method::GET => {
+          sqlx::query_as!(
+            OidcClient,
+            "
+SELECT id, client_id, name, secret IS NOT NULL AS \"confidential!\", redirect_uris
+FROM oidc_clients WHERE id = $1
+            ",
+            id,
+          )
+          .fetch_optional(&state.db_pool)
+          .await?
+        }
+        &Method::PUT => {
+          let update: UpdateOidcClient = parse_json(&mut req, state.max_content_len).await?;
+          verify_redirect_uris(&update.redirect_uris)?;
+          sqlx::query_as!(
+            OidcClient,
+            "
+UPDATE oidc_clients SET name = $2, redirect_uris = $3 WHERE id = $1
+RETURNING id, client_id, name, secret IS NOT NULL AS \"confidential!\", redirect_uris
+            ",
+            id,
+            update.name,
+            &update.redirect_uris,
+          )
+          .fetch_optional(&state.db_pool)
+          .await?
+        }
+        // Deleting a client also deletes its codes and access tokens
+        &Method::DELETE => {
+          let affected = sqlx::query!("DELETE FROM oidc_clients WHERE id = $1", id)
+            .execute(&state.db_pool)
+            .await?
+            .rows_affected();
+          return match affected {
+            0 => Err(Error::path_not_found(&req)),
+            _ => empty(),
+          };
+        }
+        _ => return Err(Error::method_not_found(&req)),
+      };
+      match client {
+        Some(client) => json(&client),
+        None => Err(Error::path_not_found(&req)),
+      }
+    }
+  }
+}
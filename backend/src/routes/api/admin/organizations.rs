@@ -0,0 +1,234 @@
+use super::*;
+
+use shared_types::{NewOrganization, Organization, OrganizationMember, UpdateMembership};
+
+fn map_name_taken(e: sqlx::Error) -> Error {
+  match e {
+    sqlx::Error::Database(ref err) => match err.constraint() {
+      Some("organizations_name_key") => Error::organization_name_taken(),
+      _ => e.into(),
+    },
+    _ => e.into(),
+  }
+}
+
+// Org admins may only see their own organization
+fn verify_visible(permissions: &Permissions, orgid: i32) -> Result<(), Error> {
+  match permissions.tenant() {
+    Some(tenant) if tenant != orgid => Err(Error::forbidden()),
+    _ => Ok(()),
+  }
+}
+
+pub async fn route(
+  state: &'static State,
+  mut req: Request,
+  mut path_vec: Vec<String>,
+  permissions: Permissions,
+) -> Result<Response, Error> {
+  match path_vec.pop().as_deref() {
+    None | Some("") => {
+      verify_path_end(&path_vec, &req)?;
+      match req.method() {
+        &Method::GET => {
+          let organizations = sqlx::query_as!(
+            Organization,
+            "
+SELECT id, name, created_at FROM organizations
+WHERE id = $1 OR $1 IS NULL
+ORDER BY id
+            ",
+            permissions.tenant(),
+          )
+          .fetch_all(&state.db_pool)
+          .await?;
+          if organizations.is_empty() {
+            empty()
+          } else {
+            json(&organizations)
+          }
+        }
+        &Method::POST => {
+          crate::auth::require_super_admin(&permissions)?;
+          let new_org: NewOrganization = parse_json(&mut req, state.max_content_len).await?;
+          let organization = sqlx::query_as!(
+            Organization,
+            "INSERT INTO organizations(name) VALUES($1) RETURNING id, name, created_at",
+            new_org.name,
+          )
+          .fetch_one(&state.db_pool)
+          .await
+          .map_err(map_name_taken)?;
+          set_status(json(&organization), StatusCode::CREATED)
+        }
+        _ => Err(Error::method_not_found(&req)),
+      }
+    }
+    // If there is more than base path parse it as the id of an organization
+    Some(id) => {
+      let orgid = id.parse::<i32>()?;
+      verify_visible(&permissions, orgid)?;
+      match path_vec.pop().as_deref() {
+        None | Some("") => {
+          verify_path_end(&path_vec, &req)?;
+          organization(state, req, permissions, orgid).await
+        }
+        Some("members") => members(state, req, path_vec, permissions, orgid).await,
+        Some(_) => Err(Error::path_not_found(&req)),
+      }
+    }
+  }
+}
+
+async fn organization(
+  state: &'static State,
+  mut req: Request,
+  permissions: Permissions,
+  orgid: i32,
+) -> Result<Response, Error> {
+  let organization = match req.method() {
+    &Method::GET => {
+      sqlx::query_as!(
+        Organization,
+        "SELECT id, name, created_at FROM organizations WHERE id = $1",
+        orgid,
+      )
+      .fetch_optional(&state.db_pool)
+      .await?
+    }
+    &Method::PUT => {
+      crate::auth::require_super_admin(&permissions)?;
+      let update: NewOrganization = parse_json(&mut req, state.max_content_len).await?;
+      sqlx::query_as!(
+        Organization,
+        "UPDATE organizations SET name = $2 WHERE id = $1 RETURNING id, name, created_at",
+        orgid,
+        update.name,
+      )
+      .fetch_optional(&state.db_pool)
+      .await
+      .map_err(map_name_taken)?
+    }
+    // Deleting an organization ends the memberships and the sessions scoped to it
+    &Method::DELETE => {
+      crate::auth::require_super_admin(&permissions)?;
+      let mut tx = state.db_pool.begin().await?;
+      let members = sqlx::query!(
+        "SELECT userid FROM memberships WHERE orgid = $1 FOR UPDATE",
+        orgid,
+      )
+      .fetch_all(&mut tx)
+      .await?;
+      let affected = sqlx::query!("DELETE FROM organizations WHERE id = $1", orgid)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+      tx.commit().await?;
+      for member in members {
+        crate::auth::session_cache::forget_user(state, member.userid);
+      }
+      return match affected {
+        0 => Err(Error::path_not_found(&req)),
+        _ => empty(),
+      };
+    }
+    _ => return Err(Error::method_not_found(&req)),
+  };
+  match organization {
+    Some(organization) => json(&organization),
+    None => Err(Error::path_not_found(&req)),
+  }
+}
+
+async fn members(
+  state: &'static State,
+  mut req: Request,
+  mut path_vec: Vec<String>,
+  permissions: Permissions,
+  orgid: i32,
+) -> Result<Response, Error> {
+  match path_vec.pop().as_deref() {
+    None | Some("") => {
+      verify_method_path_end(&path_vec, &req, &Method::GET)?;
+      let members = sqlx::query_as!(
+        OrganizationMember,
+        "
+SELECT userid, username, org_admin
+FROM memberships JOIN users ON users.id = memberships.userid
+WHERE orgid = $1 ORDER BY userid
+        ",
+        orgid,
+      )
+      .fetch_all(&state.db_pool)
+      .await?;
+      if members.is_empty() {
+        empty()
+      } else {
+        json(&members)
+      }
+    }
+    // Only admins above organizations add members and appoint org admins
+    // Org admins may remove members of their organization that aren't admins
+    Some(userid) => {
+      verify_path_end(&path_vec, &req)?;
+      let userid = userid.parse::<i32>()?;
+      match req.method() {
+        &Method::PUT => {
+          crate::auth::require_super_admin(&permissions)?;
+          let update: UpdateMembership = parse_json(&mut req, state.max_content_len).await?;
+          let member = sqlx::query_as!(
+            OrganizationMember,
+            "
+WITH m AS (
+  INSERT INTO memberships(orgid, userid, org_admin) VALUES($1, $2, $3)
+  ON CONFLICT (orgid, userid) DO UPDATE SET org_admin = EXCLUDED.org_admin
+  RETURNING userid, org_admin
+)
+SELECT m.userid AS \"userid!\", users.username AS \"username!\", m.org_admin AS \"org_admin!\"
+FROM m JOIN users ON users.id = m.userid
+            ",
+            orgid,
+            userid,
+            update.org_admin,
+          )
+          .fetch_one(&state.db_pool)
+          .await
+          .map_err(|e| -> Error {
+            match e {
+              sqlx::Error::Database(ref err) => match err.constraint() {
+                Some("memberships_orgid_fkey") | Some("memberships_userid_fkey") => {
+                  Error::path_not_found(&req)
+                }
+                _ => e.into(),
+              },
+              _ => e.into(),
+            }
+          })?;
+          crate::auth::session_cache::forget_user(state, userid);
+          json(&member)
+        }
+        // Also ends the user's sessions scoped to the organization
+        &Method::DELETE => {
+          let affected = sqlx::query!(
+            "
+DELETE FROM memberships WHERE orgid = $1 AND userid = $2 AND
+  ($3 OR NOT (org_admin OR userid IN (SELECT id FROM users WHERE admin)))
+            ",
+            orgid,
+            userid,
+            permissions.admin,
+          )
+          .execute(&state.db_pool)
+          .await?
+          .rows_affected();
+          crate::auth::session_cache::forget_user(state, userid);
+          match affected {
+            0 => Err(Error::path_not_found(&req)),
+            _ => empty(),
+          }
+        }
+        _ => Err(Error::method_not_found(&req)),
+      }
+    }
+  }
+}
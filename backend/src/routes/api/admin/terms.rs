@@ -0,0 +1,89 @@
+use super::*;
+
+use shared_types::{NewTerms, Terms, TermsAcceptance, TermsAcceptancesFilter};
+
+pub async fn route(
+  state: &'static State,
+  mut req: Request,
+  mut path_vec: Vec<String>,
+  permissions: Permissions,
+) -> Result<Response, Error> {
+  match path_vec.pop().as_deref() {
+    None | Some("") => {
+      verify_path_end(&path_vec, &req)?;
+      match req.method() {
+        &Method::GET => {
+          let terms = sqlx::query_as!(
+            Terms,
+            "SELECT version, text, published_at FROM terms ORDER BY version"
+          )
+          .fetch_all(&state.db_pool)
+          .await?;
+          if terms.is_empty() {
+            empty()
+          } else {
+            json(&terms)
+          }
+        }
+        // Publishing applies to all organizations, so not for org admins
+        &Method::POST => {
+          crate::auth::require_super_admin(&permissions)?;
+          let new_terms: NewTerms = parse_json(&mut req, state.max_content_len).await?;
+          let terms = crate::terms::publish(state, &new_terms.text).await?;
+          set_status(json(&terms), StatusCode::CREATED)
+        }
+        _ => Err(Error::method_not_found(&req)),
+      }
+    }
+    Some("acceptances") => {
+      verify_method_path_end(&path_vec, &req, &Method::GET)?;
+      let filter: TermsAcceptancesFilter = parse_filter(&req)?;
+      // Note the null checking around every filter
+      // Org admins only see the users they manage
+      let acceptances = sqlx::query_as!(
+        TermsAcceptance,
+        "
+SELECT userid, username, version, accepted_at
+FROM terms_acceptances JOIN users ON users.id = terms_acceptances.userid
+WHERE
+  (userid = $1 OR $1 IS NULL) AND
+  (version = $2 OR $2 IS NULL) AND
+  (accepted_at <= $3 OR $3 IS NULL) AND
+  (accepted_at >= $4 OR $4 IS NULL) AND
+  ($6::int IS NULL OR org_manages($6, userid))
+ORDER BY accepted_at, userid
+LIMIT $5
+        ",
+        filter.userid_eq,
+        filter.version_eq,
+        filter.accepted_at_lte,
+        filter.accepted_at_mte,
+        filter.limit,
+        permissions.tenant(),
+      )
+      .fetch_all(&state.db_pool)
+      .await?;
+      if acceptances.is_empty() {
+        empty()
+      } else {
+        json(&acceptances)
+      }
+    }
+    // If there is more than base path parse it as a version
+    Some(version) => {
+      verify_method_path_end(&path_vec, &req, &Method::GET)?;
+      let version = version.parse::<i32>()?;
+      let terms = sqlx::query_as!(
+        Terms,
+        "SELECT version, text, published_at FROM terms WHERE version = $1",
+        version,
+      )
+      .fetch_optional(&state.db_pool)
+      .await?;
+      match terms {
+        Some(terms) => json(&terms),
+        None => Err(Error::path_not_found(&req)),
+      }
+    }
+  }
+}
@@ -0,0 +1,157 @@
+// Importing and exporting users in bulk, as CSV or JSON Lines
+// Both are streamed, so they aren't limited by max_content_len
+
+use super::*;
+
+use crate::db::Importer;
+use futures::StreamExt;
+use shared_types::{ExportFormat, ExportOptions, ImportOptions, ImportUser};
+
+// Exported rows are sent in chunks of about this many bytes
+const EXPORT_CHUNK_LEN: usize = 16384;
+
+// Parse a single CSV record, as split out by BodyRecords
+fn parse_csv_record(bytes: &[u8], trim: csv::Trim) -> Result<csv::StringRecord, Error> {
+  let mut reader = csv::ReaderBuilder::new()
+    .has_headers(false)
+    .trim(trim)
+    .from_reader(bytes);
+  let mut record = csv::StringRecord::new();
+  reader.read_record(&mut record)?;
+  Ok(record)
+}
+
+pub async fn import(
+  state: &'static State,
+  mut req: Request,
+  tenant: Option<i32>,
+) -> Result<Response, Error> {
+  let options: ImportOptions = parse_filter(&req)?;
+  let content_type = get_header(&req, "Content-Type")?.unwrap_or("").to_owned();
+  let mut importer = Importer::new(state, options.dry_run, tenant);
+  match content_type.split(';').next().unwrap_or("").trim() {
+    // A single array, which has to be read whole
+    "application/json" => {
+      let users: Vec<ImportUser> = parse_json(&mut req, state.max_import_len).await?;
+      for user in users {
+        importer.add(Ok(user)).await?;
+      }
+    }
+    "application/x-ndjson" => {
+      let mut records = BodyRecords::new(&mut req, state.max_import_len, false)?;
+      while let Some(line) = records.next().await? {
+        if line.iter().all(u8::is_ascii_whitespace) {
+          continue;
+        }
+        importer
+          .add(serde_json::from_slice(&line).map_err(Error::from))
+          .await?;
+      }
+    }
+    // The first record is the header, naming the columns
+    "text/csv" => {
+      let mut records = BodyRecords::new(&mut req, state.max_import_len, true)?;
+      let mut header = None;
+      while let Some(record) = records.next().await? {
+        if record.is_empty() {
+          continue;
+        }
+        match &header {
+          None => header = Some(parse_csv_record(&record, csv::Trim::All)?),
+          Some(header) => {
+            let user = parse_csv_record(&record, csv::Trim::None)
+              .and_then(|record| Ok(record.deserialize(Some(header))?));
+            importer.add(user).await?;
+          }
+        }
+      }
+    }
+    _ => {
+      return Err(Error::invalid_content_type(
+        "text/csv, application/x-ndjson or application/json; charset=utf-8",
+        &content_type,
+      ))
+    }
+  }
+  if options.dry_run {
+    json(&importer.report().await?)
+  } else {
+    set_status(json(&importer.commit().await?), StatusCode::CREATED)
+  }
+}
+
+// Append a user to the buffer, the first with a CSV header
+fn serialize_user(
+  format: ExportFormat,
+  first: bool,
+  user: &AdminReturnableUser,
+  buffer: &mut Vec<u8>,
+) -> Result<(), String> {
+  match format {
+    ExportFormat::Ndjson => {
+      serde_json::to_writer(&mut *buffer, user).map_err(|e| e.to_string())?;
+      buffer.push(b'\n');
+    }
+    ExportFormat::Csv => {
+      let mut writer = csv::WriterBuilder::new()
+        .has_headers(first)
+        .from_writer(buffer);
+      writer.serialize(user).map_err(|e| e.to_string())?;
+      writer.flush().map_err(|e| e.to_string())?;
+    }
+  }
+  Ok(())
+}
+
+pub fn export(state: &'static State, req: Request, tenant: Option<i32>) -> Result<Response, Error> {
+  let filter: UsersFilter = parse_filter(&req)?;
+  let format = parse_filter::<ExportOptions>(&req)?.format;
+  let (mut sender, body) = hyper::Body::channel();
+  tokio::task::spawn(async move {
+    let mut users = filtered_users(state, &filter, tenant);
+    let mut buffer = Vec::with_capacity(EXPORT_CHUNK_LEN);
+    let mut first = true;
+    loop {
+      let user = users.next().await;
+      let result = match &user {
+        Some(Ok(user)) => serialize_user(format, first, user, &mut buffer),
+        Some(Err(e)) => Err(e.to_string()),
+        None => Ok(()),
+      };
+      // Abort rather than end the body, so the export isn't taken as complete
+      if let Err(e) = result {
+        eprintln!("User export failed: {}", e);
+        sender.abort();
+        return;
+      }
+      if buffer.len() >= EXPORT_CHUNK_LEN || (user.is_none() && !buffer.is_empty()) {
+        let chunk = std::mem::replace(&mut buffer, Vec::with_capacity(EXPORT_CHUNK_LEN));
+        // Fails if the client went away
+        if sender.send_data(chunk.into()).await.is_err() {
+          return;
+        }
+      }
+      if user.is_none() {
+        return;
+      }
+      first = false;
+    }
+  });
+
+  let (content_type, disposition) = match format {
+    ExportFormat::Ndjson => (
+      "application/x-ndjson",
+      "attachment; filename=\"users.jsonl\"",
+    ),
+    ExportFormat::Csv => (
+      "text/csv; charset=utf-8",
+      "attachment; filename=\"users.csv\"",
+    ),
+  };
+  let mut re = Response::new(body);
+  re.headers_mut()
+    .insert("Content-Type", HeaderValue::from_static(content_type));
+  re.headers_mut()
+    .insert("Content-Disposition", HeaderValue::from_static(disposition));
+  Ok(re)
+}
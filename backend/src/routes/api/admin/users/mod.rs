@@ -1,9 +1,80 @@
 use super::*;
 
+mod bulk;
 mod user;
 
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
 use shared_types::{AdminReturnableUser, NewUser, UsersFilter, UsersOrder};
 
+// The users matching a filter, as listed and exported
+// Limited to the ones managed by the org admin if given their tenant
+fn filtered_users<'a>(
+  state: &'static State,
+  filter: &'a UsersFilter,
+  tenant: Option<i32>,
+) -> BoxStream<'a, Result<AdminReturnableUser, sqlx::Error>> {
+  // Note the null checking around every filter
+  // Usernames are matched normalized and case-insensitively, as they're compared
+  sqlx_order!( stream AdminReturnableUser, &state.db_pool;
+    "
+SELECT id, username, admin, locked, locked_until, locked_reason, deleted_at,
+  max_sessions, email FROM users
+WHERE
+      (id <= $1 OR $1 IS NULL) AND
+      (id >= $2 OR $2 IS NULL) AND
+      (username_normalized COLLATE \"und-x-icu\" ~* $3 OR $3 IS NULL) AND
+      (username_normalized COLLATE \"und-x-icu\" !~* $4 OR $4 IS NULL) AND
+      (admin = $5 OR $5 IS NULL) AND
+      (locked = $6 OR $6 IS NULL) AND
+      ((locked AND (locked_until IS NULL OR locked_until > NOW())) = $7 OR $7 IS NULL) AND
+      (deleted_at IS NULL OR $8 OR $11::bool IS NOT NULL) AND
+      ((deleted_at IS NOT NULL) = $11 OR $11 IS NULL) AND
+      ($10::int IS NULL OR org_manages($10, id))
+    ",
+    "
+LIMIT $9
+    ",
+    filter.id_lte,
+    filter.id_mte,
+    filter.username_regex,
+    filter.username_nregex,
+    filter.admin_eq,
+    filter.locked_eq,
+    filter.lock_active_eq,
+    filter.include_deleted,
+    filter.limit,
+    tenant,
+    filter.deleted_eq,
+    // Define match cases and what ORDER TO to insert for each
+    ; filter.order_by ;
+    UsersOrder::IdAsc , "ORDER BY id ASC";
+    UsersOrder::IdDesc , "ORDER BY id DESC";
+    UsersOrder::UsernameAsc , "ORDER BY username_normalized ASC";
+    UsersOrder::UsernameDesc , "ORDER BY username_normalized DESC";
+  )
+}
+
+// Org admins manage the members of their organization that are in no other
+// and aren't admins or org admins (see org_manages in the migrations)
+// Others are as good as nonexistent to them
+async fn verify_managed(
+  state: &'static State,
+  req: &Request,
+  orgid: i32,
+  userid: i32,
+) -> Result<(), Error> {
+  let managed = sqlx::query!("SELECT org_manages($1, $2) AS \"managed!\"", orgid, userid,)
+    .fetch_one(&state.db_pool)
+    .await?
+    .managed;
+  if managed {
+    Ok(())
+  } else {
+    Err(Error::path_not_found(req))
+  }
+}
+
 pub async fn route(
   state: &'static State,
   mut req: Request,
@@ -17,36 +88,10 @@ pub async fn route(
         &Method::GET => {
           // Parse out query part of URI into filter
           let filter: UsersFilter = parse_filter(&req)?;
-          // Fetch the data from database
-          // Note the null checking around every filter
-          let users = sqlx_order!( AdminReturnableUser, &state.db_pool;
-            "
-SELECT id, username, admin, locked FROM users
-WHERE
-      id <= $1 OR $1 IS NULL AND
-      id >= $2 OR $2 IS NULL AND
-      username ~ $3 OR $3 IS NULL AND
-      username !~ $4 OR $4 IS NULL AND
-      admin = $5 OR $5 IS NULL AND
-      locked = $6 OR $6 IS NULL
-            ",
-            "
-LIMIT $7
-            ",
-            filter.id_lte,
-            filter.id_mte,
-            filter.username_regex,
-            filter.username_nregex,
-            filter.admin_eq,
-            filter.locked_eq,
-            filter.limit,
-            // Define match cases and what ORDER TO to insert for each
-            ; filter.order_by ;
-            UsersOrder::IdAsc , "ORDER BY id ASC";
-            UsersOrder::IdDesc , "ORDER BY id DESC";
-            UsersOrder::UsernameAsc , "ORDER BY username ASC";
-            UsersOrder::UsernameDesc , "ORDER BY username DESC";
-          );
+          let users: Vec<AdminReturnableUser> =
+            filtered_users(state, &filter, permissions.tenant())
+              .try_collect()
+              .await?;
           if users.is_empty() {
             empty()
           } else {
@@ -55,35 +100,62 @@ LIMIT $7
         }
         &Method::POST => {
           let new_user: NewUser = parse_json(&mut req, state.max_content_len).await?;
+          // Org admins create members of their organization, never admins
+          if permissions.tenant().is_some() && new_user.admin {
+            return Err(Error::forbidden());
+          }
+          state.username_rules.verify(&new_user.username)?;
+          crate::mail::verify_address(new_user.email.as_deref())?;
+          let mut tx = state.db_pool.begin().await?;
           let created_user = sqlx::query_as!(
             AdminReturnableUser,
             "
-INSERT INTO users(username,locked,admin) VALUES($1,$2,$3)
-RETURNING id,username,admin,locked
+INSERT INTO users(username,locked,admin,locked_until,locked_reason,email)
+VALUES($1,$2,$3,$4,$5,$6)
+RETURNING id,username,admin,locked,locked_until,locked_reason,deleted_at,max_sessions,email
             ",
             new_user.username,
             new_user.locked,
             new_user.admin,
+            new_user.locked_until,
+            new_user.locked_reason,
+            new_user.email,
           )
-          .fetch_one(&state.db_pool)
+          .fetch_one(&mut tx)
           .await
           .map_err(|e| -> Error {
             match e {
               sqlx::Error::Database(ref err) => match err.constraint() {
-                Some("username") => Error::username_taken(),
+                Some("users_username_normalized_key") => Error::username_taken(),
                 _ => e.into(),
               },
               _ => e.into(),
             }
           })?;
+          if let Some(orgid) = permissions.tenant() {
+            crate::db::join_organization(&mut tx, orgid, created_user.id).await?;
+          }
+          tx.commit().await?;
           set_status(json(&created_user), StatusCode::CREATED)
         }
         _ => Err(Error::method_not_found(&req)),
       }
     }
+    // Create users with existing password hashes
+    Some("import") => {
+      verify_method_path_end(&path_vec, &req, &Method::POST)?;
+      bulk::import(state, req, permissions.tenant()).await
+    }
+    Some("export") => {
+      verify_method_path_end(&path_vec, &req, &Method::GET)?;
+      bulk::export(state, req, permissions.tenant())
+    }
     // If there is more than base path parse it as a userid
     Some(sessionid) => {
       let parsed = sessionid.parse::<i32>()?;
+      if let Some(orgid) = permissions.tenant() {
+        verify_managed(state, &req, orgid, parsed).await?;
+      }
       user::route(state, req, path_vec, permissions, parsed).await
     }
   }
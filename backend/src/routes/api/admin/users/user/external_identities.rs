@@ -0,0 +1,81 @@
+use super::*;
+
+use shared_types::ExternalIdentity;
+
+pub async fn route(
+  state: &'static State,
+  mut req: Request,
+  path_vec: Vec<String>,
+  userid: i32,
+) -> Result<Response, Error> {
+  verify_path_end(&path_vec, &req)?;
+  match *req.method() {
+    Method::GET => {
+      let identities = sqlx::query_as!(
+        ExternalIdentity,
+        "SELECT issuer, subject FROM external_identities WHERE userid = $1 ORDER BY issuer, subject",
+        userid,
+      )
+      .fetch_all(&state.db_pool)
+      .await?;
+      if identities.is_empty() {
+        empty()
+      } else {
+        json(&identities)
+      }
+    }
+    // Link an identity, so logging in as it logs in as the user
+    Method::PUT => {
+      crate::db::verify_not_protected(userid)?;
+      let identity: ExternalIdentity = parse_json(&mut req, state.max_content_len).await?;
+      let linked = sqlx::query!(
+        "
+INSERT INTO external_identities(issuer, subject, userid)
+SELECT $1, $2, id FROM users WHERE id = $3 AND deleted_at IS NULL
+        ",
+        identity.issuer,
+        identity.subject,
+        userid,
+      )
+      .execute(&state.db_pool)
+      .await
+      .map_err(|e| -> Error {
+        match e {
+          sqlx::Error::Database(ref err) => match err.constraint() {
+            Some("external_identities_pkey") => Error::external_identity_taken(),
+            _ => e.into(),
+          },
+          _ => e.into(),
+        }
+      })?
+      .rows_affected();
+      if linked == 0 {
+        return Err(Error::path_not_found(&req));
+      }
+      empty()
+    }
+    // Unlinking may leave an admin without a way to log in
+    Method::DELETE => {
+      crate::db::verify_not_protected(userid)?;
+      let identity: ExternalIdentity = parse_filter(&req)?;
+      let mut tx = state.db_pool.begin().await?;
+      let admins = crate::db::lock_active_admins(state, &mut tx).await?;
+      let unlinked = sqlx::query!(
+        "DELETE FROM external_identities WHERE issuer = $1 AND subject = $2 AND userid = $3",
+        identity.issuer,
+        identity.subject,
+        userid,
+      )
+      .execute(&mut tx)
+      .await?
+      .rows_affected();
+      if unlinked == 0 {
+        return Err(Error::path_not_found(&req));
+      }
+      crate::db::verify_admins_remain(state, &mut tx, admins).await?;
+      tx.commit().await?;
+      empty()
+    }
+    _ => Err(Error::method_not_found(&req)),
+  }
+}
@@ -1,9 +1,11 @@
 use super::*;
 
+mod external_identities;
 mod impersonate;
 mod password;
+mod preferences;
 
-use shared_types::UpdateUser;
+use shared_types::{RestoreUser, UpdateUser};
 
 pub async fn route(
   state: &'static State,
@@ -19,7 +21,11 @@ pub async fn route(
         &Method::GET => {
           let user = sqlx::query_as!(
             super::AdminReturnableUser,
-            "SELECT id, username, admin, locked FROM users WHERE id = $1",
+            "
+SELECT id, username, admin, locked, locked_until, locked_reason, deleted_at,
+  max_sessions, email
+FROM users WHERE id = $1
+            ",
             userid
           )
           .fetch_one(&state.db_pool)
@@ -27,43 +33,138 @@ pub async fn route(
           json(&user)
         }
         &Method::PUT => {
-          if userid < 1 {
-            return Err(Error::method_not_found(&req));
-          }
+          crate::db::verify_not_protected(userid)?;
           let update: UpdateUser = parse_json(&mut req, state.max_content_len).await?;
+          // Deleted users are restored before they're changed, so their
+          // (possibly freed) usernames aren't checked against the rules
+          let exists = sqlx::query!(
+            "SELECT id FROM users WHERE id = $1 AND deleted_at IS NULL",
+            userid,
+          )
+          .fetch_optional(&state.db_pool)
+          .await?;
+          if exists.is_none() {
+            return Err(Error::path_not_found(&req));
+          }
+          // Only admins above organizations make admins
+          if permissions.tenant().is_some() && update.admin {
+            return Err(Error::forbidden());
+          }
+          state
+            .username_rules
+            .verify_rename(&state.db_pool, userid, &update.username)
+            .await?;
+          crate::mail::verify_address(update.email.as_deref())?;
+          if update.max_sessions.is_some_and(|max| max < 0) {
+            return Err(Error::invalid_max_sessions());
+          }
+          let mut tx = state.db_pool.begin().await?;
+          let admins = crate::db::lock_active_admins(state, &mut tx).await?;
           let updated = sqlx::query_as!(
             super::AdminReturnableUser,
             "
-UPDATE users SET username = $2, admin = $3, locked = $4 WHERE id = $1
-RETURNING id, username, admin, locked
+UPDATE users SET
+  username = $2, admin = $3, locked = $4, locked_until = $5, locked_reason = $6,
+  max_sessions = $7, email = $8
+WHERE id = $1 AND deleted_at IS NULL
+RETURNING id, username, admin, locked, locked_until, locked_reason, deleted_at,
+  max_sessions, email
             ",
             userid,
             update.username,
             update.admin,
             update.locked,
+            update.locked_until,
+            update.locked_reason,
+            update.max_sessions,
+            update.email,
           )
-          .fetch_one(&state.db_pool)
-          .await?;
-          json(&updated)
+          .fetch_optional(&mut tx)
+          .await
+          .map_err(|e| -> Error {
+            match e {
+              sqlx::Error::Database(ref err) => match err.constraint() {
+                Some("users_username_normalized_key") => Error::username_taken(),
+                _ => e.into(),
+              },
+              _ => e.into(),
+            }
+          })?;
+          crate::db::verify_admins_remain(state, &mut tx, admins).await?;
+          tx.commit().await?;
+          crate::auth::session_cache::forget_user(state, userid);
+          match updated {
+            Some(updated) => json(&updated),
+            None => Err(Error::path_not_found(&req)),
+          }
         }
         &Method::DELETE => {
-          if userid < 1 {
-            return Err(Error::method_not_found(&req));
-          }
-          let affected = sqlx::query!("DELETE FROM users WHERE id = $1", userid)
-            .execute(&state.db_pool)
-            .await?
-            .rows_affected();
-          match affected {
-            0 => Err(Error::path_not_found(&req)),
-            _ => empty(),
+          crate::db::verify_not_protected(userid)?;
+          match crate::db::delete_user(state, userid).await? {
+            false => Err(Error::path_not_found(&req)),
+            true => empty(),
           }
         }
         _ => Err(Error::method_not_found(&req)),
       }
     }
-    Some("password") => password::route(state, req, path_vec, permissions, userid).await,
-    Some("impersonate") => impersonate::route(state, req, path_vec, permissions, userid).await,
+    Some("restore") => {
+      verify_method_path_end(&path_vec, &req, &Method::POST)?;
+      crate::db::verify_not_protected(userid)?;
+      // Under another username if the old one has been taken meanwhile
+      let restore: RestoreUser = parse_filter(&req)?;
+      if let Some(username) = &restore.username {
+        state.username_rules.verify(username)?;
+      }
+      // Undelete the user, restoring the username if it was freed
+      let restored = sqlx::query_as!(
+        super::AdminReturnableUser,
+        "
+UPDATE users SET
+  deleted_at = NULL,
+  username = COALESCE($2, regexp_replace(username, '^deleted:' || id || ':', ''))
+WHERE id = $1 AND deleted_at IS NOT NULL
+RETURNING id, username, admin, locked, locked_until, locked_reason, deleted_at,
+  max_sessions, email
+        ",
+        userid,
+        restore.username,
+      )
+      .fetch_optional(&state.db_pool)
+      .await
+      .map_err(|e| -> Error {
+        match e {
+          sqlx::Error::Database(ref err) => match err.constraint() {
+            Some("users_username_normalized_key") => Error::username_taken(),
+            _ => e.into(),
+          },
+          _ => e.into(),
+        }
+      })?;
+      match restored {
+        Some(restored) => json(&restored),
+        None => Err(Error::path_not_found(&req)),
+      }
+    }
+    // Take down an inappropriate avatar
+    Some("avatar") => {
+      verify_method_path_end(&path_vec, &req, &Method::DELETE)?;
+      match crate::avatars::remove(state, userid).await? {
+        false => Err(Error::path_not_found(&req)),
+        true => empty(),
+      }
+    }
+    Some("password") => password::route(state, req, path_vec, userid).await,
+    // Linking lets whoever has the identity log in, so not for org admins
+    Some("external_identities") => {
+      crate::auth::require_super_admin(&permissions)?;
+      external_identities::route(state, req, path_vec, userid).await
+    }
+    Some("impersonate") => {
+      // Org admins' impersonation stays within their organization
+      impersonate::route(state, req, path_vec, userid, permissions.tenant()).await
+    }
+    Some("preferences") => preferences::route(state, req, path_vec, userid).await,
     _ => Err(Error::path_not_found(&req)),
   }
 }
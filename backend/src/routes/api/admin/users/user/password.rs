@@ -6,71 +6,62 @@ pub async fn route(
   state: &'static State,
   mut req: Request,
   path_vec: Vec<String>,
-  permissions: Permissions,
   userid: i32,
 ) -> Result<Response, Error> {
   verify_path_end(&path_vec, &req)?;
   match req.method() {
     &Method::DELETE => {
-      if userid < 1 {
-        return Err(Error::method_not_found(&req));
+      crate::db::verify_not_protected(userid)?;
+      let mut tx = state.db_pool.begin().await?;
+      let admins = crate::db::lock_active_admins(state, &mut tx).await?;
+      let affected = sqlx::query!(
+        "UPDATE users SET pass = NULL WHERE id = $1 AND deleted_at IS NULL",
+        userid,
+      )
+      .execute(&mut tx)
+      .await?
+      .rows_affected();
+      if affected == 0 {
+        return Err(Error::path_not_found(&req));
       }
-      sqlx::query!("UPDATE users SET pass = NULL WHERE id = $1", userid,)
-        .execute(&state.db_pool)
-        .await?;
       // Also invalidate sessions, as per documentation
       sqlx::query!("DELETE FROM sessions WHERE userid = $1", userid,)
-        .execute(&state.db_pool)
+        .execute(&mut tx)
         .await?;
+      crate::db::verify_admins_remain(state, &mut tx, admins).await?;
+      tx.commit().await?;
+      crate::auth::session_cache::forget_user(state, userid);
       empty()
     }
     &Method::POST => {
       let query: PasswordReset = parse_json(&mut req, state.max_content_len).await?;
 
-      // Verify the admin_password, so it takes more than a session key to
-      // create unlimited session keys
-      let admin_user = sqlx::query!(
-        "SELECT pass, locked FROM users WHERE id = $1",
-        permissions.userid
-      )
-      .fetch_one(&state.db_pool)
-      .await?;
-      let admin_hash = match admin_user.pass {
-        Some(hash) => hash,
-        None => {
-          // Normally impossible, since setting passhash to None
-          // also deletes all sessions (but maybe race condition).
-          // However, impersonate makes it possible again.
-          return Err(Error::bad_login());
-        }
-      };
-      let correct_pass = crate::auth::hash::verify(
-        &state.cpu_semaphore,
-        &state.hasher,
-        admin_hash,
-        query.admin_password,
-      )
-      .await?;
-      if !correct_pass {
-        return Err(Error::bad_login());
-      }
-      if admin_user.locked {
-        return Err(Error::account_locked());
-      }
-
       // Hash the new user password
       let new_hash =
         crate::auth::hash::hash(&state.cpu_semaphore, &state.hasher, query.new_password).await?;
-      // Apply the new password
-      sqlx::query!("UPDATE users SET pass = $1 WHERE id = $2", new_hash, userid,)
-        .execute(&state.db_pool)
-        .await?;
+      // Apply the new password, requiring the user to change it on next login
+      // Deleted users are restored first, to not make them usable unnoticed
+      let affected = sqlx::query!(
+        "
+UPDATE users SET pass = $1, password_changed_at = NOW(), must_change_password = true
+WHERE id = $2 AND deleted_at IS NULL
+        ",
+        new_hash,
+        userid,
+      )
+      .execute(&state.db_pool)
+      .await?
+      .rows_affected();
+      if affected == 0 {
+        return Err(Error::path_not_found(&req));
+      }
       // If clear_sessions given we do so _after_ changing the password
       if query.clear_sessions {
         sqlx::query!("DELETE FROM sessions WHERE userid = $1", userid)
           .execute(&state.db_pool)
           .await?;
       }
+      crate::auth::session_cache::forget_user(state, userid);
       empty()
     }
     _ => Err(Error::method_not_found(&req)),
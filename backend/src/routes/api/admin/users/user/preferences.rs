@@ -0,0 +1,33 @@
+use super::*;
+
+use crate::preferences;
+
+// Read access to a user's preferences, as the user has
+pub async fn route(
+  state: &'static State,
+  req: Request,
+  mut path_vec: Vec<String>,
+  userid: i32,
+) -> Result<Response, Error> {
+  verify_method(&req, &Method::GET)?;
+  let namespace = path_vec.pop().filter(|namespace| !namespace.is_empty());
+  let key = path_vec.pop().filter(|key| !key.is_empty());
+  verify_path_end(&path_vec, &req)?;
+  match (namespace, key) {
+    (None, None) => json(&preferences::list(&state.db_pool, userid, None).await?),
+    (Some(namespace), None) => {
+      preferences::verify_name(&namespace)?;
+      let mut all = preferences::list(&state.db_pool, userid, Some(&namespace)).await?;
+      json(&all.remove(&namespace).unwrap_or_default())
+    }
+    (Some(namespace), Some(key)) => {
+      preferences::verify_name(&namespace)?;
+      preferences::verify_name(&key)?;
+      match preferences::get(state, userid, &namespace, &key).await? {
+        Some(preference) => json_with_etag(&req, &preference.value, &preference.etag),
+        None => Err(Error::path_not_found(&req)),
+      }
+    }
+    (None, Some(_)) => Err(Error::path_not_found(&req)),
+  }
+}
@@ -0,0 +1,117 @@
+use super::*;
+
+use crate::auth::external::Finished;
+use serde::Deserialize;
+use shared_types::{ExternalLoginTicket, Session};
+
+// Holds the state of the login being made in the browser, so that the
+// callback only finishes logins the same browser started
+const LOGIN_COOKIE: &str = "external_login";
+
+// Set the cookie of a started login, on the response sending the user on
+// Lax, since the provider sends the user back with a cross-site redirect
+pub fn set_login_cookie(re: &mut Response, login_state: &str) {
+  let cookie = format!(
+    "{}={}; Path=/api/external_login; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+    LOGIN_COOKIE,
+    login_state,
+    crate::auth::external::LOGIN_LIFETIME_MINUTES * 60,
+  );
+  // The state is generated by nanoid, so it is a valid header value
+  re.headers_mut().append(
+    "Set-Cookie",
+    HeaderValue::from_str(&cookie).expect("Generated cookie is an invalid header."),
+  );
+}
+
+// What the provider sends back to the callback
+#[derive(Deserialize)]
+struct Callback {
+  code: Option<String>,
+  state: Option<String>,
+}
+
+// The frontend links to the base path, which sends the user on to the
+// provider. The provider sends the user back to the callback, which creates
+// the session and sends the user to the frontend with a ticket. The frontend
+// then redeems the ticket for the session.
+pub async fn route(
+  state: &'static State,
+  mut req: Request,
+  mut path_vec: Vec<String>,
+) -> Result<Response, Error> {
+  let idp = match &state.external_idp {
+    Some(idp) => idp,
+    None => return Err(Error::path_not_found(&req)),
+  };
+  match path_vec.pop().as_deref() {
+    None | Some("") => {
+      verify_method_path_end(&path_vec, &req, &Method::GET)?;
+      let (uri, login_state) = crate::auth::external::start(state, idp, None).await?;
+      let mut re = redirect(&uri)?;
+      set_login_cookie(&mut re, &login_state);
+      Ok(re)
+    }
+    Some("callback") => {
+      verify_method_path_end(&path_vec, &req, &Method::GET)?;
+      let callback: Callback = parse_filter(&req)?;
+      // The provider gives no code if the user didn't log in
+      let (code, login_state) = match (callback.code, callback.state) {
+        (Some(code), Some(login_state)) => (code, login_state),
+        _ => return Err(Error::bad_login()),
+      };
+      // Otherwise another site could log the user in as someone else
+      if get_cookie(&req, LOGIN_COOKIE)? != Some(login_state.as_str()) {
+        return Err(Error::bad_login());
+      }
+      let mut re = match crate::auth::external::finish(state, idp, &code, &login_state).await? {
+        Finished::Ticket(ticket) => redirect(&format!("/#external_login/{}", ticket))?,
+        Finished::Elevated => redirect("/#external_elevation")?,
+      };
+      re.headers_mut().append(
+        "Set-Cookie",
+        HeaderValue::from_str(&format!(
+          "{}=; Path=/api/external_login; Max-Age=0; HttpOnly; Secure; SameSite=Lax",
+          LOGIN_COOKIE
+        ))
+        .expect("Generated cookie is an invalid header."),
+      );
+      Ok(re)
+    }
+    Some("ticket") => {
+      verify_method_path_end(&path_vec, &req, &Method::POST)?;
+      let redeem: ExternalLoginTicket = parse_json(&mut req, state.max_content_len).await?;
+      let session = sqlx::query_as!(
+        Session,
+        "
+WITH t AS (
+  DELETE FROM external_login_tickets WHERE ticket = $1 AND until > NOW()
+  RETURNING sessionid
+)
+SELECT sessions.id AS \"id!\", sessions.key AS \"key!\", users.admin AS \"is_admin!\",
+  users.username AS \"username!\", sessions.until AS \"until!\",
+  (users.must_change_password OR COALESCE(users.password_changed_at < $2, false))
+    AS \"must_change_password!\",
+  sessions.orgid,
+  must_accept_terms(users.id) AS \"must_accept_terms!\"
+FROM t
+JOIN sessions ON sessions.id = t.sessionid
+JOIN users ON users.id = sessions.userid
+        ",
+        redeem.ticket,
+        state.password_expiry_cutoff(),
+      )
+      .fetch_optional(&state.db_pool)
+      .await?;
+      match session {
+        #[cfg(feature = "session_cookies")]
+        Some(session) if redeem.session_cookie => {
+          set_status(session_cookie_response(session), StatusCode::CREATED)
+        }
+        Some(session) => set_status(json(&session), StatusCode::CREATED),
+        None => Err(Error::bad_login()),
+      }
+    }
+    Some(_) => Err(Error::path_not_found(&req)),
+  }
+}
@@ -0,0 +1,37 @@
+use super::*;
+
+use shared_types::{MagicLinkRequest, MagicLinkToken};
+
+// The base path emails a link to the frontend, as /#magic_link/$token.
+// The frontend then redeems the token for a session.
+pub async fn route(
+  state: &'static State,
+  mut req: Request,
+  mut path_vec: Vec<String>,
+) -> Result<Response, Error> {
+  if state.mailer.is_none() || !(state.magic_link_users || state.magic_link_admins) {
+    return Err(Error::path_not_found(&req));
+  }
+  match path_vec.pop().as_deref() {
+    None | Some("") => {
+      verify_method_path_end(&path_vec, &req, &Method::POST)?;
+      let form: MagicLinkRequest = parse_json(&mut req, state.max_content_len).await?;
+      crate::auth::magic_link::request(state, form).await?;
+      empty()
+    }
+    Some("redeem") => {
+      verify_method_path_end(&path_vec, &req, &Method::POST)?;
+      let redeem: MagicLinkToken = parse_json(&mut req, state.max_content_len).await?;
+      let session = crate::auth::magic_link::redeem(state, &redeem.token).await?;
+      match session {
+        #[cfg(feature = "session_cookies")]
+        Some(session) if redeem.session_cookie => {
+          set_status(session_cookie_response(session), StatusCode::CREATED)
+        }
+        Some(session) => set_status(json(&session), StatusCode::CREATED),
+        None => Err(Error::bad_login()),
+      }
+    }
+    Some(_) => Err(Error::path_not_found(&req)),
+  }
+}
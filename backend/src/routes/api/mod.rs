@@ -2,8 +2,13 @@ use super::*;
 use shared_types::Login;
 
 mod admin;
+pub mod external_login;
+mod magic_link;
+mod oidc;
 mod user;
 
+pub use oidc::discovery;
+
 pub async fn route(
   state: &'static State,
   mut req: Request,
@@ -18,34 +23,96 @@ pub async fn route(
       verify_method_path_end(&path_vec, &req, &Method::POST)?;
       // Parse out request
       let credentials: Login = parse_json(&mut req, state.max_content_len).await?;
+      #[cfg(feature = "session_cookies")]
+      let session_cookie = credentials.session_cookie;
       // Call login handler
       let session = crate::auth::login(state, credentials).await?;
       // Slightly wrap up the result
       match session {
-        Some(session) => json(&session).map(|mut re| {
-          *re.status_mut() = StatusCode::CREATED;
-          re
-        }),
+        #[cfg(feature = "session_cookies")]
+        Some(session) if session_cookie => {
+          set_status(session_cookie_response(session), StatusCode::CREATED)
+        }
+        Some(session) => set_status(json(&session), StatusCode::CREATED),
         None => Err(Error::bad_login()),
       }
     }
+    // Signed access tokens, issued for the session given
+    Some("access_token") => {
+      verify_method_path_end(&path_vec, &req, &Method::POST)?;
+      if state.access_token_lifetime.is_none() {
+        return Err(Error::path_not_found(&req));
+      }
+      let session_key = get_session_key(&req)?;
+      let token = crate::auth::access_token::refresh(state, session_key).await?;
+      set_status(json(&token), StatusCode::CREATED)
+    }
+    // The latest terms of service, readable before logging in
+    Some("terms") => {
+      verify_method_path_end(&path_vec, &req, &Method::GET)?;
+      match crate::terms::latest(state).await? {
+        Some(terms) => json(&terms),
+        None => Err(Error::path_not_found(&req)),
+      }
+    }
+    // Logging in by emailed link
+    Some("magic_link") => magic_link::route(state, req, path_vec).await,
+    // Logging in through an external identity provider
+    Some("external_login") => external_login::route(state, req, path_vec).await,
+    // OpenID Connect provider, authenticates as needed per endpoint
+    Some("oidc") => oidc::route(state, req, path_vec).await,
     Some("admin") => {
       // Require authentication
-      let session_key = unwrap_bearer(get_header(&req, "Authorization")?);
+      let session_key = get_session_key(&req)?;
       let permissions = crate::auth::require_admin(state, session_key.clone()).await?;
+      // Access tokens given to OpenID Connect clients are only for userinfo
+      if permissions.clientid.is_some() {
+        return Err(Error::forbidden());
+      }
+      // Sessions that must change password or accept the terms can't administrate
+      if permissions.must_change_password {
+        return Err(Error::password_change_required());
+      }
+      if permissions.must_accept_terms {
+        return Err(Error::terms_acceptance_required());
+      }
       // Call into detail routing
       admin::route(state, req, path_vec, permissions).await
     }
     Some(p) => {
       // Require authentication
-      let session_key = unwrap_bearer(get_header(&req, "Authorization")?);
-      let permissions = crate::auth::require_session(state, session_key.clone()).await?;
+      let session_key = get_session_key(&req)?;
+      let permissions = crate::auth::require_session(state, session_key).await?;
+      if permissions.clientid.is_some() {
+        return Err(Error::forbidden());
+      }
+      // Sessions that must change password may only do that (or log out)
+      if permissions.must_change_password {
+        match (p, path_vec.last().map(|s| s.as_str())) {
+          ("logout", _) | ("user", Some("password")) => (),
+          _ => return Err(Error::password_change_required()),
+        }
+      }
+      // Likewise for accepting the terms, after any password change
+      if permissions.must_accept_terms {
+        match (p, path_vec.last().map(|s| s.as_str())) {
+          ("logout", _) | ("user", Some("password")) | ("user", Some("terms")) => (),
+          _ => return Err(Error::terms_acceptance_required()),
+        }
+      }
       match p {
         "logout" => {
           verify_method_path_end(&path_vec, &req, &Method::POST)?;
           // Call logout handler
-          crate::auth::logout(state, session_key).await?;
-          empty()
+          crate::auth::logout(state, permissions.sessionid).await?;
+          let re = empty()?;
+          #[cfg(feature = "session_cookies")]
+          let re = {
+            let mut re = re;
+            clear_session_cookies(&mut re);
+            re
+          };
+          Ok(re)
         }
         "user" => user::route(state, req, path_vec, permissions).await,
         _ => Err(Error::path_not_found(&req)),
@@ -0,0 +1,117 @@
+use super::*;
+
+use shared_types::{AuthorizeRedirect, AuthorizeRequest};
+
+// The user's browser is first sent here by the client (GET), which validates
+// the request and sends the browser on to the frontend. There the user logs in
+// if needed and confirms, which POSTs the same query with the user's session.
+// That creates the code and gives the frontend the redirect back to the client.
+pub async fn route(
+  state: &'static State,
+  req: Request,
+  path_vec: Vec<String>,
+) -> Result<Response, Error> {
+  verify_path_end(&path_vec, &req)?;
+  let request: AuthorizeRequest = parse_filter(&req)?;
+  // Until the redirect_uri is verified errors must not be sent to it
+  let clientid = validate_client(state, &request).await?;
+  match req.method() {
+    &Method::GET => match validate_request(&request) {
+      Ok(()) => redirect(&format!("/?{}#authorize", req.uri().query().unwrap_or(""))),
+      Err(error) => redirect(&client_redirect(&request, "error", error)?),
+    },
+    &Method::POST => {
+      let permissions = crate::auth::require_session(state, get_session_key(&req)?).await?;
+      // Client tokens may not authorize other clients, and restricted sessions
+      // may only change password or accept the terms
+      if permissions.clientid.is_some() {
+        return Err(Error::forbidden());
+      }
+      if permissions.must_change_password {
+        return Err(Error::password_change_required());
+      }
+      if permissions.must_accept_terms {
+        return Err(Error::terms_acceptance_required());
+      }
+      let redirect = match validate_request(&request) {
+        Ok(()) => {
+          let code = nanoid::nanoid!(32);
+          sqlx::query!(
+            "
+INSERT INTO oidc_codes(code, clientid, userid, redirect_uri, scope, nonce, code_challenge, until)
+VALUES($1, $2, $3, $4, $5, $6, $7, $8)
+            ",
+            &code,
+            clientid,
+            permissions.userid,
+            request.redirect_uri,
+            request.scope,
+            request.nonce,
+            request.code_challenge,
+            chrono::offset::Utc::now().naive_utc()
+              + chrono::Duration::minutes(CODE_LIFETIME_MINUTES),
+          )
+          .execute(&state.db_pool)
+          .await?;
+          client_redirect(&request, "code", &code)?
+        }
+        Err(error) => client_redirect(&request, "error", error)?,
+      };
+      json(&AuthorizeRedirect { redirect })
+    }
+    _ => Err(Error::method_not_found(&req)),
+  }
+}
+
+// Get the id of the client, if it exists and has the redirect_uri registered
+async fn validate_client(state: &'static State, request: &AuthorizeRequest) -> Result<i32, Error> {
+  let client = sqlx::query!(
+    "SELECT id FROM oidc_clients WHERE client_id = $1 AND $2 = ANY(redirect_uris)",
+    request.client_id,
+    request.redirect_uri,
+  )
+  .fetch_optional(&state.db_pool)
+  .await?;
+  match client {
+    Some(client) => Ok(client.id),
+    None => Err(Error::invalid_client()),
+  }
+}
+
+// Check the parts of the request that are reported back to the client
+// Returns the OAuth 2.0 error code if invalid
+fn validate_request(request: &AuthorizeRequest) -> Result<(), &'static str> {
+  if request.response_type != "code" {
+    return Err("unsupported_response_type");
+  }
+  if !request.scope.split(' ').any(|scope| scope == "openid") {
+    return Err("invalid_scope");
+  }
+  // PKCE is required for all clients
+  match (
+    &request.code_challenge,
+    request.code_challenge_method.as_deref(),
+  ) {
+    (Some(challenge), Some("S256")) if !challenge.is_empty() => Ok(()),
+    _ => Err("invalid_request"),
+  }
+}
+
+// Build the URI to send the user back to the client with
+fn client_redirect(request: &AuthorizeRequest, key: &str, value: &str) -> Result<String, Error> {
+  let mut params = vec![(key, value)];
+  if let Some(state) = &request.state {
+    params.push(("state", state.as_str()));
+  }
+  let separator = if request.redirect_uri.contains('?') {
+    '&'
+  } else {
+    '?'
+  };
+  Ok(format!(
+    "{}{}{}",
+    request.redirect_uri,
+    separator,
+    serde_urlencoded::to_string(params).map_err(|_| Error::invalid_redirect_uri())?
+  ))
+}
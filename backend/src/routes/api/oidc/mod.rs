@@ -0,0 +1,64 @@
+use super::*;
+
+use shared_types::{OidcDiscovery, UserInfo};
+
+mod authorize;
+mod token;
+
+// How long the different credentials of the flow are valid
+const CODE_LIFETIME_MINUTES: i64 = 5;
+const ACCESS_TOKEN_LIFETIME_MINUTES: i64 = 60;
+
+// Served at /.well-known/openid-configuration
+pub async fn discovery(
+  state: &'static State,
+  req: Request,
+  path_vec: Vec<String>,
+) -> Result<Response, Error> {
+  verify_method_path_end(&path_vec, &req, &Method::GET)?;
+  let issuer = &state.oidc_issuer;
+  let strings = |list: &[&str]| list.iter().map(|s| s.to_string()).collect();
+  json(&OidcDiscovery {
+    issuer: issuer.clone(),
+    authorization_endpoint: format!("{}/api/oidc/authorize", issuer),
+    token_endpoint: format!("{}/api/oidc/token", issuer),
+    userinfo_endpoint: format!("{}/api/oidc/userinfo", issuer),
+    jwks_uri: format!("{}/api/oidc/jwks", issuer),
+    response_types_supported: strings(&["code"]),
+    grant_types_supported: strings(&["authorization_code"]),
+    subject_types_supported: strings(&["public"]),
+    id_token_signing_alg_values_supported: strings(&["EdDSA"]),
+    scopes_supported: strings(&["openid", "profile"]),
+    token_endpoint_auth_methods_supported: strings(&["client_secret_post", "none"]),
+    code_challenge_methods_supported: strings(&["S256"]),
+  })
+}
+
+pub async fn route(
+  state: &'static State,
+  req: Request,
+  mut path_vec: Vec<String>,
+) -> Result<Response, Error> {
+  match path_vec.pop().as_deref() {
+    Some("authorize") => authorize::route(state, req, path_vec).await,
+    Some("token") => token::route(state, req, path_vec).await,
+    Some("jwks") => {
+      verify_method_path_end(&path_vec, &req, &Method::GET)?;
+      json(&crate::auth::jwt::Jwks {
+        keys: vec![state.signing_key.jwk()],
+      })
+    }
+    Some("userinfo") => {
+      verify_path_end(&path_vec, &req)?;
+      if req.method() != Method::GET && req.method() != Method::POST {
+        return Err(Error::method_not_found(&req));
+      }
+      let permissions = crate::auth::require_session(state, get_session_key(&req)?).await?;
+      json(&UserInfo {
+        sub: permissions.userid.to_string(),
+        preferred_username: permissions.username,
+      })
+    }
+    _ => Err(Error::path_not_found(&req)),
+  }
+}
@@ -0,0 +1,116 @@
+use super::*;
+
+use chrono::{offset::Utc, Duration};
+use sha2::{Digest, Sha256};
+use shared_types::{IdTokenClaims, TokenError, TokenRequest, TokenResponse};
+
+// Errors from this endpoint are in the OAuth 2.0 format, so clients understand them
+fn token_error(status: StatusCode, error: &str) -> Result<Response, Error> {
+  set_status(
+    json(&TokenError {
+      error: error.to_string(),
+    }),
+    status,
+  )
+}
+
+pub async fn route(
+  state: &'static State,
+  mut req: Request,
+  path_vec: Vec<String>,
+) -> Result<Response, Error> {
+  verify_method_path_end(&path_vec, &req, &Method::POST)?;
+  let form: TokenRequest = parse_form(&mut req, state.max_content_len).await?;
+  if form.grant_type != "authorization_code" {
+    return token_error(StatusCode::BAD_REQUEST, "unsupported_grant_type");
+  }
+
+  // Deleting the code makes sure it is only used once
+  let code = match sqlx::query!(
+    "
+DELETE FROM oidc_codes WHERE code = $1 AND until > NOW()
+RETURNING clientid, userid, redirect_uri, scope, nonce, code_challenge
+    ",
+    form.code,
+  )
+  .fetch_optional(&state.db_pool)
+  .await?
+  {
+    Some(code) => code,
+    None => return token_error(StatusCode::BAD_REQUEST, "invalid_grant"),
+  };
+  let client = sqlx::query!(
+    "SELECT client_id, secret FROM oidc_clients WHERE id = $1",
+    code.clientid,
+  )
+  .fetch_one(&state.db_pool)
+  .await?;
+  if client.client_id != form.client_id || code.redirect_uri != form.redirect_uri {
+    return token_error(StatusCode::BAD_REQUEST, "invalid_grant");
+  }
+  // Confidential clients must also authenticate with their secret
+  if let Some(hash) = client.secret {
+    let correct_secret = match form.client_secret {
+      Some(secret) => {
+        crate::auth::hash::verify(&state.cpu_semaphore, &state.hasher, hash, secret).await?
+      }
+      None => false,
+    };
+    if !correct_secret {
+      return token_error(StatusCode::UNAUTHORIZED, "invalid_client");
+    }
+  }
+  // PKCE, only the client that started the flow knows the verifier
+  let challenge = base64::encode_config(
+    Sha256::digest(form.code_verifier.as_bytes()),
+    base64::URL_SAFE_NO_PAD,
+  );
+  if challenge != code.code_challenge {
+    return token_error(StatusCode::BAD_REQUEST, "invalid_grant");
+  }
+
+  // The user may have been locked or deleted since authorizing
+  let user = match sqlx::query!(
+    "
+SELECT username FROM users
+WHERE id = $1 AND deleted_at IS NULL AND
+  NOT (locked AND (locked_until IS NULL OR locked_until > NOW()))
+    ",
+    code.userid,
+  )
+  .fetch_optional(&state.db_pool)
+  .await?
+  {
+    Some(user) => user,
+    None => return token_error(StatusCode::BAD_REQUEST, "invalid_grant"),
+  };
+
+  // The access token is a session restricted to userinfo
+  let now = Utc::now();
+  let access_token = nanoid::nanoid!(32);
+  sqlx::query!(
+    "INSERT INTO sessions(userid, key, until, clientid) VALUES($1, $2, $3, $4)",
+    code.userid,
+    &access_token,
+    now.naive_utc() + Duration::minutes(ACCESS_TOKEN_LIFETIME_MINUTES),
+    code.clientid,
+  )
+  .execute(&state.db_pool)
+  .await?;
+  let id_token = state.signing_key.sign(&IdTokenClaims {
+    iss: state.oidc_issuer.clone(),
+    sub: code.userid.to_string(),
+    aud: form.client_id,
+    exp: (now + Duration::minutes(ACCESS_TOKEN_LIFETIME_MINUTES)).timestamp(),
+    iat: now.timestamp(),
+    nonce: code.nonce,
+    preferred_username: user.username,
+  })?;
+  json(&TokenResponse {
+    access_token,
+    token_type: "Bearer".to_string(),
+    expires_in: ACCESS_TOKEN_LIFETIME_MINUTES * 60,
+    id_token,
+    scope: code.scope,
+  })
+}
@@ -0,0 +1,33 @@
+use super::*;
+
+use crate::avatars;
+
+pub async fn route(
+  state: &'static State,
+  mut req: Request,
+  path_vec: Vec<String>,
+  permissions: Permissions,
+) -> Result<Response, Error> {
+  verify_path_end(&path_vec, &req)?;
+  match req.method() {
+    // Upload as the field avatar of a multipart form
+    &Method::PUT => {
+      let mut form = get_multipart(&mut req, state.max_avatar_len)?;
+      let mut upload = None;
+      while let Some(field) = form.next_field().await? {
+        if field.name() == Some("avatar") {
+          upload = Some(field.bytes().await?);
+          break;
+        }
+      }
+      let upload = upload.ok_or_else(|| Error::invalid_image("No avatar field in form"))?;
+      avatars::store(state, permissions.userid, upload.to_vec()).await?;
+      json(&get_user(state, permissions.userid).await?)
+    }
+    &Method::DELETE => match avatars::remove(state, permissions.userid).await? {
+      false => Err(Error::path_not_found(&req)),
+      true => empty(),
+    },
+    _ => Err(Error::method_not_found(&req)),
+  }
+}
@@ -0,0 +1,106 @@
+use super::*;
+
+use shared_types::ExportedTermsAcceptance;
+use shared_types::{ExportedAuthorizationCode, ExportedExternalIdentity, ExportedMagicLink};
+use shared_types::{ExportedSession, ExportedUser, UserDataExport, UserOrganization};
+
+// Everything held about the user, as a json file to download
+// Read in one transaction, so the parts are consistent with each other
+pub async fn route(
+  state: &'static State,
+  req: Request,
+  path_vec: Vec<String>,
+  permissions: Permissions,
+) -> Result<Response, Error> {
+  verify_method_path_end(&path_vec, &req, &Method::GET)?;
+  let mut tx = state.db_pool.begin().await?;
+  sqlx::query!("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY")
+    .execute(&mut tx)
+    .await?;
+  let user = sqlx::query_as!(
+    ExportedUser,
+    "
+SELECT id, username, admin, display_name, locale, timezone, avatar, email, external_id,
+  pass IS NOT NULL AS \"has_password!\", password_changed_at, must_change_password,
+  locked, locked_until, locked_reason, max_sessions
+FROM users WHERE id = $1
+    ",
+    permissions.userid,
+  )
+  .fetch_one(&mut tx)
+  .await?;
+  let sessions = sqlx::query_as!(
+    ExportedSession,
+    "
+SELECT sessions.id, until, elevated_until, oidc_clients.name AS \"client?\"
+FROM sessions LEFT JOIN oidc_clients ON oidc_clients.id = sessions.clientid
+WHERE userid = $1 ORDER BY sessions.id
+    ",
+    permissions.userid,
+  )
+  .fetch_all(&mut tx)
+  .await?;
+  let external_identities = sqlx::query_as!(
+    ExportedExternalIdentity,
+    "SELECT issuer, subject FROM external_identities WHERE userid = $1 ORDER BY issuer, subject",
+    permissions.userid,
+  )
+  .fetch_all(&mut tx)
+  .await?;
+  let magic_links = sqlx::query_as!(
+    ExportedMagicLink,
+    "SELECT extended, until FROM magic_links WHERE userid = $1 ORDER BY until",
+    permissions.userid,
+  )
+  .fetch_all(&mut tx)
+  .await?;
+  let authorization_codes = sqlx::query_as!(
+    ExportedAuthorizationCode,
+    "
+SELECT oidc_clients.name AS client, scope, until
+FROM oidc_codes JOIN oidc_clients ON oidc_clients.id = oidc_codes.clientid
+WHERE userid = $1 ORDER BY until
+    ",
+    permissions.userid,
+  )
+  .fetch_all(&mut tx)
+  .await?;
+  let organizations = sqlx::query_as!(
+    UserOrganization,
+    "
+SELECT organizations.id, organizations.name, memberships.org_admin
+FROM memberships JOIN organizations ON organizations.id = memberships.orgid
+WHERE memberships.userid = $1 ORDER BY organizations.id
+    ",
+    permissions.userid,
+  )
+  .fetch_all(&mut tx)
+  .await?;
+  let terms_acceptances = sqlx::query_as!(
+    ExportedTermsAcceptance,
+    "SELECT version, accepted_at FROM terms_acceptances WHERE userid = $1 ORDER BY version",
+    permissions.userid,
+  )
+  .fetch_all(&mut tx)
+  .await?;
+  let preferences = crate::preferences::list(&mut *tx, permissions.userid, None).await?;
+  tx.commit().await?;
+
+  let export = UserDataExport {
+    exported_at: chrono::offset::Utc::now().naive_utc(),
+    user: user,
+    sessions: sessions,
+    external_identities: external_identities,
+    magic_links: magic_links,
+    authorization_codes: authorization_codes,
+    organizations: organizations,
+    terms_acceptances: terms_acceptances,
+    preferences: preferences,
+  };
+  let mut re = json(&export)?;
+  re.headers_mut().insert(
+    "Content-Disposition",
+    HeaderValue::from_static("attachment; filename=\"user-data.json\""),
+  );
+  Ok(re)
+}
@@ -1,31 +1,187 @@
 use super::*;
 
-use shared_types::ReturnableUser;
+use shared_types::{DeleteAccount, ReturnableUser, UpdateProfile};
 
+mod avatar;
+mod export;
+mod organizations;
 mod password;
+mod preferences;
 mod sessions;
+mod terms;
+
+// Structural check of a BCP 47 language tag, such as 'en', 'sv-SE' or
+// 'zh-Hant-TW', without checking the subtags against the registry
+fn is_language_tag(tag: &str) -> bool {
+  let mut subtags = tag.split('-');
+  let language = subtags.next().unwrap_or("");
+  tag.len() <= 35
+    && matches!(language.len(), 2..=3 | 5..=8)
+    && language.chars().all(|c| c.is_ascii_alphabetic())
+    && subtags.all(|subtag| {
+      (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+    })
+}
+
+async fn get_user(state: &'static State, userid: i32) -> Result<ReturnableUser, Error> {
+  Ok(
+    sqlx::query_as!(
+      ReturnableUser,
+      "
+SELECT id, username, admin, display_name, locale, timezone, avatar FROM users WHERE id = $1
+      ",
+      userid,
+    )
+    .fetch_one(&state.db_pool)
+    .await?,
+  )
+}
+
+async fn update_profile(
+  state: &'static State,
+  userid: i32,
+  update: UpdateProfile,
+) -> Result<ReturnableUser, Error> {
+  if let Some(locale) = &update.locale {
+    if !is_language_tag(locale) {
+      return Err(Error::invalid_locale());
+    }
+  }
+  if let Some(timezone) = &update.timezone {
+    // The database knows the IANA time zone names
+    let known = sqlx::query!(
+      "SELECT EXISTS(SELECT 1 FROM pg_timezone_names WHERE name = $1) AS \"known!\"",
+      timezone,
+    )
+    .fetch_one(&state.db_pool)
+    .await?
+    .known;
+    if !known {
+      return Err(Error::invalid_timezone());
+    }
+  }
+  // The reserved accounts' usernames are set by configuration and testing
+  if let Some(username) = &update.username {
+    crate::db::verify_not_protected(userid)?;
+    state
+      .username_rules
+      .verify_rename(&state.db_pool, userid, username)
+      .await?;
+  }
+  let user = sqlx::query_as!(
+    ReturnableUser,
+    "
+UPDATE users SET
+  username = COALESCE($2, username),
+  display_name = $3,
+  locale = $4,
+  timezone = $5
+WHERE id = $1
+RETURNING id, username, admin, display_name, locale, timezone, avatar
+    ",
+    userid,
+    update.username,
+    update.display_name.filter(|name| !name.trim().is_empty()),
+    update.locale,
+    update.timezone,
+  )
+  .fetch_one(&state.db_pool)
+  .await
+  .map_err(|e| -> Error {
+    match e {
+      sqlx::Error::Database(ref err) => match err.constraint() {
+        Some("users_username_normalized_key") => Error::username_taken(),
+        _ => e.into(),
+      },
+      _ => e.into(),
+    }
+  })?;
+  crate::auth::session_cache::forget_user(state, userid);
+  Ok(user)
+}
+
+// How recently accounts without a password must have logged in to delete
+// themselves
+const RECENT_LOGIN_MINUTES: i64 = 5;
+
+async fn confirm_deletion(
+  state: &'static State,
+  permissions: &Permissions,
+  form: DeleteAccount,
+) -> Result<(), Error> {
+  let confirmed = match form.password {
+    Some(password) => crate::auth::credentials::check_user(state, permissions.userid, password)
+      .await?
+      .is_some(),
+    None => {
+      sqlx::query!(
+        "
+SELECT users.pass IS NULL AND COALESCE(sessions.authenticated_at > $2, false) AS \"recent!\"
+FROM sessions JOIN users ON users.id = sessions.userid
+WHERE sessions.id = $1
+        ",
+        permissions.sessionid,
+        chrono::offset::Utc::now().naive_utc() - chrono::Duration::minutes(RECENT_LOGIN_MINUTES),
+      )
+      .fetch_one(&state.db_pool)
+      .await?
+      .recent
+    }
+  };
+  if confirmed {
+    Ok(())
+  } else {
+    Err(Error::bad_login())
+  }
+}
 
 pub async fn route(
   state: &'static State,
-  req: Request,
+  mut req: Request,
   mut path_vec: Vec<String>,
   permissions: Permissions,
 ) -> Result<Response, Error> {
   match path_vec.pop().as_deref() {
     None | Some("") => {
-      verify_method_path_end(&path_vec, &req, &Method::GET)?;
-      // Return the public information on the user
-      let user = sqlx::query_as!(
-        ReturnableUser,
-        "SELECT id, username, admin FROM users WHERE id = $1",
-        permissions.userid,
-      )
-      .fetch_one(&state.db_pool)
-      .await?;
-      json(&user)
+      verify_path_end(&path_vec, &req)?;
+      match req.method() {
+        &Method::GET => {
+          // Return the public information on the user
+          json(&get_user(state, permissions.userid).await?)
+        }
+        &Method::PUT => {
+          let update: UpdateProfile = parse_json(&mut req, state.max_content_len).await?;
+          let user = update_profile(state, permissions.userid, update).await?;
+          json(&user)
+        }
+        // Delete the account, as an admin would
+        &Method::DELETE => {
+          crate::db::verify_not_protected(permissions.userid)?;
+          let form: DeleteAccount = parse_json(&mut req, state.max_content_len).await?;
+          // Confirm with the password, so a stolen session isn't enough
+          // Within the login delay, so this can't be used to time passwords
+          crate::auth::with_login_delay(state, confirm_deletion(state, &permissions, form)).await?;
+          crate::db::delete_user(state, permissions.userid).await?;
+          let re = empty()?;
+          #[cfg(feature = "session_cookies")]
+          let re = {
+            let mut re = re;
+            clear_session_cookies(&mut re);
+            re
+          };
+          Ok(re)
+        }
+        _ => Err(Error::method_not_found(&req)),
+      }
     }
+    Some("avatar") => avatar::route(state, req, path_vec, permissions).await,
+    Some("export") => export::route(state, req, path_vec, permissions).await,
+    Some("organizations") => organizations::list(state, req, path_vec, permissions).await,
+    Some("organization") => organizations::switch(state, req, path_vec, permissions).await,
     Some("password") => password::route(state, req, path_vec, permissions).await,
+    Some("preferences") => preferences::route(state, req, path_vec, permissions).await,
     Some("sessions") => sessions::route(state, req, path_vec, permissions).await,
+    Some("terms") => terms::route(state, req, path_vec, permissions).await,
     Some(_) => Err(Error::path_not_found(&req)),
   }
 }
@@ -0,0 +1,60 @@
+use super::*;
+
+use shared_types::{ActiveOrganization, UserOrganization};
+
+// The organizations the user belongs to
+pub async fn list(
+  state: &'static State,
+  req: Request,
+  path_vec: Vec<String>,
+  permissions: Permissions,
+) -> Result<Response, Error> {
+  verify_method_path_end(&path_vec, &req, &Method::GET)?;
+  let organizations = sqlx::query_as!(
+    UserOrganization,
+    "
+SELECT organizations.id, organizations.name, memberships.org_admin
+FROM memberships JOIN organizations ON organizations.id = memberships.orgid
+WHERE memberships.userid = $1 ORDER BY organizations.id
+    ",
+    permissions.userid,
+  )
+  .fetch_all(&state.db_pool)
+  .await?;
+  if organizations.is_empty() {
+    empty()
+  } else {
+    json(&organizations)
+  }
+}
+
+// Scope the current session to one of the user's organizations, or none
+// Access tokens already issued keep the organization they were issued with
+pub async fn switch(
+  state: &'static State,
+  mut req: Request,
+  path_vec: Vec<String>,
+  permissions: Permissions,
+) -> Result<Response, Error> {
+  verify_method_path_end(&path_vec, &req, &Method::PUT)?;
+  let active: ActiveOrganization = parse_json(&mut req, state.max_content_len).await?;
+  sqlx::query!(
+    "UPDATE sessions SET orgid = $2 WHERE id = $1",
+    permissions.sessionid,
+    active.orgid,
+  )
+  .execute(&state.db_pool)
+  .await
+  .map_err(|e| -> Error {
+    match e {
+      // Not a member of the organization
+      sqlx::Error::Database(ref err) => match err.constraint() {
+        Some("sessions_orgid_userid_fkey") => Error::forbidden(),
+        _ => e.into(),
+      },
+      _ => e.into(),
+    }
+  })?;
+  crate::auth::session_cache::forget_session(state, permissions.sessionid);
+  empty()
+}
@@ -40,7 +40,10 @@ pub async fn route(
   )
   .await?;
   sqlx::query!(
-    "UPDATE users SET pass = $1 WHERE id = $2",
+    "
+UPDATE users SET pass = $1, password_changed_at = NOW(), must_change_password = false
+WHERE id = $2
+    ",
     new_hash,
     permissions.userid,
   )
@@ -52,5 +55,6 @@ pub async fn route(
       .execute(&state.db_pool)
       .await?;
   }
+  crate::auth::session_cache::forget_user(state, permissions.userid);
   empty()
 }
@@ -0,0 +1,75 @@
+use super::*;
+
+use crate::preferences;
+
+pub async fn route(
+  state: &'static State,
+  mut req: Request,
+  mut path_vec: Vec<String>,
+  permissions: Permissions,
+) -> Result<Response, Error> {
+  let namespace = path_vec.pop().filter(|namespace| !namespace.is_empty());
+  let key = path_vec.pop().filter(|key| !key.is_empty());
+  verify_path_end(&path_vec, &req)?;
+  match (namespace, key) {
+    // Listing all the user's values, or those in a namespace
+    (namespace, None) => {
+      verify_method(&req, &Method::GET)?;
+      match namespace {
+        Some(namespace) => {
+          preferences::verify_name(&namespace)?;
+          let mut all =
+            preferences::list(&state.db_pool, permissions.userid, Some(&namespace)).await?;
+          json(&all.remove(&namespace).unwrap_or_default())
+        }
+        None => json(&preferences::list(&state.db_pool, permissions.userid, None).await?),
+      }
+    }
+    (Some(namespace), Some(key)) => {
+      preferences::verify_name(&namespace)?;
+      preferences::verify_name(&key)?;
+      match req.method() {
+        &Method::GET => {
+          match preferences::get(state, permissions.userid, &namespace, &key).await? {
+            Some(preference) => json_with_etag(&req, &preference.value, &preference.etag),
+            None => Err(Error::path_not_found(&req)),
+          }
+        }
+        // Values are limited in size by max_content_len
+        &Method::PUT => {
+          let precondition = get_precondition(&req)?;
+          let value: serde_json::Value = parse_json(&mut req, state.max_content_len).await?;
+          let (preference, created) = preferences::put(
+            state,
+            permissions.userid,
+            &namespace,
+            &key,
+            value,
+            precondition,
+          )
+          .await?;
+          let mut re = if created {
+            set_status(empty(), StatusCode::CREATED)?
+          } else {
+            empty()?
+          };
+          re.headers_mut().insert(
+            "ETag",
+            HeaderValue::from_str(&preference.etag).expect("ETags should be valid header values."),
+          );
+          Ok(re)
+        }
+        &Method::DELETE => {
+          let precondition = get_precondition(&req)?;
+          if preferences::delete(state, permissions.userid, &namespace, &key, precondition).await? {
+            empty()
+          } else {
+            Err(Error::path_not_found(&req))
+          }
+        }
+        _ => Err(Error::method_not_found(&req)),
+      }
+    }
+    (None, Some(_)) => Err(Error::path_not_found(&req)),
+  }
+}
@@ -0,0 +1,18 @@
+use super::*;
+
+use shared_types::AcceptTerms;
+
+// Accept the latest terms, lifting the session restriction
+pub async fn route(
+  state: &'static State,
+  mut req: Request,
+  path_vec: Vec<String>,
+  permissions: Permissions,
+) -> Result<Response, Error> {
+  verify_method_path_end(&path_vec, &req, &Method::POST)?;
+  let accept: AcceptTerms = parse_json(&mut req, state.max_content_len).await?;
+  match crate::terms::accept(state, permissions.userid, accept.version).await? {
+    false => Err(Error::path_not_found(&req)),
+    true => empty(),
+  }
+}
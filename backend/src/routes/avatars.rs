@@ -0,0 +1,50 @@
+use super::*;
+
+// Avatars change whenever their user uploads a new one, so always validate
+static AVATAR_CACHE_CONTROL: HeaderValue = HeaderValue::from_static("no-cache");
+
+// Serve a user's avatar, at /avatars/$userid/$version/$size
+// Public, so they can be shown by plain image tags, but only the current
+// version is served, which can't be guessed
+pub async fn route(
+  state: &'static State,
+  req: Request,
+  mut path_vec: Vec<String>,
+) -> Result<Response, Error> {
+  let userid = path_vec.pop().unwrap_or_default().parse::<i32>()?;
+  let version = path_vec.pop().unwrap_or_default();
+  let size = path_vec.pop().unwrap_or_default().parse::<u32>()?;
+  verify_method_path_end(&path_vec, &req, &Method::GET)?;
+  if !crate::avatars::SIZES.contains(&size) {
+    return Err(Error::path_not_found(&req));
+  }
+  let current = sqlx::query!(
+    "SELECT avatar FROM users WHERE id = $1 AND avatar = $2 AND deleted_at IS NULL",
+    userid,
+    version,
+  )
+  .fetch_optional(&state.db_pool)
+  .await?;
+  if current.is_none() {
+    return Err(Error::path_not_found(&req));
+  }
+  let etag = HeaderValue::from_str(&format!("\"{}\"", version))
+    .expect("Avatar versions should be valid header values.");
+  // Use if-none-match to only send data if needed
+  let mut re = if Some(&etag) == req.headers().get("if-none-match") {
+    not_modified()?
+  } else {
+    match tokio::fs::read(crate::avatars::path(state, userid, &version, size)).await {
+      Ok(data) => png(data)?,
+      // Replaced since the version was read
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+        return Err(Error::path_not_found(&req))
+      }
+      Err(e) => return Err(e.into()),
+    }
+  };
+  re.headers_mut().insert("etag", etag);
+  re.headers_mut()
+    .insert("cache-control", AVATAR_CACHE_CONTROL.clone());
+  Ok(re)
+}
@@ -10,6 +10,8 @@ mod utils;
 pub use utils::*;
 
 mod api;
+mod avatars;
+mod scim;
 
 type Response = hyper::Response<hyper::Body>;
 type Request = hyper::Request<hyper::Body>;
@@ -72,6 +74,17 @@ async fn route(state: &'static State, req: Request) -> Result<Response, Error> {
         .insert("cache-control", HeaderValue::from_static("no-store"));
       Ok(re)
     }
+    Some("scim") => {
+      let mut re = scim::route(state, req, path_vec).await?;
+      re.headers_mut()
+        .insert("cache-control", HeaderValue::from_static("no-store"));
+      Ok(re)
+    }
+    Some("avatars") => avatars::route(state, req, path_vec).await,
+    Some(".well-known") => match path_vec.pop().as_deref() {
+      Some("openid-configuration") => api::discovery(state, req, path_vec).await,
+      _ => Err(Error::path_not_found(&req)),
+    },
     None | Some("") | Some("index.html") => {
       verify_method_path_end(&path_vec, &req, &Method::GET)?;
       // Use if-none-match to only send data if needed
@@ -0,0 +1,127 @@
+use crate::Error;
+
+// The parts of the SCIM filter language (RFC 7644 3.4.2.2) we support:
+// comparisons on userName (eq, sw, ew, co, pr), id (eq, pr),
+// externalId (eq, pr) and active (eq), joined by 'and'.
+//
+// Each kind of comparison becomes a parameter of the query, as an array
+// compared with ALL (or a flag), so that no comparisons match all users.
+#[derive(Debug, Default)]
+pub struct UserFilter {
+  // Operators and values the username must all match, as pairs
+  // Compared normalized, as usernames are unique (userName is case insensitive)
+  pub username_operators: Vec<String>,
+  pub username_values: Vec<String>,
+  pub ids: Vec<String>,
+  pub external_ids: Vec<String>,
+  pub external_id_present: bool,
+  pub active: Vec<bool>,
+}
+
+enum Token {
+  Word(String),
+  Str(String),
+}
+
+fn tokenize(filter: &str) -> Result<Vec<Token>, Error> {
+  let mut tokens = Vec::new();
+  let mut chars = filter.char_indices().peekable();
+  while let Some(&(start, c)) = chars.peek() {
+    if c.is_whitespace() {
+      chars.next();
+    } else if c == '"' {
+      // Find the closing quote, skipping escaped characters
+      chars.next();
+      let mut end = None;
+      while let Some((i, c)) = chars.next() {
+        match c {
+          '\\' => {
+            chars.next();
+          }
+          '"' => {
+            end = Some(i);
+            break;
+          }
+          _ => (),
+        }
+      }
+      let end = end.ok_or_else(|| Error::invalid_filter("Unterminated string"))?;
+      // Strings are JSON strings, escapes included
+      let value = serde_json::from_str(&filter[start..=end])
+        .map_err(|_| Error::invalid_filter("Invalid string"))?;
+      tokens.push(Token::Str(value));
+    } else if c == '(' || c == ')' || c == '[' || c == ']' {
+      return Err(Error::invalid_filter("Grouping is not supported"));
+    } else {
+      // Ended by what starts another token as well, so userName eq"bob" works
+      let mut word = String::new();
+      while let Some(&(_, c)) = chars.peek() {
+        if c.is_whitespace() || matches!(c, '"' | '(' | ')' | '[' | ']') {
+          break;
+        }
+        word.push(c);
+        chars.next();
+      }
+      tokens.push(Token::Word(word));
+    }
+  }
+  Ok(tokens)
+}
+
+pub fn parse_user_filter(filter: &str) -> Result<UserFilter, Error> {
+  let mut parsed = UserFilter::default();
+  let mut tokens = tokenize(filter)?.into_iter();
+  loop {
+    let attribute = match tokens.next() {
+      Some(Token::Word(word)) => word.to_lowercase(),
+      _ => return Err(Error::invalid_filter("Expected an attribute")),
+    };
+    // Attributes may be given with the schema they are in
+    let attribute = attribute
+      .strip_prefix("urn:ietf:params:scim:schemas:core:2.0:user:")
+      .unwrap_or(&attribute)
+      .to_string();
+    let operator = match tokens.next() {
+      Some(Token::Word(word)) => word.to_lowercase(),
+      _ => return Err(Error::invalid_filter("Expected an operator")),
+    };
+    if operator == "pr" {
+      match attribute.as_str() {
+        // Always present
+        "username" | "id" => (),
+        "externalid" => parsed.external_id_present = true,
+        _ => return Err(Error::invalid_filter("Unsupported attribute")),
+      }
+    } else {
+      let value = match tokens.next() {
+        Some(token) => token,
+        None => return Err(Error::invalid_filter("Expected a value")),
+      };
+      match (attribute.as_str(), operator.as_str(), value) {
+        // The LIKE patterns are built in the query, as normalizing can turn
+        // characters into wildcards (a fullwidth '％' into '%')
+        ("username", "eq" | "sw" | "ew" | "co", Token::Str(value)) => {
+          parsed.username_operators.push(operator);
+          parsed.username_values.push(value);
+        }
+        ("id", "eq", Token::Str(value)) => parsed.ids.push(value),
+        ("externalid", "eq", Token::Str(value)) => parsed.external_ids.push(value),
+        ("active", "eq", Token::Word(value)) => match value.to_lowercase().as_str() {
+          "true" => parsed.active.push(true),
+          "false" => parsed.active.push(false),
+          _ => return Err(Error::invalid_filter("Expected a boolean")),
+        },
+        _ => return Err(Error::invalid_filter("Unsupported comparison")),
+      }
+    }
+    match tokens.next() {
+      None => return Ok(parsed),
+      Some(Token::Word(word)) if word.eq_ignore_ascii_case("and") => (),
+      _ => {
+        return Err(Error::invalid_filter(
+          "Only 'and' is supported between comparisons",
+        ))
+      }
+    }
+  }
+}
@@ -0,0 +1,117 @@
+use super::*;
+
+use serde::{de::DeserializeOwned, Serialize};
+use shared_types::ClientError;
+
+mod filter;
+mod users;
+
+const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+const LIST_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+// Upper limit on users returned per page
+const MAX_RESULTS: i64 = 100;
+
+// SCIM bodies may be sent as either application/scim+json or application/json
+async fn parse_scim_json<T: DeserializeOwned>(
+  req: &mut Request,
+  max_len: usize,
+) -> Result<T, Error> {
+  let content_type = get_header(req, "Content-Type")?.unwrap_or("");
+  if !content_type.starts_with("application/scim+json")
+    && !content_type.starts_with("application/json")
+  {
+    return Err(Error::invalid_content_type(
+      "application/scim+json",
+      content_type,
+    ));
+  }
+  let bytes = get_body(req, max_len).await?;
+  let data: T = serde_json::from_slice(&bytes)?;
+  Ok(data)
+}
+fn scim_json<T: Serialize + ?Sized>(data: &T) -> Result<Response, Error> {
+  let mut re = json(data)?;
+  re.headers_mut().insert(
+    "Content-Type",
+    HeaderValue::from_static("application/scim+json; charset=utf-8"),
+  );
+  Ok(re)
+}
+
+// Client errors in the format SCIM clients expect
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ScimError {
+  schemas: [&'static str; 1],
+  status: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  scim_type: Option<&'static str>,
+  detail: String,
+}
+fn scim_error(error: ClientError) -> Response {
+  let detail = format!("{:?}", error);
+  let (status, scim_type) = match error {
+    // SCIM uses conflict for uniqueness violations
+    ClientError::UsernameTaken => (StatusCode::CONFLICT, Some("uniqueness")),
+    ClientError::InvalidUsername => (StatusCode::BAD_REQUEST, Some("invalidValue")),
+    ClientError::InvalidFilter(_) => (StatusCode::BAD_REQUEST, Some("invalidFilter")),
+    ClientError::InvalidPatch(_) => (StatusCode::BAD_REQUEST, Some("invalidPath")),
+    ClientError::InvalidJson(_) => (StatusCode::BAD_REQUEST, Some("invalidSyntax")),
+    ClientError::ProtectedAccount | ClientError::LastAdmin => {
+      (StatusCode::BAD_REQUEST, Some("mutability"))
+    }
+    error => (error.into_response().status(), None),
+  };
+  let body = ScimError {
+    schemas: [ERROR_SCHEMA],
+    status: status.as_u16().to_string(),
+    scim_type,
+    detail,
+  };
+  let mut re = scim_json(&body).into_response();
+  *re.status_mut() = status;
+  re
+}
+
+// SCIM 2.0 provisioning (RFC 7644), authenticated by an API token
+pub async fn route(
+  state: &'static State,
+  req: Request,
+  path_vec: Vec<String>,
+) -> Result<Response, Error> {
+  match route_inner(state, req, path_vec).await {
+    Err(Error::ClientError(error)) => Ok(scim_error(error)),
+    re => re,
+  }
+}
+async fn route_inner(
+  state: &'static State,
+  req: Request,
+  mut path_vec: Vec<String>,
+) -> Result<Response, Error> {
+  if path_vec.pop().as_deref() != Some("v2") {
+    return Err(Error::path_not_found(&req));
+  }
+  crate::auth::require_api_token(state, unwrap_bearer(get_header(&req, "Authorization")?)).await?;
+  match path_vec.pop().as_deref() {
+    Some("Users") => users::route(state, req, path_vec).await,
+    Some("ServiceProviderConfig") => {
+      verify_method_path_end(&path_vec, &req, &Method::GET)?;
+      scim_json(&serde_json::json!({
+        "schemas": ["urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig"],
+        "patch": { "supported": true },
+        "bulk": { "supported": false, "maxOperations": 0, "maxPayloadSize": 0 },
+        "filter": { "supported": true, "maxResults": MAX_RESULTS },
+        "changePassword": { "supported": true },
+        "sort": { "supported": true },
+        "etag": { "supported": false },
+        "authenticationSchemes": [{
+          "type": "oauthbearertoken",
+          "name": "API token",
+          "description": "Bearer token created through /api/admin/api_tokens",
+        }],
+      }))
+    }
+    _ => Err(Error::path_not_found(&req)),
+  }
+}
@@ -0,0 +1,480 @@
+use super::*;
+
+use crate::db::PROTECTED_USERIDS;
+use serde::Deserialize;
+use sqlx::{Postgres, Transaction};
+
+const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+// The role that maps onto the admin flag
+const ADMIN_ROLE: &str = "admin";
+
+struct ScimUserRow {
+  id: i32,
+  username: String,
+  external_id: Option<String>,
+  admin: bool,
+  active: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScimRole {
+  value: String,
+}
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ScimMeta {
+  resource_type: &'static str,
+  location: String,
+}
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ScimUser {
+  schemas: [&'static str; 1],
+  id: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  external_id: Option<String>,
+  user_name: String,
+  active: bool,
+  roles: Vec<ScimRole>,
+  meta: ScimMeta,
+}
+impl ScimUser {
+  fn new(state: &State, row: ScimUserRow) -> Self {
+    Self {
+      schemas: [USER_SCHEMA],
+      id: row.id.to_string(),
+      external_id: row.external_id,
+      user_name: row.username,
+      active: row.active,
+      roles: if row.admin {
+        vec![ScimRole {
+          value: ADMIN_ROLE.to_string(),
+        }]
+      } else {
+        Vec::new()
+      },
+      meta: ScimMeta {
+        resource_type: "User",
+        location: format!("{}/scim/v2/Users/{}", state.oidc_issuer, row.id),
+      },
+    }
+  }
+}
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ListResponse {
+  schemas: [&'static str; 1],
+  total_results: i64,
+  start_index: i64,
+  items_per_page: usize,
+  #[serde(rename = "Resources")]
+  resources: Vec<ScimUser>,
+}
+
+// Created and replaced users
+// Attributes we don't store (name, emails, ...) are ignored
+fn default_active() -> bool {
+  true
+}
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ScimUserInput {
+  user_name: String,
+  #[serde(default)]
+  external_id: Option<String>,
+  #[serde(default = "default_active")]
+  active: bool,
+  #[serde(default)]
+  roles: Vec<ScimRole>,
+  #[serde(default)]
+  password: Option<String>,
+}
+fn has_admin_role(roles: &[ScimRole]) -> bool {
+  roles.iter().any(|role| role.value == ADMIN_ROLE)
+}
+
+#[derive(Deserialize)]
+struct PatchRequest {
+  #[serde(rename = "Operations")]
+  operations: Vec<PatchOperation>,
+}
+#[derive(Deserialize)]
+struct PatchOperation {
+  op: String,
+  #[serde(default)]
+  path: Option<String>,
+  #[serde(default)]
+  value: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListQuery {
+  filter: Option<String>,
+  start_index: Option<i64>,
+  count: Option<i64>,
+  sort_by: Option<String>,
+  sort_order: Option<String>,
+}
+enum ListOrder {
+  IdAsc,
+  IdDesc,
+  UsernameAsc,
+  UsernameDesc,
+}
+
+// The new state of a user, as given by a replace or patch
+struct UserChanges {
+  username: String,
+  external_id: Option<String>,
+  admin: bool,
+  active: bool,
+  password: Option<String>,
+}
+impl UserChanges {
+  // Set an attribute, as given by name by an add or replace operation
+  fn set(&mut self, attribute: &str, value: serde_json::Value, add: bool) -> Result<(), Error> {
+    use serde_json::Value;
+    let attribute = attribute.to_lowercase();
+    let attribute = attribute
+      .strip_prefix("urn:ietf:params:scim:schemas:core:2.0:user:")
+      .unwrap_or(&attribute);
+    match (attribute, value) {
+      ("username", Value::String(username)) => self.username = username,
+      ("externalid", Value::String(external_id)) => self.external_id = Some(external_id),
+      ("externalid", Value::Null) => self.external_id = None,
+      ("active", Value::Bool(active)) => self.active = active,
+      // Some clients send booleans as strings
+      ("active", Value::String(active)) => {
+        self.active = match active.to_lowercase().as_str() {
+          "true" => true,
+          "false" => false,
+          _ => return Err(Error::invalid_patch("Expected a boolean for active")),
+        }
+      }
+      ("roles", roles) => {
+        let roles: Vec<ScimRole> = serde_json::from_value(roles)
+          .map_err(|_| Error::invalid_patch("Expected a list of roles"))?;
+        // Adding roles keeps the ones the user has
+        self.admin = has_admin_role(&roles) || (add && self.admin);
+      }
+      ("password", Value::String(password)) => self.password = Some(password),
+      ("username", _) | ("externalid", _) | ("active", _) | ("password", _) => {
+        return Err(Error::invalid_patch("Invalid value"))
+      }
+      // Attributes we don't store are ignored, as on creation
+      _ => (),
+    }
+    Ok(())
+  }
+  fn apply(&mut self, operation: PatchOperation) -> Result<(), Error> {
+    let op = operation.op.to_lowercase();
+    match (op.as_str(), operation.path, operation.value) {
+      ("add", Some(path), Some(value)) | ("replace", Some(path), Some(value)) => {
+        self.set(&path, value, op == "add")
+      }
+      // Without a path the value holds the attributes to set
+      ("add", None, Some(serde_json::Value::Object(values)))
+      | ("replace", None, Some(serde_json::Value::Object(values))) => {
+        for (attribute, value) in values {
+          self.set(&attribute, value, op == "add")?;
+        }
+        Ok(())
+      }
+      ("remove", Some(path), _) => {
+        let path = path.to_lowercase();
+        if path == "externalid" {
+          self.external_id = None;
+        } else if path == "roles" || path.starts_with("roles[") {
+          // Admin is the only role
+          self.admin = false;
+        } else if path == "username" || path == "active" {
+          return Err(Error::invalid_patch("Required attribute"));
+        }
+        Ok(())
+      }
+      _ => Err(Error::invalid_patch("Unsupported operation")),
+    }
+  }
+}
+
+fn map_username_taken(e: sqlx::Error) -> Error {
+  match e {
+    sqlx::Error::Database(ref err) => match err.constraint() {
+      Some("users_username_normalized_key") => Error::username_taken(),
+      _ => e.into(),
+    },
+    _ => e.into(),
+  }
+}
+
+// Apply changes to a user, within the transaction
+async fn update_user(
+  state: &'static State,
+  tx: &mut Transaction<'_, Postgres>,
+  userid: i32,
+  changes: UserChanges,
+) -> Result<Option<ScimUserRow>, Error> {
+  state
+    .username_rules
+    .verify_rename(&mut **tx, userid, &changes.username)
+    .await?;
+  let hash = match changes.password {
+    Some(password) => {
+      Some(crate::auth::hash::hash(&state.cpu_semaphore, &state.hasher, password).await?)
+    }
+    None => None,
+  };
+  let admins = crate::db::lock_active_admins(state, tx).await?;
+  // Only change the lock if active changes, to keep temporary locks
+  let updated = sqlx::query_as!(
+    ScimUserRow,
+    "
+UPDATE users SET
+  username = $2, external_id = $3, admin = $4,
+  locked = CASE WHEN $5 = (NOT (locked AND (locked_until IS NULL OR locked_until > NOW())))
+    THEN locked ELSE NOT $5 END,
+  locked_until = CASE WHEN $5 = (NOT (locked AND (locked_until IS NULL OR locked_until > NOW())))
+    THEN locked_until ELSE NULL END,
+  pass = COALESCE($6, pass),
+  password_changed_at = CASE WHEN $6 IS NULL THEN password_changed_at ELSE NOW() END
+WHERE id = $1 AND deleted_at IS NULL
+RETURNING id, username, external_id, admin,
+  NOT (locked AND (locked_until IS NULL OR locked_until > NOW())) AS \"active!\"
+    ",
+    userid,
+    changes.username,
+    changes.external_id,
+    changes.admin,
+    changes.active,
+    hash,
+  )
+  .fetch_optional(&mut *tx)
+  .await
+  .map_err(map_username_taken)?;
+  // Deactivated users are logged out
+  if !changes.active {
+    sqlx::query!("DELETE FROM sessions WHERE userid = $1", userid)
+      .execute(&mut *tx)
+      .await?;
+  }
+  crate::db::verify_admins_remain(state, tx, admins).await?;
+  crate::auth::session_cache::forget_user(state, userid);
+  Ok(updated)
+}
+
+pub async fn route(
+  state: &'static State,
+  mut req: Request,
+  mut path_vec: Vec<String>,
+) -> Result<Response, Error> {
+  match path_vec.pop().as_deref() {
+    None | Some("") => {
+      verify_path_end(&path_vec, &req)?;
+      match req.method() {
+        &Method::GET => {
+          let query: ListQuery = parse_filter(&req)?;
+          let filter = match &query.filter {
+            Some(filter) => filter::parse_user_filter(filter)?,
+            None => filter::UserFilter::default(),
+          };
+          // Pages are indexed from 1
+          let start_index = query.start_index.unwrap_or(1).max(1);
+          let count = query.count.unwrap_or(MAX_RESULTS).clamp(0, MAX_RESULTS);
+          let descending = query.sort_order.as_deref() == Some("descending");
+          let order = match (query.sort_by.as_deref(), descending) {
+            (None, false) | (Some("id"), false) => ListOrder::IdAsc,
+            (None, true) | (Some("id"), true) => ListOrder::IdDesc,
+            (Some("userName"), false) => ListOrder::UsernameAsc,
+            (Some("userName"), true) => ListOrder::UsernameDesc,
+            _ => return Err(Error::invalid_filter("Unsupported sortBy")),
+          };
+          let total = sqlx::query!(
+            "
+SELECT COUNT(*) AS \"count!\" FROM users
+WHERE deleted_at IS NULL AND NOT (id = ANY($1)) AND
+  username_normalized LIKE ALL(
+    SELECT CASE WHEN operator IN ('ew', 'co') THEN '%' ELSE '' END ||
+      replace(replace(replace(normalize_username(value), '\\', '\\\\'), '%', '\\%'), '_', '\\_') ||
+      CASE WHEN operator IN ('sw', 'co') THEN '%' ELSE '' END
+    FROM unnest($2::text[], $3::text[]) AS comparisons(operator, value)
+  ) AND
+  id::text = ALL($4) AND
+  external_id = ALL($5) AND
+  (external_id IS NOT NULL OR NOT $6) AND
+  (NOT (locked AND (locked_until IS NULL OR locked_until > NOW()))) = ALL($7)
+            ",
+            &PROTECTED_USERIDS[..],
+            &filter.username_operators,
+            &filter.username_values,
+            &filter.ids,
+            &filter.external_ids,
+            filter.external_id_present,
+            &filter.active,
+          )
+          .fetch_one(&state.db_pool)
+          .await?
+          .count;
+          let users = sqlx_order!( ScimUserRow, &state.db_pool;
+            "
+SELECT id, username, external_id, admin,
+  NOT (locked AND (locked_until IS NULL OR locked_until > NOW())) AS \"active!\"
+FROM users
+WHERE deleted_at IS NULL AND NOT (id = ANY($1)) AND
+  username_normalized LIKE ALL(
+    SELECT CASE WHEN operator IN ('ew', 'co') THEN '%' ELSE '' END ||
+      replace(replace(replace(normalize_username(value), '\\', '\\\\'), '%', '\\%'), '_', '\\_') ||
+      CASE WHEN operator IN ('sw', 'co') THEN '%' ELSE '' END
+    FROM unnest($2::text[], $3::text[]) AS comparisons(operator, value)
+  ) AND
+  id::text = ALL($4) AND
+  external_id = ALL($5) AND
+  (external_id IS NOT NULL OR NOT $6) AND
+  (NOT (locked AND (locked_until IS NULL OR locked_until > NOW()))) = ALL($7)
+            ",
+            "
+OFFSET $8 LIMIT $9
+            ",
+            &PROTECTED_USERIDS[..],
+            &filter.username_operators,
+            &filter.username_values,
+            &filter.ids,
+            &filter.external_ids,
+            filter.external_id_present,
+            &filter.active,
+            start_index - 1,
+            count,
+            ; order ;
+            ListOrder::IdAsc , "ORDER BY id ASC";
+            ListOrder::IdDesc , "ORDER BY id DESC";
+            ListOrder::UsernameAsc , "ORDER BY username ASC";
+            ListOrder::UsernameDesc , "ORDER BY username DESC";
+          );
+          scim_json(&ListResponse {
+            schemas: [LIST_SCHEMA],
+            total_results: total,
+            start_index,
+            items_per_page: users.len(),
+            resources: users
+              .into_iter()
+              .map(|row| ScimUser::new(state, row))
+              .collect(),
+          })
+        }
+        &Method::POST => {
+          let input: ScimUserInput = parse_scim_json(&mut req, state.max_content_len).await?;
+          state.username_rules.verify(&input.user_name)?;
+          let hash = match input.password {
+            Some(password) => {
+              Some(crate::auth::hash::hash(&state.cpu_semaphore, &state.hasher, password).await?)
+            }
+            None => None,
+          };
+          let created = sqlx::query_as!(
+            ScimUserRow,
+            "
+INSERT INTO users(username, external_id, admin, locked, pass, password_changed_at)
+VALUES($1, $2, $3, NOT $4, $5, CASE WHEN $5::text IS NULL THEN NULL ELSE NOW() END)
+RETURNING id, username, external_id, admin, NOT locked AS \"active!\"
+            ",
+            input.user_name,
+            input.external_id,
+            has_admin_role(&input.roles),
+            input.active,
+            hash,
+          )
+          .fetch_one(&state.db_pool)
+          .await
+          .map_err(map_username_taken)?;
+          set_status(
+            scim_json(&ScimUser::new(state, created)),
+            StatusCode::CREATED,
+          )
+        }
+        _ => Err(Error::method_not_found(&req)),
+      }
+    }
+    // If there is more than base path parse it as the id of a user
+    Some(id) => {
+      verify_path_end(&path_vec, &req)?;
+      // Protected accounts aren't provisioned, so they don't exist here
+      let userid = match id.parse::<i32>() {
+        Ok(userid) if !PROTECTED_USERIDS.contains(&userid) => userid,
+        _ => return Err(Error::path_not_found(&req)),
+      };
+      let user = match req.method() {
+        &Method::GET => {
+          sqlx::query_as!(
+            ScimUserRow,
+            "
+SELECT id, username, external_id, admin,
+  NOT (locked AND (locked_until IS NULL OR locked_until > NOW())) AS \"active!\"
+FROM users WHERE id = $1 AND deleted_at IS NULL
+            ",
+            userid,
+          )
+          .fetch_optional(&state.db_pool)
+          .await?
+        }
+        &Method::PUT => {
+          let input: ScimUserInput = parse_scim_json(&mut req, state.max_content_len).await?;
+          let changes = UserChanges {
+            admin: has_admin_role(&input.roles),
+            username: input.user_name,
+            external_id: input.external_id,
+            active: input.active,
+            password: input.password,
+          };
+          let mut tx = state.db_pool.begin().await?;
+          let updated = update_user(state, &mut tx, userid, changes).await?;
+          tx.commit().await?;
+          updated
+        }
+        &Method::PATCH => {
+          let patch: PatchRequest = parse_scim_json(&mut req, state.max_content_len).await?;
+          let mut tx = state.db_pool.begin().await?;
+          let current = sqlx::query_as!(
+            ScimUserRow,
+            "
+SELECT id, username, external_id, admin,
+  NOT (locked AND (locked_until IS NULL OR locked_until > NOW())) AS \"active!\"
+FROM users WHERE id = $1 AND deleted_at IS NULL
+FOR UPDATE
+            ",
+            userid,
+          )
+          .fetch_optional(&mut tx)
+          .await?;
+          let current = match current {
+            Some(current) => current,
+            None => return Err(Error::path_not_found(&req)),
+          };
+          let mut changes = UserChanges {
+            username: current.username,
+            external_id: current.external_id,
+            admin: current.admin,
+            active: current.active,
+            password: None,
+          };
+          for operation in patch.operations {
+            changes.apply(operation)?;
+          }
+          let updated = update_user(state, &mut tx, userid, changes).await?;
+          tx.commit().await?;
+          updated
+        }
+        &Method::DELETE => {
+          return match crate::db::delete_user(state, userid).await? {
+            false => Err(Error::path_not_found(&req)),
+            true => empty(),
+          };
+        }
+        _ => return Err(Error::method_not_found(&req)),
+      };
+      match user {
+        Some(user) => scim_json(&ScimUser::new(state, user)),
+        None => Err(Error::path_not_found(&req)),
+      }
+    }
+  }
+}
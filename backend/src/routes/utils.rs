@@ -8,6 +8,14 @@ pub fn set_status(re: Result<Response, Error>, status: StatusCode) -> Result<Res
   })
 }
 
+// Redirect with 303 See Other, so the client follows with a GET
+pub fn redirect(location: &str) -> Result<Response, Error> {
+  let mut re = Response::new("".into());
+  *re.status_mut() = StatusCode::SEE_OTHER;
+  let location = HeaderValue::from_str(location).map_err(|_| Error::invalid_redirect_uri())?;
+  re.headers_mut().insert("Location", location);
+  Ok(re)
+}
 pub fn empty() -> Result<Response, Error> {
   let mut re = Response::new("".into());
   *re.status_mut() = StatusCode::NO_CONTENT;
@@ -49,6 +57,12 @@ pub fn webassembly(data: &'static [u8]) -> Result<Response, Error> {
     .insert("Content-Type", HeaderValue::from_static("application/wasm"));
   Ok(re)
 }
+pub fn png(data: Vec<u8>) -> Result<Response, Error> {
+  let mut re = Response::new(data.into());
+  re.headers_mut()
+    .insert("Content-Type", HeaderValue::from_static("image/png"));
+  Ok(re)
+}
 
 pub fn json<T: Serialize + ?Sized>(data: &T) -> Result<Response, Error> {
   let mut re = Response::new(serde_json::to_string(data)?.into());
@@ -59,6 +73,47 @@ pub fn json<T: Serialize + ?Sized>(data: &T) -> Result<Response, Error> {
   Ok(re)
 }
 
+// Json response with an ETag, or Not Modified if If-None-Match has it
+pub fn json_with_etag<T: Serialize + ?Sized>(
+  req: &Request,
+  data: &T,
+  etag: &str,
+) -> Result<Response, Error> {
+  let cached = get_header(req, "If-None-Match")?.is_some_and(|tags| {
+    tags
+      .split(',')
+      .any(|tag| tag.trim() == etag || tag.trim() == "*")
+  });
+  let mut re = if cached { not_modified()? } else { json(data)? };
+  re.headers_mut().insert(
+    "ETag",
+    HeaderValue::from_str(etag).expect("ETags should be valid header values."),
+  );
+  Ok(re)
+}
+
+// The condition for a change given by If-Match or If-None-Match
+pub fn get_precondition(req: &Request) -> Result<crate::preferences::Precondition, Error> {
+  use crate::preferences::Precondition;
+  let tags = |header: &str| {
+    header
+      .split(',')
+      .map(|tag| tag.trim().to_string())
+      .collect()
+  };
+  Ok(
+    match (
+      get_header(req, "If-Match")?,
+      get_header(req, "If-None-Match")?,
+    ) {
+      (Some("*"), _) => Precondition::Exists,
+      (Some(header), _) => Precondition::Matches(tags(header)),
+      (None, Some("*")) => Precondition::Absent,
+      (None, _) => Precondition::Always,
+    },
+  )
+}
+
 pub fn get_header<'a>(req: &'a Request, header_name: &str) -> Result<Option<&'a str>, Error> {
   Ok(match req.headers().get(header_name) {
     Some(val) => Some(
@@ -117,6 +172,121 @@ pub async fn get_body(req: &mut Request, max_len: usize) -> Result<Vec<u8>, Erro
   }
 }
 
+// How long a streamed body may stall before it is given up on
+const BODY_STALL_SECONDS: u64 = 30;
+
+// Reads a body too large to hold in memory record by record, where records
+// are lines, or CSV records (whose quoted fields may contain newlines).
+// Content-Length is optional, but the body may be at most max_len.
+// Stalling for BODY_STALL_SECONDS fails it, as records may be handled within a
+// transaction.
+pub struct BodyRecords {
+  body: hyper::Body,
+  max_len: usize,
+  csv: bool,
+  received: usize,
+  buffer: Vec<u8>,
+  // How far the buffer has been searched for a record end, and if that
+  // position is inside quotes
+  scanned: usize,
+  quoted: bool,
+  done: bool,
+}
+impl BodyRecords {
+  pub fn new(req: &mut Request, max_len: usize, csv: bool) -> Result<Self, Error> {
+    if get_header(req, "Content-Length")?.is_some() {
+      validate_get_content_len(req, max_len)?;
+    }
+    Ok(Self {
+      body: std::mem::take(req.body_mut()),
+      max_len: max_len,
+      csv: csv,
+      received: 0,
+      buffer: Vec::new(),
+      scanned: 0,
+      quoted: false,
+      done: false,
+    })
+  }
+
+  // The next record, without its line ending. None when the body is done.
+  pub async fn next(&mut self) -> Result<Option<Vec<u8>>, Error> {
+    use hyper::body::HttpBody;
+
+    loop {
+      if let Some(end) = self.find_end() {
+        let rest = self.buffer.split_off(end + 1);
+        let mut record = std::mem::replace(&mut self.buffer, rest);
+        record.pop();
+        if record.last() == Some(&b'\r') {
+          record.pop();
+        }
+        self.scanned = 0;
+        self.quoted = false;
+        return Ok(Some(record));
+      }
+      if self.done {
+        // The last record needn't end with a newline
+        return Ok(if self.buffer.is_empty() {
+          None
+        } else {
+          Some(std::mem::take(&mut self.buffer))
+        });
+      }
+      let stall = std::time::Duration::from_secs(BODY_STALL_SECONDS);
+      let data = tokio::time::timeout(stall, self.body.data())
+        .await
+        .map_err(|_| Error::body_timeout())?;
+      match data {
+        Some(data) => {
+          let data = data?;
+          self.received += data.len();
+          if self.received > self.max_len {
+            return Err(Error::content_length_too_large(self.received, self.max_len));
+          }
+          self.buffer.extend_from_slice(&data);
+        }
+        None => self.done = true,
+      }
+    }
+  }
+
+  fn find_end(&mut self) -> Option<usize> {
+    while self.scanned < self.buffer.len() {
+      let position = self.scanned;
+      self.scanned += 1;
+      match self.buffer[position] {
+        // Escaped quotes ("") toggle twice, so this holds for them too
+        b'"' if self.csv => self.quoted = !self.quoted,
+        b'\n' if !self.quoted => return Some(position),
+        _ => (),
+      }
+    }
+    None
+  }
+}
+
+// Reads a multipart/form-data body field by field, as it arrives.
+// Content-Length is optional, but the body may be at most max_len.
+pub fn get_multipart(
+  req: &mut Request,
+  max_len: usize,
+) -> Result<multer::Multipart<'static>, Error> {
+  if get_header(req, "Content-Length")?.is_some() {
+    validate_get_content_len(req, max_len)?;
+  }
+  let content_type = get_header(req, "Content-Type")?.unwrap_or("");
+  let boundary = multer::parse_boundary(content_type)
+    .map_err(|_| Error::invalid_content_type("multipart/form-data", content_type))?;
+  let constraints =
+    multer::Constraints::new().size_limit(multer::SizeLimit::new().whole_stream(max_len as u64));
+  Ok(multer::Multipart::with_constraints(
+    std::mem::take(req.body_mut()),
+    boundary,
+    constraints,
+  ))
+}
+
 pub async fn parse_json<T: DeserializeOwned>(
   req: &mut Request,
   max_len: usize,
@@ -135,6 +305,22 @@ pub async fn parse_json<T: DeserializeOwned>(
   let data: T = serde_json::from_slice(&bytes)?;
   Ok(data)
 }
+pub async fn parse_form<T: DeserializeOwned>(
+  req: &mut Request,
+  max_len: usize,
+) -> Result<T, Error> {
+  // Verify content type, ignoring the charset since urlencoding is ascii
+  let content_type = get_header(req, "Content-Type")?.unwrap_or("");
+  if !content_type.starts_with("application/x-www-form-urlencoded") {
+    return Err(Error::invalid_content_type(
+      "application/x-www-form-urlencoded",
+      content_type,
+    ));
+  }
+  let bytes = get_body(req, max_len).await?;
+  let data: T = serde_urlencoded::from_bytes(&bytes)?;
+  Ok(data)
+}
 pub fn parse_filter<T: DeserializeOwned>(req: &Request) -> Result<T, Error> {
   let query_str = req.uri().query().unwrap_or("");
   let filter: T = serde_urlencoded::from_str(query_str)?;
@@ -181,3 +367,100 @@ pub fn unwrap_bearer(key: Option<&str>) -> Option<String> {
     None => None,
   }
 }
+
+// Get the session key for a request
+// Taken from bearer auth, or if that is missing and session cookies are enabled
+// from the session cookie. Cookie sessions must also pass the CSRF check.
+pub fn get_session_key(req: &Request) -> Result<Option<String>, Error> {
+  let bearer = unwrap_bearer(get_header(req, "Authorization")?);
+  #[cfg(feature = "session_cookies")]
+  if bearer.is_none() {
+    if let Some(key) = get_cookie(req, SESSION_COOKIE)? {
+      verify_csrf(req)?;
+      return Ok(Some(key.to_string()));
+    }
+  }
+  Ok(bearer)
+}
+
+pub fn get_cookie<'a>(req: &'a Request, name: &str) -> Result<Option<&'a str>, Error> {
+  Ok(get_header(req, "Cookie")?.and_then(|cookies| {
+    cookies
+      .split(';')
+      .filter_map(|cookie| cookie.trim().split_once('='))
+      .find(|(cookie_name, _)| *cookie_name == name)
+      .map(|(_, value)| value)
+  }))
+}
+
+#[cfg(feature = "session_cookies")]
+pub use cookies::*;
+#[cfg(feature = "session_cookies")]
+mod cookies {
+  use super::*;
+  use chrono::NaiveDateTime;
+  use shared_types::Session;
+
+  pub const SESSION_COOKIE: &str = "session";
+  // Readable by the frontend, which sends it back in the CSRF header
+  pub const CSRF_COOKIE: &str = "csrf_token";
+  pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+  // Double-submit check, a cross-site request can send the cookies but can't
+  // read the token to put it in the header
+  pub fn verify_csrf(req: &Request) -> Result<(), Error> {
+    match req.method() {
+      &Method::GET | &Method::HEAD | &Method::OPTIONS => Ok(()),
+      _ => match (get_cookie(req, CSRF_COOKIE)?, get_header(req, CSRF_HEADER)?) {
+        (Some(cookie), Some(header)) if !cookie.is_empty() && cookie == header => Ok(()),
+        _ => Err(Error::csrf_mismatch()),
+      },
+    }
+  }
+
+  // Return the session with its key moved into an HttpOnly cookie
+  pub fn session_cookie_response(mut session: Session) -> Result<Response, Error> {
+    let key = std::mem::take(&mut session.key);
+    let mut re = json(&session)?;
+    set_session_cookies(&mut re, &key, session.until);
+    Ok(re)
+  }
+  // Give the session key as an HttpOnly cookie, with a new CSRF token
+  pub fn set_session_cookies(re: &mut Response, key: &str, until: NaiveDateTime) {
+    let expires = until.format("%a, %d %b %Y %H:%M:%S GMT");
+    let session = format!(
+      "{}={}; Path=/api; Expires={}; HttpOnly; Secure; SameSite=Strict",
+      SESSION_COOKIE, key, expires
+    );
+    let csrf = format!(
+      "{}={}; Path=/; Expires={}; Secure; SameSite=Strict",
+      CSRF_COOKIE,
+      nanoid::nanoid!(32),
+      expires
+    );
+    for cookie in [session, csrf] {
+      // Both values are generated by nanoid, so they are valid header values
+      re.headers_mut().append(
+        "Set-Cookie",
+        HeaderValue::from_str(&cookie).expect("Generated cookie is an invalid header."),
+      );
+    }
+  }
+  pub fn clear_session_cookies(re: &mut Response) {
+    for cookie in [
+      format!(
+        "{}=; Path=/api; Max-Age=0; HttpOnly; Secure; SameSite=Strict",
+        SESSION_COOKIE
+      ),
+      format!(
+        "{}=; Path=/; Max-Age=0; Secure; SameSite=Strict",
+        CSRF_COOKIE
+      ),
+    ] {
+      re.headers_mut().append(
+        "Set-Cookie",
+        HeaderValue::from_str(&cookie).expect("Generated cookie is an invalid header."),
+      );
+    }
+  }
+}
@@ -2,6 +2,24 @@ use argon2::Argon2;
 use std::env::var;
 use tokio::sync::Semaphore;
 
+use crate::usernames::{UsernameCharacters, UsernameRules};
+
+// What to do with the username of a deleted user
+pub enum DeletedUsernamePolicy {
+  // Keep it reserved until the user is purged
+  Keep,
+  // Rename the user, so the username can be taken
+  Free,
+}
+
+// What to do when a login would exceed the session limit
+pub enum SessionLimitPolicy {
+  // Refuse the login
+  Reject,
+  // Delete the oldest live sessions to make room
+  Evict,
+}
+
 pub struct State {
   // Used to limit the number of concurrent CPU-bound tasks
   pub cpu_semaphore: Semaphore,
@@ -13,7 +31,62 @@ pub struct State {
 
   // Configurations used directly
   pub login_delay: u64,
+  // What usernames users can be given
+  pub username_rules: UsernameRules,
   pub max_content_len: usize,
+  // Bulk imports are read in a streaming fashion, so they get their own limit
+  pub max_import_len: usize,
+  // Total size of the values a user may store as preferences
+  pub preferences_max_len: usize,
+  // Largest avatar upload accepted, before it is resized
+  pub max_avatar_len: usize,
+  // Where the resized avatars are stored
+  pub avatar_dir: std::path::PathBuf,
+  // None means passwords never expire
+  pub password_max_age: Option<chrono::Duration>,
+  // If the reason for a lock is given to the locked user
+  pub show_lock_reason: bool,
+  pub deleted_username_policy: DeletedUsernamePolicy,
+  // None means deleted users are never purged
+  pub deleted_user_retention: Option<chrono::Duration>,
+  // How long admin privileges stay elevated after confirming password
+  pub admin_elevation: chrono::Duration,
+  // Limits on concurrent sessions per role, None means unlimited
+  pub max_sessions_user: Option<i64>,
+  pub max_sessions_admin: Option<i64>,
+  pub session_limit_policy: SessionLimitPolicy,
+  // Public URL of this service, as used in OpenID Connect
+  pub oidc_issuer: String,
+  // Key used to sign ID tokens
+  pub signing_key: crate::auth::jwt::JwtKey,
+  // Identity provider users may log in through, if any
+  pub external_idp: Option<crate::auth::external::ExternalIdp>,
+  // Where passwords are checked on login
+  pub credentials: Box<dyn crate::auth::credentials::CredentialBackend>,
+  // How long signed access tokens are valid, None means they aren't issued
+  pub access_token_lifetime: Option<chrono::Duration>,
+  // Sessions whose access tokens may not have expired yet, but are revoked
+  pub revoked_sessions: std::sync::RwLock<std::collections::HashSet<i32>>,
+  // When the above was last reloaded, access tokens are refused if too long ago
+  pub revocations_refreshed: std::sync::RwLock<Option<std::time::Instant>>,
+  // The latest version of the terms, which access tokens issued before it was
+  // published don't know must be accepted
+  pub latest_terms: std::sync::RwLock<Option<i32>>,
+  // Validated sessions kept in memory, if configured
+  pub session_cache: Option<crate::auth::session_cache::SessionCache>,
+  // Where emails are sent through, None if not configured
+  pub mailer: Option<crate::mail::Mailer>,
+  // If users of each role may log in by emailed link
+  pub magic_link_users: bool,
+  pub magic_link_admins: bool,
+}
+impl State {
+  // Passwords last changed before this are expired
+  pub fn password_expiry_cutoff(&self) -> Option<chrono::NaiveDateTime> {
+    self
+      .password_max_age
+      .map(|age| chrono::offset::Utc::now().naive_utc() - age)
+  }
 }
 
 pub async fn init_state() -> &'static State {
@@ -37,13 +110,209 @@ pub async fn init_state() -> &'static State {
     .expect("LOGIN_DELAY must be present in environment or .env.")
     .parse::<u64>()
     .expect("LOGIN_DELAY could not be parsed as an unsigned integer.");
+  let username_rules = UsernameRules {
+    min_len: var("USERNAME_MIN_LEN")
+      .expect("USERNAME_MIN_LEN must be present in environment or .env.")
+      .parse::<usize>()
+      .expect("USERNAME_MIN_LEN could not be parsed as an unsigned integer."),
+    max_len: var("USERNAME_MAX_LEN")
+      .expect("USERNAME_MAX_LEN must be present in environment or .env.")
+      .parse::<usize>()
+      .expect("USERNAME_MAX_LEN could not be parsed as an unsigned integer."),
+    characters: match var("USERNAME_CHARACTERS")
+      .expect("USERNAME_CHARACTERS must be present in environment or .env.")
+      .as_str()
+    {
+      "ascii" => UsernameCharacters::Ascii,
+      "unicode" => UsernameCharacters::Unicode,
+      _ => panic!("USERNAME_CHARACTERS must be 'ascii' or 'unicode'."),
+    },
+    punctuation: var("USERNAME_PUNCTUATION")
+      .expect("USERNAME_PUNCTUATION must be present in environment or .env."),
+  };
   let max_content_len = var("MAX_CONTENT_LEN")
     .expect("MAX_CONTENT_LEN must be present in environment or .env.")
     .parse::<usize>()
     .expect("MAX_CONTENT_LEN could not be parsed as an unsigned integer.");
+  let max_import_len = var("MAX_IMPORT_LEN")
+    .expect("MAX_IMPORT_LEN must be present in environment or .env.")
+    .parse::<usize>()
+    .expect("MAX_IMPORT_LEN could not be parsed as an unsigned integer.");
+  let preferences_max_len = var("PREFERENCES_MAX_LEN")
+    .expect("PREFERENCES_MAX_LEN must be present in environment or .env.")
+    .parse::<usize>()
+    .expect("PREFERENCES_MAX_LEN could not be parsed as an unsigned integer.");
+  let max_avatar_len = var("MAX_AVATAR_LEN")
+    .expect("MAX_AVATAR_LEN must be present in environment or .env.")
+    .parse::<usize>()
+    .expect("MAX_AVATAR_LEN could not be parsed as an unsigned integer.");
+  let avatar_dir = std::path::PathBuf::from(
+    var("AVATAR_DIR").expect("AVATAR_DIR must be present in environment or .env."),
+  );
+  std::fs::create_dir_all(&avatar_dir).expect("AVATAR_DIR could not be created.");
+  let show_lock_reason = var("SHOW_LOCK_REASON")
+    .expect("SHOW_LOCK_REASON must be present in environment or .env.")
+    .parse::<bool>()
+    .expect("SHOW_LOCK_REASON could not be parsed as a bool.");
+  let deleted_username_policy = match var("DELETED_USERNAME_POLICY")
+    .expect("DELETED_USERNAME_POLICY must be present in environment or .env.")
+    .as_str()
+  {
+    "keep" => DeletedUsernamePolicy::Keep,
+    "free" => DeletedUsernamePolicy::Free,
+    _ => panic!("DELETED_USERNAME_POLICY must be 'keep' or 'free'."),
+  };
+  let deleted_user_retention_days = var("DELETED_USER_RETENTION_DAYS")
+    .expect("DELETED_USER_RETENTION_DAYS must be present in environment or .env.")
+    .parse::<i64>()
+    .expect("DELETED_USER_RETENTION_DAYS could not be parsed as an integer.");
+  let admin_elevation_minutes = var("ADMIN_ELEVATION_MINUTES")
+    .expect("ADMIN_ELEVATION_MINUTES must be present in environment or .env.")
+    .parse::<i64>()
+    .expect("ADMIN_ELEVATION_MINUTES could not be parsed as an integer.");
+  let access_token_minutes = var("ACCESS_TOKEN_MINUTES")
+    .expect("ACCESS_TOKEN_MINUTES must be present in environment or .env.")
+    .parse::<i64>()
+    .expect("ACCESS_TOKEN_MINUTES could not be parsed as an integer.");
+  let session_cache_size = var("SESSION_CACHE_SIZE")
+    .expect("SESSION_CACHE_SIZE must be present in environment or .env.")
+    .parse::<usize>()
+    .expect("SESSION_CACHE_SIZE could not be parsed as an unsigned integer.");
+  let session_cache_seconds = var("SESSION_CACHE_SECONDS")
+    .expect("SESSION_CACHE_SECONDS must be present in environment or .env.")
+    .parse::<u64>()
+    .expect("SESSION_CACHE_SECONDS could not be parsed as an unsigned integer.");
+  let smtp_url = var("SMTP_URL").expect("SMTP_URL must be present in environment or .env.");
+  let mail_from = var("MAIL_FROM").expect("MAIL_FROM must be present in environment or .env.");
+  let mailer = if smtp_url.is_empty() {
+    None
+  } else {
+    Some(crate::mail::Mailer::new(&smtp_url, &mail_from))
+  };
+  let magic_link_users = var("MAGIC_LINK_USERS")
+    .expect("MAGIC_LINK_USERS must be present in environment or .env.")
+    .parse::<bool>()
+    .expect("MAGIC_LINK_USERS could not be parsed as a bool.");
+  let magic_link_admins = var("MAGIC_LINK_ADMINS")
+    .expect("MAGIC_LINK_ADMINS must be present in environment or .env.")
+    .parse::<bool>()
+    .expect("MAGIC_LINK_ADMINS could not be parsed as a bool.");
+  let max_sessions_user = var("MAX_SESSIONS_USER")
+    .expect("MAX_SESSIONS_USER must be present in environment or .env.")
+    .parse::<i64>()
+    .expect("MAX_SESSIONS_USER could not be parsed as an integer.");
+  let max_sessions_admin = var("MAX_SESSIONS_ADMIN")
+    .expect("MAX_SESSIONS_ADMIN must be present in environment or .env.")
+    .parse::<i64>()
+    .expect("MAX_SESSIONS_ADMIN could not be parsed as an integer.");
+  let session_limit_policy = match var("SESSION_LIMIT_POLICY")
+    .expect("SESSION_LIMIT_POLICY must be present in environment or .env.")
+    .as_str()
+  {
+    "reject" => SessionLimitPolicy::Reject,
+    "evict" => SessionLimitPolicy::Evict,
+    _ => panic!("SESSION_LIMIT_POLICY must be 'reject' or 'evict'."),
+  };
+  let oidc_issuer = var("OIDC_ISSUER")
+    .expect("OIDC_ISSUER must be present in environment or .env.")
+    .trim_end_matches('/')
+    .to_string();
+  let signing_key =
+    var("SIGNING_KEY").expect("SIGNING_KEY must be present in environment or .env.");
+  let signing_key = if signing_key.is_empty() {
+    // Tokens signed with a generated key become invalid on restart
+    eprintln!("No SIGNING_KEY given, generating one for this run.");
+    crate::auth::jwt::JwtKey::from_bytes(&rand::random())
+  } else {
+    crate::auth::jwt::JwtKey::from_base64(&signing_key)
+      .expect("SIGNING_KEY could not be parsed as 32 base64 encoded bytes.")
+  };
+  let idp_issuer = var("OIDC_IDP_ISSUER")
+    .expect("OIDC_IDP_ISSUER must be present in environment or .env.")
+    .trim_end_matches('/')
+    .to_string();
+  let idp_client_id =
+    var("OIDC_IDP_CLIENT_ID").expect("OIDC_IDP_CLIENT_ID must be present in environment or .env.");
+  let idp_client_secret = var("OIDC_IDP_CLIENT_SECRET")
+    .expect("OIDC_IDP_CLIENT_SECRET must be present in environment or .env.");
+  // Given as claim=value
+  let idp_admin_claim = var("OIDC_IDP_ADMIN_CLAIM")
+    .expect("OIDC_IDP_ADMIN_CLAIM must be present in environment or .env.");
+  let idp_admin_claim = if idp_admin_claim.is_empty() {
+    None
+  } else {
+    let (claim, value) = idp_admin_claim
+      .split_once('=')
+      .expect("OIDC_IDP_ADMIN_CLAIM must be given as claim=value.");
+    Some((claim.to_string(), value.to_string()))
+  };
+  let idp_provision = var("OIDC_IDP_PROVISION")
+    .expect("OIDC_IDP_PROVISION must be present in environment or .env.")
+    .parse::<bool>()
+    .expect("OIDC_IDP_PROVISION could not be parsed as a bool.");
+  let external_idp = if idp_issuer.is_empty() {
+    None
+  } else {
+    if !crate::auth::external::secure_uri(&idp_issuer) {
+      panic!("OIDC_IDP_ISSUER must be an https URI (or http on localhost).");
+    }
+    Some(crate::auth::external::ExternalIdp {
+      issuer: idp_issuer,
+      client_id: idp_client_id,
+      client_secret: idp_client_secret,
+      admin_claim: idp_admin_claim,
+      provision: idp_provision,
+      client: hyper::Client::builder().build(hyper_rustls::HttpsConnector::with_native_roots()),
+    })
+  };
+  let credentials: Box<dyn crate::auth::credentials::CredentialBackend> =
+    match var("CREDENTIAL_BACKEND")
+      .expect("CREDENTIAL_BACKEND must be present in environment or .env.")
+      .as_str()
+    {
+      "local" => Box::new(crate::auth::credentials::LocalCredentials),
+      "ldap" => {
+        let admin_group = var("LDAP_ADMIN_GROUP")
+          .expect("LDAP_ADMIN_GROUP must be present in environment or .env.");
+        Box::new(crate::auth::credentials::LdapCredentials {
+          url: var("LDAP_URL").expect("LDAP_URL must be present in environment or .env."),
+          user_dn: var("LDAP_USER_DN")
+            .expect("LDAP_USER_DN must be present in environment or .env."),
+          admin_group: Some(admin_group).filter(|group| !group.is_empty()),
+        })
+      }
+      _ => panic!("CREDENTIAL_BACKEND must be 'local' or 'ldap'."),
+    };
+  let password_max_age_days = var("PASSWORD_MAX_AGE_DAYS")
+    .expect("PASSWORD_MAX_AGE_DAYS must be present in environment or .env.")
+    .parse::<i64>()
+    .expect("PASSWORD_MAX_AGE_DAYS could not be parsed as an integer.");
 
   // When we have all needed data, construct objects
   let cpu_semaphore = Semaphore::new(max_nr_cpu_threads);
+  let password_max_age = if password_max_age_days > 0 {
+    Some(chrono::Duration::days(password_max_age_days))
+  } else {
+    None
+  };
+  let access_token_lifetime = if access_token_minutes > 0 {
+    Some(chrono::Duration::minutes(access_token_minutes))
+  } else {
+    None
+  };
+  let session_cache = if session_cache_size > 0 && session_cache_seconds > 0 {
+    Some(crate::auth::session_cache::SessionCache::new(
+      session_cache_size,
+      std::time::Duration::from_secs(session_cache_seconds),
+    ))
+  } else {
+    None
+  };
+  let deleted_user_retention = if deleted_user_retention_days > 0 {
+    Some(chrono::Duration::days(deleted_user_retention_days))
+  } else {
+    None
+  };
   let hasher = Argon2::new(
     Some(secret_key.as_bytes()),
     argon2::Params::DEFAULT_T_COST,
@@ -81,6 +350,31 @@ pub async fn init_state() -> &'static State {
     cpu_semaphore: cpu_semaphore,
     db_pool: db_pool,
     login_delay: login_delay,
+    username_rules: username_rules,
     max_content_len: max_content_len,
+    max_import_len: max_import_len,
+    preferences_max_len: preferences_max_len,
+    max_avatar_len: max_avatar_len,
+    avatar_dir: avatar_dir,
+    password_max_age: password_max_age,
+    show_lock_reason: show_lock_reason,
+    deleted_username_policy: deleted_username_policy,
+    deleted_user_retention: deleted_user_retention,
+    admin_elevation: chrono::Duration::minutes(admin_elevation_minutes),
+    max_sessions_user: Some(max_sessions_user).filter(|&max| max > 0),
+    max_sessions_admin: Some(max_sessions_admin).filter(|&max| max > 0),
+    session_limit_policy: session_limit_policy,
+    oidc_issuer: oidc_issuer,
+    signing_key: signing_key,
+    external_idp: external_idp,
+    credentials: credentials,
+    access_token_lifetime: access_token_lifetime,
+    revoked_sessions: std::sync::RwLock::new(std::collections::HashSet::new()),
+    revocations_refreshed: std::sync::RwLock::new(None),
+    latest_terms: std::sync::RwLock::new(None),
+    session_cache: session_cache,
+    mailer: mailer,
+    magic_link_users: magic_link_users,
+    magic_link_admins: magic_link_admins,
   }))
 }
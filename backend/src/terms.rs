@@ -0,0 +1,69 @@
+//! Versioned terms of service, which users must accept the latest of
+//!
+//! Publishing a version restricts every session of users who haven't accepted
+//! it to accepting it (see must_accept_terms in the database), so the cached
+//! sessions are all dropped. Access tokens are checked against the version
+//! that was latest when they were issued instead.
+
+use crate::Error;
+use crate::State;
+
+use shared_types::Terms;
+
+pub async fn latest(state: &'static State) -> Result<Option<Terms>, Error> {
+  Ok(
+    sqlx::query_as!(
+      Terms,
+      "SELECT version, text, published_at FROM terms ORDER BY version DESC LIMIT 1"
+    )
+    .fetch_optional(&state.db_pool)
+    .await?,
+  )
+}
+
+pub async fn publish(state: &'static State, text: &str) -> Result<Terms, Error> {
+  let terms = sqlx::query_as!(
+    Terms,
+    "INSERT INTO terms(text) VALUES($1) RETURNING version, text, published_at",
+    text,
+  )
+  .fetch_one(&state.db_pool)
+  .await?;
+  crate::auth::session_cache::forget_all(state);
+  // Other processes pick this up as they reload the revocation list
+  *state.latest_terms.write().unwrap() = Some(terms.version);
+  Ok(terms)
+}
+
+// Record the user accepting the given version, which must be the latest
+// Accepting the same version again keeps the first acceptance
+// Returns false if there are no terms
+pub async fn accept(state: &'static State, userid: i32, version: i32) -> Result<bool, Error> {
+  let mut tx = state.db_pool.begin().await?;
+  // So no newer version is published in between
+  sqlx::query!("LOCK TABLE terms IN SHARE MODE")
+    .execute(&mut tx)
+    .await?;
+  let latest = sqlx::query!("SELECT MAX(version) AS version FROM terms")
+    .fetch_one(&mut tx)
+    .await?
+    .version;
+  match latest {
+    None => return Ok(false),
+    Some(latest) if latest != version => return Err(Error::outdated_terms()),
+    Some(_) => (),
+  }
+  sqlx::query!(
+    "
+INSERT INTO terms_acceptances(userid, version) VALUES($1, $2)
+ON CONFLICT (userid, version) DO NOTHING
+    ",
+    userid,
+    version,
+  )
+  .execute(&mut tx)
+  .await?;
+  tx.commit().await?;
+  crate::auth::session_cache::forget_user(state, userid);
+  Ok(true)
+}
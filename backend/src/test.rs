@@ -16,8 +16,16 @@ use super::*;
 use hyper::body::Buf;
 use hyper::{Body, Client, Request, Response, StatusCode};
 use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 const TEST_SERVER_PORT: u16 = 38080;
+const TEST_RELYING_PARTY_PORT: u16 = 38081;
+const TEST_IDENTITY_PROVIDER_PORT: u16 = 38082;
+const TEST_LDAP_SERVER_PORT: u16 = 38083;
+const TEST_SMTP_SERVER_PORT: u16 = 38084;
+const TEST_IDP_CLIENT_ID: &str = "test-idp-client";
+const TEST_IDP_CLIENT_SECRET: &str = "test-idp-secret";
 
 async fn print_json(res: &mut Response<Body>) {
   if res.status() == hyper::StatusCode::NO_CONTENT {
@@ -37,6 +45,23 @@ async fn print_json(res: &mut Response<Body>) {
   let s = String::from_utf8(bytes).unwrap();
   println!("{}", s);
 }
+async fn from_scim_json(res: &mut Response<Body>) -> serde_json::Value {
+  let content_type = res
+    .headers()
+    .get("Content-Type")
+    .map(|x| x.to_str().unwrap())
+    .unwrap();
+  assert_eq!("application/scim+json; charset=utf-8", content_type);
+  let data = serde_json::from_reader(
+    hyper::body::aggregate(res.body_mut())
+      .await
+      .unwrap()
+      .reader(),
+  )
+  .unwrap();
+  println!("{}", data);
+  data
+}
 async fn from_json<T: DeserializeOwned>(res: &mut Response<Body>) -> T {
   let content_type = res
     .headers()
@@ -54,10 +79,442 @@ async fn from_json<T: DeserializeOwned>(res: &mut Response<Body>) -> T {
   data
 }
 
+async fn login(
+  client: &Client<hyper::client::HttpConnector>,
+  username: &str,
+  password: &str,
+) -> shared_types::Session {
+  let request = Request::post(format!("http://127.0.0.1:{}/api/login", TEST_SERVER_PORT))
+    .header("Content-Type", "application/json; charset=utf-8")
+    .body(
+      format!(
+        "{{ \"username\":\"{}\", \"password\":\"{}\", \"extended\":false }}",
+        username, password
+      )
+      .into(),
+    )
+    .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to {} login: {:?}", username, response);
+  assert_eq!(StatusCode::CREATED, response.status());
+  from_json(&mut response).await
+}
+
+// Act as the browser at the external identity provider stub, returning the
+// response to the callback it sends the browser back to
+async fn follow_external_login(
+  client: &Client<hyper::client::HttpConnector>,
+  authorize_uri: &str,
+  login_cookie: &str,
+) -> Response<Body> {
+  let response = client.get(authorize_uri.parse().unwrap()).await.unwrap();
+  assert_eq!(StatusCode::SEE_OTHER, response.status());
+  let request = Request::get(response.headers()["Location"].to_str().unwrap())
+    .header("Cookie", login_cookie)
+    .body(Body::empty())
+    .unwrap();
+  client.request(request).await.unwrap()
+}
+
+// Log in through the external identity provider stub and redeem the ticket
+async fn external_login(client: &Client<hyper::client::HttpConnector>) -> shared_types::Session {
+  let response = client
+    .get(
+      format!("http://127.0.0.1:{}/api/external_login", TEST_SERVER_PORT)
+        .parse()
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::SEE_OTHER, response.status());
+  let login_cookie = response.headers()["Set-Cookie"]
+    .to_str()
+    .unwrap()
+    .split(';')
+    .next()
+    .unwrap()
+    .to_string();
+  let authorize_uri = response.headers()["Location"].to_str().unwrap();
+  let mut response = follow_external_login(client, authorize_uri, &login_cookie).await;
+  println!("Response to external login callback: {:?}", response);
+  if response.status() != StatusCode::SEE_OTHER {
+    print_json(&mut response).await;
+  }
+  assert_eq!(StatusCode::SEE_OTHER, response.status());
+  let ticket = response.headers()["Location"]
+    .to_str()
+    .unwrap()
+    .strip_prefix("/#external_login/")
+    .unwrap()
+    .to_string();
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/external_login/ticket",
+    TEST_SERVER_PORT
+  ))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body(format!("{{ \"ticket\":\"{}\" }}", ticket).into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::CREATED, response.status());
+  from_json(&mut response).await
+}
+
+// A minimal OpenID Connect relying party, like the applications logging in
+// through this service. GET /login starts a login and GET /callback finishes
+// it, responding with the username from userinfo if all verification passed.
+struct RelyingParty {
+  client_id: String,
+  secret: Option<String>,
+  // Verifier and nonce for each ongoing login, by state
+  flows: Mutex<HashMap<String, (String, String)>>,
+}
+async fn relying_party_stub(client_id: String, secret: Option<String>) {
+  let rp = Arc::new(RelyingParty {
+    client_id,
+    secret,
+    flows: Mutex::new(HashMap::new()),
+  });
+  let make_service = make_service_fn(move |_conn| {
+    let rp = rp.clone();
+    async move {
+      Ok::<_, Infallible>(service_fn(move |req| {
+        let rp = rp.clone();
+        async move { Ok::<_, Infallible>(relying_party_handle(&rp, req).await) }
+      }))
+    }
+  });
+  Server::bind(&SocketAddr::from(([127, 0, 0, 1], TEST_RELYING_PARTY_PORT)))
+    .serve(make_service)
+    .await
+    .unwrap();
+}
+async fn relying_party_handle(rp: &RelyingParty, req: Request<Body>) -> Response<Body> {
+  use sha2::{Digest, Sha256};
+  let client = Client::new();
+  let redirect_uri = format!("http://127.0.0.1:{}/callback", TEST_RELYING_PARTY_PORT);
+  // Find the provider's endpoints like a real client would
+  let mut response = client
+    .get(
+      format!(
+        "http://127.0.0.1:{}/.well-known/openid-configuration",
+        TEST_SERVER_PORT
+      )
+      .parse()
+      .unwrap(),
+    )
+    .await
+    .unwrap();
+  let discovery: shared_types::OidcDiscovery = from_json(&mut response).await;
+  match req.uri().path() {
+    "/login" => {
+      let state = nanoid::nanoid!(16);
+      let nonce = nanoid::nanoid!(16);
+      let verifier = nanoid::nanoid!(64);
+      let challenge =
+        base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+      let query = serde_urlencoded::to_string(&[
+        ("response_type", "code"),
+        ("client_id", &rp.client_id),
+        ("redirect_uri", &redirect_uri),
+        ("scope", "openid profile"),
+        ("state", &state),
+        ("nonce", &nonce),
+        ("code_challenge", &challenge),
+        ("code_challenge_method", "S256"),
+      ])
+      .unwrap();
+      rp.flows.lock().unwrap().insert(state, (verifier, nonce));
+      Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(
+          "Location",
+          format!("{}?{}", discovery.authorization_endpoint, query),
+        )
+        .body(Body::empty())
+        .unwrap()
+    }
+    "/callback" => {
+      let query: HashMap<String, String> =
+        serde_urlencoded::from_str(req.uri().query().unwrap_or("")).unwrap();
+      let failure = |message: String| {
+        Response::builder()
+          .status(StatusCode::BAD_REQUEST)
+          .body(message.into())
+          .unwrap()
+      };
+      let (verifier, nonce) = match query
+        .get("state")
+        .and_then(|state| rp.flows.lock().unwrap().remove(state))
+      {
+        Some(flow) => flow,
+        None => return failure("Unknown state".to_string()),
+      };
+      let code = match query.get("code") {
+        Some(code) => code,
+        None => return failure(format!("Authorization failed: {:?}", query.get("error"))),
+      };
+      // Exchange the code for tokens
+      let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", &redirect_uri),
+        ("client_id", &rp.client_id),
+        ("code_verifier", &verifier),
+      ];
+      if let Some(secret) = &rp.secret {
+        form.push(("client_secret", secret));
+      }
+      let request = Request::post(&discovery.token_endpoint)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(serde_urlencoded::to_string(&form).unwrap().into())
+        .unwrap();
+      let mut response = client.request(request).await.unwrap();
+      if response.status() != StatusCode::OK {
+        let error: shared_types::TokenError = from_json(&mut response).await;
+        return failure(format!("Token request failed: {}", error.error));
+      }
+      let tokens: shared_types::TokenResponse = from_json(&mut response).await;
+      // Verify the ID token against the published keys
+      let mut response = client
+        .get(discovery.jwks_uri.parse().unwrap())
+        .await
+        .unwrap();
+      let jwks: crate::auth::jwt::Jwks = from_json(&mut response).await;
+      let claims: shared_types::IdTokenClaims = match jwks
+        .keys
+        .iter()
+        .filter_map(|key| key.verifying_key())
+        .find_map(|key| crate::auth::jwt::verify(&key, &tokens.id_token))
+      {
+        Some(claims) => claims,
+        None => return failure("Invalid ID token signature".to_string()),
+      };
+      if claims.iss != discovery.issuer
+        || claims.aud != rp.client_id
+        || claims.nonce.as_deref() != Some(nonce.as_str())
+        || claims.exp < chrono::offset::Utc::now().timestamp()
+      {
+        return failure(format!("Invalid ID token claims: {:?}", claims));
+      }
+      // And get the user's info with the access token
+      let request = Request::get(&discovery.userinfo_endpoint)
+        .header("Authorization", format!("bearer {}", tokens.access_token))
+        .body(Body::empty())
+        .unwrap();
+      let mut response = client.request(request).await.unwrap();
+      let userinfo: shared_types::UserInfo = from_json(&mut response).await;
+      if userinfo.sub != claims.sub {
+        return failure("Userinfo is for another user".to_string());
+      }
+      Response::new(userinfo.preferred_username.into())
+    }
+    _ => Response::builder()
+      .status(StatusCode::NOT_FOUND)
+      .body(Body::empty())
+      .unwrap(),
+  }
+}
+
+// A minimal external OpenID Connect identity provider, which users log in
+// through. It logs in the same user right away on every authorization request.
+struct IdentityProvider {
+  // Shared with the test, which can change who logs in
+  username: Arc<Mutex<String>>,
+  key: crate::auth::jwt::JwtKey,
+  // Challenge and nonce for each issued code
+  codes: Mutex<HashMap<String, (String, String)>>,
+}
+async fn identity_provider_stub(username: Arc<Mutex<String>>) {
+  let idp = Arc::new(IdentityProvider {
+    username,
+    key: crate::auth::jwt::JwtKey::from_bytes(&rand::random()),
+    codes: Mutex::new(HashMap::new()),
+  });
+  let make_service = make_service_fn(move |_conn| {
+    let idp = idp.clone();
+    async move {
+      Ok::<_, Infallible>(service_fn(move |req| {
+        let idp = idp.clone();
+        async move { Ok::<_, Infallible>(identity_provider_handle(&idp, req).await) }
+      }))
+    }
+  });
+  Server::bind(&SocketAddr::from((
+    [127, 0, 0, 1],
+    TEST_IDENTITY_PROVIDER_PORT,
+  )))
+  .serve(make_service)
+  .await
+  .unwrap();
+}
+// A minimal SMTP server, collecting the mails sent to it
+async fn smtp_stub(mails: Arc<Mutex<Vec<String>>>) {
+  use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+  let listener = tokio::net::TcpListener::bind(("127.0.0.1", TEST_SMTP_SERVER_PORT))
+    .await
+    .unwrap();
+  loop {
+    let (socket, _) = listener.accept().await.unwrap();
+    let mails = mails.clone();
+    tokio::task::spawn(async move {
+      let (read, mut write) = socket.into_split();
+      let mut lines = BufReader::new(read).lines();
+      write.write_all(b"220 localhost\r\n").await.unwrap();
+      // The mail being received, after DATA
+      let mut data: Option<String> = None;
+      while let Ok(Some(line)) = lines.next_line().await {
+        if let Some(mail) = data.as_mut() {
+          if line == "." {
+            mails.lock().unwrap().push(data.take().unwrap());
+            write.write_all(b"250 Queued\r\n").await.unwrap();
+          } else {
+            mail.push_str(&line);
+            mail.push('\n');
+          }
+          continue;
+        }
+        let command = line.to_uppercase();
+        if command.starts_with("DATA") {
+          data = Some(String::new());
+          write.write_all(b"354 Go ahead\r\n").await.unwrap();
+        } else if command.starts_with("QUIT") {
+          write.write_all(b"221 Bye\r\n").await.unwrap();
+          break;
+        } else {
+          write.write_all(b"250 OK\r\n").await.unwrap();
+        }
+      }
+    });
+  }
+}
+
+async fn identity_provider_handle(
+  idp: &IdentityProvider,
+  mut req: Request<Body>,
+) -> Response<Body> {
+  use sha2::{Digest, Sha256};
+  let issuer = format!("http://127.0.0.1:{}", TEST_IDENTITY_PROVIDER_PORT);
+  let failure = |message: &str| {
+    Response::builder()
+      .status(StatusCode::BAD_REQUEST)
+      .body(message.to_string().into())
+      .unwrap()
+  };
+  match req.uri().path() {
+    "/.well-known/openid-configuration" => Response::new(
+      serde_json::json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+      })
+      .to_string()
+      .into(),
+    ),
+    "/authorize" => {
+      let query: HashMap<String, String> =
+        serde_urlencoded::from_str(req.uri().query().unwrap_or("")).unwrap();
+      if query.get("client_id").map(|s| s.as_str()) != Some(TEST_IDP_CLIENT_ID)
+        || query.get("code_challenge_method").map(|s| s.as_str()) != Some("S256")
+      {
+        return failure("Invalid authorization request");
+      }
+      let code = nanoid::nanoid!(16);
+      idp.codes.lock().unwrap().insert(
+        code.clone(),
+        (query["code_challenge"].clone(), query["nonce"].clone()),
+      );
+      let redirect_uri = &query["redirect_uri"];
+      let query =
+        serde_urlencoded::to_string([("code", &code), ("state", &query["state"])]).unwrap();
+      Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header("Location", format!("{}?{}", redirect_uri, query))
+        .body(Body::empty())
+        .unwrap()
+    }
+    "/token" => {
+      let body = hyper::body::to_bytes(req.body_mut()).await.unwrap();
+      let form: HashMap<String, String> = serde_urlencoded::from_bytes(&body).unwrap();
+      let (challenge, nonce) = match idp.codes.lock().unwrap().remove(&form["code"]) {
+        Some(flow) => flow,
+        None => return failure("Unknown code"),
+      };
+      let verifier_challenge = base64::encode_config(
+        Sha256::digest(form["code_verifier"].as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+      );
+      if verifier_challenge != challenge
+        || form.get("client_secret").map(|s| s.as_str()) != Some(TEST_IDP_CLIENT_SECRET)
+      {
+        return failure("Invalid token request");
+      }
+      let now = chrono::offset::Utc::now().timestamp();
+      let username = idp.username.lock().unwrap().clone();
+      let id_token = idp
+        .key
+        .sign(&serde_json::json!({
+          "iss": issuer,
+          "sub": format!("subject-{}", username),
+          "aud": [TEST_IDP_CLIENT_ID],
+          "exp": now + 60,
+          "iat": now,
+          "auth_time": now,
+          "nonce": nonce,
+          "preferred_username": username,
+          "groups": ["users", "admins"],
+        }))
+        .unwrap();
+      Response::new(
+        serde_json::json!({
+          "access_token": nanoid::nanoid!(16),
+          "token_type": "Bearer",
+          "id_token": id_token,
+        })
+        .to_string()
+        .into(),
+      )
+    }
+    _ => Response::builder()
+      .status(StatusCode::NOT_FOUND)
+      .body(Body::empty())
+      .unwrap(),
+  }
+}
+
 #[tokio::test]
 async fn integration_tests() {
   // Start a server for testing
   // Beware that no error is returned if the server doesn't start
+  // (The issuer must match where the test server is reachable)
+  std::env::set_var(
+    "OIDC_ISSUER",
+    format!("http://127.0.0.1:{}", TEST_SERVER_PORT),
+  );
+  // Users may also log in through the test identity provider
+  std::env::set_var(
+    "OIDC_IDP_ISSUER",
+    format!("http://127.0.0.1:{}", TEST_IDENTITY_PROVIDER_PORT),
+  );
+  std::env::set_var("OIDC_IDP_CLIENT_ID", TEST_IDP_CLIENT_ID);
+  std::env::set_var("OIDC_IDP_CLIENT_SECRET", TEST_IDP_CLIENT_SECRET);
+  std::env::set_var("OIDC_IDP_ADMIN_CLAIM", "groups=admins");
+  std::env::set_var("OIDC_IDP_PROVISION", "true");
+  std::env::set_var("ACCESS_TOKEN_MINUTES", "5");
+  std::env::set_var("SESSION_CACHE_SIZE", "1000");
+  std::env::set_var(
+    "SMTP_URL",
+    format!("smtp://127.0.0.1:{}", TEST_SMTP_SERVER_PORT),
+  );
+  std::env::set_var("MAGIC_LINK_USERS", "true");
+  std::env::set_var("MAGIC_LINK_ADMINS", "false");
+  std::env::set_var("MAX_IMPORT_LEN", "65536");
+  std::env::set_var("PREFERENCES_MAX_LEN", "4096");
+  std::env::set_var("MAX_AVATAR_LEN", "65536");
+  let avatar_dir =
+    std::env::temp_dir().join(format!("backend-test-avatars-{}", std::process::id()));
+  std::env::set_var("AVATAR_DIR", &avatar_dir);
+  let mails = Arc::new(Mutex::new(Vec::new()));
+  let _smtp_server = tokio::task::spawn(smtp_stub(mails.clone()));
   let state = init_state().await;
   let addr = SocketAddr::from(([127, 0, 0, 1], TEST_SERVER_PORT));
   let _server = tokio::task::spawn(async move {
@@ -204,8 +661,3028 @@ async fn integration_tests() {
   print_json(&mut response).await;
   assert_eq!(StatusCode::UNAUTHORIZED, response.status());
 
-  // Cleanup database after testing
-  sqlx::query!("UPDATE users SET pass = NULL WHERE id = -1 OR id = -2")
+  println!("\nTest admin elevation.");
+  let admin_session = login(&client, "test-admin", &testing_password).await;
+  let request = Request::get(format!(
+    "http://127.0.0.1:{}/api/admin/users",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body("".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to unelevated admin request: {:?}", response);
+  print_json(&mut response).await;
+  assert_eq!(StatusCode::FORBIDDEN, response.status());
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/elevate",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body(format!("{{ \"password\":\"{}bad\" }}", testing_password).into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to elevation with bad password: {:?}", response);
+  print_json(&mut response).await;
+  assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/elevate",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body(format!("{{ \"password\":\"{}\" }}", testing_password).into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to elevation: {:?}", response);
+  assert_eq!(StatusCode::OK, response.status());
+  let elevation: shared_types::Elevation = from_json(&mut response).await;
+  println!("{:?}", elevation);
+
+  println!("\nTest forced password change after admin reset.");
+  let reset_password = nanoid::nanoid!(32);
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/users/-2/password",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body(
+    format!(
+      "{{ \"new_password\":\"{}\", \"clear_sessions\":true }}",
+      reset_password
+    )
+    .into(),
+  )
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to password reset: {:?}", response);
+  print_json(&mut response).await;
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  let user_session = login(&client, "test-user", &reset_password).await;
+  assert!(user_session.must_change_password);
+  // Other paths are refused
+  let request = Request::get(format!("http://127.0.0.1:{}/api/user", TEST_SERVER_PORT))
+    .header("Authorization", format!("bearer {}", user_session.key))
+    .body("".into())
+    .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to restricted user get: {:?}", response);
+  print_json(&mut response).await;
+  assert_eq!(StatusCode::FORBIDDEN, response.status());
+  // Changing the password lifts the restriction
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/user/password",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", user_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body(
+    format!(
+      "{{ \"old_password\":\"{}\", \"new_password\":\"{}\", \"clear_sessions\":false }}",
+      reset_password, testing_password
+    )
+    .into(),
+  )
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to password change: {:?}", response);
+  print_json(&mut response).await;
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  let request = Request::get(format!("http://127.0.0.1:{}/api/user", TEST_SERVER_PORT))
+    .header("Authorization", format!("bearer {}", user_session.key))
+    .body("".into())
+    .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to unrestricted user get: {:?}", response);
+  print_json(&mut response).await;
+  assert_eq!(StatusCode::OK, response.status());
+
+  println!("\nTest temporary account locks.");
+  sqlx::query!(
+    "
+UPDATE users SET locked = true, locked_until = NOW() + interval '1 hour', locked_reason = 'Testing'
+WHERE id = -2
+    ",
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  let request = Request::post(format!("http://127.0.0.1:{}/api/login", TEST_SERVER_PORT))
+    .header("Content-Type", "application/json; charset=utf-8")
+    .body(
+      format!(
+        "{{ \"username\":\"test-user\", \"password\":\"{}\", \"extended\":false }}",
+        testing_password
+      )
+      .into(),
+    )
+    .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to locked user login: {:?}", response);
+  assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+  match from_json(&mut response).await {
+    shared_types::ClientError::AccountLocked { until, reason } => {
+      assert!(until.is_some());
+      if state.show_lock_reason {
+        assert_eq!(Some("Testing".to_string()), reason);
+      }
+    }
+    e => panic!("Expected AccountLocked, got {:?}", e),
+  }
+  // Listed as an active lock
+  let request = Request::get(format!(
+    "http://127.0.0.1:{}/api/admin/users?lock_active_eq=true&id_lte=-2",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body("".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  let users: Vec<shared_types::AdminReturnableUser> = from_json(&mut response).await;
+  assert_eq!(1, users.len());
+  // Once the lock expires login works again
+  sqlx::query!("UPDATE users SET locked_until = NOW() - interval '1 hour' WHERE id = -2")
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+  login(&client, "test-user", &testing_password).await;
+  let request = Request::get(format!(
+    "http://127.0.0.1:{}/api/admin/users?lock_active_eq=false&locked_eq=true&id_lte=-2",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body("".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  let users: Vec<shared_types::AdminReturnableUser> = from_json(&mut response).await;
+  assert_eq!(1, users.len());
+  sqlx::query!(
+    "UPDATE users SET locked = false, locked_until = NULL, locked_reason = NULL WHERE id = -2"
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+
+  println!("\nTest soft deletion and restoration of users.");
+  let deleted_username = format!("test-deleted-{}", nanoid::nanoid!(8));
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/users",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body(format!("{{ \"username\":\"{}\" }}", deleted_username).into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to user creation: {:?}", response);
+  assert_eq!(StatusCode::CREATED, response.status());
+  let created: shared_types::AdminReturnableUser = from_json(&mut response).await;
+  sqlx::query!(
+    "UPDATE users SET pass = $1 WHERE id = $2",
+    &testing_hash,
+    created.id
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  let deleted_session = login(&client, &deleted_username, &testing_password).await;
+  let request = Request::delete(format!(
+    "http://127.0.0.1:{}/api/admin/users/{}",
+    TEST_SERVER_PORT, created.id
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body("".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to user deletion: {:?}", response);
+  print_json(&mut response).await;
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  // The session was revoked with the deletion
+  let request = Request::get(format!("http://127.0.0.1:{}/api/user", TEST_SERVER_PORT))
+    .header("Authorization", format!("bearer {}", deleted_session.key))
+    .body("".into())
+    .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+  // Deleted users are only listed on request
+  let request = Request::get(format!(
+    "http://127.0.0.1:{}/api/admin/users?id_mte={}&id_lte={}",
+    TEST_SERVER_PORT, created.id, created.id
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body("".into())
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  let request = Request::get(format!(
+    "http://127.0.0.1:{}/api/admin/users?id_mte={}&id_lte={}&include_deleted=true",
+    TEST_SERVER_PORT, created.id, created.id
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body("".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  let users: Vec<shared_types::AdminReturnableUser> = from_json(&mut response).await;
+  assert!(users[0].deleted_at.is_some());
+  let request = Request::get(format!(
+    "http://127.0.0.1:{}/api/admin/users?id_mte={}&deleted_eq=true",
+    TEST_SERVER_PORT,
+    created.id - 1
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body("".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  let users: Vec<shared_types::AdminReturnableUser> = from_json(&mut response).await;
+  assert!(users.iter().all(|u| u.deleted_at.is_some()));
+  assert!(users.iter().any(|u| u.id == created.id));
+  // Deleted users can't be impersonated, given passwords or changed
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/users/{}/impersonate",
+    TEST_SERVER_PORT, created.id
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body("".into())
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  println!("Response to impersonating a deleted user: {:?}", response);
+  assert_eq!(StatusCode::NOT_FOUND, response.status());
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/users/{}/password",
+    TEST_SERVER_PORT, created.id
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body(
+    format!(
+      "{{ \"new_password\":\"{}\", \"clear_sessions\":false }}",
+      nanoid::nanoid!(32)
+    )
+    .into(),
+  )
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  println!(
+    "Response to resetting a deleted user's password: {:?}",
+    response
+  );
+  assert_eq!(StatusCode::NOT_FOUND, response.status());
+  let request = Request::put(format!(
+    "http://127.0.0.1:{}/api/admin/users/{}",
+    TEST_SERVER_PORT, created.id
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body(
+    format!(
+      "{{ \"username\":\"{}\", \"admin\":false, \"locked\":false }}",
+      deleted_username
+    )
+    .into(),
+  )
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  println!("Response to updating a deleted user: {:?}", response);
+  assert_eq!(StatusCode::NOT_FOUND, response.status());
+  // Restoring under another username, for when the old one has been taken
+  let restored_username = format!("test-restored-{}", nanoid::nanoid!(8));
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/users/{}/restore?username={}",
+    TEST_SERVER_PORT, created.id, restored_username
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body("".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to user restoration: {:?}", response);
+  assert_eq!(StatusCode::OK, response.status());
+  let restored: shared_types::AdminReturnableUser = from_json(&mut response).await;
+  assert_eq!(restored_username, restored.username);
+  assert!(restored.deleted_at.is_none());
+  // Without one the username from before deletion is restored
+  let request = Request::delete(format!(
+    "http://127.0.0.1:{}/api/admin/users/{}",
+    TEST_SERVER_PORT, created.id
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body("".into())
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/users/{}/restore",
+    TEST_SERVER_PORT, created.id
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body("".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  let restored: shared_types::AdminReturnableUser = from_json(&mut response).await;
+  assert_eq!(restored_username, restored.username);
+  sqlx::query!("DELETE FROM users WHERE id = $1", created.id)
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+
+  println!("\nTest protection of reserved accounts and the last admin.");
+  let request = Request::put(format!(
+    "http://127.0.0.1:{}/api/admin/users/-2",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body("{ \"username\":\"test-user\", \"admin\":true, \"locked\":false }".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to reserved user update: {:?}", response);
+  print_json(&mut response).await;
+  assert_eq!(StatusCode::FORBIDDEN, response.status());
+  let admin_username = format!("test-admin-{}", nanoid::nanoid!(8));
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/users",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body(format!("{{ \"username\":\"{}\", \"admin\":true }}", admin_username).into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::CREATED, response.status());
+  let created: shared_types::AdminReturnableUser = from_json(&mut response).await;
+  sqlx::query!(
+    "UPDATE users SET pass = $1 WHERE id = $2",
+    &testing_hash,
+    created.id
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  // Make it the only admin that can log in, besides the reserved accounts
+  let other_admins = sqlx::query!(
+    "UPDATE users SET admin = false WHERE admin AND id > 0 AND id <> $1 RETURNING id",
+    created.id,
+  )
+  .fetch_all(&state.db_pool)
+  .await
+  .unwrap();
+  let demote = || {
+    let request = Request::put(format!(
+      "http://127.0.0.1:{}/api/admin/users/{}",
+      TEST_SERVER_PORT, created.id
+    ))
+    .header("Authorization", format!("bearer {}", admin_session.key))
+    .header("Content-Type", "application/json; charset=utf-8")
+    .body(
+      format!(
+        "{{ \"username\":\"{}\", \"admin\":false, \"locked\":false }}",
+        admin_username
+      )
+      .into(),
+    )
+    .unwrap();
+    client.request(request)
+  };
+  let mut response = demote().await.unwrap();
+  println!("Response to demoting the last admin: {:?}", response);
+  assert_eq!(StatusCode::BAD_REQUEST, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(error, shared_types::ClientError::LastAdmin));
+  // Admins without a password here count if they log in otherwise
+  let external_admin = sqlx::query!(
+    "INSERT INTO users(username, admin) VALUES($1, true) RETURNING id",
+    format!("{}-external", admin_username),
+  )
+  .fetch_one(&state.db_pool)
+  .await
+  .unwrap()
+  .id;
+  sqlx::query!(
+    "INSERT INTO external_identities(issuer, subject, userid) VALUES('test-issuer', $1, $2)",
+    format!("subject-{}", external_admin),
+    external_admin,
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  let mut response = demote().await.unwrap();
+  println!("Response to demoting admin: {:?}", response);
+  print_json(&mut response).await;
+  assert_eq!(StatusCode::OK, response.status());
+  sqlx::query!("DELETE FROM users WHERE id = $1", external_admin)
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+  for admin in other_admins {
+    sqlx::query!("UPDATE users SET admin = true WHERE id = $1", admin.id)
+      .execute(&state.db_pool)
+      .await
+      .unwrap();
+  }
+  println!("\nTest limits on concurrent sessions.");
+  let request = Request::put(format!(
+    "http://127.0.0.1:{}/api/admin/users/{}",
+    TEST_SERVER_PORT, created.id
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body(
+    format!(
+      "{{ \"username\":\"{}\", \"admin\":true, \"locked\":false, \"max_sessions\":1 }}",
+      admin_username
+    )
+    .into(),
+  )
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to setting session limit: {:?}", response);
+  assert_eq!(StatusCode::OK, response.status());
+  let updated: shared_types::AdminReturnableUser = from_json(&mut response).await;
+  assert_eq!(Some(1), updated.max_sessions);
+  let first_session = login(&client, &admin_username, &testing_password).await;
+  let request = Request::post(format!("http://127.0.0.1:{}/api/login", TEST_SERVER_PORT))
+    .header("Content-Type", "application/json; charset=utf-8")
+    .body(
+      format!(
+        "{{ \"username\":\"{}\", \"password\":\"{}\", \"extended\":false }}",
+        admin_username, testing_password
+      )
+      .into(),
+    )
+    .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to login beyond session limit: {:?}", response);
+  print_json(&mut response).await;
+  let active = sqlx::query!("SELECT id FROM sessions WHERE userid = $1", created.id)
+    .fetch_all(&state.db_pool)
+    .await
+    .unwrap();
+  assert_eq!(1, active.len());
+  match state.session_limit_policy {
+    crate::SessionLimitPolicy::Reject => {
+      assert_eq!(StatusCode::FORBIDDEN, response.status());
+      assert_eq!(first_session.id, active[0].id);
+    }
+    crate::SessionLimitPolicy::Evict => {
+      assert_eq!(StatusCode::CREATED, response.status());
+      assert_ne!(first_session.id, active[0].id);
+    }
+  }
+  // Concurrent logins can't exceed the limit together
+  let logins = (0..4).map(|_| {
+    let request = Request::post(format!("http://127.0.0.1:{}/api/login", TEST_SERVER_PORT))
+      .header("Content-Type", "application/json; charset=utf-8")
+      .body(
+        format!(
+          "{{ \"username\":\"{}\", \"password\":\"{}\", \"extended\":false }}",
+          admin_username, testing_password
+        )
+        .into(),
+      )
+      .unwrap();
+    client.request(request)
+  });
+  futures::future::join_all(logins).await;
+  let active = sqlx::query!("SELECT id FROM sessions WHERE userid = $1", created.id)
+    .fetch_all(&state.db_pool)
+    .await
+    .unwrap();
+  assert_eq!(1, active.len());
+  // Newer expired sessions don't take the place of live ones
+  sqlx::query!(
+    "
+INSERT INTO sessions(userid, key, until)
+VALUES($1, $2, NOW() - INTERVAL '1 hour')
+    ",
+    created.id,
+    nanoid::nanoid!(32),
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  sqlx::query!(
+    "UPDATE users SET max_sessions = 2 WHERE id = $1",
+    created.id
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  login(&client, &admin_username, &testing_password).await;
+  let live = sqlx::query!("SELECT id FROM sessions WHERE id = $1", active[0].id)
+    .fetch_optional(&state.db_pool)
+    .await
+    .unwrap();
+  assert!(live.is_some());
+  // Negative limits are refused, and 0 is unlimited
+  for (max_sessions, status) in [(-1, StatusCode::BAD_REQUEST), (0, StatusCode::OK)] {
+    let request = Request::put(format!(
+      "http://127.0.0.1:{}/api/admin/users/{}",
+      TEST_SERVER_PORT, created.id
+    ))
+    .header("Authorization", format!("bearer {}", admin_session.key))
+    .header("Content-Type", "application/json; charset=utf-8")
+    .body(
+      serde_json::json!({
+        "username": admin_username, "admin": true, "locked": false,
+        "max_sessions": max_sessions,
+      })
+      .to_string()
+      .into(),
+    )
+    .unwrap();
+    let response = client.request(request).await.unwrap();
+    assert_eq!(status, response.status());
+  }
+  login(&client, &admin_username, &testing_password).await;
+  login(&client, &admin_username, &testing_password).await;
+  sqlx::query!("DELETE FROM sessions WHERE userid = $1", created.id)
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+  sqlx::query!("DELETE FROM users WHERE id = $1", created.id)
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+
+  println!("\nTest bulk revocation of sessions.");
+  let current_session = login(&client, "test-user", &testing_password).await;
+  login(&client, "test-user", &testing_password).await;
+  // Expired sessions aren't counted as revoked
+  sqlx::query!(
+    "
+INSERT INTO sessions(userid, key, until)
+VALUES(-2, $1, NOW() - INTERVAL '1 hour')
+    ",
+    nanoid::nanoid!(32),
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  let live = sqlx::query!(
+    "
+SELECT COUNT(*) AS \"count!\" FROM sessions
+WHERE userid = -2 AND id <> $1 AND until >= NOW()
+    ",
+    current_session.id,
+  )
+  .fetch_one(&state.db_pool)
+  .await
+  .unwrap()
+  .count;
+  let request = Request::delete(format!(
+    "http://127.0.0.1:{}/api/user/sessions?except_current=true",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", current_session.key))
+  .body("".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to revoking other sessions: {:?}", response);
+  assert_eq!(StatusCode::OK, response.status());
+  let revoked: shared_types::RevokedSessions = from_json(&mut response).await;
+  assert_eq!(live as u64, revoked.revoked);
+  let remaining = sqlx::query!("SELECT id FROM sessions WHERE userid = -2 AND until >= NOW()")
+    .fetch_all(&state.db_pool)
+    .await
+    .unwrap();
+  assert_eq!(1, remaining.len());
+  assert_eq!(current_session.id, remaining[0].id);
+  let newer_session = login(&client, "test-user", &testing_password).await;
+  // Filters combine, rather than any of them matching other users' sessions
+  let request = Request::get(format!(
+    "http://127.0.0.1:{}/api/user/sessions?id_mte={}",
+    TEST_SERVER_PORT, newer_session.id
+  ))
+  .header("Authorization", format!("bearer {}", current_session.key))
+  .body("".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  let listed: Vec<shared_types::ReturnableSession> = from_json(&mut response).await;
+  assert_eq!(
+    vec![newer_session.id],
+    listed.iter().map(|s| s.id).collect::<Vec<_>>()
+  );
+  // Revoking every session must be asked for explicitly
+  let request = Request::delete(format!(
+    "http://127.0.0.1:{}/api/admin/sessions",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body("".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::BAD_REQUEST, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(error, shared_types::ClientError::InvalidFilter(_)));
+  let request = Request::delete(format!(
+    "http://127.0.0.1:{}/api/admin/sessions?userid_eq=-2",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body("".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to admin revoking user's sessions: {:?}", response);
+  assert_eq!(StatusCode::OK, response.status());
+  let revoked: shared_types::RevokedSessions = from_json(&mut response).await;
+  assert_eq!(2, revoked.revoked);
+  // The admin's own session is unaffected
+  let request = Request::get(format!("http://127.0.0.1:{}/api/user", TEST_SERVER_PORT))
+    .header("Authorization", format!("bearer {}", admin_session.key))
+    .body("".into())
+    .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+
+  #[cfg(feature = "session_cookies")]
+  {
+    println!("\nTest cookie session transport.");
+    let request = Request::post(format!("http://127.0.0.1:{}/api/login", TEST_SERVER_PORT))
+      .header("Content-Type", "application/json; charset=utf-8")
+      .body(
+        format!(
+          "{{ \"username\":\"test-user\", \"password\":\"{}\", \"extended\":false, \"session_cookie\":true }}",
+          testing_password
+        )
+        .into(),
+      )
+      .unwrap();
+    let mut response = client.request(request).await.unwrap();
+    println!("Response to cookie login: {:?}", response);
+    assert_eq!(StatusCode::CREATED, response.status());
+    // Collect the cookies as a client would send them back
+    let cookies: Vec<String> = response
+      .headers()
+      .get_all("Set-Cookie")
+      .iter()
+      .map(|c| c.to_str().unwrap().split(';').next().unwrap().to_string())
+      .collect();
+    let csrf_token = cookies
+      .iter()
+      .find_map(|c| c.strip_prefix("csrf_token="))
+      .unwrap()
+      .to_string();
+    let cookie_header = cookies.join("; ");
+    let session: shared_types::Session = from_json(&mut response).await;
+    assert!(session.key.is_empty());
+    let request = Request::get(format!("http://127.0.0.1:{}/api/user", TEST_SERVER_PORT))
+      .header("Cookie", &cookie_header)
+      .body("".into())
+      .unwrap();
+    let response = client.request(request).await.unwrap();
+    println!("Response to user get with cookie: {:?}", response);
+    assert_eq!(StatusCode::OK, response.status());
+    let request = Request::post(format!("http://127.0.0.1:{}/api/logout", TEST_SERVER_PORT))
+      .header("Cookie", &cookie_header)
+      .body("".into())
+      .unwrap();
+    let mut response = client.request(request).await.unwrap();
+    println!("Response to logout without CSRF token: {:?}", response);
+    print_json(&mut response).await;
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+    let request = Request::post(format!("http://127.0.0.1:{}/api/logout", TEST_SERVER_PORT))
+      .header("Cookie", &cookie_header)
+      .header("X-CSRF-Token", &csrf_token)
+      .body("".into())
+      .unwrap();
+    let response = client.request(request).await.unwrap();
+    println!("Response to logout with CSRF token: {:?}", response);
+    assert_eq!(StatusCode::NO_CONTENT, response.status());
+    assert_eq!(2, response.headers().get_all("Set-Cookie").iter().count());
+  }
+
+  println!("\nTest OpenID Connect login through a relying party.");
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/oidc_clients",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body(
+    format!(
+      "{{ \"client_id\":\"test-rp-{}\", \"name\":\"Test RP\", \"confidential\":true, \"redirect_uris\":[\"http://127.0.0.1:{}/callback\"] }}",
+      nanoid::nanoid!(8),
+      TEST_RELYING_PARTY_PORT
+    )
+    .into(),
+  )
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to client creation: {:?}", response);
+  assert_eq!(StatusCode::CREATED, response.status());
+  let created_client: shared_types::CreatedOidcClient = from_json(&mut response).await;
+  assert!(created_client.secret.is_some());
+  let _relying_party = tokio::task::spawn(relying_party_stub(
+    created_client.client.client_id.clone(),
+    created_client.secret.clone(),
+  ));
+  // Act as the user's browser, going through the flow
+  let user_session = login(&client, "test-user", &testing_password).await;
+  let response = client
+    .get(
+      format!("http://127.0.0.1:{}/login", TEST_RELYING_PARTY_PORT)
+        .parse()
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::SEE_OTHER, response.status());
+  let authorize_uri = response.headers()["Location"].to_str().unwrap().to_string();
+  let response = client.get(authorize_uri.parse().unwrap()).await.unwrap();
+  println!("Response to authorization request: {:?}", response);
+  assert_eq!(StatusCode::SEE_OTHER, response.status());
+  // The frontend confirms the same request with the user's session
+  let frontend_uri = response.headers()["Location"].to_str().unwrap();
+  let query = frontend_uri
+    .strip_prefix("/?")
+    .and_then(|uri| uri.strip_suffix("#authorize"))
+    .unwrap();
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/oidc/authorize?{}",
+    TEST_SERVER_PORT, query
+  ))
+  .header("Authorization", format!("bearer {}", user_session.key))
+  .body("".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to authorization: {:?}", response);
+  assert_eq!(StatusCode::OK, response.status());
+  let redirect: shared_types::AuthorizeRedirect = from_json(&mut response).await;
+  let mut response = client
+    .get(redirect.redirect.parse().unwrap())
+    .await
+    .unwrap();
+  println!("Response to callback: {:?}", response);
+  let body = hyper::body::to_bytes(response.body_mut()).await.unwrap();
+  println!("{}", String::from_utf8_lossy(&body));
+  assert_eq!(StatusCode::OK, response.status());
+  assert_eq!(&b"test-user"[..], &body[..]);
+  // The access token given to the client is only valid for userinfo
+  let access_token = sqlx::query!(
+    "SELECT key FROM sessions WHERE clientid = $1",
+    created_client.client.id
+  )
+  .fetch_one(&state.db_pool)
+  .await
+  .unwrap()
+  .key;
+  let request = Request::get(format!("http://127.0.0.1:{}/api/user", TEST_SERVER_PORT))
+    .header("Authorization", format!("bearer {}", access_token))
+    .body("".into())
+    .unwrap();
+  let response = client.request(request).await.unwrap();
+  println!("Response to user get with access token: {:?}", response);
+  assert_eq!(StatusCode::FORBIDDEN, response.status());
+  // Unregistered redirect URIs are refused without redirecting
+  let request = Request::get(format!(
+    "http://127.0.0.1:{}/api/oidc/authorize?{}",
+    TEST_SERVER_PORT,
+    query.replace("callback", "elsewhere")
+  ))
+  .body("".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to unregistered redirect URI: {:?}", response);
+  print_json(&mut response).await;
+  assert_eq!(StatusCode::BAD_REQUEST, response.status());
+  let request = Request::delete(format!(
+    "http://127.0.0.1:{}/api/admin/oidc_clients/{}",
+    TEST_SERVER_PORT, created_client.client.id
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body("".into())
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+
+  println!("\nTest login through an external identity provider.");
+  let external_username = format!("ext-{}", nanoid::nanoid!(8));
+  let identity_provider_user = Arc::new(Mutex::new(external_username.clone()));
+  let _identity_provider =
+    tokio::task::spawn(identity_provider_stub(identity_provider_user.clone()));
+  // Twice, first creating the user and then logging in as it again
+  let mut external_sessions = Vec::new();
+  for _ in 0..2 {
+    // Act as the user's browser, following the redirects
+    let response = client
+      .get(
+        format!("http://127.0.0.1:{}/api/external_login", TEST_SERVER_PORT)
+          .parse()
+          .unwrap(),
+      )
+      .await
+      .unwrap();
+    println!("Response to external login: {:?}", response);
+    assert_eq!(StatusCode::SEE_OTHER, response.status());
+    let authorize_uri = response.headers()["Location"].to_str().unwrap().to_string();
+    let login_cookie = response.headers()["Set-Cookie"]
+      .to_str()
+      .unwrap()
+      .split(';')
+      .next()
+      .unwrap()
+      .to_string();
+    let response = client.get(authorize_uri.parse().unwrap()).await.unwrap();
+    assert_eq!(StatusCode::SEE_OTHER, response.status());
+    let callback_uri = response.headers()["Location"].to_str().unwrap().to_string();
+    // Another browser can't finish the login (login CSRF)
+    for cookie in [None, Some("external_login=forged")] {
+      let mut request = Request::get(&callback_uri);
+      if let Some(cookie) = cookie {
+        request = request.header("Cookie", cookie);
+      }
+      let response = client
+        .request(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+      assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
+    let request = Request::get(&callback_uri)
+      .header("Cookie", &login_cookie)
+      .body(Body::empty())
+      .unwrap();
+    let mut response = client.request(request).await.unwrap();
+    println!("Response to external login callback: {:?}", response);
+    if response.status() != StatusCode::SEE_OTHER {
+      print_json(&mut response).await;
+    }
+    assert_eq!(StatusCode::SEE_OTHER, response.status());
+    // The frontend redeems the ticket for the session
+    let ticket = response.headers()["Location"]
+      .to_str()
+      .unwrap()
+      .strip_prefix("/#external_login/")
+      .unwrap()
+      .to_string();
+    let request = Request::post(format!(
+      "http://127.0.0.1:{}/api/external_login/ticket",
+      TEST_SERVER_PORT
+    ))
+    .header("Content-Type", "application/json; charset=utf-8")
+    .body(format!("{{ \"ticket\":\"{}\" }}", ticket).into())
+    .unwrap();
+    let mut response = client.request(request).await.unwrap();
+    println!("Response to ticket redemption: {:?}", response);
+    assert_eq!(StatusCode::CREATED, response.status());
+    let session: shared_types::Session = from_json(&mut response).await;
+    assert_eq!(external_username, session.username);
+    // The provider's groups claim made the user admin
+    assert!(session.is_admin);
+    // Tickets are only valid once
+    let request = Request::post(format!(
+      "http://127.0.0.1:{}/api/external_login/ticket",
+      TEST_SERVER_PORT
+    ))
+    .header("Content-Type", "application/json; charset=utf-8")
+    .body(format!("{{ \"ticket\":\"{}\" }}", ticket).into())
+    .unwrap();
+    let response = client.request(request).await.unwrap();
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    external_sessions.push(session);
+  }
+  // Admins without a password elevate by logging in at the provider again
+  let admin_users = |key: String| {
+    let request = Request::get(format!(
+      "http://127.0.0.1:{}/api/admin/users",
+      TEST_SERVER_PORT
+    ))
+    .header("Authorization", format!("bearer {}", key))
+    .body(Body::empty())
+    .unwrap();
+    client.request(request)
+  };
+  let external_key = external_sessions[1].key.clone();
+  let mut response = admin_users(external_key.clone()).await.unwrap();
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(
+    error,
+    shared_types::ClientError::ElevationRequired
+  ));
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/elevate/external",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", external_key))
+  .body(Body::empty())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to external elevation: {:?}", response);
+  assert_eq!(StatusCode::OK, response.status());
+  let login_cookie = response.headers()["Set-Cookie"]
+    .to_str()
+    .unwrap()
+    .split(';')
+    .next()
+    .unwrap()
+    .to_string();
+  let elevation: shared_types::ExternalElevation = from_json(&mut response).await;
+  assert!(elevation.uri.contains("prompt=login"));
+  assert!(elevation.uri.contains("max_age=0"));
+  let response = follow_external_login(&client, &elevation.uri, &login_cookie).await;
+  println!("Response to external elevation callback: {:?}", response);
+  assert_eq!(StatusCode::SEE_OTHER, response.status());
+  assert_eq!("/#external_elevation", response.headers()["Location"]);
+  let response = admin_users(external_key).await.unwrap();
+  assert!(response.status().is_success());
+  let external_users = sqlx::query!(
+    "SELECT DISTINCT userid FROM sessions WHERE id = $1 OR id = $2",
+    external_sessions[0].id,
+    external_sessions[1].id,
+  )
+  .fetch_all(&state.db_pool)
+  .await
+  .unwrap();
+  assert_eq!(1, external_users.len());
+  // Admins can link the identity to an existing user instead
+  let external_identities = |method: hyper::Method, userid: i32, query: &str, body: &str| {
+    let request = Request::builder()
+      .method(method)
+      .uri(format!(
+        "http://127.0.0.1:{}/api/admin/users/{}/external_identities{}",
+        TEST_SERVER_PORT, userid, query
+      ))
+      .header("Authorization", format!("bearer {}", admin_session.key))
+      .header("Content-Type", "application/json; charset=utf-8")
+      .body(body.to_string().into())
+      .unwrap();
+    client.request(request)
+  };
+  let issuer = format!("http://127.0.0.1:{}", TEST_IDENTITY_PROVIDER_PORT);
+  let identity = serde_json::json!({
+    "issuer": issuer,
+    "subject": format!("subject-{}", external_username),
+  });
+  let mut response = external_identities(hyper::Method::GET, external_users[0].userid, "", "")
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  let linked: Vec<shared_types::ExternalIdentity> = from_json(&mut response).await;
+  assert_eq!(1, linked.len());
+  assert_eq!(issuer, linked[0].issuer);
+  let linked_username = format!("test-linked-{}", nanoid::nanoid!(8));
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/users",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body(format!("{{ \"username\":\"{}\" }}", linked_username).into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::CREATED, response.status());
+  let linked_user: shared_types::AdminReturnableUser = from_json(&mut response).await;
+  let mut response = external_identities(
+    hyper::Method::PUT,
+    linked_user.id,
+    "",
+    &identity.to_string(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::BAD_REQUEST, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(
+    error,
+    shared_types::ClientError::ExternalIdentityTaken
+  ));
+  let query = format!(
+    "?{}",
+    serde_urlencoded::to_string([
+      ("issuer", issuer.as_str()),
+      ("subject", &format!("subject-{}", external_username)),
+    ])
+    .unwrap()
+  );
+  // Not while it is the only way the last admin can log in
+  let mut response =
+    external_identities(hyper::Method::DELETE, external_users[0].userid, &query, "")
+      .await
+      .unwrap();
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(error, shared_types::ClientError::LastAdmin));
+  sqlx::query!(
+    "UPDATE users SET pass = $1 WHERE id = $2",
+    &testing_hash,
+    external_users[0].userid
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  let response = external_identities(hyper::Method::DELETE, external_users[0].userid, &query, "")
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  let response = external_identities(
+    hyper::Method::PUT,
+    linked_user.id,
+    "",
+    &identity.to_string(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  let session = external_login(&client).await;
+  assert_eq!(linked_username, session.username);
+  sqlx::query!(
+    "DELETE FROM users WHERE username = $1 OR id = $2",
+    external_username,
+    linked_user.id
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  // Users are only created if named as the rules for usernames allow
+  *identity_provider_user.lock().unwrap() = format!("ext {}", nanoid::nanoid!(8));
+  let response = client
+    .get(
+      format!("http://127.0.0.1:{}/api/external_login", TEST_SERVER_PORT)
+        .parse()
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  let login_cookie = response.headers()["Set-Cookie"]
+    .to_str()
+    .unwrap()
+    .split(';')
+    .next()
+    .unwrap()
+    .to_string();
+  let authorize_uri = response.headers()["Location"].to_str().unwrap();
+  let mut response = follow_external_login(&client, authorize_uri, &login_cookie).await;
+  println!(
+    "Response to external login with an invalid username: {:?}",
+    response
+  );
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(error, shared_types::ClientError::InvalidUsername));
+
+  println!("\nTest SCIM provisioning with an API token.");
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/api_tokens",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body("{ \"name\":\"Test SCIM client\" }".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to API token creation: {:?}", response);
+  assert_eq!(StatusCode::CREATED, response.status());
+  let api_token: shared_types::CreatedApiToken = from_json(&mut response).await;
+  let scim_request = |method: hyper::Method, path: &str, body: Option<serde_json::Value>| {
+    let request = Request::builder()
+      .method(method)
+      .uri(format!(
+        "http://127.0.0.1:{}/scim/v2/{}",
+        TEST_SERVER_PORT, path
+      ))
+      .header("Authorization", format!("Bearer {}", api_token.token));
+    let request = match body {
+      Some(body) => request
+        .header("Content-Type", "application/scim+json")
+        .body(body.to_string().into()),
+      None => request.body(Body::empty()),
+    };
+    client.request(request.unwrap())
+  };
+  // Tokens are required
+  let request = Request::get(format!(
+    "http://127.0.0.1:{}/scim/v2/Users",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("Bearer {}", admin_session.key))
+  .body(Body::empty())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to SCIM with a session key: {:?}", response);
+  from_scim_json(&mut response).await;
+  assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+  let scim_username = format!("scim-{}", nanoid::nanoid!(8, &nanoid::alphabet::SAFE[2..]));
+  let scim_user = serde_json::json!({
+    "schemas": ["urn:ietf:params:scim:schemas:core:2.0:User"],
+    "userName": scim_username,
+    "externalId": "hr-1234",
+    "name": { "givenName": "Test" },
+    "active": true,
+    "password": testing_password,
+  });
+  let mut response = scim_request(hyper::Method::POST, "Users", Some(scim_user.clone()))
+    .await
+    .unwrap();
+  println!("Response to SCIM user creation: {:?}", response);
+  assert_eq!(StatusCode::CREATED, response.status());
+  let created = from_scim_json(&mut response).await;
+  let scim_id = created["id"].as_str().unwrap().to_string();
+  let response = scim_request(hyper::Method::POST, "Users", Some(scim_user))
+    .await
+    .unwrap();
+  println!("Response to duplicate SCIM user creation: {:?}", response);
+  assert_eq!(StatusCode::CONFLICT, response.status());
+  // Provisioned users can log in with the given password
+  let scim_session = login(&client, &scim_username, &testing_password).await;
+  // Filters
+  for (filter, expected) in [
+    (
+      format!("userName eq \"{}\"", scim_username.to_uppercase()),
+      1,
+    ),
+    (
+      "externalId eq \"hr-1234\" and active eq true".to_string(),
+      1,
+    ),
+    (
+      format!(
+        "userName sw \"{}\" and id eq \"{}\"",
+        &scim_username[..6],
+        scim_id
+      ),
+      1,
+    ),
+    (format!("userName co \"{}_\"", &scim_username[5..]), 0),
+    // Compared as normalized usernames, so fullwidth forms match as well
+    (
+      format!(
+        "userName ew \"{}\"",
+        scim_username[5..]
+          .to_uppercase()
+          .chars()
+          .map(|c| char::from_u32(c as u32 + 0xFEE0).unwrap())
+          .collect::<String>()
+      ),
+      1,
+    ),
+    // Without becoming wildcards once normalized
+    ("userName co \"％\"".to_string(), 0),
+    // Strings needn't be spaced from the operator
+    (format!("userName eq\"{}\"", scim_username), 1),
+  ] {
+    let mut response = scim_request(
+      hyper::Method::GET,
+      &format!(
+        "Users?{}",
+        serde_urlencoded::to_string([("filter", &filter)]).unwrap()
+      ),
+      None,
+    )
+    .await
+    .unwrap();
+    println!("Response to SCIM filter {}: {:?}", filter, response);
+    assert_eq!(StatusCode::OK, response.status());
+    let list = from_scim_json(&mut response).await;
+    assert_eq!(expected, list["totalResults"]);
+  }
+  let mut response = scim_request(
+    hyper::Method::GET,
+    "Users?filter=userName%20eq%20%22a%22%20or%20active%20eq%20true",
+    None,
+  )
+  .await
+  .unwrap();
+  println!("Response to unsupported SCIM filter: {:?}", response);
+  let error = from_scim_json(&mut response).await;
+  assert_eq!("invalidFilter", error["scimType"]);
+  // Deactivating locks the user and ends its sessions
+  let mut response = scim_request(
+    hyper::Method::PATCH,
+    &format!("Users/{}", scim_id),
+    Some(serde_json::json!({
+      "schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"],
+      "Operations": [
+        { "op": "Replace", "path": "active", "value": "False" },
+        { "op": "add", "value": { "roles": [{ "value": "admin" }] } },
+      ],
+    })),
+  )
+  .await
+  .unwrap();
+  println!("Response to SCIM patch: {:?}", response);
+  assert_eq!(StatusCode::OK, response.status());
+  let patched = from_scim_json(&mut response).await;
+  assert_eq!(false, patched["active"]);
+  assert_eq!("admin", patched["roles"][0]["value"]);
+  let request = Request::get(format!("http://127.0.0.1:{}/api/user", TEST_SERVER_PORT))
+    .header("Authorization", format!("bearer {}", scim_session.key))
+    .body(Body::empty())
+    .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+  let response = scim_request(hyper::Method::DELETE, &format!("Users/{}", scim_id), None)
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  let response = scim_request(hyper::Method::GET, &format!("Users/{}", scim_id), None)
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::NOT_FOUND, response.status());
+  // Revoked tokens stop working
+  let request = Request::delete(format!(
+    "http://127.0.0.1:{}/api/admin/api_tokens/{}",
+    TEST_SERVER_PORT, api_token.api_token.id
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body(Body::empty())
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  let response = scim_request(hyper::Method::GET, "Users", None)
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+  sqlx::query!(
+    "DELETE FROM users WHERE id = $1",
+    scim_id.parse::<i32>().unwrap()
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+
+  println!("\nTest signed access tokens.");
+  let session = login(&client, "test-user", &testing_password).await;
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/access_token",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", session.key))
+  .body(Body::empty())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::CREATED, response.status());
+  let token: shared_types::AccessToken = from_json(&mut response).await;
+  assert!(token.until <= session.until);
+  // Usable in place of the session key
+  let request = Request::get(format!("http://127.0.0.1:{}/api/user", TEST_SERVER_PORT))
+    .header("Authorization", format!("bearer {}", token.access_token))
+    .body(Body::empty())
+    .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  print_json(&mut response).await;
+  // But not to get more tokens
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/access_token",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", token.access_token))
+  .body(Body::empty())
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+  // Nor for admin paths by users
+  let request = Request::get(format!(
+    "http://127.0.0.1:{}/api/admin/users",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", token.access_token))
+  .body(Body::empty())
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::FORBIDDEN, response.status());
+  // Tampered tokens are refused
+  let tampered = format!("{}x", token.access_token);
+  let request = Request::get(format!("http://127.0.0.1:{}/api/user", TEST_SERVER_PORT))
+    .header("Authorization", format!("bearer {}", tampered))
+    .body(Body::empty())
+    .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+  // Other services can check them against the published keys
+  let mut response = client
+    .get(
+      format!("http://127.0.0.1:{}/api/oidc/jwks", TEST_SERVER_PORT)
+        .parse()
+        .unwrap(),
+    )
+    .await
+    .unwrap();
+  let jwks: crate::auth::jwt::Jwks = from_json(&mut response).await;
+  let claims: crate::auth::access_token::AccessTokenClaims = jwks
+    .keys
+    .iter()
+    .find_map(|jwk| crate::auth::jwt::verify(&jwk.verifying_key()?, &token.access_token))
+    .unwrap();
+  assert_eq!("-2", claims.sub);
+  assert_eq!(session.id, claims.sid);
+  assert_eq!("access", claims.token_use);
+  // Logging out with the token revokes it, once the revocation list reloads
+  let request = Request::post(format!("http://127.0.0.1:{}/api/logout", TEST_SERVER_PORT))
+    .header("Authorization", format!("bearer {}", token.access_token))
+    .body(Body::empty())
+    .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
+  let request = Request::get(format!("http://127.0.0.1:{}/api/user", TEST_SERVER_PORT))
+    .header("Authorization", format!("bearer {}", token.access_token))
+    .body(Body::empty())
+    .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+  println!("\nTest session cache invalidation by notification.");
+  let session = login(&client, "test-user", &testing_password).await;
+  let get_user = |key: String| {
+    let request = Request::get(format!("http://127.0.0.1:{}/api/user", TEST_SERVER_PORT))
+      .header("Authorization", format!("bearer {}", key))
+      .body(Body::empty())
+      .unwrap();
+    client.request(request)
+  };
+  let response = get_user(session.key.clone()).await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  let (hits, _) = state.session_cache.as_ref().unwrap().stats();
+  let response = get_user(session.key.clone()).await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  assert_eq!(hits + 1, state.session_cache.as_ref().unwrap().stats().0);
+  // Deleted directly, as another instance would, so only the trigger tells
+  sqlx::query!("DELETE FROM sessions WHERE id = $1", session.id)
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+  tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+  let response = get_user(session.key.clone()).await.unwrap();
+  assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+  // Only changes to what sessions are validated with are notified
+  let session = login(&client, "test-user", &testing_password).await;
+  get_user(session.key.clone()).await.unwrap();
+  for (update, cached) in [
+    (
+      "UPDATE users SET display_name = 'Cached' WHERE id = -2",
+      true,
+    ),
+    (
+      "UPDATE users SET must_change_password = false WHERE id = -2",
+      true,
+    ),
+    (
+      "UPDATE users SET display_name = NULL, admin = true WHERE id = -2",
+      false,
+    ),
+  ] {
+    let (hits, _) = state.session_cache.as_ref().unwrap().stats();
+    sqlx::query(update).execute(&state.db_pool).await.unwrap();
+    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    let response = get_user(session.key.clone()).await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+    let expected_hits = if cached { hits + 1 } else { hits };
+    assert_eq!(
+      expected_hits,
+      state.session_cache.as_ref().unwrap().stats().0
+    );
+  }
+  sqlx::query!("UPDATE users SET admin = false WHERE id = -2")
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+
+  println!("\nTest login by emailed link.");
+  sqlx::query!("UPDATE users SET email = 'test-' || -id || '@example.com' WHERE id IN (-1, -2)")
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+  let request_link = |username: &str| {
+    let request = Request::post(format!(
+      "http://127.0.0.1:{}/api/magic_link",
+      TEST_SERVER_PORT
+    ))
+    .header("Content-Type", "application/json; charset=utf-8")
+    .body(format!("{{ \"username\":\"{}\" }}", username).into())
+    .unwrap();
+    client.request(request)
+  };
+  // Responds the same for users, admins (not allowed) and unknown users
+  for username in ["test-user", "test-admin", "test-nonexistent"] {
+    let response = request_link(username).await.unwrap();
+    println!("Response to link request for {}: {:?}", username, response);
+    assert_eq!(StatusCode::NO_CONTENT, response.status());
+  }
+  tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+  let mail = {
+    let mut mails = mails.lock().unwrap();
+    assert_eq!(1, mails.len());
+    mails.pop().unwrap()
+  };
+  println!("Mail sent: {}", mail);
+  assert!(mail.contains("test-2@example.com"));
+  let token: String = mail
+    .split("#magic_link/")
+    .nth(1)
+    .unwrap()
+    .chars()
+    .take(32)
+    .collect();
+  // Not another link right away
+  let response = request_link("test-user").await.unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+  assert!(mails.lock().unwrap().is_empty());
+  let redeem_link = |token: String| {
+    let request = Request::post(format!(
+      "http://127.0.0.1:{}/api/magic_link/redeem",
+      TEST_SERVER_PORT
+    ))
+    .header("Content-Type", "application/json; charset=utf-8")
+    .body(
+      serde_json::to_string(&shared_types::MagicLinkToken {
+        token,
+        session_cookie: false,
+      })
+      .unwrap()
+      .into(),
+    )
+    .unwrap();
+    client.request(request)
+  };
+  let mut response = redeem_link(token.clone()).await.unwrap();
+  assert_eq!(StatusCode::CREATED, response.status());
+  let link_session: shared_types::Session = from_json(&mut response).await;
+  assert_eq!("test-user", link_session.username);
+  // Links can only be used once
+  let response = redeem_link(token).await.unwrap();
+  assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+  // Email addresses set by admins are validated
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/users",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body("{ \"username\":\"test-invalid-email\", \"email\":\"not an address\" }".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::BAD_REQUEST, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(error, shared_types::ClientError::InvalidEmail));
+
+  println!("\nTest importing users with password hashes from other systems.");
+  let legacy_hashes = {
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    let salt = SaltString::generate(&mut rand_core::OsRng);
+    vec![
+      ("bcrypt", bcrypt::hash(&testing_password, 4).unwrap()),
+      (
+        "pbkdf2",
+        pbkdf2::Pbkdf2
+          .hash_password_simple(testing_password.as_bytes(), &salt)
+          .unwrap()
+          .to_string(),
+      ),
+      (
+        "scrypt",
+        scrypt::Scrypt
+          .hash_password_simple(testing_password.as_bytes(), &salt)
+          .unwrap()
+          .to_string(),
+      ),
+    ]
+  };
+  let import = |users: serde_json::Value| {
+    let request = Request::post(format!(
+      "http://127.0.0.1:{}/api/admin/users/import",
+      TEST_SERVER_PORT
+    ))
+    .header("Authorization", format!("bearer {}", admin_session.key))
+    .header("Content-Type", "application/json; charset=utf-8")
+    .body(users.to_string().into())
+    .unwrap();
+    client.request(request)
+  };
+  // Hashes in unknown formats are refused, importing nothing
+  let mut users: Vec<serde_json::Value> = legacy_hashes
+    .iter()
+    .map(|(kind, hash)| {
+      serde_json::json!({
+        "username": format!("test-import-{}", kind),
+        "password_hash": hash,
+      })
+    })
+    .collect();
+  // Argon2 hashes from elsewhere aren't made with the secret key used here
+  let foreign_argon2 = {
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    argon2::Argon2::default()
+      .hash_password_simple(
+        testing_password.as_bytes(),
+        &SaltString::generate(&mut rand_core::OsRng),
+      )
+      .unwrap()
+      .to_string()
+  };
+  for (username, hash) in [
+    ("test-import-md5", "5f4dcc3b5aa765d61d8327deb882cf99"),
+    ("test-import-argon2", foreign_argon2.as_str()),
+  ] {
+    users.push(serde_json::json!({
+      "username": username,
+      "password_hash": hash,
+    }));
+    let mut response = import(serde_json::Value::Array(users.clone()))
+      .await
+      .unwrap();
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    let error: shared_types::ClientError = from_json(&mut response).await;
+    assert!(
+      matches!(error, shared_types::ClientError::UnsupportedHash(rejected) if rejected == username)
+    );
+    users.pop();
+  }
+  let mut response = import(serde_json::Value::Array(users)).await.unwrap();
+  println!("Response to import: {:?}", response);
+  assert_eq!(StatusCode::CREATED, response.status());
+  let imported: Vec<shared_types::AdminReturnableUser> = from_json(&mut response).await;
+  assert_eq!(legacy_hashes.len(), imported.len());
+  for user in &imported {
+    // Logging in works with the old hash, and replaces it
+    let request = Request::post(format!("http://127.0.0.1:{}/api/login", TEST_SERVER_PORT))
+      .header("Content-Type", "application/json; charset=utf-8")
+      .body(
+        format!(
+          "{{ \"username\":\"{}\", \"password\":\"wrong\", \"extended\":false }}",
+          user.username
+        )
+        .into(),
+      )
+      .unwrap();
+    let response = client.request(request).await.unwrap();
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    login(&client, &user.username, &testing_password).await;
+  }
+  tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+  for user in &imported {
+    let pass = sqlx::query!("SELECT pass FROM users WHERE id = $1", user.id)
+      .fetch_one(&state.db_pool)
+      .await
+      .unwrap()
+      .pass
+      .unwrap();
+    assert!(crate::auth::hash::is_current(&pass));
+    login(&client, &user.username, &testing_password).await;
+  }
+  for user in &imported {
+    sqlx::query!("DELETE FROM sessions WHERE userid = $1", user.id)
+      .execute(&state.db_pool)
+      .await
+      .unwrap();
+    sqlx::query!("DELETE FROM users WHERE id = $1", user.id)
+      .execute(&state.db_pool)
+      .await
+      .unwrap();
+  }
+
+  println!("\nTest bulk importing and exporting users as CSV and JSON Lines.");
+  // Streamed in chunks, without Content-Length
+  let import = |query: &str, content_type: &str, chunks: Vec<String>| {
+    let chunks: Vec<Result<String, std::io::Error>> = chunks.into_iter().map(Ok).collect();
+    let request = Request::post(format!(
+      "http://127.0.0.1:{}/api/admin/users/import{}",
+      TEST_SERVER_PORT, query
+    ))
+    .header("Authorization", format!("bearer {}", admin_session.key))
+    .header("Content-Type", content_type)
+    .body(Body::wrap_stream(futures::stream::iter(chunks)))
+    .unwrap();
+    client.request(request)
+  };
+  // A dry run reports on every row and creates nothing
+  let rows = vec![
+    "{\"username\":\"test-bulk-a\"}\n".to_string(),
+    "{\"username\":\"test-bulk-a\"}\n\n{\"username\":\"test-bulk-b\",\"email\":\"nope\"}\n"
+      .to_string(),
+    "{\"username\":\"test-bulk-c\",\"admin\":\"maybe\"}\n{\"username\":\"test-bulk-b\"}"
+      .to_string(),
+  ];
+  let mut response = import("?dry_run=true", "application/x-ndjson", rows)
+    .await
+    .unwrap();
+  println!("Response to dry run import: {:?}", response);
+  assert_eq!(StatusCode::OK, response.status());
+  let report: Vec<shared_types::ImportRowReport> = from_json(&mut response).await;
+  assert_eq!(
+    vec![1, 2, 3, 4, 5],
+    report.iter().map(|row| row.row).collect::<Vec<_>>()
+  );
+  assert!(report[0].error.is_none());
+  assert!(matches!(
+    report[1].error,
+    Some(shared_types::ClientError::UsernameTaken)
+  ));
+  assert!(matches!(
+    report[2].error,
+    Some(shared_types::ClientError::InvalidEmail)
+  ));
+  assert!(report[3].username.is_none());
+  assert!(matches!(
+    report[3].error,
+    Some(shared_types::ClientError::InvalidJson(_))
+  ));
+  assert_eq!(Some("test-bulk-b"), report[4].username.as_deref());
+  assert!(report[4].error.is_none());
+  let created = sqlx::query!("SELECT id FROM users WHERE username LIKE 'test-bulk-%'")
+    .fetch_all(&state.db_pool)
+    .await
+    .unwrap();
+  assert!(created.is_empty());
+  // CSV, with a record split between chunks and quoted fields
+  let rows = vec![
+    "username, admin ,email\r\ntest-bulk-a,true,a@exa".to_string(),
+    "mple.com\r\n\"test-bulk-b\",false,\r\n".to_string(),
+  ];
+  let mut response = import("", "text/csv", rows).await.unwrap();
+  println!("Response to CSV import: {:?}", response);
+  assert_eq!(StatusCode::CREATED, response.status());
+  let imported: Vec<shared_types::AdminReturnableUser> = from_json(&mut response).await;
+  assert_eq!(2, imported.len());
+  assert!(imported[0].admin);
+  assert_eq!(Some("a@example.com"), imported[0].email.as_deref());
+  assert_eq!("test-bulk-b", imported[1].username);
+  assert_eq!(None, imported[1].email);
+  // Quoted fields may hold quotes and newlines, though usernames may not
+  let rows = vec!["username\n\"test-bulk-\"\"c\"\",\nd\"\n".to_string()];
+  let mut response = import("?dry_run=true", "text/csv", rows).await.unwrap();
+  let report: Vec<shared_types::ImportRowReport> = from_json(&mut response).await;
+  assert_eq!(1, report.len());
+  assert_eq!(Some("test-bulk-\"c\",\nd"), report[0].username.as_deref());
+  assert!(matches!(
+    report[0].error,
+    Some(shared_types::ClientError::InvalidUsername)
+  ));
+  // A failed row fails the whole import
+  let rows = vec!["username\ntest-bulk-d\ntest-bulk-a\n".to_string()];
+  let mut response = import("", "text/csv", rows).await.unwrap();
+  assert_eq!(StatusCode::BAD_REQUEST, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(error, shared_types::ClientError::UsernameTaken));
+  let created = sqlx::query!("SELECT id FROM users WHERE username = 'test-bulk-d'")
+    .fetch_all(&state.db_pool)
+    .await
+    .unwrap();
+  assert!(created.is_empty());
+  // Bodies over MAX_IMPORT_LEN are refused, even without Content-Length
+  let rows = (0..100)
+    .map(|i| format!("{{\"username\":\"test-bulk-{}\"}}{}\n", i, " ".repeat(1000)))
+    .collect();
+  let mut response = import("", "application/x-ndjson", rows).await.unwrap();
+  assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(
+    error,
+    shared_types::ClientError::ContentTooLarge(_)
+  ));
+  // Exports stream the filtered users
+  let export = |query: &str| {
+    let request = Request::get(format!(
+      "http://127.0.0.1:{}/api/admin/users/export?username_regex=^test-bulk-&order_by=id_asc{}",
+      TEST_SERVER_PORT, query
+    ))
+    .header("Authorization", format!("bearer {}", admin_session.key))
+    .body(Body::empty())
+    .unwrap();
+    client.request(request)
+  };
+  let response = export("").await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  assert_eq!(
+    "application/x-ndjson",
+    response.headers().get("Content-Type").unwrap()
+  );
+  let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+  let exported: Vec<shared_types::AdminReturnableUser> = body
+    .split(|b| *b == b'\n')
+    .filter(|line| !line.is_empty())
+    .map(|line| serde_json::from_slice(line).unwrap())
+    .collect();
+  assert_eq!(
+    imported.iter().map(|user| user.id).collect::<Vec<_>>(),
+    exported.iter().map(|user| user.id).collect::<Vec<_>>()
+  );
+  let response = export("&format=csv&admin_eq=true").await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+  println!("CSV export: {}", String::from_utf8_lossy(&body));
+  let mut reader = csv::Reader::from_reader(&body[..]);
+  assert_eq!(
+    vec![
+      "id",
+      "username",
+      "admin",
+      "locked",
+      "locked_until",
+      "locked_reason",
+      "deleted_at",
+      "max_sessions",
+      "email"
+    ],
+    reader.headers().unwrap().iter().collect::<Vec<_>>()
+  );
+  let exported: Vec<shared_types::AdminReturnableUser> =
+    reader.deserialize().map(|row| row.unwrap()).collect();
+  assert_eq!(1, exported.len());
+  assert_eq!(imported[0].username, exported[0].username);
+  assert_eq!(imported[0].email, exported[0].email);
+  sqlx::query!("DELETE FROM users WHERE username LIKE 'test-bulk-%'")
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+
+  println!("\nTest exporting personal data and deleting the own account.");
+  let userid = sqlx::query!(
+    "INSERT INTO users(username, pass, email) VALUES('test-self-delete', $1, 'self@example.com') RETURNING id",
+    &testing_hash,
+  )
+  .fetch_one(&state.db_pool)
+  .await
+  .unwrap()
+  .id;
+  login(&client, "test-self-delete", &testing_password).await;
+  let session = login(&client, "test-self-delete", &testing_password).await;
+  let request = Request::get(format!(
+    "http://127.0.0.1:{}/api/user/export",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", session.key))
+  .body(Body::empty())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to data export: {:?}", response);
+  assert_eq!(StatusCode::OK, response.status());
+  assert_eq!(
+    "attachment; filename=\"user-data.json\"",
+    response.headers().get("Content-Disposition").unwrap()
+  );
+  let export: shared_types::UserDataExport = from_json(&mut response).await;
+  assert_eq!(userid, export.user.id);
+  assert_eq!(Some("self@example.com"), export.user.email.as_deref());
+  assert!(export.user.has_password);
+  assert_eq!(2, export.sessions.len());
+  let delete_account = |key: &str, password: Option<&str>| {
+    let request = Request::delete(format!("http://127.0.0.1:{}/api/user", TEST_SERVER_PORT))
+      .header("Authorization", format!("bearer {}", key))
+      .header("Content-Type", "application/json; charset=utf-8")
+      .body(
+        serde_json::json!({ "password": password })
+          .to_string()
+          .into(),
+      )
+      .unwrap();
+    client.request(request)
+  };
+  // The reserved accounts can't be deleted this way
+  let reserved_session = login(&client, "test-user", &testing_password).await;
+  let mut response = delete_account(&reserved_session.key, Some(&testing_password))
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::FORBIDDEN, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(error, shared_types::ClientError::ProtectedAccount));
+  // The password is required, even right after logging in
+  for password in [Some("wrong"), None] {
+    let response = delete_account(&session.key, password).await.unwrap();
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+  }
+  let response = delete_account(&session.key, Some(&testing_password))
+    .await
+    .unwrap();
+  println!("Response to deleting own account: {:?}", response);
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  let deleted = sqlx::query!(
+    "SELECT deleted_at IS NOT NULL AS \"deleted!\", (SELECT COUNT(*) FROM sessions WHERE userid = $1) AS \"sessions!\" FROM users WHERE id = $1",
+    userid,
+  )
+  .fetch_one(&state.db_pool)
+  .await
+  .unwrap();
+  assert!(deleted.deleted);
+  assert_eq!(0, deleted.sessions);
+  let request = Request::get(format!(
+    "http://127.0.0.1:{}/api/user/export",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", session.key))
+  .body(Body::empty())
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+  sqlx::query!("DELETE FROM users WHERE id = $1", userid)
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+  // Accounts without a password confirm by a recent login instead
+  let userid = sqlx::query!(
+    "INSERT INTO users(username) VALUES('test-self-delete-passwordless') RETURNING id"
+  )
+  .fetch_one(&state.db_pool)
+  .await
+  .unwrap()
+  .id;
+  let key = nanoid::nanoid!(32);
+  sqlx::query!(
+    "
+INSERT INTO sessions(userid, key, until, authenticated_at)
+VALUES($1, $2, NOW() + INTERVAL '1 hour', NOW() - INTERVAL '1 hour')
+    ",
+    userid,
+    key,
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  let response = delete_account(&key, None).await.unwrap();
+  assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+  sqlx::query!(
+    "UPDATE sessions SET authenticated_at = NOW() WHERE key = $1",
+    key
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  let response = delete_account(&key, None).await.unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  sqlx::query!("DELETE FROM users WHERE id = $1", userid)
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+
+  println!("\nTest updating the own profile.");
+  let userid = sqlx::query!(
+    "INSERT INTO users(username, pass) VALUES('test-profile', $1) RETURNING id",
+    &testing_hash,
+  )
+  .fetch_one(&state.db_pool)
+  .await
+  .unwrap()
+  .id;
+  let session = login(&client, "test-profile", &testing_password).await;
+  let update_profile = |key: &str, update: serde_json::Value| {
+    let request = Request::put(format!("http://127.0.0.1:{}/api/user", TEST_SERVER_PORT))
+      .header("Authorization", format!("bearer {}", key))
+      .header("Content-Type", "application/json; charset=utf-8")
+      .body(update.to_string().into())
+      .unwrap();
+    client.request(request)
+  };
+  let mut response = update_profile(
+    &session.key,
+    serde_json::json!({
+      "display_name": "Test Profile",
+      "locale": "sv-SE",
+      "timezone": "Europe/Stockholm",
+    }),
+  )
+  .await
+  .unwrap();
+  println!("Response to profile update: {:?}", response);
+  assert_eq!(StatusCode::OK, response.status());
+  let user: shared_types::ReturnableUser = from_json(&mut response).await;
+  assert_eq!("test-profile", user.username);
+  assert_eq!(Some("Test Profile"), user.display_name.as_deref());
+  let request = Request::get(format!("http://127.0.0.1:{}/api/user", TEST_SERVER_PORT))
+    .header("Authorization", format!("bearer {}", session.key))
+    .body(Body::empty())
+    .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  let user: shared_types::ReturnableUser = from_json(&mut response).await;
+  assert_eq!(Some("sv-SE"), user.locale.as_deref());
+  assert_eq!(Some("Europe/Stockholm"), user.timezone.as_deref());
+  // Invalid fields are refused
+  for (update, expected) in [
+    (
+      serde_json::json!({ "display_name": null, "locale": "sv_SE", "timezone": null }),
+      "InvalidLocale",
+    ),
+    (
+      serde_json::json!({ "display_name": null, "locale": null, "timezone": "Mars/Olympus" }),
+      "InvalidTimezone",
+    ),
+    (
+      serde_json::json!({ "username": "test-admin", "display_name": null, "locale": null, "timezone": null }),
+      "UsernameTaken",
+    ),
+  ] {
+    let mut response = update_profile(&session.key, update).await.unwrap();
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    let error: shared_types::ClientError = from_json(&mut response).await;
+    assert_eq!(format!("{:?}", error), expected);
+  }
+  // Renaming, which also clears the unset profile fields
+  let mut response = update_profile(
+    &session.key,
+    serde_json::json!({ "username": "test-profile-renamed", "display_name": "", "locale": null, "timezone": null }),
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  let user: shared_types::ReturnableUser = from_json(&mut response).await;
+  assert_eq!("test-profile-renamed", user.username);
+  assert_eq!(None, user.display_name);
+  assert_eq!(None, user.timezone);
+  login(&client, "test-profile-renamed", &testing_password).await;
+  // The reserved accounts can't be renamed
+  let reserved_session = login(&client, "test-user", &testing_password).await;
+  let mut response = update_profile(
+    &reserved_session.key,
+    serde_json::json!({ "username": "test-user-renamed", "display_name": null, "locale": null, "timezone": null }),
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::FORBIDDEN, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(error, shared_types::ClientError::ProtectedAccount));
+  // Admins creating a user with a taken username get the same error
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/users",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body(
+    serde_json::json!({ "username": "test-profile-renamed", "admin": false, "locked": false })
+      .to_string()
+      .into(),
+  )
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::BAD_REQUEST, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(error, shared_types::ClientError::UsernameTaken));
+  sqlx::query!("DELETE FROM users WHERE id = $1", userid)
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+
+  println!("\nTest storing preferences with optimistic concurrency.");
+  let session = login(&client, "test-user", &testing_password).await;
+  let preference =
+    |method: &str, path: &str, headers: Vec<(&str, &str)>, body: Option<serde_json::Value>| {
+      let mut request = Request::builder()
+        .method(method)
+        .uri(format!(
+          "http://127.0.0.1:{}/api/user/preferences{}",
+          TEST_SERVER_PORT, path
+        ))
+        .header("Authorization", format!("bearer {}", session.key));
+      for (name, value) in headers {
+        request = request.header(name, value);
+      }
+      let request = match body {
+        Some(body) => request
+          .header("Content-Type", "application/json; charset=utf-8")
+          .body(body.to_string().into()),
+        None => request.body(Body::empty()),
+      }
+      .unwrap();
+      client.request(request)
+    };
+  let etag = |response: &Response<Body>| {
+    response
+      .headers()
+      .get("ETag")
+      .unwrap()
+      .to_str()
+      .unwrap()
+      .to_string()
+  };
+  let value = serde_json::json!({ "theme": "dark", "columns": [1, 2] });
+  let response = preference(
+    "PUT",
+    "/test-app/layout",
+    vec![("If-None-Match", "*")],
+    Some(value.clone()),
+  )
+  .await
+  .unwrap();
+  println!("Response to creating preference: {:?}", response);
+  assert_eq!(StatusCode::CREATED, response.status());
+  let first_etag = etag(&response);
+  // Creating again fails, since it now exists
+  let response = preference(
+    "PUT",
+    "/test-app/layout",
+    vec![("If-None-Match", "*")],
+    Some(value.clone()),
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::PRECONDITION_FAILED, response.status());
+  let mut response = preference("GET", "/test-app/layout", vec![], None)
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  assert_eq!(first_etag, etag(&response));
+  let stored: serde_json::Value = from_json(&mut response).await;
+  assert_eq!(value, stored);
+  let response = preference(
+    "GET",
+    "/test-app/layout",
+    vec![("If-None-Match", &first_etag)],
+    None,
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+  // Updates only apply if based on the current value
+  let response = preference(
+    "PUT",
+    "/test-app/layout",
+    vec![("If-Match", &first_etag)],
+    Some(serde_json::json!("light")),
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  let second_etag = etag(&response);
+  assert_ne!(first_etag, second_etag);
+  let mut response = preference(
+    "PUT",
+    "/test-app/layout",
+    vec![("If-Match", &first_etag)],
+    Some(value.clone()),
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::PRECONDITION_FAILED, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(
+    error,
+    shared_types::ClientError::PreconditionFailed
+  ));
+  let response = preference(
+    "PUT",
+    "/test-app/zoom",
+    vec![],
+    Some(serde_json::json!(1.5)),
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::CREATED, response.status());
+  let mut response = preference("GET", "/test-app", vec![], None).await.unwrap();
+  let namespace: HashMap<String, serde_json::Value> = from_json(&mut response).await;
+  assert_eq!(Some(&serde_json::json!("light")), namespace.get("layout"));
+  assert_eq!(Some(&serde_json::json!(1.5)), namespace.get("zoom"));
+  // Names are limited, as is the total size
+  let mut response = preference(
+    "PUT",
+    "/test-app/bad%20key",
+    vec![],
+    Some(serde_json::json!(1)),
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::BAD_REQUEST, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(
+    error,
+    shared_types::ClientError::InvalidPreferenceName
+  ));
+  let large = serde_json::json!("x".repeat(1500));
+  for i in 0..2 {
+    let response = preference(
+      "PUT",
+      &format!("/test-app/large-{}", i),
+      vec![],
+      Some(large.clone()),
+    )
+    .await
+    .unwrap();
+    assert_eq!(StatusCode::CREATED, response.status());
+  }
+  let mut response = preference("PUT", "/test-app/large-2", vec![], Some(large.clone()))
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::BAD_REQUEST, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(error, shared_types::ClientError::PreferencesFull));
+  // Admins can read, but not write
+  let admin_preference = |method: &str, path: &str| {
+    let request = Request::builder()
+      .method(method)
+      .uri(format!(
+        "http://127.0.0.1:{}/api/admin/users/-2/preferences{}",
+        TEST_SERVER_PORT, path
+      ))
+      .header("Authorization", format!("bearer {}", admin_session.key))
+      .body(Body::empty())
+      .unwrap();
+    client.request(request)
+  };
+  let mut response = admin_preference("GET", "/test-app/layout").await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  assert_eq!(second_etag, etag(&response));
+  let stored: serde_json::Value = from_json(&mut response).await;
+  assert_eq!(serde_json::json!("light"), stored);
+  let mut response = admin_preference("GET", "").await.unwrap();
+  let all: HashMap<String, HashMap<String, serde_json::Value>> = from_json(&mut response).await;
+  assert_eq!(4, all["test-app"].len());
+  let response = admin_preference("DELETE", "/test-app/layout")
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::METHOD_NOT_ALLOWED, response.status());
+  // They are part of the user's data export
+  let request = Request::get(format!(
+    "http://127.0.0.1:{}/api/user/export",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", session.key))
+  .body(Body::empty())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  let export: shared_types::UserDataExport = from_json(&mut response).await;
+  assert_eq!(4, export.preferences["test-app"].len());
+  // Deleting, also conditionally
+  let response = preference(
+    "DELETE",
+    "/test-app/layout",
+    vec![("If-Match", &first_etag)],
+    None,
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::PRECONDITION_FAILED, response.status());
+  let response = preference(
+    "DELETE",
+    "/test-app/layout",
+    vec![("If-Match", &second_etag)],
+    None,
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  let response = preference("GET", "/test-app/layout", vec![], None)
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::NOT_FOUND, response.status());
+  sqlx::query!("DELETE FROM preferences WHERE userid = -2")
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+
+  println!("\nTest uploading, serving and removing avatars.");
+  let session = login(&client, "test-user", &testing_password).await;
+  let upload_avatar = |content_type: &str, data: &[u8]| {
+    let mut body = b"--avatar-boundary\r\n\
+Content-Disposition: form-data; name=\"avatar\"; filename=\"me\"\r\n"
+      .to_vec();
+    body.extend_from_slice(format!("Content-Type: {}\r\n\r\n", content_type).as_bytes());
+    body.extend_from_slice(data);
+    body.extend_from_slice(b"\r\n--avatar-boundary--\r\n");
+    let request = Request::put(format!(
+      "http://127.0.0.1:{}/api/user/avatar",
+      TEST_SERVER_PORT
+    ))
+    .header("Authorization", format!("bearer {}", session.key))
+    .header(
+      "Content-Type",
+      "multipart/form-data; boundary=avatar-boundary",
+    )
+    .body(body.into())
+    .unwrap();
+    client.request(request)
+  };
+  let get_avatar = |version: &str, size: u32, etag: Option<&str>| {
+    let mut request = Request::get(format!(
+      "http://127.0.0.1:{}/avatars/-2/{}/{}",
+      TEST_SERVER_PORT, version, size
+    ));
+    if let Some(etag) = etag {
+      request = request.header("If-None-Match", etag);
+    }
+    client.request(request.body(Body::empty()).unwrap())
+  };
+  // A wide JPEG, which is cropped to a square
+  let mut jpeg = Vec::new();
+  image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+    300,
+    200,
+    image::Rgb([200, 30, 30]),
+  ))
+  .write_to(
+    &mut std::io::Cursor::new(&mut jpeg),
+    image::ImageFormat::Jpeg,
+  )
+  .unwrap();
+  let mut response = upload_avatar("image/jpeg", &jpeg).await.unwrap();
+  println!("Response to uploading avatar: {:?}", response);
+  assert_eq!(StatusCode::OK, response.status());
+  let user: shared_types::ReturnableUser = from_json(&mut response).await;
+  let version = user.avatar.unwrap();
+  let response = get_avatar(&version, 64, None).await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  assert_eq!("image/png", response.headers()["Content-Type"]);
+  let etag = response.headers()["ETag"].to_str().unwrap().to_string();
+  assert_eq!(format!("\"{}\"", version), etag);
+  let png = hyper::body::to_bytes(response.into_body()).await.unwrap();
+  let resized = image::load_from_memory_with_format(&png, image::ImageFormat::Png).unwrap();
+  assert_eq!((64, 64), (resized.width(), resized.height()));
+  let response = get_avatar(&version, 256, Some(&etag)).await.unwrap();
+  assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+  let response = get_avatar(&version, 100, None).await.unwrap();
+  assert_eq!(StatusCode::NOT_FOUND, response.status());
+  // Avatars can't be fetched without knowing the version
+  let response = get_avatar("guessed", 64, None).await.unwrap();
+  assert_eq!(StatusCode::NOT_FOUND, response.status());
+  // Replacing it removes the old files
+  let mut png = Vec::new();
+  image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+    40,
+    40,
+    image::Rgba([0, 0, 255, 128]),
+  ))
+  .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+  .unwrap();
+  let mut response = upload_avatar("image/png", &png).await.unwrap();
+  let user: shared_types::ReturnableUser = from_json(&mut response).await;
+  assert_ne!(Some(&version), user.avatar.as_ref());
+  assert!(!crate::avatars::path(state, -2, &version, 64).exists());
+  let response = get_avatar(&version, 256, None).await.unwrap();
+  assert_eq!(StatusCode::NOT_FOUND, response.status());
+  let version = user.avatar.unwrap();
+  let response = get_avatar(&version, 256, Some(&etag)).await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  // Only images are accepted, within MAX_AVATAR_LEN
+  let mut response = upload_avatar("image/png", b"not an image").await.unwrap();
+  assert_eq!(StatusCode::BAD_REQUEST, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(error, shared_types::ClientError::InvalidImage(_)));
+  let response = upload_avatar("image/png", &vec![0; 70000]).await.unwrap();
+  assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+  // Also when streamed without Content-Length
+  let (mut sender, body) = Body::channel();
+  let request = Request::put(format!(
+    "http://127.0.0.1:{}/api/user/avatar",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", session.key))
+  .header(
+    "Content-Type",
+    "multipart/form-data; boundary=avatar-boundary",
+  )
+  .body(body)
+  .unwrap();
+  tokio::spawn(async move {
+    let mut data = b"--avatar-boundary\r\n\
+Content-Disposition: form-data; name=\"avatar\"; filename=\"me\"\r\n\
+Content-Type: image/png\r\n\r\n"
+      .to_vec();
+    data.extend_from_slice(&[0; 70000]);
+    sender.send_data(data.into()).await.ok();
+  });
+  let mut response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(
+    error,
+    shared_types::ClientError::ContentTooLarge(_)
+  ));
+  let request = Request::put(format!(
+    "http://127.0.0.1:{}/api/user/avatar",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body(Body::from("{}"))
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(
+    error,
+    shared_types::ClientError::InvalidContentType(_)
+  ));
+  // Admins can take them down
+  let request = Request::delete(format!(
+    "http://127.0.0.1:{}/api/admin/users/-2/avatar",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body(Body::empty())
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  let response = get_avatar(&version, 64, None).await.unwrap();
+  assert_eq!(StatusCode::NOT_FOUND, response.status());
+  assert_eq!(0, std::fs::read_dir(&avatar_dir).unwrap().count());
+  let request = Request::delete(format!(
+    "http://127.0.0.1:{}/api/user/avatar",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", session.key))
+  .body(Body::empty())
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::NOT_FOUND, response.status());
+  std::fs::remove_dir(&avatar_dir).unwrap();
+
+  println!("\nTest that usernames are unique and looked up normalized.");
+  let create_user = |username: &str| {
+    let request = Request::post(format!(
+      "http://127.0.0.1:{}/api/admin/users",
+      TEST_SERVER_PORT
+    ))
+    .header("Authorization", format!("bearer {}", admin_session.key))
+    .header("Content-Type", "application/json; charset=utf-8")
+    .body(
+      serde_json::json!({ "username": username })
+        .to_string()
+        .into(),
+    )
+    .unwrap();
+    client.request(request)
+  };
+  let mut response = create_user("Test-Normalized").await.unwrap();
+  println!("Response to creating user: {:?}", response);
+  assert_eq!(StatusCode::CREATED, response.status());
+  let created: shared_types::AdminReturnableUser = from_json(&mut response).await;
+  // Differing in case or by compatibility forms is the same username
+  for taken in ["test-normalized", "ＴＥＳＴ-ｎｏｒｍａｌｉｚｅｄ"] {
+    let mut response = create_user(taken).await.unwrap();
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    let error: shared_types::ClientError = from_json(&mut response).await;
+    assert!(matches!(error, shared_types::ClientError::UsernameTaken));
+  }
+  // Including by full case folding, not just lowercasing
+  let mut response = create_user("test-straße").await.unwrap();
+  assert_eq!(StatusCode::CREATED, response.status());
+  let folded: shared_types::AdminReturnableUser = from_json(&mut response).await;
+  let mut response = create_user("TEST-STRASSE").await.unwrap();
+  assert_eq!(StatusCode::BAD_REQUEST, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(error, shared_types::ClientError::UsernameTaken));
+  sqlx::query!("DELETE FROM users WHERE id = $1", folded.id)
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+  // And new usernames follow the configured rules
+  for invalid in ["x", "test normalized", "test/normalized"] {
+    let mut response = create_user(invalid).await.unwrap();
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    let error: shared_types::ClientError = from_json(&mut response).await;
+    assert!(matches!(error, shared_types::ClientError::InvalidUsername));
+  }
+  sqlx::query!(
+    "UPDATE users SET pass = $2 WHERE id = $1",
+    created.id,
+    testing_hash,
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  login(&client, "TEST-NORMALIZED", &testing_password).await;
+  let request = Request::get(format!(
+    "http://127.0.0.1:{}/api/admin/users?username_regex=^TEST-normalized$",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body(Body::empty())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  let found: Vec<shared_types::AdminReturnableUser> = from_json(&mut response).await;
+  assert_eq!(
+    vec![created.id],
+    found.iter().map(|user| user.id).collect::<Vec<_>>()
+  );
+  // Usernames from before the rules may be kept, but not taken anew
+  sqlx::query!(
+    "UPDATE users SET username = 'test legacy' WHERE id = $1",
+    created.id
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  let update_user = |username: &str| {
+    let request = Request::put(format!(
+      "http://127.0.0.1:{}/api/admin/users/{}",
+      TEST_SERVER_PORT, created.id
+    ))
+    .header("Authorization", format!("bearer {}", admin_session.key))
+    .header("Content-Type", "application/json; charset=utf-8")
+    .body(
+      serde_json::json!({ "username": username, "admin": false, "locked": true })
+        .to_string()
+        .into(),
+    )
+    .unwrap();
+    client.request(request)
+  };
+  let response = update_user("test legacy").await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  let mut response = update_user("test legacy renamed").await.unwrap();
+  assert_eq!(StatusCode::BAD_REQUEST, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(error, shared_types::ClientError::InvalidUsername));
+  let mut response = update_user("TEST-ADMIN").await.unwrap();
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(error, shared_types::ClientError::UsernameTaken));
+  sqlx::query!("DELETE FROM sessions WHERE userid = $1", created.id)
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+  sqlx::query!("DELETE FROM users WHERE id = $1", created.id)
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+
+  println!("\nTest organizations and their org admins.");
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/organizations",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body("{ \"name\": \"test-org\" }".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  println!("Response to creating organization: {:?}", response);
+  assert_eq!(StatusCode::CREATED, response.status());
+  let org: shared_types::Organization = from_json(&mut response).await;
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/organizations",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body("{ \"name\": \"test-org\" }".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(
+    error,
+    shared_types::ClientError::OrganizationNameTaken
+  ));
+  let mut response = create_user("test-org-member").await.unwrap();
+  let member: shared_types::AdminReturnableUser = from_json(&mut response).await;
+  sqlx::query!(
+    "UPDATE users SET pass = $2 WHERE id = $1",
+    member.id,
+    testing_hash,
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  // The testing user administrates the organization
+  for (userid, org_admin) in [(-2, true), (member.id, false)] {
+    let request = Request::put(format!(
+      "http://127.0.0.1:{}/api/admin/organizations/{}/members/{}",
+      TEST_SERVER_PORT, org.id, userid
+    ))
+    .header("Authorization", format!("bearer {}", admin_session.key))
+    .header("Content-Type", "application/json; charset=utf-8")
+    .body(
+      serde_json::json!({ "org_admin": org_admin })
+        .to_string()
+        .into(),
+    )
+    .unwrap();
+    let response = client.request(request).await.unwrap();
+    assert_eq!(StatusCode::OK, response.status());
+  }
+  // Sessions of users in one organization are scoped to it
+  let org_admin_session = login(&client, "test-user", &testing_password).await;
+  assert_eq!(Some(org.id), org_admin_session.orgid);
+  let member_session = login(&client, "test-org-member", &testing_password).await;
+  assert_eq!(Some(org.id), member_session.orgid);
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/elevate",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", org_admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body(format!("{{ \"password\":\"{}\" }}", testing_password).into())
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  println!("Response to org admin elevation: {:?}", response);
+  assert_eq!(StatusCode::OK, response.status());
+  let org_admin_request = |method: hyper::Method, path: &str, body: Option<serde_json::Value>| {
+    let request = Request::builder()
+      .method(method)
+      .uri(format!(
+        "http://127.0.0.1:{}/api/{}",
+        TEST_SERVER_PORT, path
+      ))
+      .header("Authorization", format!("bearer {}", org_admin_session.key))
+      .header("Content-Type", "application/json; charset=utf-8")
+      .body(body.map_or_else(Body::empty, |body| body.to_string().into()))
+      .unwrap();
+    client.request(request)
+  };
+  // Only the organization's members are seen, and no admins or org admins
+  let mut response = org_admin_request(hyper::Method::GET, "admin/users?order_by=id_asc", None)
+    .await
+    .unwrap();
+  let users: Vec<shared_types::AdminReturnableUser> = from_json(&mut response).await;
+  assert_eq!(
+    vec![member.id],
+    users.iter().map(|user| user.id).collect::<Vec<_>>()
+  );
+  let response = org_admin_request(hyper::Method::GET, "admin/users/-1", None)
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::NOT_FOUND, response.status());
+  let response = org_admin_request(
+    hyper::Method::PUT,
+    &format!("admin/users/{}", member.id),
+    Some(serde_json::json!({
+      "username": "test-org-member", "admin": true, "locked": false
+    })),
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::FORBIDDEN, response.status());
+  let response = org_admin_request(
+    hyper::Method::POST,
+    "admin/users",
+    Some(serde_json::json!({ "username": "test-org-admin", "admin": true })),
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::FORBIDDEN, response.status());
+  // Users created by org admins join the organization
+  let mut response = org_admin_request(
+    hyper::Method::POST,
+    "admin/users",
+    Some(serde_json::json!({ "username": "test-org-created" })),
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::CREATED, response.status());
+  let created: shared_types::AdminReturnableUser = from_json(&mut response).await;
+  let mut response = org_admin_request(
+    hyper::Method::GET,
+    &format!("admin/organizations/{}/members", org.id),
+    None,
+  )
+  .await
+  .unwrap();
+  let members: Vec<shared_types::OrganizationMember> = from_json(&mut response).await;
+  assert_eq!(
+    vec![-2, member.id, created.id],
+    members
+      .iter()
+      .map(|member| member.userid)
+      .collect::<Vec<_>>()
+  );
+  // Configuration for all organizations is for admins only
+  for path in ["admin/oidc_clients", "admin/api_tokens"] {
+    let response = org_admin_request(hyper::Method::GET, path, None)
+      .await
+      .unwrap();
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+  }
+  let response = org_admin_request(
+    hyper::Method::POST,
+    "admin/organizations",
+    Some(serde_json::json!({ "name": "test-org-other" })),
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::FORBIDDEN, response.status());
+  let mut response = org_admin_request(hyper::Method::GET, "admin/sessions", None)
+    .await
+    .unwrap();
+  let sessions: Vec<shared_types::AdminReturnableSession> = from_json(&mut response).await;
+  assert!(sessions.iter().any(|s| s.id == member_session.id));
+  assert!(sessions
+    .iter()
+    .all(|s| s.userid == -2 || s.userid == member.id));
+  let response = org_admin_request(
+    hyper::Method::DELETE,
+    &format!("admin/sessions/{}", admin_session.id),
+    None,
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::NOT_FOUND, response.status());
+  // Users also in another organization are only removed from this one
+  let request = Request::post(format!(
+    "http://127.0.0.1:{}/api/admin/organizations",
+    TEST_SERVER_PORT
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body("{ \"name\": \"test-org-second\" }".into())
+  .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  let second_org: shared_types::Organization = from_json(&mut response).await;
+  let request = Request::put(format!(
+    "http://127.0.0.1:{}/api/admin/organizations/{}/members/{}",
+    TEST_SERVER_PORT, second_org.id, created.id
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .header("Content-Type", "application/json; charset=utf-8")
+  .body("{ \"org_admin\": false }".into())
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  sqlx::query!(
+    "UPDATE users SET pass = $2 WHERE id = $1",
+    created.id,
+    testing_hash,
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  let two_org_session = login(&client, "test-org-created", &testing_password).await;
+  assert_eq!(None, two_org_session.orgid);
+  for (method, path) in [
+    (hyper::Method::GET, format!("admin/users/{}", created.id)),
+    (hyper::Method::DELETE, format!("admin/users/{}", created.id)),
+    (
+      hyper::Method::DELETE,
+      format!("admin/sessions/{}", two_org_session.id),
+    ),
+    // Other org admins stay
+    (
+      hyper::Method::DELETE,
+      format!("admin/organizations/{}/members/-2", org.id),
+    ),
+  ] {
+    let response = org_admin_request(method, &path, None).await.unwrap();
+    assert_eq!(StatusCode::NOT_FOUND, response.status());
+  }
+  let mut response = org_admin_request(hyper::Method::GET, "admin/users", None)
+    .await
+    .unwrap();
+  let users: Vec<shared_types::AdminReturnableUser> = from_json(&mut response).await;
+  assert!(users.iter().all(|user| user.id != created.id));
+  let mut response = org_admin_request(hyper::Method::GET, "admin/sessions", None)
+    .await
+    .unwrap();
+  let sessions: Vec<shared_types::AdminReturnableSession> = from_json(&mut response).await;
+  assert!(sessions.iter().all(|s| s.id != two_org_session.id));
+  let response = org_admin_request(
+    hyper::Method::DELETE,
+    &format!("admin/organizations/{}/members/{}", org.id, created.id),
+    None,
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  let request = Request::get(format!("http://127.0.0.1:{}/api/user", TEST_SERVER_PORT))
+    .header("Authorization", format!("bearer {}", two_org_session.key))
+    .body(Body::empty())
+    .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  let request = Request::delete(format!(
+    "http://127.0.0.1:{}/api/admin/organizations/{}",
+    TEST_SERVER_PORT, second_org.id
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body(Body::empty())
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  // Leaving the organization ends the sessions scoped to it
+  let request = Request::delete(format!(
+    "http://127.0.0.1:{}/api/admin/organizations/{}/members/{}",
+    TEST_SERVER_PORT, org.id, member.id
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body(Body::empty())
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  let request = Request::get(format!("http://127.0.0.1:{}/api/user", TEST_SERVER_PORT))
+    .header("Authorization", format!("bearer {}", member_session.key))
+    .body(Body::empty())
+    .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+  // Users choose among their own organizations only
+  let mut response = org_admin_request(hyper::Method::GET, "user/organizations", None)
+    .await
+    .unwrap();
+  let organizations: Vec<shared_types::UserOrganization> = from_json(&mut response).await;
+  assert_eq!(1, organizations.len());
+  assert!(organizations[0].org_admin);
+  let response = org_admin_request(
+    hyper::Method::PUT,
+    "user/organization",
+    Some(serde_json::json!({ "orgid": org.id + 1 })),
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::FORBIDDEN, response.status());
+  let response = org_admin_request(
+    hyper::Method::PUT,
+    "user/organization",
+    Some(serde_json::json!({ "orgid": null })),
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  let response = org_admin_request(hyper::Method::GET, "admin/users", None)
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::FORBIDDEN, response.status());
+  let request = Request::delete(format!(
+    "http://127.0.0.1:{}/api/admin/organizations/{}",
+    TEST_SERVER_PORT, org.id
+  ))
+  .header("Authorization", format!("bearer {}", admin_session.key))
+  .body(Body::empty())
+  .unwrap();
+  let response = client.request(request).await.unwrap();
+  assert_eq!(StatusCode::NO_CONTENT, response.status());
+  sqlx::query!(
+    "DELETE FROM sessions WHERE userid = $1 OR userid = $2",
+    member.id,
+    created.id,
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  sqlx::query!(
+    "DELETE FROM users WHERE id = $1 OR id = $2",
+    member.id,
+    created.id,
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+
+  println!("\nTest publishing and accepting terms of service.");
+  let terms_request =
+    |method: hyper::Method, path: &str, key: &str, body: Option<serde_json::Value>| {
+      let request = Request::builder()
+        .method(method)
+        .uri(format!(
+          "http://127.0.0.1:{}/api/{}",
+          TEST_SERVER_PORT, path
+        ))
+        .header("Authorization", format!("bearer {}", key))
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(body.map_or_else(Body::empty, |body| body.to_string().into()))
+        .unwrap();
+      client.request(request)
+    };
+  let mut response = terms_request(
+    hyper::Method::POST,
+    "admin/terms",
+    &admin_session.key,
+    Some(serde_json::json!({ "text": "Test terms" })),
+  )
+  .await
+  .unwrap();
+  println!("Response to publishing terms: {:?}", response);
+  assert_eq!(StatusCode::CREATED, response.status());
+  let terms: shared_types::Terms = from_json(&mut response).await;
+  let request = Request::get(format!("http://127.0.0.1:{}/api/terms", TEST_SERVER_PORT))
+    .body(Body::empty())
+    .unwrap();
+  let mut response = client.request(request).await.unwrap();
+  let latest: shared_types::Terms = from_json(&mut response).await;
+  assert_eq!(terms.version, latest.version);
+  // Sessions already logged in are restricted as well
+  let mut response = terms_request(hyper::Method::GET, "admin/users", &admin_session.key, None)
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::FORBIDDEN, response.status());
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(
+    error,
+    shared_types::ClientError::TermsAcceptanceRequired
+  ));
+  let session = login(&client, "test-user", &testing_password).await;
+  assert!(session.must_accept_terms);
+  let response = terms_request(hyper::Method::GET, "user", &session.key, None)
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::FORBIDDEN, response.status());
+  let mut response = terms_request(
+    hyper::Method::POST,
+    "user/terms",
+    &session.key,
+    Some(serde_json::json!({ "version": terms.version - 1 })),
+  )
+  .await
+  .unwrap();
+  let error: shared_types::ClientError = from_json(&mut response).await;
+  assert!(matches!(error, shared_types::ClientError::OutdatedTerms));
+  for key in [&session.key, &admin_session.key] {
+    let response = terms_request(
+      hyper::Method::POST,
+      "user/terms",
+      key,
+      Some(serde_json::json!({ "version": terms.version })),
+    )
+    .await
+    .unwrap();
+    assert_eq!(StatusCode::NO_CONTENT, response.status());
+  }
+  let response = terms_request(hyper::Method::GET, "user", &session.key, None)
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  // Acceptances are recorded for admins to look up
+  let mut response = terms_request(
+    hyper::Method::GET,
+    &format!("admin/terms/acceptances?version_eq={}", terms.version),
+    &admin_session.key,
+    None,
+  )
+  .await
+  .unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  let acceptances: Vec<shared_types::TermsAcceptance> = from_json(&mut response).await;
+  assert_eq!(
+    vec![-2, -1],
+    acceptances.iter().map(|a| a.userid).collect::<Vec<_>>()
+  );
+  // And a new version has to be accepted again, also by access tokens
+  let mut response = terms_request(hyper::Method::POST, "access_token", &session.key, None)
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::CREATED, response.status());
+  let token: shared_types::AccessToken = from_json(&mut response).await;
+  let response = terms_request(hyper::Method::GET, "user", &token.access_token, None)
+    .await
+    .unwrap();
+  assert_eq!(StatusCode::OK, response.status());
+  let mut response = terms_request(
+    hyper::Method::POST,
+    "admin/terms",
+    &admin_session.key,
+    Some(serde_json::json!({ "text": "Test terms, revised" })),
+  )
+  .await
+  .unwrap();
+  let revised: shared_types::Terms = from_json(&mut response).await;
+  for key in [&session.key, &token.access_token] {
+    let response = terms_request(hyper::Method::GET, "user", key, None)
+      .await
+      .unwrap();
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+  }
+  let session = login(&client, "test-user", &testing_password).await;
+  assert!(session.must_accept_terms);
+  sqlx::query!(
+    "DELETE FROM terms_acceptances WHERE version = $1 OR version = $2",
+    terms.version,
+    revised.version,
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+  sqlx::query!(
+    "DELETE FROM terms WHERE version = $1 OR version = $2",
+    terms.version,
+    revised.version,
+  )
+  .execute(&state.db_pool)
+  .await
+  .unwrap();
+
+  // Cleanup database after testing
+  sqlx::query!("DELETE FROM sessions WHERE userid = -1 OR userid = -2")
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+  sqlx::query!("UPDATE users SET pass = NULL, email = NULL WHERE id = -1 OR id = -2")
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+}
+
+// Requires a local slapd, configured by LDAP_URL, LDAP_USER_DN and
+// LDAP_ADMIN_GROUP as in example.env, with the user LDAP_TEST_USERNAME
+// (password LDAP_TEST_PASSWORD) in the admin group.
+// Run with `cargo test -- --ignored`
+#[tokio::test]
+#[ignore]
+async fn ldap_tests() {
+  std::env::set_var("CREDENTIAL_BACKEND", "ldap");
+  let state = init_state().await;
+  let addr = SocketAddr::from(([127, 0, 0, 1], TEST_LDAP_SERVER_PORT));
+  let _server = tokio::task::spawn(async move {
+    run_server(state, addr).await;
+  });
+  let client = Client::builder()
+    .http1_preserve_header_case(true)
+    .build_http::<Body>();
+  let username = std::env::var("LDAP_TEST_USERNAME").expect("LDAP_TEST_USERNAME must be set.");
+  let password = std::env::var("LDAP_TEST_PASSWORD").expect("LDAP_TEST_PASSWORD must be set.");
+  let try_login = |password: String| {
+    let request = Request::post(format!(
+      "http://127.0.0.1:{}/api/login",
+      TEST_LDAP_SERVER_PORT
+    ))
+    .header("Content-Type", "application/json; charset=utf-8")
+    .body(
+      serde_json::to_string(&shared_types::Login {
+        username: username.clone(),
+        password,
+        extended: false,
+        session_cookie: false,
+      })
+      .unwrap()
+      .into(),
+    )
+    .unwrap();
+    client.request(request)
+  };
+
+  println!("\nTest LDAP login with wrong and empty password.");
+  let response = try_login(format!("{}-wrong", password)).await.unwrap();
+  println!("Response to login with wrong password: {:?}", response);
+  assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+  let response = try_login(String::new()).await.unwrap();
+  println!("Response to login with empty password: {:?}", response);
+  assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+  println!("\nTest LDAP login, creating and then reusing the shadow user.");
+  for _ in 0..2 {
+    let mut response = try_login(password.clone()).await.unwrap();
+    println!("Response to login: {:?}", response);
+    assert_eq!(StatusCode::CREATED, response.status());
+    let session: shared_types::Session = from_json(&mut response).await;
+    assert_eq!(username, session.username);
+    // Synced from the admin group
+    assert!(session.is_admin);
+    // Confirmations also check the password against the directory
+    let request = Request::post(format!(
+      "http://127.0.0.1:{}/api/admin/elevate",
+      TEST_LDAP_SERVER_PORT
+    ))
+    .header("Authorization", format!("bearer {}", session.key))
+    .header("Content-Type", "application/json; charset=utf-8")
+    .body(
+      serde_json::json!({ "password": password })
+        .to_string()
+        .into(),
+    )
+    .unwrap();
+    let response = client.request(request).await.unwrap();
+    println!("Response to elevation: {:?}", response);
+    assert_eq!(StatusCode::OK, response.status());
+  }
+  let shadow = sqlx::query!("SELECT id, pass FROM users WHERE username = $1", username)
+    .fetch_all(&state.db_pool)
+    .await
+    .unwrap();
+  assert_eq!(1, shadow.len());
+  // The password is only in the directory
+  assert!(shadow[0].pass.is_none());
+
+  // Cleanup database after testing
+  sqlx::query!("DELETE FROM users WHERE id = $1", shadow[0].id)
+    .execute(&state.db_pool)
+    .await
+    .unwrap();
+}
+
+// Compares database round-trips for validating a session with and without
+// the session cache, printing the results.
+// Run with `cargo test session_cache_bench -- --ignored --nocapture`
+#[tokio::test]
+#[ignore]
+async fn session_cache_bench() {
+  const REQUESTS: u64 = 1000;
+  std::env::set_var("SESSION_CACHE_SIZE", "1000");
+  let state = init_state().await;
+  let cache = state.session_cache.as_ref().unwrap();
+  let key = nanoid::nanoid!(32);
+  let session = sqlx::query!(
+    "INSERT INTO sessions(userid, key, until) VALUES(0, $1, $2) RETURNING id",
+    key,
+    chrono::offset::Utc::now().naive_utc() + chrono::Duration::hours(1),
+  )
+  .fetch_one(&state.db_pool)
+  .await
+  .unwrap();
+
+  let mut round_trips = Vec::new();
+  for cached in [false, true] {
+    cache.clear();
+    let (_, misses) = cache.stats();
+    let start = std::time::Instant::now();
+    for _ in 0..REQUESTS {
+      if !cached {
+        cache.clear();
+      }
+      crate::auth::session(state, Some(key.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    }
+    let elapsed = start.elapsed();
+    let misses = cache.stats().1 - misses;
+    println!(
+      "{}: {} requests, {} database round-trips, {:?} per request",
+      if cached { "Cached" } else { "Uncached" },
+      REQUESTS,
+      misses,
+      elapsed / REQUESTS as u32,
+    );
+    round_trips.push(misses);
+  }
+  assert_eq!(REQUESTS, round_trips[0]);
+  assert!(round_trips[1] < REQUESTS / 10);
+
+  sqlx::query!("DELETE FROM sessions WHERE id = $1", session.id)
     .execute(&state.db_pool)
     .await
     .unwrap();
@@ -0,0 +1,61 @@
+//! Rules for the usernames users can be given
+//!
+//! Usernames are compared by the database in a normalized form, see
+//! normalize_username in the migrations, so that users can't take usernames
+//! that only differ in case (by full case folding, so 'straße' is 'STRASSE')
+//! or Unicode compatibility forms. This needs PostgreSQL built with ICU. Users
+//! created from an identity provider or LDAP follow the same rules.
+
+use crate::Error;
+
+// What characters, apart from the allowed punctuation, usernames may have
+pub enum UsernameCharacters {
+  // ASCII letters and digits, which rules out lookalikes in other scripts
+  Ascii,
+  // Letters and digits in any script
+  Unicode,
+}
+
+pub struct UsernameRules {
+  // Lengths are in characters
+  pub min_len: usize,
+  pub max_len: usize,
+  pub characters: UsernameCharacters,
+  pub punctuation: String,
+}
+impl UsernameRules {
+  pub fn verify(&self, username: &str) -> Result<(), Error> {
+    let len = username.chars().count();
+    let valid = len >= self.min_len
+      && len <= self.max_len
+      && username.chars().all(|c| {
+        self.punctuation.contains(c)
+          || match self.characters {
+            UsernameCharacters::Ascii => c.is_ascii_alphanumeric(),
+            UsernameCharacters::Unicode => c.is_alphanumeric(),
+          }
+      });
+    if valid {
+      Ok(())
+    } else {
+      Err(Error::invalid_username())
+    }
+  }
+
+  // As verify, but the user may keep a username from before the rules
+  pub async fn verify_rename(
+    &self,
+    db: impl sqlx::PgExecutor<'_>,
+    userid: i32,
+    username: &str,
+  ) -> Result<(), Error> {
+    let current = sqlx::query!("SELECT username FROM users WHERE id = $1", userid)
+      .fetch_optional(db)
+      .await?;
+    if current.is_some_and(|current| current.username == username) {
+      Ok(())
+    } else {
+      self.verify(username)
+    }
+  }
+}
@@ -13,6 +13,8 @@ mod login;
 use login::*;
 mod routes;
 use routes::*;
+// For apps built on this to keep their settings in
+pub mod preferences;
 
 // Define and init application state
 struct Model {
@@ -27,6 +29,16 @@ struct Model {
 impl Model {
   fn init(url: Url, orders: &mut impl Orders<Msg>) -> Model {
     orders.subscribe(Msg::UrlChanged);
+    // Coming back from logging in through the external provider
+    // or opening an emailed login link
+    if let [page, token] = url.hash_path() {
+      if page == "external_login" {
+        orders.send_msg(Msg::Login(LoginMsg::RedeemTicket(token.clone())));
+      }
+      if page == "magic_link" {
+        orders.send_msg(Msg::Login(LoginMsg::RedeemLink(token.clone())));
+      }
+    }
     let session = match LocalStorage::get("session") {
       Ok(s) => {
         let s: shared_types::Session = s; // Needed to declare expected type...
@@ -36,31 +48,10 @@ impl Model {
         } else {
           // Request updated username and is_admin to handle user changes
           // This also ensures the session hasn't been deleted
-          let req = Request::new("/api/user")
-            .method(Method::Get)
-            .header(Header::bearer(s.key.clone()))
-          ;
-          orders.perform_cmd(async {
-            let res: Result<Option<Msg>, FetchError> = async {
-              let resp = req.fetch().await?;
-              match resp.status().code {
-                200 => Ok(Some(Msg::UserdataUpdate(resp.json().await?))),
-                401 => Ok(Some(Msg::ClearAuth("Session deleted remotely"))),
-                _ => {
-                  let err: shared_types::ClientError = resp.json().await?;
-                  log!("Error updating userdata", err);
-                  Ok(None)
-                }
-              }
-            }.await;
-            match res {
-              Ok(x) => x,
-              Err(e) => {
-                log!("Error occured in userdata update request", e);
-                None
-              }
-            }
-          });
+          request_userdata(&s, orders);
+          if s.must_accept_terms {
+            orders.send_msg(Msg::Routes(RoutesMsg::Terms(TermsMsg::Load)));
+          }
           Some(s)
         }
       }
@@ -81,6 +72,61 @@ impl Model {
   }
 }
 
+// Fetch the current user's data, which updates the session and profile
+fn request_userdata(session: &shared_types::Session, orders: &mut impl Orders<Msg>) {
+  let req = Request::new("/api/user")
+    .method(Method::Get)
+    .header(auth_header(session))
+  ;
+  orders.perform_cmd(async {
+    let res: Result<Option<Msg>, FetchError> = async {
+      let resp = req.fetch().await?;
+      match resp.status().code {
+        200 => Ok(Some(Msg::UserdataUpdate(resp.json().await?))),
+        401 => Ok(Some(Msg::ClearAuth("Session deleted remotely"))),
+        // Terms published since logging in
+        403 => match resp.json().await? {
+          shared_types::ClientError::TermsAcceptanceRequired => Ok(Some(Msg::TermsRequired)),
+          err => {
+            log!("Error updating userdata", err);
+            Ok(None)
+          }
+        },
+        _ => {
+          let err: shared_types::ClientError = resp.json().await?;
+          log!("Error updating userdata", err);
+          Ok(None)
+        }
+      }
+    }.await;
+    match res {
+      Ok(x) => x,
+      Err(e) => {
+        log!("Error occured in userdata update request", e);
+        None
+      }
+    }
+  });
+}
+
+// Authenticate a request with the session
+// Cookie sessions have no key, the cookie is sent by the browser and we only
+// need to echo the CSRF token from its readable cookie
+pub(crate) fn auth_header(session: &shared_types::Session) -> Header<'static> {
+  if session.key.is_empty() {
+    let cookies = html_document().cookie().unwrap_or_default();
+    let token = cookies
+      .split(';')
+      .filter_map(|cookie| cookie.trim().strip_prefix("csrf_token="))
+      .next()
+      .unwrap_or("")
+      .to_string();
+    Header::custom("X-CSRF-Token", token)
+  } else {
+    Header::bearer(session.key.clone())
+  }
+}
+
 // Translate callbacks into state changes
 enum Msg {
   // Truly global events
@@ -88,6 +134,9 @@ enum Msg {
   SetAuth(shared_types::Session),
   ClearAuth(&'static str), // Logout message
   UserdataUpdate(shared_types::ReturnableUser),
+  PasswordChanged, // Lifts password change requirement
+  TermsRequired,   // Terms must be accepted before continuing
+  TermsAccepted,   // Lifts that requirement
   // Login view events
   Login(LoginMsg),
   Logout,
@@ -115,6 +164,10 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
         Ok(()) => (),
         Err(e) => log!("Could not save session to storage", e),
       }
+      request_userdata(&new_session, orders);
+      if new_session.must_accept_terms {
+        orders.send_msg(Msg::Routes(RoutesMsg::Terms(TermsMsg::Load)));
+      }
       model.session = Some(new_session);
     }
     Msg::ClearAuth(message) => {
@@ -131,19 +184,54 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
     },
     Msg::UserdataUpdate(user) => match &mut model.session {
       Some(s) => {
-        s.username = user.username;
+        s.username = user.username.clone();
         s.is_admin = user.admin;
+        // The settings page shows the profile
+        orders.send_msg(Msg::Routes(RoutesMsg::Settings(SettingsMsg::ProfileLoaded(user))));
+      },
+      None => (),
+    },
+    Msg::PasswordChanged => match &mut model.session {
+      Some(s) => {
+        s.must_change_password = false;
+        match LocalStorage::insert("session", &s) {
+          Ok(()) => (),
+          Err(e) => log!("Could not save session to storage", e),
+        }
+      },
+      None => (),
+    },
+    Msg::TermsRequired => match &mut model.session {
+      Some(s) => {
+        s.must_accept_terms = true;
+        match LocalStorage::insert("session", &s) {
+          Ok(()) => (),
+          Err(e) => log!("Could not save session to storage", e),
+        }
+        orders.send_msg(Msg::Routes(RoutesMsg::Terms(TermsMsg::Load)));
+      },
+      None => (),
+    },
+    Msg::TermsAccepted => match &mut model.session {
+      Some(s) => {
+        s.must_accept_terms = false;
+        match LocalStorage::insert("session", &s) {
+          Ok(()) => (),
+          Err(e) => log!("Could not save session to storage", e),
+        }
+        // The user's data couldn't be loaded until now
+        request_userdata(s, orders);
       },
       None => (),
     },
     // Event forwarder for login events, and logout handler (here since related and small)
     Msg::Login(msg) => login_update(msg, &mut model.login, orders),
-    Msg::Logout => match model.session.as_ref().map(|s| s.key.clone()) {
-      Some(session_key) => {
+    Msg::Logout => match model.session.as_ref().map(auth_header) {
+      Some(auth) => {
         // Send the request to the backend to delete the session
         let req = Request::new("/api/logout")
           .method(Method::Post)
-          .header(Header::bearer(session_key))
+          .header(auth)
           .json(&());
         orders.perform_cmd(async {
           let res: Result<(), FetchError> = async {
@@ -4,7 +4,7 @@ use super::*;
 pub(crate) struct LoginModel {
   inner: shared_types::Login,
   pub logout_message: &'static str,
-  failure_message: &'static str,
+  failure_message: String,
 }
 impl LoginModel {
   pub(crate) fn new() -> Self {
@@ -13,9 +13,10 @@ impl LoginModel {
         username: String::new(),
         password: String::new(),
         extended: false,
+        session_cookie: cfg!(feature = "session_cookies"),
       },
       logout_message: "",
-      failure_message: "",
+      failure_message: String::new(),
     }
   }
 }
@@ -25,6 +26,10 @@ pub(crate) enum LoginMsg {
   UpdateUsername(String),
   UpdatePassword(String),
   Submit,
+  RedeemTicket(String), // After logging in through the external provider
+  RequestLink,
+  LinkRequested,
+  RedeemLink(String), // From an emailed login link
   LoginSuccess(shared_types::Session),
   LoginError(shared_types::ClientError),
 }
@@ -59,21 +64,126 @@ pub(crate) fn login_update(msg: LoginMsg, model: &mut LoginModel, orders: &mut i
       model.logout_message = "";
       orders.skip();
     }
+    LoginMsg::RedeemTicket(ticket) => {
+      let req = Request::new("/api/external_login/ticket")
+        .method(Method::Post)
+        .json(&shared_types::ExternalLoginTicket {
+          ticket,
+          session_cookie: model.inner.session_cookie,
+        });
+      orders.perform_cmd(async {
+        let res: Result<Msg, FetchError> = async {
+          let resp = req?.fetch().await?;
+          match resp.status().code {
+            200 | 201 => Ok(Msg::Login(LoginMsg::LoginSuccess(resp.json().await?))),
+            _ => Ok(Msg::Login(LoginMsg::LoginError(resp.json().await?))),
+          }
+        }
+        .await;
+        match res {
+          Ok(x) => Some(x),
+          Err(e) => {
+            log!("Error occured in ticket redemption", e);
+            None
+          }
+        }
+      });
+      // Don't leave the used ticket in the address bar
+      orders.request_url(Url::new());
+      orders.skip();
+    }
+    LoginMsg::RequestLink => {
+      let req = Request::new("/api/magic_link")
+        .method(Method::Post)
+        .json(&shared_types::MagicLinkRequest {
+          username: model.inner.username.clone(),
+          extended: model.inner.extended,
+        });
+      orders.perform_cmd(async {
+        let res: Result<Msg, FetchError> = async {
+          let resp = req?.fetch().await?;
+          match resp.status().code {
+            204 => Ok(Msg::Login(LoginMsg::LinkRequested)),
+            _ => Ok(Msg::Login(LoginMsg::LoginError(resp.json().await?))),
+          }
+        }
+        .await;
+        match res {
+          Ok(x) => Some(x),
+          Err(e) => {
+            log!("Error occured in login link request", e);
+            None
+          }
+        }
+      });
+      model.logout_message = "";
+      orders.skip();
+    }
+    LoginMsg::LinkRequested => {
+      // The same whether or not a link was sent
+      model.failure_message.clear();
+      model.logout_message = "If the account can log in by link, one has been emailed to it.";
+    }
+    LoginMsg::RedeemLink(token) => {
+      let req = Request::new("/api/magic_link/redeem")
+        .method(Method::Post)
+        .json(&shared_types::MagicLinkToken {
+          token,
+          session_cookie: model.inner.session_cookie,
+        });
+      orders.perform_cmd(async {
+        let res: Result<Msg, FetchError> = async {
+          let resp = req?.fetch().await?;
+          match resp.status().code {
+            200 | 201 => Ok(Msg::Login(LoginMsg::LoginSuccess(resp.json().await?))),
+            _ => Ok(Msg::Login(LoginMsg::LoginError(resp.json().await?))),
+          }
+        }
+        .await;
+        match res {
+          Ok(x) => Some(x),
+          Err(e) => {
+            log!("Error occured in login link redemption", e);
+            None
+          }
+        }
+      });
+      // Don't leave the used token in the address bar
+      orders.request_url(Url::new());
+      orders.skip();
+    }
     LoginMsg::LoginSuccess(s) => {
       model.inner.username.clear();
       model.inner.extended = false;
-      model.failure_message = "";
+      model.failure_message.clear();
+      // If the password must be changed, go to where that is done
+      if s.must_change_password {
+        orders.request_url(Url::new().set_hash_path(&["settings"]));
+      }
       orders.send_msg(Msg::SetAuth(s));
       orders.skip();
     }
     LoginMsg::LoginError(e) => {
       use shared_types::ClientError;
       model.failure_message = match e {
-        ClientError::BadLogin => "Wrong username or password.",
-        ClientError::AccountLocked => "Account locked. Contact administrator.",
+        ClientError::BadLogin => "Wrong username or password.".to_string(),
+        ClientError::AccountLocked { until, reason } => {
+          let mut message = "Account locked".to_string();
+          if let Some(until) = until {
+            message += &format!(" until {} (UTC)", until.format("%Y-%m-%d %H:%M"));
+          }
+          match reason {
+            Some(reason) => message += &format!(": {}", reason),
+            None => message += ". Contact administrator.",
+          }
+          message
+        }
+        ClientError::TooManySessions => {
+          "Too many active sessions, log out elsewhere first.".to_string()
+        }
         _ => {
           log!("Login error:", e);
-          "Internal error"
+          "Internal error".to_string()
         }
       }
     }
@@ -121,5 +231,26 @@ pub(crate) fn login_view(model: &LoginModel) -> Node<LoginMsg> {
         LoginMsg::Submit
       })
     ],
+    if cfg!(feature = "magic_links") {
+      button![
+        C!("magic-link"),
+        attrs!(At::Type => "button"),
+        "Email me a login link instead",
+        ev(Ev::Click, |_| LoginMsg::RequestLink),
+      ]
+    } else {
+      Node::Empty
+    },
+    if cfg!(feature = "external_login") {
+      // Absolute, since seed routes relative links internally
+      let origin = window().location().origin().unwrap_or_default();
+      a![
+        C!("external-login"),
+        attrs!(At::Href => format!("{}/api/external_login", origin)),
+        "Log in with single sign-on",
+      ]
+    } else {
+      Node::Empty
+    },
   ]
 }
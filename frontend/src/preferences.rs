@@ -0,0 +1,76 @@
+// Typed access to the current user's preferences, stored by the backend as
+// json values under a namespace (one per application) and key.
+// Values are loaded with their ETag, which is sent back when saving so that
+// changes made elsewhere since loading aren't overwritten.
+
+use super::*;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+pub struct Preference<T> {
+  pub value: T,
+  pub etag: String,
+}
+
+fn preference_url(namespace: &str, key: &str) -> String {
+  format!("/api/user/preferences/{}/{}", namespace, key)
+}
+
+fn etag_of(resp: &Response) -> String {
+  resp
+    .raw_response()
+    .headers()
+    .get("ETag")
+    .ok()
+    .flatten()
+    .unwrap_or_default()
+}
+
+// Load a value, None if it has never been saved
+pub async fn load_preference<T: DeserializeOwned + 'static>(
+  session: &shared_types::Session,
+  namespace: &str,
+  key: &str,
+) -> Result<Result<Option<Preference<T>>, shared_types::ClientError>, FetchError> {
+  let resp = Request::new(preference_url(namespace, key))
+    .method(Method::Get)
+    .header(auth_header(session))
+    .fetch()
+    .await?;
+  match resp.status().code {
+    200 => Ok(Ok(Some(Preference {
+      etag: etag_of(&resp),
+      value: resp.json().await?,
+    }))),
+    404 => Ok(Ok(None)),
+    _ => Ok(Err(resp.json().await?)),
+  }
+}
+
+// Save a value, returning its new ETag
+// Give the ETag the value was loaded with, or None if it wasn't set, to get
+// PreconditionFailed if it has been changed since. Then load it again and
+// reapply the change.
+pub async fn save_preference<T: Serialize + ?Sized>(
+  session: &shared_types::Session,
+  namespace: &str,
+  key: &str,
+  value: &T,
+  etag: Option<&str>,
+) -> Result<Result<String, shared_types::ClientError>, FetchError> {
+  let precondition = match etag {
+    Some(etag) => Header::custom("If-Match", etag.to_string()),
+    None => Header::custom("If-None-Match", "*"),
+  };
+  let resp = Request::new(preference_url(namespace, key))
+    .method(Method::Put)
+    .header(auth_header(session))
+    .header(precondition)
+    .json(value)?
+    .fetch()
+    .await?;
+  match resp.status().code {
+    201 | 204 => Ok(Ok(etag_of(&resp))),
+    _ => Ok(Err(resp.json().await?)),
+  }
+}
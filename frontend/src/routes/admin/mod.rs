@@ -8,17 +8,30 @@ use shared_types::{
 pub(crate) struct AdminModel {
   users: Option<Vec<AdminReturnableUser>>,
   users_filter: UsersFilter,
+  // Set when an admin request is refused until password is confirmed
+  elevation_required: bool,
+  elevation_password: String,
+  elevation_failure: &'static str,
 }
 impl AdminModel {
   pub(crate) fn new() -> Self {
     Self {
       users: None,
       users_filter: UsersFilter::default(),
+      elevation_required: false,
+      elevation_password: String::new(),
+      elevation_failure: "",
     }
   }
 }
 
 pub(crate) enum AdminMsg {
+  // Sent when any admin request returns ElevationRequired
+  ElevationRequired,
+  SetElevationPassword(String),
+  ElevationSubmit,
+  ElevationSuccess(shared_types::Elevation),
+  ElevationError(shared_types::ClientError),
 }
 pub(crate) fn admin_update(
   msg: AdminMsg,
@@ -27,10 +40,85 @@ pub(crate) fn admin_update(
   orders: &mut impl Orders<Msg>,
 ) {
   match msg {
+    AdminMsg::ElevationRequired => model.elevation_required = true,
+    AdminMsg::SetElevationPassword(x) => model.elevation_password = x,
+    AdminMsg::ElevationSubmit => {
+      let req = Request::new("/api/admin/elevate")
+        .method(Method::Post)
+        .header(auth_header(session))
+        .json(&shared_types::Elevate {
+          password: model.elevation_password.clone(),
+        });
+      orders.perform_cmd(async {
+        let res: Result<AdminMsg, FetchError> = async {
+          let resp = req?.fetch().await?;
+          match resp.status().code {
+            200 => Ok(AdminMsg::ElevationSuccess(resp.json().await?)),
+            _ => Ok(AdminMsg::ElevationError(resp.json().await?)),
+          }
+        }
+        .await;
+        match res {
+          Ok(x) => Some(Msg::Routes(RoutesMsg::Admin(x))),
+          Err(e) => {
+            log!("Error occured in elevation request", e);
+            None
+          }
+        }
+      });
+      model.elevation_password.clear();
+      orders.skip();
+    }
+    AdminMsg::ElevationSuccess(elevation) => {
+      log!("Admin privileges elevated until", elevation.until);
+      model.elevation_required = false;
+      model.elevation_failure = "";
+    }
+    AdminMsg::ElevationError(err) => {
+      use shared_types::ClientError;
+      model.elevation_failure = match err {
+        ClientError::BadLogin => "Wrong password.",
+        ClientError::AccountLocked { .. } => "Account locked.",
+        _ => {
+          log!("Elevation error:", err);
+          "Internal error"
+        }
+      }
+    }
   }
 }
 
+// Re-authentication prompt, shown when admin privileges need elevation
+fn elevation_view(model: &AdminModel) -> Node<AdminMsg> {
+  div![
+    C!("admin-elevation"),
+    div![C!("notice"), "Confirm your password to use admin privileges.",],
+    if !model.elevation_failure.is_empty() {
+      div![C!("error"), &model.elevation_failure,]
+    } else {
+      Node::Empty
+    },
+    form![
+      "Password: ",
+      br!(),
+      input![
+        input_ev(Ev::Change, AdminMsg::SetElevationPassword),
+        attrs!(At::Value => model.elevation_password, At::Type => "password")
+      ],
+      br!(),
+      input![attrs!(At::Value => "Confirm", At::Type => "submit"),],
+      ev(Ev::Submit, |event| {
+        event.prevent_default();
+        AdminMsg::ElevationSubmit
+      })
+    ],
+  ]
+}
+
 pub(crate) fn admin_view(model: &AdminModel) -> Node<AdminMsg> {
+  if model.elevation_required {
+    return elevation_view(model);
+  }
   div![
     C!("admin-user-list"),
     form![
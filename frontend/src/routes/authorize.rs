@@ -0,0 +1,105 @@
+use super::*;
+
+// Confirmation of an OpenID Connect login into another application
+// The backend sends the user here with the authorization request in the query
+pub(crate) struct AuthorizeModel {
+  failure_message: String,
+}
+impl AuthorizeModel {
+  pub(crate) fn new() -> Self {
+    Self {
+      failure_message: String::new(),
+    }
+  }
+}
+
+pub(crate) enum AuthorizeMsg {
+  Allow,
+  Deny,
+  Redirect(shared_types::AuthorizeRedirect),
+  Error(shared_types::ClientError),
+}
+pub(crate) fn authorize_update(
+  msg: AuthorizeMsg,
+  model: &mut AuthorizeModel,
+  session: &shared_types::Session,
+  orders: &mut impl Orders<Msg>,
+) {
+  match msg {
+    AuthorizeMsg::Allow => {
+      // Confirm the same request the backend validated before sending us here
+      let query = window().location().search().unwrap_or_default();
+      let req = Request::new(format!("/api/oidc/authorize{}", query))
+        .method(Method::Post)
+        .header(auth_header(session));
+      orders.perform_cmd(async move {
+        let res: Result<AuthorizeMsg, FetchError> = async {
+          let resp = req.fetch().await?;
+          match resp.status().code {
+            200 => Ok(AuthorizeMsg::Redirect(resp.json().await?)),
+            _ => Ok(AuthorizeMsg::Error(resp.json().await?)),
+          }
+        }
+        .await;
+        match res {
+          Ok(x) => Some(Msg::Routes(RoutesMsg::Authorize(x))),
+          Err(e) => {
+            log!("Error occured in authorization request", e);
+            None
+          }
+        }
+      });
+      orders.skip();
+    }
+    // Leave without a code, clearing out the request
+    AuthorizeMsg::Deny => {
+      if let Err(e) = window().location().set_href("/") {
+        log!("Failed to leave authorization", e);
+      }
+    }
+    // Hand the user back to the application, with code or error
+    AuthorizeMsg::Redirect(redirect) => {
+      if let Err(e) = window().location().set_href(&redirect.redirect) {
+        log!("Failed to redirect to application", e);
+      }
+    }
+    AuthorizeMsg::Error(err) => {
+      use shared_types::ClientError;
+      model.failure_message = match err {
+        ClientError::InvalidClient => "Unknown application or redirect.".to_string(),
+        _ => {
+          log!("Authorization error:", err);
+          "Internal error".to_string()
+        }
+      }
+    }
+  }
+}
+
+pub(crate) fn authorize_view(
+  model: &AuthorizeModel,
+  session: &shared_types::Session,
+  url: &Url,
+) -> Node<AuthorizeMsg> {
+  let client_id = url
+    .search()
+    .get("client_id")
+    .and_then(|values| values.first())
+    .cloned()
+    .unwrap_or_default();
+  div![
+    C!["authorize"],
+    if !model.failure_message.is_empty() {
+      div![C!["error"], br!(), &model.failure_message, br!(),]
+    } else {
+      Node::Empty
+    },
+    format!(
+      "The application \"{}\" wants to log you in as {}.",
+      client_id, session.username
+    ),
+    br!(),
+    button!["Allow", ev(Ev::Click, |_| AuthorizeMsg::Allow)],
+    button!["Deny", ev(Ev::Click, |_| AuthorizeMsg::Deny)],
+  ]
+}
@@ -4,14 +4,22 @@ mod root;
 use root::*;
 mod settings;
 use settings::*;
+pub(crate) use settings::SettingsMsg;
 mod admin;
 use admin::*;
+mod authorize;
+use authorize::*;
+mod terms;
+use terms::*;
+pub(crate) use terms::TermsMsg;
 
 // Model for underlying components
 pub(crate) struct RoutesModel {
   root: RootModel,
   settings: SettingsModel,
   admin: AdminModel,
+  authorize: AuthorizeModel,
+  terms: TermsModel,
 }
 impl RoutesModel {
   pub(crate) fn new() -> Self {
@@ -19,6 +27,8 @@ impl RoutesModel {
       root: RootModel::new(),
       settings: SettingsModel::new(),
       admin: AdminModel::new(),
+      authorize: AuthorizeModel::new(),
+      terms: TermsModel::new(),
     }
   }
 }
@@ -28,6 +38,8 @@ pub(crate) enum RoutesMsg {
   Root(RootMsg),
   Settings(SettingsMsg),
   Admin(AdminMsg),
+  Authorize(AuthorizeMsg),
+  Terms(TermsMsg),
 }
 // Callback handler for those callbacks
 pub(crate) fn routes_update(
@@ -40,19 +52,38 @@ pub(crate) fn routes_update(
     RoutesMsg::Root(msg) => root_update(msg, &mut model.root, orders),
     RoutesMsg::Settings(msg) => settings_update(msg, &mut model.settings, session, orders),
     RoutesMsg::Admin(msg) => admin_update(msg, &mut model.admin, session, orders),
+    RoutesMsg::Authorize(msg) => authorize_update(msg, &mut model.authorize, session, orders),
+    RoutesMsg::Terms(msg) => terms_update(msg, &mut model.terms, session, orders),
   }
 }
 
 pub(crate) fn routes_view(
   model: &RoutesModel,
-  _session: &shared_types::Session,
+  session: &shared_types::Session,
   mut url: Url,
 ) -> Node<RoutesMsg> {
+  // Sessions that must change password can't access anything else
+  if session.must_change_password {
+    return div![
+      div![
+        C!["notice"],
+        "You must change your password before continuing.",
+      ],
+      settings_view(&model.settings).map_msg(|x| RoutesMsg::Settings(x)),
+    ];
+  }
+  // Nor can sessions that must accept the terms, until they have
+  if session.must_accept_terms {
+    return terms_view(&model.terms).map_msg(|x| RoutesMsg::Terms(x));
+  }
   // Match on first part of the path, handing down accordingly
+  let full_url = url.clone();
   match url.next_hash_path_part() {
     None => root_view(&model.root).map_msg(|x| RoutesMsg::Root(x)),
     Some("settings") => settings_view(&model.settings).map_msg(|x| RoutesMsg::Settings(x)),
     Some("admin") => admin_view(&model.admin).map_msg(|x| RoutesMsg::Admin(x)),
+    Some("authorize") => authorize_view(&model.authorize, session, &full_url)
+      .map_msg(|x| RoutesMsg::Authorize(x)),
     // If not an url we know, return a nice error page
     _ => bad_url(url),
   }
@@ -4,11 +4,23 @@ pub(crate) struct SettingsModel {
   inner: shared_types::PasswordChange,
   new_password_verification: String,
   failure_message: &'static str,
-  success_message: &'static str,
+  success_message: String,
+  // The profile form, with the username separate since it's only sent if changed
+  profile: shared_types::UpdateProfile,
+  username: String,
+  current_username: String,
+  // Avatars are uploaded on their own, as soon as a file is picked
+  userid: i32,
+  avatar: Option<String>,
 }
 impl SettingsModel {
   pub(crate) fn new() -> Self {
     Self {
+      profile: shared_types::UpdateProfile::default(),
+      username: String::new(),
+      current_username: String::new(),
+      userid: 0,
+      avatar: None,
       inner: shared_types::PasswordChange {
         old_password: String::new(),
         new_password: String::new(),
@@ -16,7 +28,7 @@ impl SettingsModel {
       },
       new_password_verification: String::new(),
       failure_message: "",
-      success_message: "",
+      success_message: String::new(),
     }
   }
 }
@@ -29,6 +41,28 @@ pub(crate) enum SettingsMsg {
   PasswordChangeSubmit,
   PasswordChangeSuccess(bool),
   PasswordChangeError(shared_types::ClientError),
+  RevokeOtherSessions,
+  RevokeSuccess(shared_types::RevokedSessions),
+  RevokeError(shared_types::ClientError),
+  ProfileLoaded(shared_types::ReturnableUser),
+  SetUsername(String),
+  SetDisplayName(String),
+  SetLocale(String),
+  SetTimezone(String),
+  ProfileSubmit,
+  ProfileSuccess(shared_types::ReturnableUser),
+  ProfileError(shared_types::ClientError),
+  AvatarUpload(web_sys::File),
+  AvatarRemove,
+  AvatarRemoved,
+}
+// Empty form fields clear the profile field
+fn non_empty(x: String) -> Option<String> {
+  if x.trim().is_empty() {
+    None
+  } else {
+    Some(x)
+  }
 }
 pub(crate) fn settings_update(
   msg: SettingsMsg,
@@ -45,7 +79,7 @@ pub(crate) fn settings_update(
       if model.new_password_verification == model.inner.new_password {
         let req = Request::new("/api/user/password")
           .method(Method::Post)
-          .header(Header::bearer(session.key.clone()))
+          .header(auth_header(session))
           .json(&model.inner);
         let clear_sessions = model.inner.clear_sessions;
         orders.perform_cmd(async move {
@@ -65,9 +99,12 @@ pub(crate) fn settings_update(
             }
           }
         });
-        let tmp = model.inner.clear_sessions;
-        *model = SettingsModel::new();
-        model.inner.clear_sessions = tmp;
+        // Clear the password form, keeping the rest
+        model.inner.old_password.clear();
+        model.inner.new_password.clear();
+        model.new_password_verification.clear();
+        model.failure_message = "";
+        model.success_message.clear();
         orders.skip(); // Let the result of the interaction cause re-render instead
       } else {
         model.new_password_verification.clear();
@@ -77,7 +114,8 @@ pub(crate) fn settings_update(
     }
     SettingsMsg::PasswordChangeSuccess(clear_sessions) => {
       if !clear_sessions {
-        model.success_message = "Password changed";
+        model.success_message = "Password changed".to_string();
+        orders.send_msg(Msg::PasswordChanged);
       } else {
         orders.send_msg(Msg::ClearAuth("Password changed and all sessions cleared"));
         orders.skip();
@@ -94,9 +132,234 @@ pub(crate) fn settings_update(
         }
       }
     }
+    SettingsMsg::RevokeOtherSessions => {
+      let req = Request::new("/api/user/sessions?except_current=true")
+        .method(Method::Delete)
+        .header(auth_header(session));
+      orders.perform_cmd(async move {
+        let res: Result<SettingsMsg, FetchError> = async {
+          let resp = req.fetch().await?;
+          match resp.status().code {
+            200 => Ok(SettingsMsg::RevokeSuccess(resp.json().await?)),
+            _ => Ok(SettingsMsg::RevokeError(resp.json().await?)),
+          }
+        }
+        .await;
+        match res {
+          Ok(x) => Some(Msg::Routes(RoutesMsg::Settings(x))),
+          Err(e) => {
+            log!("Error occured in session revocation request", e);
+            None
+          }
+        }
+      });
+      orders.skip();
+    }
+    SettingsMsg::RevokeSuccess(revoked) => {
+      model.failure_message = "";
+      model.success_message = format!("Logged out {} other session(s)", revoked.revoked);
+    }
+    SettingsMsg::RevokeError(err) => {
+      log!("Session revocation error:", err);
+      model.failure_message = "Internal error";
+    }
+    SettingsMsg::ProfileLoaded(user) => {
+      model.username = user.username.clone();
+      model.current_username = user.username;
+      model.profile.display_name = user.display_name;
+      model.profile.locale = user.locale;
+      model.profile.timezone = user.timezone;
+      model.userid = user.id;
+      model.avatar = user.avatar;
+    }
+    SettingsMsg::SetUsername(x) => model.username = x,
+    SettingsMsg::SetDisplayName(x) => model.profile.display_name = non_empty(x),
+    SettingsMsg::SetLocale(x) => model.profile.locale = non_empty(x),
+    SettingsMsg::SetTimezone(x) => model.profile.timezone = non_empty(x),
+    SettingsMsg::ProfileSubmit => {
+      let mut profile = model.profile.clone();
+      if model.username != model.current_username {
+        profile.username = Some(model.username.clone());
+      }
+      let req = Request::new("/api/user")
+        .method(Method::Put)
+        .header(auth_header(session))
+        .json(&profile);
+      orders.perform_cmd(async move {
+        let res: Result<SettingsMsg, FetchError> = async {
+          let resp = req?.fetch().await?;
+          match resp.status().code {
+            200 => Ok(SettingsMsg::ProfileSuccess(resp.json().await?)),
+            _ => Ok(SettingsMsg::ProfileError(resp.json().await?)),
+          }
+        }
+        .await;
+        match res {
+          Ok(x) => Some(Msg::Routes(RoutesMsg::Settings(x))),
+          Err(e) => {
+            log!("Error occured in profile update request", e);
+            None
+          }
+        }
+      });
+      orders.skip();
+    }
+    SettingsMsg::ProfileSuccess(user) => {
+      model.failure_message = "";
+      model.success_message = "Profile saved".to_string();
+      // Updates the session's username, and this form through ProfileLoaded
+      orders.send_msg(Msg::UserdataUpdate(user));
+    }
+    SettingsMsg::ProfileError(err) => {
+      use shared_types::ClientError;
+      model.success_message.clear();
+      model.failure_message = match err {
+        ClientError::UsernameTaken => "That username is already taken",
+        ClientError::InvalidUsername => "That username has disallowed characters or length",
+        ClientError::InvalidLocale => "Locale should be a language tag, such as en-GB",
+        ClientError::InvalidTimezone => "Timezone should be a name such as Europe/Stockholm",
+        ClientError::ProtectedAccount => "This account can't be renamed",
+        ClientError::InvalidImage(_) => "Avatar should be a PNG, JPEG or WebP image",
+        ClientError::ContentTooLarge(_) => "Avatar image is too large",
+        _ => {
+          log!("Profile update error:", err);
+          "Internal error"
+        }
+      }
+    }
+    SettingsMsg::AvatarUpload(file) => {
+      // The browser sets the multipart Content-Type, with its boundary
+      let form = web_sys::FormData::new().expect("Failed to create form");
+      form
+        .append_with_blob("avatar", &file)
+        .expect("Failed to add avatar to form");
+      let req = Request::new("/api/user/avatar")
+        .method(Method::Put)
+        .header(auth_header(session))
+        .body(form.into());
+      orders.perform_cmd(async move {
+        let res: Result<SettingsMsg, FetchError> = async {
+          let resp = req.fetch().await?;
+          match resp.status().code {
+            200 => Ok(SettingsMsg::ProfileSuccess(resp.json().await?)),
+            _ => Ok(SettingsMsg::ProfileError(resp.json().await?)),
+          }
+        }
+        .await;
+        match res {
+          Ok(x) => Some(Msg::Routes(RoutesMsg::Settings(x))),
+          Err(e) => {
+            log!("Error occured in avatar upload request", e);
+            None
+          }
+        }
+      });
+      orders.skip();
+    }
+    SettingsMsg::AvatarRemove => {
+      let req = Request::new("/api/user/avatar")
+        .method(Method::Delete)
+        .header(auth_header(session));
+      orders.perform_cmd(async move {
+        let res: Result<SettingsMsg, FetchError> = async {
+          let resp = req.fetch().await?;
+          match resp.status().code {
+            204 | 404 => Ok(SettingsMsg::AvatarRemoved),
+            _ => Ok(SettingsMsg::ProfileError(resp.json().await?)),
+          }
+        }
+        .await;
+        match res {
+          Ok(x) => Some(Msg::Routes(RoutesMsg::Settings(x))),
+          Err(e) => {
+            log!("Error occured in avatar removal request", e);
+            None
+          }
+        }
+      });
+      orders.skip();
+    }
+    SettingsMsg::AvatarRemoved => {
+      model.avatar = None;
+      model.failure_message = "";
+      model.success_message = "Avatar removed".to_string();
+    }
   }
 }
 
+fn avatar_view(model: &SettingsModel) -> Node<SettingsMsg> {
+  div![
+    "Avatar:",
+    br!(),
+    match &model.avatar {
+      // Only the current version is served, so new uploads are fetched
+      Some(version) => div![
+        img![attrs!(
+          At::Src => format!("/avatars/{}/{}/64", model.userid, version),
+          At::Alt => "Avatar"
+        )],
+        button!["Remove avatar", ev(Ev::Click, |_| SettingsMsg::AvatarRemove)],
+      ],
+      None => Node::Empty,
+    },
+    input![
+      attrs!(At::Type => "file", At::Accept => "image/png,image/jpeg,image/webp"),
+      ev(Ev::Change, |event| {
+        event
+          .target()
+          .and_then(|target| target.dyn_into::<web_sys::HtmlInputElement>().ok())
+          .and_then(|input| input.files())
+          .and_then(|files| files.get(0))
+          .map(SettingsMsg::AvatarUpload)
+      })
+    ],
+  ]
+}
+
+fn profile_view(model: &SettingsModel) -> Node<SettingsMsg> {
+  form![
+    "Username:",
+    br!(),
+    input![
+      input_ev(Ev::Change, SettingsMsg::SetUsername),
+      attrs!(At::Value => model.username)
+    ],
+    br!(),
+    "Display name:",
+    br!(),
+    input![
+      input_ev(Ev::Change, SettingsMsg::SetDisplayName),
+      attrs!(At::Value => model.profile.display_name.as_deref().unwrap_or(""))
+    ],
+    br!(),
+    "Locale:",
+    br!(),
+    input![
+      input_ev(Ev::Change, SettingsMsg::SetLocale),
+      attrs!(
+        At::Value => model.profile.locale.as_deref().unwrap_or(""),
+        At::Placeholder => "en-GB"
+      )
+    ],
+    br!(),
+    "Timezone:",
+    br!(),
+    input![
+      input_ev(Ev::Change, SettingsMsg::SetTimezone),
+      attrs!(
+        At::Value => model.profile.timezone.as_deref().unwrap_or(""),
+        At::Placeholder => "Europe/Stockholm"
+      )
+    ],
+    br!(),
+    input![attrs!(At::Value => "Save profile", At::Type => "submit"),],
+    ev(Ev::Submit, |event| {
+      event.prevent_default();
+      SettingsMsg::ProfileSubmit
+    })
+  ]
+}
+
 pub(crate) fn settings_view(model: &SettingsModel) -> Node<SettingsMsg> {
   div![
     C!["password_change"],
@@ -110,6 +373,13 @@ pub(crate) fn settings_view(model: &SettingsModel) -> Node<SettingsMsg> {
     } else {
       Node::Empty
     },
+    // Shown once loaded, which it isn't while a password change is required
+    if model.current_username.is_empty() {
+      Node::Empty
+    } else {
+      div![avatar_view(model), br!(), profile_view(model)]
+    },
+    br!(),
     form![
       "Old password:",
       br!(),
@@ -144,6 +414,11 @@ pub(crate) fn settings_view(model: &SettingsModel) -> Node<SettingsMsg> {
         event.prevent_default();
         SettingsMsg::PasswordChangeSubmit
       })
-    ]
+    ],
+    br!(),
+    button![
+      "Log out all other sessions",
+      ev(Ev::Click, |_| SettingsMsg::RevokeOtherSessions)
+    ],
   ]
 }
@@ -0,0 +1,124 @@
+use super::*;
+
+// Shown instead of everything else until the latest terms are accepted
+pub(crate) struct TermsModel {
+  terms: Option<shared_types::Terms>,
+  failure_message: &'static str,
+}
+impl TermsModel {
+  pub(crate) fn new() -> Self {
+    Self {
+      terms: None,
+      failure_message: "",
+    }
+  }
+}
+
+pub(crate) enum TermsMsg {
+  Load,
+  Loaded(shared_types::Terms),
+  Accept,
+  Accepted,
+  Error(shared_types::ClientError),
+}
+pub(crate) fn terms_update(
+  msg: TermsMsg,
+  model: &mut TermsModel,
+  session: &shared_types::Session,
+  orders: &mut impl Orders<Msg>,
+) {
+  match msg {
+    TermsMsg::Load => {
+      let req = Request::new("/api/terms").method(Method::Get);
+      orders.perform_cmd(async {
+        let res: Result<TermsMsg, FetchError> = async {
+          let resp = req.fetch().await?;
+          match resp.status().code {
+            200 => Ok(TermsMsg::Loaded(resp.json().await?)),
+            _ => Ok(TermsMsg::Error(resp.json().await?)),
+          }
+        }
+        .await;
+        match res {
+          Ok(x) => Some(Msg::Routes(RoutesMsg::Terms(x))),
+          Err(e) => {
+            log!("Error occured in terms request", e);
+            None
+          }
+        }
+      });
+      orders.skip();
+    }
+    TermsMsg::Loaded(terms) => model.terms = Some(terms),
+    TermsMsg::Accept => {
+      if let Some(terms) = &model.terms {
+        let req = Request::new("/api/user/terms")
+          .method(Method::Post)
+          .header(auth_header(session))
+          .json(&shared_types::AcceptTerms {
+            version: terms.version,
+          });
+        orders.perform_cmd(async {
+          let res: Result<TermsMsg, FetchError> = async {
+            let resp = req?.fetch().await?;
+            match resp.status().code {
+              204 => Ok(TermsMsg::Accepted),
+              _ => Ok(TermsMsg::Error(resp.json().await?)),
+            }
+          }
+          .await;
+          match res {
+            Ok(x) => Some(Msg::Routes(RoutesMsg::Terms(x))),
+            Err(e) => {
+              log!("Error occured in terms acceptance request", e);
+              None
+            }
+          }
+        });
+        model.failure_message = "";
+        orders.skip();
+      }
+    }
+    TermsMsg::Accepted => {
+      *model = TermsModel::new();
+      orders.send_msg(Msg::TermsAccepted);
+    }
+    TermsMsg::Error(err) => {
+      use shared_types::ClientError;
+      model.failure_message = match err {
+        // Revised while being read, so show the new version
+        ClientError::OutdatedTerms => {
+          orders.send_msg(Msg::Routes(RoutesMsg::Terms(TermsMsg::Load)));
+          "The terms have been revised, please read them again."
+        }
+        _ => {
+          log!("Terms error:", err);
+          "Internal error"
+        }
+      }
+    }
+  }
+}
+
+pub(crate) fn terms_view(model: &TermsModel) -> Node<TermsMsg> {
+  div![
+    C!["terms"],
+    div![
+      C!["notice"],
+      "You must accept the terms of service before continuing.",
+    ],
+    if !model.failure_message.is_empty() {
+      div![C!["error"], br!(), &model.failure_message, br!(),]
+    } else {
+      Node::Empty
+    },
+    match &model.terms {
+      Some(terms) => div![
+        h2![format!("Terms of service, version {}", terms.version)],
+        pre![&terms.text],
+        button!["Accept", ev(Ev::Click, |_| TermsMsg::Accept)],
+      ],
+      None => div!["Loading terms..."],
+    },
+  ]
+}
@@ -1,5 +1,6 @@
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 // Login form struct
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -7,6 +8,32 @@ pub struct Login {
   pub username: String,
   pub password: String,
   pub extended: bool, // If true we make session last longer
+  // If true the key is given in a cookie instead (if the server supports it)
+  #[serde(default)]
+  pub session_cookie: bool,
+}
+
+// Redeems the ticket given after logging in through an external provider
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExternalLoginTicket {
+  pub ticket: String,
+  #[serde(default)]
+  pub session_cookie: bool,
+}
+
+// Asks for a login link to be emailed to the user
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MagicLinkRequest {
+  pub username: String,
+  #[serde(default)]
+  pub extended: bool, // As for login, applied to the session the link gives
+}
+// Redeems the token from an emailed login link
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MagicLinkToken {
+  pub token: String,
+  #[serde(default)]
+  pub session_cookie: bool,
 }
 
 // Session struct, describing created Session
@@ -20,6 +47,23 @@ pub struct Session {
   pub is_admin: bool,
   pub username: String,
   pub until: NaiveDateTime,
+  // If true the session can only be used to change password
+  #[serde(default)]
+  pub must_change_password: bool,
+  // The organization the session is scoped to, if any
+  #[serde(default)]
+  pub orgid: Option<i32>,
+  // If true the session can only be used to accept the latest terms
+  #[serde(default)]
+  pub must_accept_terms: bool,
+}
+
+// Short-lived signed token usable in place of the session key
+// Issued for a session, which it is revoked together with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessToken {
+  pub access_token: String,
+  pub until: NaiveDateTime,
 }
 
 // Version of session that can be returned to user without
@@ -57,6 +101,18 @@ pub struct SessionsFilter {
   pub limit: Option<i64>,
 }
 
+// Options for users revoking all their sessions at once
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RevokeSessions {
+  #[serde(default)]
+  pub except_current: bool,
+}
+// Returned by bulk revocation of sessions
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RevokedSessions {
+  pub revoked: u64,
+}
+
 // The same structs for admin, when userid is not implied
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AdminReturnableSession {
@@ -94,6 +150,9 @@ pub struct AdminSessionsFilter {
   #[serde(default)]
   pub order_by: AdminSessionsOrder,
   pub limit: Option<i64>,
+  // Required to revoke every session, rather than revoking them all by mistake
+  #[serde(default)]
+  pub all: bool,
 }
 
 // Cleaned up user for returning to users
@@ -102,6 +161,91 @@ pub struct ReturnableUser {
   pub id: i32,
   pub username: String,
   pub admin: bool,
+  pub display_name: Option<String>,
+  pub locale: Option<String>,
+  pub timezone: Option<String>,
+  pub avatar: Option<String>, // Version of the avatar, None if there is none
+}
+// Form struct for users updating their profile
+// The profile fields are replaced (None clearing them), the username is only
+// changed if given
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct UpdateProfile {
+  #[serde(default)]
+  pub username: Option<String>,
+  pub display_name: Option<String>,
+  pub locale: Option<String>,   // BCP 47 language tag, such as en-GB
+  pub timezone: Option<String>, // IANA time zone name, such as Europe/Stockholm
+}
+// All data held about a user, for the user to download
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserDataExport {
+  pub exported_at: NaiveDateTime,
+  pub user: ExportedUser,
+  pub sessions: Vec<ExportedSession>,
+  pub external_identities: Vec<ExportedExternalIdentity>,
+  pub magic_links: Vec<ExportedMagicLink>,
+  pub authorization_codes: Vec<ExportedAuthorizationCode>,
+  pub organizations: Vec<UserOrganization>,
+  pub terms_acceptances: Vec<ExportedTermsAcceptance>,
+  // By namespace and key
+  pub preferences: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedUser {
+  pub id: i32,
+  pub username: String,
+  pub admin: bool,
+  pub display_name: Option<String>,
+  pub locale: Option<String>,
+  pub timezone: Option<String>,
+  pub avatar: Option<String>,
+  pub email: Option<String>,
+  pub external_id: Option<String>,
+  pub has_password: bool,
+  pub password_changed_at: Option<NaiveDateTime>,
+  pub must_change_password: bool,
+  pub locked: bool,
+  pub locked_until: Option<NaiveDateTime>,
+  pub locked_reason: Option<String>,
+  pub max_sessions: Option<i32>,
+}
+// Keys, codes and hashes are left out, since they are credentials
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedSession {
+  pub id: i32,
+  pub until: NaiveDateTime,
+  pub elevated_until: Option<NaiveDateTime>,
+  pub client: Option<String>, // Name of the OpenID Connect client it was issued to
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedExternalIdentity {
+  pub issuer: String,
+  pub subject: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedMagicLink {
+  pub extended: bool,
+  pub until: NaiveDateTime,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedTermsAcceptance {
+  pub version: i32,
+  pub accepted_at: NaiveDateTime,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedAuthorizationCode {
+  pub client: String,
+  pub scope: String,
+  pub until: NaiveDateTime,
+}
+// Form struct for users deleting their own account
+// Accounts without a password here confirm by having logged in recently
+// instead, such as through the identity provider or a login link
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteAccount {
+  #[serde(default)]
+  pub password: Option<String>,
 }
 // Same with some additional admin-only data
 #[derive(Debug, Serialize, Deserialize)]
@@ -110,6 +254,11 @@ pub struct AdminReturnableUser {
   pub username: String,
   pub admin: bool,
   pub locked: bool,
+  pub locked_until: Option<NaiveDateTime>,
+  pub locked_reason: Option<String>,
+  pub deleted_at: Option<NaiveDateTime>,
+  pub max_sessions: Option<i32>,
+  pub email: Option<String>,
 }
 // Types to filter user lookups (admin only)
 #[derive(Debug, Serialize, Deserialize)]
@@ -136,11 +285,24 @@ pub struct UsersFilter {
   pub username_nregex: Option<String>,
   pub admin_eq: Option<bool>,
   pub locked_eq: Option<bool>,
+  // Locked and the lock hasn't expired
+  pub lock_active_eq: Option<bool>,
+  // Deleted users are only included if this is set
+  #[serde(default)]
+  pub include_deleted: bool,
+  // Only deleted (or not deleted) users, implying include_deleted
+  pub deleted_eq: Option<bool>,
   #[serde(default)]
   pub order_by: UsersOrder,
   pub limit: Option<i64>,
 }
 
+// Query for restoring a deleted user, under another username if given
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RestoreUser {
+  pub username: Option<String>,
+}
+
 // Form struct for users changing their password
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PasswordChange {
@@ -149,6 +311,29 @@ pub struct PasswordChange {
   pub clear_sessions: bool,
 }
 
+// Form struct for admins confirming their password to use admin privileges
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Elevate {
+  pub password: String,
+}
+// Where to send the user to elevate by logging in at the external provider
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExternalElevation {
+  pub uri: String,
+}
+// Until when admin privileges are elevated
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Elevation {
+  pub until: NaiveDateTime,
+}
+
+// A user at the external identity provider, linked for logging in
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExternalIdentity {
+  pub issuer: String,
+  pub subject: String,
+}
+
 // User administration forms
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NewUser {
@@ -157,24 +342,271 @@ pub struct NewUser {
   pub admin: bool,
   #[serde(default)]
   pub locked: bool,
+  #[serde(default)]
+  pub locked_until: Option<NaiveDateTime>,
+  #[serde(default)]
+  pub locked_reason: Option<String>,
+  // Where login links are sent, if any
+  #[serde(default)]
+  pub email: Option<String>,
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UpdateUser {
   pub username: String,
   pub admin: bool,
   pub locked: bool,
+  #[serde(default)]
+  pub locked_until: Option<NaiveDateTime>,
+  #[serde(default)]
+  pub locked_reason: Option<String>,
+  // Overrides the configured limit on concurrent sessions if set, 0 is
+  // unlimited
+  #[serde(default)]
+  pub max_sessions: Option<i32>,
+  #[serde(default)]
+  pub email: Option<String>,
 }
+// A user to create together with its password hash from another system
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Impersonate {
-  pub admin_password: String,
+pub struct ImportUser {
+  pub username: String,
+  // In PHC string format (pbkdf2-sha256, pbkdf2-sha512 or scrypt, not argon2),
+  // or modular crypt format for bcrypt. Without one the user can't log in
+  // by password until one is set.
+  #[serde(default)]
+  pub password_hash: Option<String>,
+  #[serde(default)]
+  pub admin: bool,
+  #[serde(default)]
+  pub email: Option<String>,
+}
+// Query options for importing users
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ImportOptions {
+  // Validate and report on every row without creating any users
+  #[serde(default)]
+  pub dry_run: bool,
+}
+// The outcome for one row of a dry run import, rows counted from 1
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportRowReport {
+  pub row: usize,
+  pub username: Option<String>, // None if the row couldn't be parsed
+  pub error: Option<ClientError>,
+}
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+  Ndjson,
+  Csv,
+}
+impl Default for ExportFormat {
+  fn default() -> Self {
+    Self::Ndjson
+  }
+}
+// Query options for exporting users, besides the UsersFilter
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ExportOptions {
+  #[serde(default)]
+  pub format: ExportFormat,
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PasswordReset {
-  pub admin_password: String,
   pub new_password: String,
   pub clear_sessions: bool,
 }
 
+// OpenID Connect
+// Parameters of an authorization request, in the query part of the URI
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthorizeRequest {
+  pub response_type: String,
+  pub client_id: String,
+  pub redirect_uri: String,
+  pub scope: String,
+  pub state: Option<String>,
+  pub nonce: Option<String>,
+  pub code_challenge: Option<String>,
+  pub code_challenge_method: Option<String>,
+}
+// Where to send the user after authorizing, with code or error in the query
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthorizeRedirect {
+  pub redirect: String,
+}
+// Form posted by clients to the token endpoint
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenRequest {
+  pub grant_type: String,
+  pub code: String,
+  pub redirect_uri: String,
+  pub client_id: String,
+  pub code_verifier: String,
+  pub client_secret: Option<String>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenResponse {
+  pub access_token: String,
+  pub token_type: String,
+  pub expires_in: i64,
+  pub id_token: String,
+  pub scope: String,
+}
+// Error format of the token endpoint, as required by OAuth 2.0
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenError {
+  pub error: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+  pub iss: String,
+  pub sub: String,
+  pub aud: String,
+  pub exp: i64,
+  pub iat: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub nonce: Option<String>,
+  pub preferred_username: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserInfo {
+  pub sub: String,
+  pub preferred_username: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcDiscovery {
+  pub issuer: String,
+  pub authorization_endpoint: String,
+  pub token_endpoint: String,
+  pub userinfo_endpoint: String,
+  pub jwks_uri: String,
+  pub response_types_supported: Vec<String>,
+  pub grant_types_supported: Vec<String>,
+  pub subject_types_supported: Vec<String>,
+  pub id_token_signing_alg_values_supported: Vec<String>,
+  pub scopes_supported: Vec<String>,
+  pub token_endpoint_auth_methods_supported: Vec<String>,
+  pub code_challenge_methods_supported: Vec<String>,
+}
+// Client applications, as administrated
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcClient {
+  pub id: i32,
+  pub client_id: String,
+  pub name: String,
+  pub confidential: bool, // If the client authenticates with a secret
+  pub redirect_uris: Vec<String>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewOidcClient {
+  pub client_id: String,
+  pub name: String,
+  pub confidential: bool,
+  pub redirect_uris: Vec<String>,
+}
+// Only returned on creation, since only the hash of the secret is stored
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreatedOidcClient {
+  #[serde(flatten)]
+  pub client: OidcClient,
+  pub secret: Option<String>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateOidcClient {
+  pub name: String,
+  pub redirect_uris: Vec<String>,
+}
+
+// Tokens for integrations, such as SCIM provisioning, to act as admin
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiToken {
+  pub id: i32,
+  pub name: String,
+  pub created_at: NaiveDateTime,
+  pub last_used_at: Option<NaiveDateTime>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewApiToken {
+  pub name: String,
+}
+// Only returned on creation, since only the hash of the token is stored
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreatedApiToken {
+  #[serde(flatten)]
+  pub api_token: ApiToken,
+  pub token: String,
+}
+
+// Organizations that users belong to, managed by admins and org admins
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Organization {
+  pub id: i32,
+  pub name: String,
+  pub created_at: NaiveDateTime,
+}
+// For both creating and renaming
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewOrganization {
+  pub name: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrganizationMember {
+  pub userid: i32,
+  pub username: String,
+  pub org_admin: bool,
+}
+// Adds the user to the organization if not already a member
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateMembership {
+  #[serde(default)]
+  pub org_admin: bool,
+}
+// An organization the current user belongs to
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserOrganization {
+  pub id: i32,
+  pub name: String,
+  pub org_admin: bool,
+}
+// Scope the current session to another organization, or none
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ActiveOrganization {
+  pub orgid: Option<i32>,
+}
+
+// Terms of service, published by admins in versions
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Terms {
+  pub version: i32,
+  pub text: String,
+  pub published_at: NaiveDateTime,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewTerms {
+  pub text: String,
+}
+// The version being accepted, which must be the latest
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AcceptTerms {
+  pub version: i32,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TermsAcceptance {
+  pub userid: i32,
+  pub username: String,
+  pub version: i32,
+  pub accepted_at: NaiveDateTime,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TermsAcceptancesFilter {
+  pub userid_eq: Option<i32>,
+  pub version_eq: Option<i32>,
+  pub accepted_at_lte: Option<NaiveDateTime>,
+  pub accepted_at_mte: Option<NaiveDateTime>,
+  pub limit: Option<i64>,
+}
+
 // Declare an object for public errors
 // These are fully returned as json to API users
 #[derive(Debug, Serialize, Deserialize)]
@@ -189,14 +621,46 @@ pub enum ClientError {
   PathDataBeforeRoot(String),
   UnreadableHeader(String),
   InvalidContentLength(String),
+  ContentTooLarge(String),
+  BodyTimeout, // A streamed body stalled for too long
   InvalidContentType(String),
   InvalidJson(String),
+  InvalidCsv(String),
+  InvalidMultipart(String),
   InvalidUrlEncoding(String),
   InvalidIndexPath(String),
+  InvalidFilter(String), // Unsupported or malformed SCIM filter
+  InvalidPatch(String),  // Unsupported or malformed SCIM patch operation
 
   // Finally non-parsing user errors
   BadPassword,
   UsernameTaken,
+  InvalidUsername, // Breaks the configured rules on length and characters
+  InvalidEmail,
+  InvalidLocale,
+  InvalidTimezone,
+  InvalidMaxSessions, // A negative limit on concurrent sessions
+  InvalidImage(String),    // Not a PNG, JPEG or WebP image, or too large
+  InvalidPreferenceName,   // Namespaces and keys are 1-64 of A-Z, a-z, 0-9, '-', '_' and '.'
+  PreferencesFull,         // The user's preferences would exceed PREFERENCES_MAX_LEN
+  PreconditionFailed,      // If-Match or If-None-Match didn't hold
+  UnsupportedHash(String), // Imported password hash in an unknown format, by username
   BadLogin,
-  AccountLocked,
+  AccountLocked {
+    until: Option<NaiveDateTime>, // None if locked indefinitely
+    reason: Option<String>,       // Only given if so configured
+  },
+  PasswordChangeRequired,
+  TermsAcceptanceRequired, // The session may only accept the latest terms
+  OutdatedTerms,           // Accepting other than the latest terms
+  ProtectedAccount,        // The reserved system and testing accounts
+  LastAdmin,               // Would leave no admin able to log in
+  ElevationRequired,       // Admin privileges need recent password confirmation
+  TooManySessions,         // Login would exceed the limit on concurrent sessions
+  CsrfMismatch,            // Cookie session used without a matching CSRF token
+  InvalidClient,           // Unknown OpenID Connect client or unregistered redirect URI
+  InvalidRedirectUri,
+  ClientIdTaken,
+  OrganizationNameTaken,
+  ExternalIdentityTaken, // Already linked to a user
 }